@@ -0,0 +1,43 @@
+// gRPC interface of the MS, sharing the auth path (and its worker limit) with the HTTP handler
+use super::{AppError, MsState, authenticate, metrics::AuthOutcome};
+use axum::http::StatusCode;
+use ibc_aaka_scheme::{
+    UserAuthRequest,
+    proto::{self, ms_server::Ms},
+};
+use tonic::{Request, Response, Status};
+
+pub use ibc_aaka_scheme::proto::ms_server::MsServer;
+
+pub struct MsService {
+    pub state: MsState,
+}
+
+impl From<AppError> for Status {
+    fn from(err: AppError) -> Self {
+        let message = err.error.to_string();
+        match err.status {
+            StatusCode::BAD_REQUEST => Status::invalid_argument(message),
+            StatusCode::SERVICE_UNAVAILABLE => Status::unavailable(message),
+            StatusCode::UNAUTHORIZED => Status::unauthenticated(message),
+            _ => Status::internal(message),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Ms for MsService {
+    async fn initiate_auth(
+        &self,
+        request: Request<proto::UserAuthRequest>,
+    ) -> Result<Response<proto::ServerAuthResponse>, Status> {
+        let request = UserAuthRequest::try_from(request.into_inner()).map_err(|e| {
+            self.state.metrics.record(AuthOutcome::DeserializationError);
+            Status::invalid_argument(e.to_string())
+        })?;
+        let (response, ..) = authenticate(&self.state, request, false).await?;
+        let response = proto::ServerAuthResponse::try_from(&response)
+            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(response))
+    }
+}
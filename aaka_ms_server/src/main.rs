@@ -3,29 +3,202 @@ use ark_serialize::{CanonicalDeserialize, CanonicalSerialize}; // For deserializ
 use ark_std::rand::{SeedableRng, rngs::StdRng};
 use axum::{
     Router,
-    extract::State,
-    http::StatusCode,
-    response::{IntoResponse, Json},
-    routing::post,
+    body::Bytes,
+    extract::{
+        State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::{HeaderMap, StatusCode, header},
+    middleware,
+    response::{IntoResponse, Json, Response},
+    routing::{get, post},
 };
+use clap::Parser;
 use dotenvy::dotenv;
+use grpc::{MsServer, MsService};
 use ibc_aaka_scheme::{
-    G1Point, ScalarField, ServerSecretKey, SessionKey, SystemParameters, UserAuthRequest, decrypt,
-    encrypt, server,
+    AAKAError, CheckedPoint, PointValidation, ServerAuthResponse, ServerSecretKey, SessionKey,
+    SystemParameters, Transcript, UserAuthRequest,
+    backoff::Backoff,
+    clock::ClockCheckConfig,
+    config,
+    confirm::{ConfirmDirection, build_confirm, verify_confirm},
+    cors::CorsConfig,
+    deserialize_point,
+    envelope::{ENVELOPE_ALGORITHM, ENVELOPE_HEADER, EnvelopeKey},
+    hash_utils::SESSION_ID_BYTES,
+    http::HttpClientConfig,
+    id::{EncodedId, IdEncoding},
+    keyfile,
+    params_cache::ParamsCache,
+    rc,
+    rc_client::{HttpRcClient, RcClient},
+    replay::{
+        BloomConfig, BloomReplayCache, MemoryReplayCache, ReplayCache, RequestNonce, request_nonce,
+    },
+    request_id, server, session_id,
+    suite::DEFAULT_KEY_LEN,
+    user,
+    wire::{CBOR_CONTENT_TYPE, Cbor, POINT_ENCODING_HEADER, PointEncoding},
 };
+use metrics::{AuthMetrics, AuthOutcome, SessionEvent, SessionStats};
 use parking_lot::RwLock;
-use reqwest::Client;
 // Although state is read-only after init, use RwLock for consistency pattern
 use serde::{Deserialize, Serialize};
-use std::{env, sync::Arc}; // For RNG
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Arc, // For RNG
+    time::{Duration, Instant},
+};
+use tokio::sync::Semaphore;
+use tower_http::{compression::CompressionLayer, limit::RequestBodyLimitLayer};
+
+mod grpc;
+mod metrics;
 
 const MS_STATE_FILE: &str = "ms_state.json"; // File to save MS state
 
+// Command line arguments
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+struct Cli {
+    /// Config file (JSON, TOML or YAML, by extension); when missing only `MS_*` env vars and defaults are used
+    #[arg(long, default_value = "config.json")]
+    config: std::path::PathBuf,
+    /// Override a config entry (repeatable), taking precedence over the file and env, e.g. `--set ms_addr=0.0.0.0:4001`
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    overrides: Vec<String>,
+}
+
+// Load the config from the file, then `MS_*` env vars, then `--set` overrides
+fn load_config(cli: &Cli) -> Result<Config> {
+    Ok(config::load(&cli.config, "MS_", &cli.overrides)?)
+}
+
 #[derive(Deserialize, Debug)]
 struct Config {
     ms_id: String,
+    /// `ms_id` 的编码：`utf8`（默认）、`hex` 或 `base64`，后两者用于含 NUL 或非 UTF-8 字节的 ID
+    #[serde(default)]
+    ms_id_encoding: IdEncoding,
     ms_addr: String,
     rc_url: String, // URL for the Registration Center (RC)
+    /// 无状态模式：不读写 `ms_state.json`，每次启动都重新向 RC 注册
+    #[serde(default)]
+    stateless: bool,
+    /// 同时进行的认证计算上限，超出时直接返回 503
+    #[serde(default = "default_max_concurrent_auth")]
+    max_concurrent_auth: usize,
+    /// gRPC 服务监听地址，未设置时只提供 HTTP 接口
+    #[serde(default)]
+    grpc_addr: Option<String>,
+    /// 定期向 RC 检查系统参数是否轮换的间隔（秒），未设置时不刷新
+    #[serde(default)]
+    params_refresh_secs: Option<u64>,
+    /// 启动时 RC 不可用的重试策略
+    #[serde(default)]
+    rc_retry: RetryConfig,
+    /// 重放缓存的 sled 数据库目录（需要 `persistent-replay` feature），未设置时只保存在内存中
+    #[serde(default)]
+    replay_db: Option<String>,
+    /// 使用布隆过滤器记录请求（见 `BloomReplayCache`），内存固定但有极小的误拒率
+    #[serde(default)]
+    replay_bloom: Option<BloomConfig>,
+    /// 请求体大小上限（字节），超出时返回 413
+    #[serde(default = "default_max_body_bytes")]
+    max_body_bytes: usize,
+    /// 只接受带外层信封（见 `ibc_aaka_scheme::envelope`）的认证请求
+    #[serde(default)]
+    require_envelope: bool,
+    /// 会话密钥的有效期（秒），过期后 `/communicate` 返回 410，未设置时不过期
+    #[serde(default)]
+    session_ttl_secs: Option<u64>,
+    /// 预先签发的密钥文件（见 `ibc_aaka_scheme::keyfile`），设置时直接加载其中的系统参数与私钥，
+    /// 不再向 RC 注册，适用于无法访问 RC 的部署
+    #[serde(default)]
+    key_file: Option<String>,
+    /// 密钥文件的口令，建议通过环境变量 `MS_KEY_FILE_PASSPHRASE` 提供
+    #[serde(default)]
+    key_file_passphrase: Option<String>,
+    /// 允许浏览器跨域访问的配置，未设置时不返回 CORS 头（只允许同源访问）
+    #[serde(default)]
+    cors: Option<CorsConfig>,
+    /// 访问 RC 的 HTTP 客户端的超时与连接池设置
+    #[serde(default)]
+    http: HttpClientConfig,
+    /// `/auth/batch` 一次最多接受的认证请求数，超出时返回 400
+    #[serde(default = "default_max_auth_batch")]
+    max_auth_batch: usize,
+    /// 启动时与参考时间（NTP 服务器或其他节点的 `Date` 头）比较本地时钟，未设置时不检查
+    #[serde(default)]
+    clock_check: Option<ClockCheckConfig>,
+    /// 启动时向 RC 注册一个测试用户，用本节点的私钥与系统参数完成一次握手，失败时拒绝启动
+    #[serde(default)]
+    self_test: bool,
+    /// 解析认证请求中 Ru'、X' 的校验方式：默认 `strict`；`lenient` 跳过子群检查，
+    /// 只应在请求来自可信网关时使用
+    #[serde(default)]
+    point_validation: PointValidation,
+    /// 在此时长（秒）内逐字节相同的认证请求视为客户端重试，返回第一次的响应（相同的 Y、t），
+    /// 而不是按重放拒绝；未设置时一律按重放拒绝
+    #[serde(default)]
+    auth_retry_window_secs: Option<u64>,
+    /// 独立模式：直接使用 `params_hex` 与 `ssk_hex`，不访问 RC、不读写 `ms_state.json`，
+    /// 用于 CI 与本地实验
+    #[serde(default)]
+    standalone: bool,
+    /// 独立模式下的系统参数，格式与 `ms_state.json` 中的 `params` 相同
+    #[serde(default)]
+    params_hex: Option<String>,
+    /// 独立模式下的服务器私钥，格式与 `ms_state.json` 中的 `ssk` 相同，
+    /// 建议通过环境变量 `MS_SSK_HEX` 提供
+    #[serde(default)]
+    ssk_hex: Option<String>,
+    /// `/debug/state` 要求的 `Authorization: Bearer <token>`，未设置时该接口返回 403，
+    /// 建议通过环境变量 `MS_ADMIN_TOKEN` 提供
+    #[serde(default)]
+    admin_token: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+struct RetryConfig {
+    /// 最多尝试的次数（包括第一次）
+    attempts: u32,
+    /// 第一次重试前的等待时间（毫秒），之后每次翻倍
+    backoff_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            backoff_ms: 1000,
+        }
+    }
+}
+
+fn default_max_concurrent_auth() -> usize {
+    std::thread::available_parallelism().map_or(4, |n| n.get())
+}
+
+fn default_max_auth_batch() -> usize {
+    32
+}
+
+// 认证请求的 JSON 编码不到 1 KiB，留出余量给 `/communicate` 的消息
+impl Config {
+    // IDms as bytes, the form the key is bound to
+    fn ms_id_bytes(&self) -> Result<Vec<u8>> {
+        self.ms_id_encoding
+            .decode(&self.ms_id)
+            .context("Invalid ms_id")
+    }
+}
+
+fn default_max_body_bytes() -> usize {
+    64 * 1024
 }
 
 // --- State Management ---
@@ -34,28 +207,184 @@ struct Config {
 #[derive(Clone)]
 struct MsState {
     inner: Arc<RwLock<InnerMsState>>,
+    // Permits for the blocking auth workers; bounds the CPU spent on pairings
+    auth_permits: Arc<Semaphore>,
+    // Digests of recently accepted auth requests, rejects replays within the timestamp window
+    replay: Arc<dyn ReplayCache>,
+    // Auth outcome counters served on /metrics
+    metrics: Arc<AuthMetrics>,
+    // Reject auth requests that are not wrapped in an envelope
+    require_envelope: bool,
+    // Lifetime of an established session, `None` for no expiry
+    session_ttl: Option<Duration>,
+    // Largest number of requests accepted by /auth/batch
+    max_auth_batch: usize,
+    // How the decrypted Ru' and X' of auth requests are validated
+    point_validation: PointValidation,
+    // Responses of recently accepted requests, returned again on an identical retry
+    auth_retries: Option<Arc<AuthRetryCache>>,
+    // Access to and inputs of /debug/state
+    debug: Arc<DebugConfig>,
+}
+
+#[derive(Default)]
+struct DebugConfig {
+    // Bearer token required by /debug/state, `None` disables the endpoint
+    admin_token: Option<String>,
+    // RC probed for reachability, `None` in standalone mode
+    rc_url: Option<String>,
+}
+
+// Accepted auth requests and their outcomes, keyed by request nonce. A client whose response was
+// lost resends the exact same request and gets the same `Y`/`t` back instead of a replay error,
+// so its session key matches the one the MS kept. Returning the cached response to anyone else
+// sending the same bytes reveals nothing beyond the response already sent on the wire.
+struct AuthRetryCache {
+    window: Duration,
+    entries: parking_lot::Mutex<HashMap<RequestNonce, CachedAuth>>,
+}
+
+struct CachedAuth {
+    request: UserAuthRequest,
+    outcome: (ServerAuthResponse, SessionKey, [u8; SESSION_ID_BYTES]),
+    expires_at: Instant,
+}
+
+impl AuthRetryCache {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            entries: Default::default(),
+        }
+    }
+
+    // The cached outcome of `request`, only when the retry is byte-identical to the original
+    fn get(
+        &self,
+        nonce: &RequestNonce,
+        request: &UserAuthRequest,
+    ) -> Option<(ServerAuthResponse, SessionKey, [u8; SESSION_ID_BYTES])> {
+        let now = Instant::now();
+        let entries = self.entries.lock();
+        entries
+            .get(nonce)
+            .filter(|cached| cached.expires_at > now && cached.request == *request)
+            .map(|cached| cached.outcome.clone())
+    }
+
+    fn insert(
+        &self,
+        nonce: RequestNonce,
+        request: &UserAuthRequest,
+        outcome: &(ServerAuthResponse, SessionKey, [u8; SESSION_ID_BYTES]),
+    ) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock();
+        entries.retain(|_, cached| cached.expires_at > now);
+        entries.insert(
+            nonce,
+            CachedAuth {
+                request: request.clone(),
+                outcome: outcome.clone(),
+                expires_at: now + self.window,
+            },
+        );
+    }
+
+    fn len(&self) -> usize {
+        self.entries.lock().len()
+    }
 }
 
 #[derive(Debug)]
 struct InnerMsState {
-    ms_id: String,
+    // IDms as bytes, decoded from the configured `ms_id`
+    ms_id: Vec<u8>,
     params: SystemParameters,
     ssk: ServerSecretKey, // Server's own secret key
     rng: StdRng,          // RNG for server operations (like generating y)
-    sk: Option<SessionKey>,
+    sk: Option<Session>,
+}
+
+// The session established by the last successful handshake
+#[derive(Debug)]
+enum Session {
+    Active {
+        // Derived from the public handshake transcript, identical on the user side
+        id: [u8; SESSION_ID_BYTES],
+        key: SessionKey,
+        established_at: Instant,
+        expires_at: Option<Instant>,
+    },
+    // Past its TTL; the key has been dropped by the sweep
+    Expired,
+}
+
+impl Session {
+    fn new(id: [u8; SESSION_ID_BYTES], key: SessionKey, ttl: Option<Duration>) -> Self {
+        let now = Instant::now();
+        Self::Active {
+            id,
+            key,
+            established_at: now,
+            expires_at: ttl.map(|ttl| now + ttl),
+        }
+    }
+
+    // The session key, or `None` once the TTL has passed (checked lazily on every lookup)
+    fn key(&self) -> Option<&SessionKey> {
+        match self {
+            Self::Active {
+                key, expires_at, ..
+            } if expires_at.is_none_or(|t| Instant::now() < t) => Some(key),
+            _ => None,
+        }
+    }
+
+    // The session ID, `None` once the key has been swept
+    fn id(&self) -> Option<&[u8; SESSION_ID_BYTES]> {
+        match self {
+            Self::Active { id, .. } => Some(id),
+            Self::Expired => None,
+        }
+    }
+
+    // How long ago the session was established, `None` once it is no longer usable
+    fn age(&self) -> Option<Duration> {
+        match self {
+            Self::Active { established_at, .. } if self.key().is_some() => {
+                Some(established_at.elapsed())
+            }
+            _ => None,
+        }
+    }
+
+    // Drop the key material of an expired session, returns whether it did
+    fn sweep(&mut self) -> bool {
+        let expired = matches!(self, Self::Active { .. }) && self.key().is_none();
+        if expired {
+            *self = Self::Expired;
+        }
+        expired
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 struct MsStateTemp {
     ms_id: String,
+    // Files written before binary IDs were supported have no encoding, they are UTF-8
+    #[serde(default)]
+    ms_id_encoding: IdEncoding,
     params: String,
     ssk: String,
 }
 
 impl InnerMsState {
     fn save_to_file(&self, path: &str) -> Result<()> {
+        let ms_id = EncodedId::from_bytes(&self.ms_id);
         let ms_state_temp = MsStateTemp {
-            ms_id: self.ms_id.clone(),
+            ms_id: ms_id.id,
+            ms_id_encoding: ms_id.id_encoding,
             params: ark_to_hex(&self.params).context("Failed to serialize system parameter P")?,
             ssk: ark_to_hex(&self.ssk).context("Failed to serialize server secret key")?,
         };
@@ -66,21 +395,26 @@ impl InnerMsState {
     }
 
     fn load_from_file(path: &str) -> Result<Self> {
-        // FIX:
-        bail!("CanonicalDeserialize 似乎有bug");
-
         let data = std::fs::read_to_string(path).context("Failed to read MS state file")?;
         let ms_state_temp: MsStateTemp =
             serde_json::from_str(&data).context("Failed to deserialize MS state")?;
 
-        let params = hex_to_ark(&ms_state_temp.params)
-            .context("Failed to deserialize system parameters from hex")?;
+        // `TryFrom<&[u8]>` checks the points and validates the params
+        let params_bytes = hex::decode(&ms_state_temp.params)
+            .context("Failed to decode system parameters from hex")?;
+        let params = SystemParameters::try_from(&params_bytes[..])
+            .context("Invalid system parameters in MS state file")?;
 
-        let ssk = hex_to_ark(&ms_state_temp.ssk)
-            .context("Failed to deserialize server secret key from hex")?;
+        let ssk_bytes = hex::decode(&ms_state_temp.ssk)
+            .context("Failed to decode server secret key from hex")?;
+        let ssk = ServerSecretKey::try_from(&ssk_bytes[..])
+            .context("Invalid server secret key in MS state file")?;
 
         Ok(Self {
-            ms_id: ms_state_temp.ms_id,
+            ms_id: ms_state_temp
+                .ms_id_encoding
+                .decode(&ms_state_temp.ms_id)
+                .context("Invalid server ID in MS state file")?,
             params,
             ssk,
             sk: None,
@@ -94,17 +428,18 @@ impl InnerMsState {
 // UserAuthRequest is defined in the library, but we need to deserialize it from JSON.
 // We expect the JSON fields to match the UserAuthRequest struct fields,
 // potentially with hex-encoded points/scalars.
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 struct AuthRequestPayload {
     // Assume points and scalars are sent as hex strings from the client
     m_hex: String,
     n: String, // N is Vec<u8>, maybe base64 encode it? Or keep as hex? Let's try hex.
     sigma_hex: String,
     timestamp: u64,
+    suite_id: u16,
 }
 
 // ServerAuthResponse is defined in the library, but we need to serialize it to JSON.
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 struct AuthResponsePayload {
     // Serialize points and scalars to hex strings
     t_hex: String,
@@ -119,27 +454,27 @@ struct AuthSuccessResponse {
     // In a real app, we wouldn't send the key back!
     // For demo purposes ONLY:
     session_key_hex: String,
+    // Public, the user derives the same ID from its own transcript
+    session_id_hex: String,
 }
 
-// --- Data structure for RC /register/server response ---
-#[derive(Deserialize, Debug)]
-struct RcServerRegistrationResponse {
-    sid_ms_hex: String,
+#[derive(Deserialize, Serialize)]
+struct Ciphertext {
+    text: Vec<u8>,
+    nonce: Vec<u8>,
 }
 
-// --- Data structure for RC /params response ---
-#[derive(Deserialize, Debug)]
-struct RcSystemParametersResponse {
-    p_hex: String,
-    p_pub_hex: String,
-    p_pub_hat_hex: String,
-    g_hex: String,
+#[derive(Deserialize, Serialize)]
+struct ConfirmRequest {
+    session_id_hex: String,
+    // The user's client-to-server confirmation value
+    confirm_hex: String,
 }
 
 #[derive(Deserialize, Serialize)]
-struct Ciphertext {
-    text: Vec<u8>,
-    nonce: Vec<u8>,
+struct ConfirmResponse {
+    // The MS's server-to-client confirmation value
+    confirm_hex: String,
 }
 
 // --- Utility Functions ---
@@ -151,6 +486,13 @@ fn hex_to_ark<T: CanonicalDeserialize>(hex_str: &str) -> Result<T> {
     T::deserialize_compressed(&bytes[..]).map_err(|e| anyhow!("Ark Deserialization failed: {}", e))
 }
 
+// Helper to deserialize a curve point from hex, rejecting identity/off-curve/off-subgroup points
+fn hex_to_point<P: CheckedPoint>(hex_str: &str) -> Result<P> {
+    let bytes = hex::decode(hex_str)
+        .map_err(|e| anyhow!("Hex decoding failed for '{}': {}", hex_str, e))?;
+    deserialize_point(&bytes).map_err(|e| anyhow!("Invalid curve point: {}", e))
+}
+
 // Helper to serialize arkworks types to hex string
 // FIX:
 fn ark_to_hex<T: CanonicalSerialize>(item: &T) -> Result<String> {
@@ -162,296 +504,2967 @@ fn ark_to_hex<T: CanonicalSerialize>(item: &T) -> Result<String> {
 
 #[test]
 fn test_serde() {
-    let p = G1Point::default();
+    let p = ibc_aaka_scheme::G1Point::default();
 
     let mut buf = Vec::new();
     p.serialize_compressed(&mut buf).unwrap();
-    let deserialized: G1Point = G1Point::deserialize_compressed(&buf[..]).unwrap();
+    let _deserialized: ibc_aaka_scheme::G1Point =
+        ibc_aaka_scheme::G1Point::deserialize_compressed(&buf[..]).unwrap();
 }
 
 // --- Axum Handler ---
 
-// Handler for POST /auth/initiate
-async fn handle_auth_request(
-    State(state): State<MsState>,
-    Json(payload): Json<AuthRequestPayload>,
-) -> Result<Json<AuthSuccessResponse>, AppError> {
-    println!("Received authentication request");
-    let mut state_locked = state.inner.write(); // Read lock should be sufficient
-    let mut rng = state_locked.rng.clone(); // Clone RNG if needed per request, or lock state_write
-
-    // 1. Deserialize request data from hex/base64
-    let m: G1Point = hex_to_ark(&payload.m_hex).context("Failed to deserialize M from hex")?;
-    let n_bytes = hex::decode(&payload.n).context("Failed to decode N from hex")?;
-    let sigma: ScalarField =
-        hex_to_ark(&payload.sigma_hex).context("Failed to deserialize sigma from hex")?;
-
-    let request = UserAuthRequest {
-        m,
-        n: n_bytes,
-        sigma,
-        timestamp: payload.timestamp,
-    };
+// Whether a header value names the CBOR media type
+fn is_cbor(headers: &HeaderMap, name: header::HeaderName) -> bool {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains(CBOR_CONTENT_TYPE))
+}
 
-    // 2. Call the core library function
-    // Assuming key_len_bytes is fixed for this server instance
-    let key_len_bytes = 32; // e.g., AES-256
+// Decode an auth request from CBOR or hex JSON, depending on `Content-Type`
+fn parse_auth_request(headers: &HeaderMap, body: &[u8]) -> Result<UserAuthRequest> {
+    if is_cbor(headers, header::CONTENT_TYPE) {
+        return Ok(UserAuthRequest::from_cbor(body)?);
+    }
+    let payload: AuthRequestPayload =
+        serde_json::from_slice(body).context("Failed to parse JSON auth request")?;
+    request_from_payload(payload)
+}
 
-    let server_result = server::process_user_request(
-        &state_locked.ssk,
-        &request,
-        state_locked.ms_id.as_bytes(), // Server's own ID
-        &state_locked.params,
-        &mut rng, // Pass the cloned RNG
-        key_len_bytes,
-    );
+fn request_from_payload(payload: AuthRequestPayload) -> Result<UserAuthRequest> {
+    Ok(UserAuthRequest {
+        m: hex_to_point(&payload.m_hex).map_err(|e| InvalidField::new("m_hex", e))?,
+        n: hex::decode(&payload.n).map_err(|e| InvalidField::new("n", e))?,
+        sigma: hex_to_ark(&payload.sigma_hex).map_err(|e| InvalidField::new("sigma_hex", e))?,
+        timestamp: payload.timestamp,
+        suite_id: payload.suite_id,
+    })
+}
 
-    match server_result {
-        Ok((response, session_key)) => {
-            println!(
-                "Authentication successful. Server Session Key: {}",
-                hex::encode(&session_key.0)
-            );
+// A request field that could not be decoded; the error response names it in `field`
+#[derive(Debug)]
+struct InvalidField {
+    field: &'static str,
+    reason: String,
+}
 
-            // 3. Serialize the response to hex JSON format
-            let response_payload = AuthResponsePayload {
-                t_hex: ark_to_hex(&response.t)?,
-                y_hex: ark_to_hex(&response.y)?,
-                timestamp: response.timestamp,
-            };
+impl InvalidField {
+    fn new(field: &'static str, reason: impl fmt::Display) -> Self {
+        Self {
+            field,
+            reason: reason.to_string(),
+        }
+    }
+}
 
-            let success_response = AuthSuccessResponse {
-                message: "Authentication successful".to_string(),
-                response: response_payload,
-                session_key_hex: hex::encode(&session_key.0), // DEMO ONLY
-            };
+impl fmt::Display for InvalidField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid {}: {}", self.field, self.reason)
+    }
+}
 
-            state_locked.sk = Some(session_key);
+impl std::error::Error for InvalidField {}
 
-            Ok(Json(success_response))
-        }
-        Err(e) => {
-            println!("Authentication failed: {:?}", e);
-            // Convert specific AAKAError types to appropriate HTTP status codes if desired
-            // For now, just return a generic error via AppError
-            Err(AppError(anyhow!("Authentication failed: {}", e)))
-        }
+// Unwrap the outer envelope when the request carries the envelope header
+fn open_envelope(state: &MsState, headers: &HeaderMap, body: Bytes) -> Result<Bytes> {
+    let Some(algorithm) = headers.get(ENVELOPE_HEADER) else {
+        return Ok(body);
+    };
+    if algorithm.as_bytes() != ENVELOPE_ALGORITHM.as_bytes() {
+        bail!("Unsupported envelope algorithm {algorithm:?}");
     }
+    let key = {
+        let state_locked = state.inner.read();
+        EnvelopeKey::derive(&state_locked.params, &state_locked.ms_id)?
+    };
+    Ok(key.open(&body)?.into())
 }
 
-async fn handle_communicate(
-    State(state): State<MsState>,
-    Json(payload): Json<Ciphertext>,
-) -> Result<Json<Ciphertext>, AppError> {
-    let state_locked = state.inner.read();
-    let Some(sk) = &state_locked.sk else {
-        return Err(AppError(anyhow!("Need auth first!")));
-    };
+// Handler for GET /metrics
+async fn handle_metrics(State(state): State<MsState>) -> impl IntoResponse {
+    let ms_id = EncodedId::from_bytes(&state.inner.read().ms_id).to_string();
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(&ms_id, &session_stats(&state)),
+    )
+}
 
-    let mut key = [0; 32];
-    key.clone_from_slice(&sk.0[..32]);
+// Handler for GET /sessions/stats
+// Counts and ages only, never session IDs or keys
+async fn handle_session_stats(State(state): State<MsState>) -> Json<SessionStats> {
+    Json(session_stats(&state))
+}
 
-    let msg = String::from_utf8(
-        decrypt(&key, &payload.text, &payload.nonce)
-            .map_err(|e| AppError(anyhow!(e.to_string())))?,
-    )?;
+fn session_stats(state: &MsState) -> SessionStats {
+    let ages: Vec<Duration> = state
+        .inner
+        .read()
+        .sk
+        .iter()
+        .filter_map(Session::age)
+        .collect();
+    state.metrics.session_stats(&ages)
+}
 
-    println!("Received message: {msg}");
+#[derive(Serialize)]
+struct DebugState {
+    ms_id: String,
+    build: BuildInfo,
+    params_fingerprint: String,
+    ssk_loaded: bool,
+    ssk_fingerprint: String,
+    sessions: SessionStats,
+    // `None` for caches that do not count entries (bloom filter) or are disabled
+    replay_cache_entries: Option<usize>,
+    auth_retry_cache_entries: Option<usize>,
+    rc: Option<RcReachability>,
+}
 
-    let msg = "Hi Any";
+#[derive(Serialize)]
+struct BuildInfo {
+    package: &'static str,
+    version: &'static str,
+}
 
-    println!("Sending message: {msg}");
+#[derive(Serialize)]
+struct RcReachability {
+    url: String,
+    // The RC answered /ready at all, and answered it with a success status
+    reachable: bool,
+    ready: bool,
+}
 
-    let (ciphertext, nonce) =
-        encrypt(&key, msg.as_bytes()).map_err(|e| AppError(anyhow!(e.to_string())))?;
+// Upper bound on the RC probe made by /debug/state
+const DEBUG_PROBE_TIMEOUT: Duration = Duration::from_millis(500);
 
-    let resp = Ciphertext {
-        text: ciphertext,
-        nonce,
+// Handler for GET /debug/state
+// 供现场排查使用的节点状态：只有指纹、计数与可达性，不包含私钥、会话密钥等任何秘密值
+async fn handle_debug_state(
+    State(state): State<MsState>,
+    headers: HeaderMap,
+) -> Result<Json<DebugState>, AppError> {
+    let Some(expected) = &state.debug.admin_token else {
+        return Err(AppError::forbidden(anyhow!(
+            "Debug endpoints are disabled, set admin_token to enable them"
+        )));
     };
+    let provided = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !provided.is_some_and(|provided| token_matches(expected, provided)) {
+        return Err(AppError::forbidden(anyhow!(
+            "Missing or invalid admin token"
+        )));
+    }
 
-    Ok(axum::Json(resp))
+    let (ms_id, params_fingerprint, ssk_fingerprint) = {
+        let inner = state.inner.read();
+        (
+            EncodedId::from_bytes(&inner.ms_id).to_string(),
+            inner.params.fingerprint(),
+            inner.ssk.fingerprint(),
+        )
+    };
+    let rc = match &state.debug.rc_url {
+        Some(url) => Some(probe_rc(url).await),
+        None => None,
+    };
+    Ok(Json(DebugState {
+        ms_id,
+        build: BuildInfo {
+            package: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+        },
+        params_fingerprint,
+        ssk_loaded: true,
+        ssk_fingerprint,
+        sessions: session_stats(&state),
+        replay_cache_entries: state.replay.entries(),
+        auth_retry_cache_entries: state.auth_retries.as_ref().map(|cache| cache.len()),
+        rc,
+    }))
 }
-// --- Main Application Setup ---
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    dotenv().ok();
+// 比较耗时与令牌内容无关
+fn token_matches(expected: &str, provided: &str) -> bool {
+    expected.len() == provided.len()
+        && expected
+            .bytes()
+            .zip(provided.bytes())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
 
-    let config: Config = serde_json::from_str(&std::fs::read_to_string("config.json")?)?;
-    let Config {
-        ms_id,
-        ms_addr,
-        rc_url,
-    } = config;
+// Probe the RC's /ready once, without retrying
+async fn probe_rc(url: &str) -> RcReachability {
+    let client = reqwest::Client::builder()
+        .timeout(DEBUG_PROBE_TIMEOUT)
+        .build()
+        .unwrap_or_default();
+    let response = client.get(format!("{url}/ready")).send().await;
+    RcReachability {
+        url: url.to_string(),
+        reachable: response.is_ok(),
+        ready: response.is_ok_and(|res| res.status().is_success()),
+    }
+}
 
-    let ms_state = if let Ok(state) = InnerMsState::load_from_file(MS_STATE_FILE) {
-        println!("Loaded existing MS state from file {MS_STATE_FILE}.");
-        MsState {
-            inner: Arc::new(RwLock::new(state)),
-        }
-    } else {
-        // --- Load/Fetch System Parameters ---
-        println!("Fetching system parameters...");
-        let client = reqwest::Client::new();
-        let params_rc_url = format!("{rc_url}/params");
-        let resp = client.get(&params_rc_url).send().await.context(format!(
-            "Failed to connect to RC params endpoint: {params_rc_url}"
-        ))?;
+// Handler for POST /auth/initiate
+// The request is CBOR when `Content-Type: application/cbor`, JSON otherwise;
+// the response is CBOR when `Accept: application/cbor`, JSON otherwise.
+async fn handle_auth_request(
+    State(state): State<MsState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, AppError> {
+    println!("Received authentication request");
 
-        if !resp.status().is_success() {
-            let status = resp.status();
-            let body = resp
-                .text()
-                .await
-                .unwrap_or_else(|_| "Failed to read body".into());
-            return Err(anyhow!(
-                "RC returned error status {} when fetching params: {}",
-                status,
-                body
-            ));
+    // 1. Unwrap the envelope, rejecting tampered bodies before any protocol processing
+    let body = match open_envelope(&state, &headers, body) {
+        Ok(body) => body,
+        Err(e) => {
+            state.metrics.record(AuthOutcome::DeserializationError);
+            return Err(AppError::bad_request(e));
+        }
+    };
+
+    // 2. Deserialize request data from CBOR or hex JSON; a malformed payload is the client's fault
+    let request = match parse_auth_request(&headers, &body) {
+        Ok(request) => request,
+        Err(e) => {
+            state.metrics.record(AuthOutcome::DeserializationError);
+            return Err(AppError::bad_request(e));
         }
+    };
 
-        let params_resp: RcSystemParametersResponse = resp
-            .json()
-            .await
-            .context("Failed to parse JSON params response from RC")?;
+    // Point encoding asked for the CBOR response, compressed unless the client opts out
+    let encoding = match headers.get(POINT_ENCODING_HEADER) {
+        Some(value) => value
+            .to_str()
+            .map_err(|e| AppError::bad_request(anyhow!("Invalid {POINT_ENCODING_HEADER}: {e}")))?
+            .parse::<PointEncoding>()
+            .map_err(AppError::bad_request)?,
+        None => PointEncoding::default(),
+    };
 
-        println!("Deserializing parameters received from RC...");
-        let params = SystemParameters {
-            p: hex_to_ark(&params_resp.p_hex).context("Failed to load param P from RC response")?,
-            p_pub: hex_to_ark(&params_resp.p_pub_hex)
-                .context("Failed to load param Ppub from RC response")?,
-            p_pub_hat: hex_to_ark(&params_resp.p_pub_hat_hex)
-                .context("Failed to load param Ppub_hat from RC response")?,
-            g: hex_to_ark(&params_resp.g_hex).context("Failed to load param G from RC response")?,
-        };
-        println!("Parameters loaded successfully from RC.");
-
-        // --- Load Server Secret Key (must be present in env) ---
-        println!("Loading server secret key...");
-        let register_url = format!("{rc_url}/register/server");
-
-        #[derive(Serialize)] // Need Serialize for the request body
-        struct RegisterPayload<'a> {
-            id: &'a str,
-        }
-        let payload = RegisterPayload { id: &ms_id };
-
-        let resp = client
-            .post(&register_url)
-            .json(&payload)
-            .send()
-            .await
-            .context(format!(
-                "Failed to send registration request to RC: {}",
-                &register_url
-            ))?;
+    let enveloped = headers.contains_key(ENVELOPE_HEADER);
+    let (response, session_key, session_id) = authenticate(&state, request, enveloped).await?;
 
-        if !resp.status().is_success() {
-            let status = resp.status();
-            let body = resp
-                .text()
-                .await
-                .unwrap_or_else(|_| "Failed to read body".into());
-            return Err(anyhow!(
-                "RC returned error status {} during server registration: {}",
-                status,
-                body
-            ));
-        }
+    // 3. Serialize the response in the requested format
+    if is_cbor(&headers, header::ACCEPT) {
+        return Ok((
+            [(header::CONTENT_TYPE, CBOR_CONTENT_TYPE)],
+            response.to_cbor_with(encoding)?,
+        )
+            .into_response());
+    }
 
-        let reg_resp: RcServerRegistrationResponse = resp
-            .json()
-            .await
-            .context("Failed to parse JSON registration response from RC")?;
+    let success_response = AuthSuccessResponse {
+        message: "Authentication successful".to_string(),
+        response: response_payload(&response)?,
+        session_key_hex: hex::encode(&session_key.0), // DEMO ONLY
+        session_id_hex: hex::encode(session_id),
+    };
 
-        println!("Successfully registered with RC. Deserializing received key...");
-        let ssk = ServerSecretKey {
-            sid_ms: hex_to_ark(&reg_resp.sid_ms_hex)
-                .context("Failed to load server key SIDms (G2) from RC response")?,
-        };
-        println!("Server secret key obtained successfully from RC.");
+    Ok(Json(success_response).into_response())
+}
 
-        let ms_state = InnerMsState {
-            ms_id,
-            params,
-            ssk,
-            rng: StdRng::from_entropy(),
-            sk: None,
-        };
+fn response_payload(response: &ServerAuthResponse) -> Result<AuthResponsePayload> {
+    Ok(AuthResponsePayload {
+        t_hex: ark_to_hex(&response.t)?,
+        y_hex: ark_to_hex(&response.y)?,
+        timestamp: response.timestamp,
+    })
+}
 
-        // Save the state to file for future runs
-        ms_state
-            .save_to_file(MS_STATE_FILE)
-            .context("Failed to save MS state to file")?;
+// Frames exchanged on /auth/ws, one JSON text frame per message
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WsFrame {
+    AuthRequest(AuthRequestPayload),
+    AuthResponse(AuthResponsePayload),
+    Error { message: String },
+}
 
-        println!("MS state saved to file {MS_STATE_FILE}.");
+// Handler for GET /auth/ws
+// Carries the handshake as discrete frames over one connection, so that multi-message
+// extensions can be added later; every request frame is answered by one response or error frame.
+async fn handle_auth_ws(State(state): State<MsState>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(move |socket| auth_ws_session(state, socket))
+}
 
-        MsState {
-            inner: Arc::new(RwLock::new(ms_state)),
+async fn auth_ws_session(state: MsState, mut socket: WebSocket) {
+    while let Some(Ok(message)) = socket.recv().await {
+        let reply = match message {
+            Message::Text(text) => auth_ws_reply(&state, &text).await,
+            Message::Binary(_) => WsFrame::Error {
+                message: "Binary frames are not supported".to_string(),
+            },
+            Message::Close(_) => break,
+            // Pings are answered by axum
+            Message::Ping(_) | Message::Pong(_) => continue,
+        };
+        let Ok(reply) = serde_json::to_string(&reply) else {
+            break;
+        };
+        if socket.send(Message::Text(reply.into())).await.is_err() {
+            break;
         }
-    };
+    }
+}
 
-    // --- Build Axum app ---
-    let app = Router::new()
-        .route("/auth/initiate", post(handle_auth_request))
-        .route("/communicate", post(handle_communicate))
-        .with_state(ms_state);
+async fn auth_ws_reply(state: &MsState, text: &str) -> WsFrame {
+    let request = serde_json::from_str::<WsFrame>(text)
+        .context("Failed to parse WebSocket frame")
+        .and_then(|frame| match frame {
+            WsFrame::AuthRequest(payload) => request_from_payload(payload),
+            _ => Err(anyhow!("Expected an auth_request frame")),
+        });
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => {
+            state.metrics.record(AuthOutcome::DeserializationError);
+            return WsFrame::Error {
+                message: format!("{e:#}"),
+            };
+        }
+    };
 
-    // --- Run the server ---
-    let listener = tokio::net::TcpListener::bind(&ms_addr).await?;
-    println!("MS Server listening on {}", listener.local_addr()?);
-    axum::serve(listener, app).await?;
+    match authenticate(state, request, false).await {
+        Ok((response, ..)) => match response_payload(&response) {
+            Ok(payload) => WsFrame::AuthResponse(payload),
+            Err(e) => WsFrame::Error {
+                message: e.to_string(),
+            },
+        },
+        Err(e) => WsFrame::Error {
+            message: e.error.to_string(),
+        },
+    }
+}
 
+// Refuse requests that did not arrive in an envelope when envelopes are required.
+// gRPC and WebSocket requests cannot carry one, so they are always refused then.
+fn check_envelope(state: &MsState, enveloped: bool) -> Result<(), AppError> {
+    if state.require_envelope && !enveloped {
+        state.metrics.record(AuthOutcome::DeserializationError);
+        return Err(AppError::bad_request(anyhow!(
+            "Auth request must be wrapped in an envelope"
+        )));
+    }
     Ok(())
 }
-// --- Custom Error Type for Axum (same as in RC app) ---
-struct AppError(anyhow::Error);
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> axum::response::Response {
-        eprintln!("Error occurred: {:?}", self.0);
+// Run the server side of the key agreement and remember the session key under its session ID
+async fn authenticate(
+    state: &MsState,
+    request: UserAuthRequest,
+    enveloped: bool,
+) -> Result<(ServerAuthResponse, SessionKey, [u8; SESSION_ID_BYTES]), AppError> {
+    check_envelope(state, enveloped)?;
+
+    // An identical retry of an accepted request gets the original response without new work
+    if let Some(retries) = &state.auth_retries
+        && let Some(outcome) = retries.get(&request_nonce(&request)?, &request)
+    {
+        tracing::info!(
+            session_id = %hex::encode(outcome.2),
+            "Returning the cached response to a retried auth request"
+        );
+        return Ok(outcome);
+    }
+
+    // Reject instead of queueing when all workers are busy
+    let permit = state
+        .auth_permits
+        .clone()
+        .try_acquire_owned()
+        .map_err(|_| AppError::unavailable(anyhow!("Server busy, too many auth requests")))?;
+
+    let (ssk, ms_id, params, mut rng) = {
+        let mut state_locked = state.inner.write();
+        // Fork a fresh RNG per request so concurrent requests never reuse randomness
+        let rng = StdRng::from_rng(&mut state_locked.rng)?;
         (
-            StatusCode::INTERNAL_SERVER_ERROR, // Or map specific errors (e.g., Bad Request for deserialization)
-            format!("Error: {}", self.0),
+            state_locked.ssk.clone(),
+            state_locked.ms_id.clone(),
+            state_locked.params.clone(),
+            rng,
         )
-            .into_response()
-    }
+    };
+
+    // 2. Call the core library function on the blocking pool
+    // Assuming key_len_bytes is fixed for this server instance
+    let key_len_bytes = DEFAULT_KEY_LEN; // e.g., AES-256
+    let point_validation = state.point_validation;
+
+    let server_result = tokio::task::spawn_blocking({
+        let request = request.clone();
+        move || {
+            let _permit = permit;
+            server::process_user_request_with_validation(
+                &ssk,
+                &request,
+                &ms_id, // Server's own ID
+                &params,
+                &mut rng,
+                key_len_bytes,
+                &[],
+                point_validation,
+            )
+        }
+    })
+    .await?;
+
+    complete_auth(state, &request, server_result)
 }
 
-impl<E> From<E> for AppError
-where
-    E: Into<anyhow::Error>,
-{
-    fn from(err: E) -> Self {
-        Self(err.into())
+// Record the outcome of one processed request; on success check for replays and keep the session
+fn complete_auth(
+    state: &MsState,
+    request: &UserAuthRequest,
+    server_result: Result<(ServerAuthResponse, SessionKey, Transcript), AAKAError>,
+) -> Result<(ServerAuthResponse, SessionKey, [u8; SESSION_ID_BYTES]), AppError> {
+    match server_result {
+        Ok((response, session_key, transcript)) => {
+            // Only verified requests are recorded, so forged requests cannot fill the cache
+            let nonce = request_nonce(request)?;
+            if !state.replay.check_and_insert(&nonce, request.timestamp)? {
+                state.metrics.record(AuthOutcome::Replay);
+                return Err(AppError::replayed());
+            }
+
+            state.metrics.record(AuthOutcome::Success);
+            let session_id = session_id(&transcript)?;
+            tracing::info!(
+                session_id = %hex::encode(session_id),
+                outcome = AuthOutcome::Success.label(),
+                "Authentication successful"
+            );
+            // 会话密钥只在显式开启 `unsafe-log-keys` 时写入日志，仅用于调试
+            #[cfg(feature = "unsafe-log-keys")]
+            tracing::warn!(
+                session_id = %hex::encode(session_id),
+                session_key = %hex::encode(&session_key.0),
+                "Logging the session key (unsafe-log-keys)"
+            );
+
+            let previous = state.inner.write().sk.replace(Session::new(
+                session_id,
+                session_key.clone(),
+                state.session_ttl,
+            ));
+            // The store holds one session, a new handshake evicts the previous one
+            match previous {
+                Some(previous) if previous.key().is_some() => {
+                    state.metrics.record_session(SessionEvent::Replaced)
+                }
+                Some(Session::Active { .. }) => state.metrics.record_session(SessionEvent::Expired),
+                _ => {}
+            }
+            state.metrics.record_session(SessionEvent::Established);
+
+            let outcome = (response, session_key, session_id);
+            if let Some(retries) = &state.auth_retries {
+                retries.insert(nonce, request, &outcome);
+            }
+            Ok(outcome)
+        }
+        Err(e) => {
+            let outcome = AuthOutcome::from(&e);
+            state.metrics.record(outcome);
+            tracing::warn!(outcome = outcome.label(), error = %e, "Authentication failed");
+            // Convert specific AAKAError types to appropriate HTTP status codes if desired
+            // For now, just return a generic error via AppError, keeping the AAKAError for its code
+            let message = format!("Authentication failed: {e}");
+            Err(anyhow::Error::new(e).context(message).into())
+        }
     }
 }
 
-#[test]
-fn foo() {
-    use anyhow::{Context, Result};
-    use tracing::{error, instrument};
-    use tracing_subscriber;
+#[derive(Deserialize)]
+struct BatchAuthRequest {
+    requests: Vec<AuthRequestPayload>,
+}
 
-    fn read_data() -> Result<()> {
-        // 模拟一个底层错误
-        let err = std::io::Error::new(std::io::ErrorKind::NotFound, "File not found on disk");
-        Err(err).context("Failed to load critical data from source")
-    }
+#[derive(Serialize)]
+struct BatchAuthResponse {
+    // One entry per request, in request order
+    results: Vec<BatchAuthResult>,
+}
 
-    #[instrument] // `instrument` 会创建一个 span
-    fn process_request() -> Result<()> {
-        read_data().context("Could not process user request")
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum BatchAuthResult {
+    Ok {
+        response: AuthResponsePayload,
+        session_id_hex: String,
+    },
+    Error {
+        code: &'static str,
+        message: String,
+    },
+}
+
+impl From<AppError> for BatchAuthResult {
+    fn from(err: AppError) -> Self {
+        BatchAuthResult::Error {
+            code: err.code(),
+            message: err.error.to_string(),
+        }
     }
+}
 
-    fn main() {
-        tracing_subscriber::fmt::init(); // 初始化一个简单的格式化 subscriber
+// Handler for POST /auth/batch
+// For gateways authenticating many devices at once: the signatures of all entries are checked
+// together (see `server::process_user_requests`) and each entry gets its own result, so one bad
+// entry never fails the batch. The session of the last successful entry becomes the MS session.
+async fn handle_auth_batch(
+    State(state): State<MsState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<BatchAuthResponse>, AppError> {
+    let body = open_envelope(&state, &headers, body).map_err(AppError::bad_request)?;
+    check_envelope(&state, headers.contains_key(ENVELOPE_HEADER))?;
+    let batch: BatchAuthRequest = serde_json::from_slice(&body)
+        .context("Failed to parse JSON batch auth request")
+        .map_err(AppError::bad_request)?;
+    if batch.requests.len() > state.max_auth_batch {
+        return Err(AppError::bad_request(anyhow!(
+            "Batch of {} requests exceeds the limit of {}",
+            batch.requests.len(),
+            state.max_auth_batch
+        )));
+    }
 
-        if let Err(err) = process_request() {
-            // 使用 `%err` 而不是 `?err` 或 `err`
-            error!(error = %err, "A top-level operation failed");
+    let mut results: Vec<Option<BatchAuthResult>> = Vec::with_capacity(batch.requests.len());
+    let mut requests = Vec::new();
+    for payload in batch.requests {
+        match request_from_payload(payload) {
+            Ok(request) => {
+                results.push(None);
+                requests.push(request);
+            }
+            Err(e) => {
+                state.metrics.record(AuthOutcome::DeserializationError);
+                results.push(Some(AppError::bad_request(e).into()));
+            }
         }
     }
+
+    // The whole batch runs as one blocking job under one permit
+    let permit = state
+        .auth_permits
+        .clone()
+        .try_acquire_owned()
+        .map_err(|_| AppError::unavailable(anyhow!("Server busy, too many auth requests")))?;
+    let (ssk, ms_id, params, mut rng) = {
+        let mut state_locked = state.inner.write();
+        let rng = StdRng::from_rng(&mut state_locked.rng)?;
+        (
+            state_locked.ssk.clone(),
+            state_locked.ms_id.clone(),
+            state_locked.params.clone(),
+            rng,
+        )
+    };
+    let point_validation = state.point_validation;
+    let (requests, outcomes) = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        let outcomes = server::process_user_requests_with_validation(
+            &ssk,
+            &requests,
+            &ms_id,
+            &params,
+            &mut rng,
+            DEFAULT_KEY_LEN,
+            point_validation,
+        );
+        (requests, outcomes)
+    })
+    .await?;
+
+    let mut outcomes = requests.iter().zip(outcomes);
+    let results = results
+        .into_iter()
+        .map(|result| {
+            if let Some(result) = result {
+                return Ok(result);
+            }
+            let (request, outcome) = outcomes.next().expect("one outcome per parsed request");
+            Ok(match complete_auth(&state, request, outcome) {
+                Ok((response, _, session_id)) => BatchAuthResult::Ok {
+                    response: response_payload(&response)?,
+                    session_id_hex: hex::encode(session_id),
+                },
+                Err(e) => e.into(),
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok(Json(BatchAuthResponse { results }))
+}
+
+// Handler for POST /auth/confirm
+// Optional explicit key confirmation after the handshake: the user proves it derived the same
+// session key and gets the MS's proof back, without either side revealing the key
+async fn handle_auth_confirm(
+    State(state): State<MsState>,
+    Json(payload): Json<ConfirmRequest>,
+) -> Result<Json<ConfirmResponse>, AppError> {
+    let state_locked = state.inner.read();
+    let Some(session) = &state_locked.sk else {
+        return Err(AppError::bad_request(anyhow!("Need auth first!")));
+    };
+    let Some(sk) = session.key() else {
+        return Err(AppError::session_expired());
+    };
+    if session.id().map(hex::encode).as_deref() != Some(payload.session_id_hex.as_str()) {
+        return Err(AppError::bad_request(anyhow!(
+            "Unknown session {}",
+            payload.session_id_hex
+        )));
+    }
+    let confirm = hex::decode(&payload.confirm_hex)
+        .map_err(|e| AppError::bad_request(anyhow!("Invalid confirm_hex: {e}")))?;
+    if let Err(e) = verify_confirm(sk, ConfirmDirection::ClientToServer, &confirm) {
+        tracing::warn!(session_id = %payload.session_id_hex, "Key confirmation failed");
+        return Err(AppError {
+            status: StatusCode::FORBIDDEN,
+            error: e.into(),
+        });
+    }
+
+    Ok(Json(ConfirmResponse {
+        confirm_hex: hex::encode(build_confirm(sk, ConfirmDirection::ServerToClient)),
+    }))
+}
+
+async fn handle_communicate(
+    State(state): State<MsState>,
+    Json(payload): Json<Ciphertext>,
+) -> Result<Json<Ciphertext>, AppError> {
+    let state_locked = state.inner.read();
+    let Some(session) = &state_locked.sk else {
+        return Err(anyhow!("Need auth first!").into());
+    };
+    let Some(sk) = session.key() else {
+        return Err(AppError::session_expired());
+    };
+
+    let msg = String::from_utf8(sk.open(&payload.text, &payload.nonce)?)?;
+
+    if let Some(id) = session.id() {
+        println!("Received message in session {}: {msg}", hex::encode(id));
+    }
+
+    // Echo the message back, encrypted under the same session subkey
+    println!("Sending message: {msg}");
+
+    let (ciphertext, nonce) = sk.seal(msg.as_bytes())?;
+
+    let resp = Ciphertext {
+        text: ciphertext,
+        nonce,
+    };
+
+    Ok(axum::Json(resp))
+}
+// --- Main Application Setup ---
+
+// Fetch the system parameters from the RC and register this MS to obtain its secret key.
+async fn register_with_rc(rc: &impl RcClient, ms_id: Vec<u8>) -> Result<InnerMsState> {
+    // --- Load/Fetch System Parameters ---
+    println!("Fetching system parameters...");
+    let params = rc
+        .fetch_params()
+        .await
+        .context("Failed to fetch system parameters from RC")?;
+    println!(
+        "Parameters loaded successfully from RC (fingerprint {}).",
+        params.fingerprint()
+    );
+
+    // --- Register with the RC to obtain the server secret key ---
+    println!("Registering with RC...");
+    let ssk = rc
+        .register_server(&ms_id)
+        .await
+        .context("Failed to register server with RC")?;
+    println!(
+        "Server secret key obtained successfully from RC (fingerprint {}).",
+        ssk.fingerprint()
+    );
+
+    Ok(InnerMsState {
+        ms_id,
+        params,
+        ssk,
+        rng: StdRng::from_entropy(),
+        sk: None,
+    })
+}
+
+// Upper bound on a single wait between RC registration attempts
+const MAX_RC_BACKOFF: std::time::Duration = Duration::from_secs(60);
+
+// Register with the RC, retrying with jittered exponential backoff while it is unavailable
+async fn register_with_retry(
+    rc: &impl RcClient,
+    ms_id: Vec<u8>,
+    retry: &RetryConfig,
+) -> Result<InnerMsState> {
+    let mut backoff = Backoff::new(Duration::from_millis(retry.backoff_ms), MAX_RC_BACKOFF);
+    let mut attempt = 1;
+    loop {
+        match register_with_rc(rc, ms_id.clone()).await {
+            Ok(state) => return Ok(state),
+            Err(e) if attempt < retry.attempts => {
+                let delay = backoff.next_delay(&mut rand::thread_rng());
+                tracing::warn!(
+                    attempt,
+                    max_attempts = retry.attempts,
+                    "RC unavailable, retrying in {delay:?}: {e:#}"
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                return Err(e.context(format!("RC still unavailable after {attempt} attempts")));
+            }
+        }
+    }
+}
+
+// 启动自检：向 RC 注册测试用户 `self-test@<ms_id>`，用本节点的私钥与系统参数完成一次完整握手，
+// 双方的会话密钥一致才开始服务。系统参数或私钥与 RC 不匹配（例如 `ms_state.json` 来自另一个 RC、
+// 密钥文件属于其他 ID）时，所有认证请求都会失败，这里提前拒绝启动。
+async fn self_test(rc: &impl RcClient, state: &InnerMsState) -> Result<()> {
+    let user_id = [b"self-test@", &state.ms_id[..]].concat();
+    let usk = rc
+        .register_user(&user_id)
+        .await
+        .context("Failed to register the self-test user with RC")?;
+
+    let ms_id = &state.ms_id[..];
+    let mut rng = StdRng::from_entropy();
+    let (request, user_state) =
+        user::initiate_authentication(&usk, &user_id, ms_id, &state.params, &mut rng)
+            .context("Self-test failed to build an authentication request")?;
+    let (response, server_key) = server::process_user_request(
+        &state.ssk,
+        &request,
+        ms_id,
+        &state.params,
+        &mut rng,
+        DEFAULT_KEY_LEN,
+    )
+    .context(
+        "Self-test failed: this MS rejected a request from a user freshly registered with the RC",
+    )?;
+    let user_key = user::process_server_response(
+        &usk,
+        &user_state,
+        &response,
+        ms_id,
+        &state.params,
+        DEFAULT_KEY_LEN,
+    )
+    .context("Self-test failed: the test user rejected this MS's response")?;
+    if user_key.0 != server_key.0 {
+        bail!("Self-test failed: the user and this MS derived different session keys");
+    }
+    println!(
+        "Self-test passed (params {}, key {}).",
+        state.params.fingerprint(),
+        state.ssk.fingerprint()
+    );
+    Ok(())
+}
+
+// Load the MS state from `state_file`, or register with the RC and save it there.
+// In stateless mode the file is never touched and the MS registers on every start.
+async fn init_state(
+    rc: &impl RcClient,
+    ms_id: Vec<u8>,
+    state_file: &str,
+    stateless: bool,
+    retry: &RetryConfig,
+) -> Result<InnerMsState> {
+    if stateless {
+        println!("Stateless mode: skipping MS state file.");
+        return register_with_retry(rc, ms_id, retry).await;
+    }
+
+    match InnerMsState::load_from_file(state_file) {
+        Ok(state) => match check_key_against_rc(rc, &state).await {
+            Ok(()) => {
+                println!(
+                    "Loaded existing MS state from file {state_file} (params {}, key {}).",
+                    state.params.fingerprint(),
+                    state.ssk.fingerprint()
+                );
+                return Ok(state);
+            }
+            Err(e) => eprintln!("{e:#}; re-registering and overwriting {state_file}."),
+        },
+        // No file on the first start; an unreadable one is reported before it is overwritten
+        Err(_) if !std::path::Path::new(state_file).exists() => {}
+        Err(e) => eprintln!("{e:#}; re-registering and overwriting {state_file}."),
+    }
+
+    let ms_state = register_with_retry(rc, ms_id, retry).await?;
+
+    // Save the state to file for future runs
+    ms_state
+        .save_to_file(state_file)
+        .context("Failed to save MS state to file")?;
+
+    println!("MS state saved to file {state_file}.");
+
+    Ok(ms_state)
+}
+
+// 持久化的私钥（状态文件、key_file）可能是 RC 重新 /setup 之前签发的：用户按 RC 当前的参数认证，
+// 这把私钥算出的会话密钥对不上，每次认证都会失败。启动时按 RC 当前的参数校验私钥，
+// 不一致时返回说明原因的错误；RC 不可达时无法判断，只输出警告。
+async fn check_key_against_rc(rc: &impl RcClient, state: &InnerMsState) -> Result<()> {
+    let params = match rc.fetch_params().await {
+        Ok(params) => params,
+        Err(e) => {
+            tracing::warn!("Could not check the server key against the RC's params: {e}");
+            return Ok(());
+        }
+    };
+    if params == state.params {
+        return Ok(());
+    }
+    rc::verify_server_key(&params, &state.ms_id, &state.ssk).with_context(|| {
+        format!(
+            "Server key {} for {} was issued under a different master key than the RC's \
+             current params {} (this MS has params {}); the RC was probably set up again, \
+             every authentication would fail until this MS re-registers with the RC",
+            state.ssk.fingerprint(),
+            EncodedId::from_bytes(&state.ms_id),
+            params.fingerprint(),
+            state.params.fingerprint()
+        )
+    })
+}
+
+// Load pre-provisioned params and key from an encrypted key file instead of registering with the RC
+fn load_key_file(ms_id: Vec<u8>, path: &str, passphrase: Option<&str>) -> Result<InnerMsState> {
+    let passphrase = passphrase.context("`key_file` requires `key_file_passphrase`")?;
+    let bytes = std::fs::read(path).context(format!("Failed to read key file {path}"))?;
+    let (params, ssk) = keyfile::open(&bytes, passphrase.as_bytes())
+        .context(format!("Failed to open key file {path}"))?;
+    params
+        .validate_strict()
+        .context(format!("Key file {path} holds invalid system parameters"))?;
+    rc::verify_server_key(&params, &ms_id, &ssk).context(format!(
+        "Key file {path} does not hold a valid key for {}",
+        EncodedId::from_bytes(&ms_id)
+    ))?;
+    println!(
+        "Loaded pre-provisioned key from {path} (params {}, key {}), skipping RC registration.",
+        params.fingerprint(),
+        ssk.fingerprint()
+    );
+
+    Ok(InnerMsState {
+        ms_id,
+        params,
+        ssk,
+        rng: StdRng::from_entropy(),
+        sk: None,
+    })
+}
+
+// Use params and key injected through the config, never contacting the RC
+fn load_standalone(
+    ms_id: Vec<u8>,
+    params_hex: Option<&str>,
+    ssk_hex: Option<&str>,
+) -> Result<InnerMsState> {
+    let params_hex = params_hex.context("`standalone` requires `params_hex`")?;
+    let ssk_hex = ssk_hex.context("`standalone` requires `ssk_hex`")?;
+    let params_bytes = hex::decode(params_hex).context("`params_hex` is not valid hex")?;
+    let params = SystemParameters::try_from(&params_bytes[..])
+        .context("`params_hex` holds invalid system parameters")?;
+    params
+        .validate()
+        .context("`params_hex` holds invalid system parameters")?;
+    let ssk_bytes = hex::decode(ssk_hex).context("`ssk_hex` is not valid hex")?;
+    let ssk = ServerSecretKey::try_from(&ssk_bytes[..])
+        .context("`ssk_hex` holds an invalid server secret key")?;
+    rc::verify_server_key(&params, &ms_id, &ssk).context(format!(
+        "`ssk_hex` is not a valid key for {}",
+        EncodedId::from_bytes(&ms_id)
+    ))?;
+    println!(
+        "Standalone mode: using configured params {} and key {}, skipping the RC.",
+        params.fingerprint(),
+        ssk.fingerprint()
+    );
+
+    Ok(InnerMsState {
+        ms_id,
+        params,
+        ssk,
+        rng: StdRng::from_entropy(),
+        sk: None,
+    })
+}
+
+// Refresh the cached params once, keeping the last-known-good params if the RC is unreachable
+async fn refresh_params(state: &MsState, cache: &ParamsCache, client: &reqwest::Client) {
+    match cache.refresh(client).await {
+        Ok(true) => {
+            if let Some(params) = cache.get() {
+                println!(
+                    "System parameters changed on the RC, reloaded (fingerprint {}).",
+                    params.fingerprint()
+                );
+                let mut inner = state.inner.write();
+                if let Err(e) = rc::verify_server_key(&params, &inner.ms_id, &inner.ssk) {
+                    eprintln!(
+                        "The RC's new params {} do not match this MS's key {} ({e}), \
+                         authentication will fail until this MS re-registers with the RC.",
+                        params.fingerprint(),
+                        inner.ssk.fingerprint()
+                    );
+                }
+                inner.params = params;
+            }
+        }
+        Ok(false) => {}
+        Err(e) => eprintln!("Failed to refresh system parameters, keeping cached ones: {e}"),
+    }
+}
+
+// Open the persistent replay cache at `path` or the bloom filter one, falling back to the in-memory one
+fn open_replay_cache(
+    path: Option<&str>,
+    bloom: Option<&BloomConfig>,
+) -> Result<Arc<dyn ReplayCache>> {
+    let Some(path) = path else {
+        return Ok(match bloom {
+            Some(config) => {
+                let cache = BloomReplayCache::new(config)?;
+                println!(
+                    "Using bloom filter replay cache ({} bytes)",
+                    cache.memory_bytes()
+                );
+                Arc::new(cache)
+            }
+            None => Arc::new(MemoryReplayCache::default()),
+        });
+    };
+    if bloom.is_some() {
+        bail!("`replay_db` and `replay_bloom` cannot be used together");
+    }
+
+    #[cfg(feature = "persistent-replay")]
+    {
+        println!("Using persistent replay cache at {path}");
+        Ok(Arc::new(ibc_aaka_scheme::replay::SledReplayCache::open(
+            path,
+        )?))
+    }
+    #[cfg(not(feature = "persistent-replay"))]
+    bail!("`replay_db` = {path:?} requires the `persistent-replay` feature")
+}
+
+// Drop the current session's key once it has expired and count the eviction
+fn sweep_session(state: &MsState) {
+    if let Some(session) = &mut state.inner.write().sk
+        && session.sweep()
+    {
+        state.metrics.record_session(SessionEvent::Expired);
+    }
+}
+
+// Upper bound on how long an expired session key stays in memory
+const SESSION_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenv().ok();
+    tracing_subscriber::fmt::init();
+
+    let config = load_config(&Cli::parse())?;
+    let ms_id = config.ms_id_bytes()?;
+    let Config {
+        ms_id: _,
+        ms_id_encoding: _,
+        ms_addr,
+        rc_url,
+        stateless,
+        max_concurrent_auth,
+        grpc_addr,
+        params_refresh_secs,
+        rc_retry,
+        replay_db,
+        replay_bloom,
+        max_body_bytes,
+        require_envelope,
+        session_ttl_secs,
+        key_file,
+        key_file_passphrase,
+        cors,
+        http,
+        max_auth_batch,
+        clock_check,
+        self_test: run_self_test,
+        point_validation,
+        auth_retry_window_secs,
+        standalone,
+        params_hex,
+        ssk_hex,
+        admin_token,
+    } = config;
+    // 独立模式不访问 RC，需要 RC 的功能不能同时开启
+    if standalone {
+        if key_file.is_some() {
+            bail!("`standalone` and `key_file` cannot be used together");
+        }
+        if run_self_test || params_refresh_secs.is_some() {
+            bail!(
+                "`self_test` and `params_refresh_secs` need the RC, disable them in standalone mode"
+            );
+        }
+    }
+    let cors = cors.as_ref().map(CorsConfig::layer).transpose()?;
+    let client = http.build()?;
+    if let Some(clock_check) = &clock_check {
+        clock_check.run(&client).await.context(format!(
+            "Refusing to start, clock check against {} failed",
+            clock_check.reference
+        ))?;
+    }
+    let rc = HttpRcClient::new(client.clone(), &rc_url);
+
+    let inner = match &key_file {
+        _ if standalone => load_standalone(ms_id, params_hex.as_deref(), ssk_hex.as_deref())?,
+        Some(path) => {
+            let inner = load_key_file(ms_id, path, key_file_passphrase.as_deref())?;
+            check_key_against_rc(&rc, &inner).await.context(format!(
+                "Refusing to start, provision a new key file {path} for this MS"
+            ))?;
+            inner
+        }
+        None => init_state(&rc, ms_id, MS_STATE_FILE, stateless, &rc_retry).await?,
+    };
+    if run_self_test {
+        self_test(&rc, &inner).await.context("Refusing to start")?;
+    }
+    let ms_state = MsState {
+        inner: Arc::new(RwLock::new(inner)),
+        auth_permits: Arc::new(Semaphore::new(max_concurrent_auth)),
+        replay: open_replay_cache(replay_db.as_deref(), replay_bloom.as_ref())?,
+        metrics: Arc::default(),
+        require_envelope,
+        session_ttl: session_ttl_secs.map(Duration::from_secs),
+        max_auth_batch,
+        point_validation,
+        auth_retries: auth_retry_window_secs
+            .map(|secs| Arc::new(AuthRetryCache::new(Duration::from_secs(secs)))),
+        debug: Arc::new(DebugConfig {
+            admin_token,
+            rc_url: (!standalone).then(|| rc_url.clone()),
+        }),
+    };
+
+    // --- Periodically drop expired session keys ---
+    if let Some(ttl) = ms_state.session_ttl {
+        let state = ms_state.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(ttl.min(SESSION_SWEEP_INTERVAL));
+            loop {
+                interval.tick().await;
+                sweep_session(&state);
+            }
+        });
+    }
+
+    // --- Periodically pick up rotated system parameters ---
+    if let Some(secs) = params_refresh_secs {
+        let cache = ParamsCache::with_params(&rc_url, ms_state.inner.read().params.clone());
+        let state = ms_state.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(secs));
+            loop {
+                interval.tick().await;
+                refresh_params(&state, &cache, &client).await;
+            }
+        });
+    }
+
+    // --- Run the gRPC server alongside the HTTP one ---
+    if let Some(grpc_addr) = grpc_addr {
+        let service = MsServer::new(MsService {
+            state: ms_state.clone(),
+        });
+        let grpc_addr = grpc_addr.parse()?;
+        println!("MS gRPC server listening on {grpc_addr}");
+        tokio::spawn(async move {
+            if let Err(e) = tonic::transport::Server::builder()
+                .add_service(service)
+                .serve(grpc_addr)
+                .await
+            {
+                eprintln!("gRPC server stopped: {e}");
+            }
+        });
+    }
+
+    // --- Run the server ---
+    let listener = tokio::net::TcpListener::bind(&ms_addr).await?;
+    println!("MS Server listening on {}", listener.local_addr()?);
+    let mut app = router(ms_state, max_body_bytes);
+    if let Some(cors) = cors {
+        app = app.layer(cors);
+    }
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
+
+fn router(ms_state: MsState, max_body_bytes: usize) -> Router {
+    Router::new()
+        .route("/auth/initiate", post(handle_auth_request))
+        .route("/auth/batch", post(handle_auth_batch))
+        .route("/auth/ws", get(handle_auth_ws))
+        .route("/auth/confirm", post(handle_auth_confirm))
+        .route("/communicate", post(handle_communicate))
+        .route("/metrics", get(handle_metrics))
+        .route("/sessions/stats", get(handle_session_stats))
+        .route("/debug/state", get(handle_debug_state))
+        .layer(RequestBodyLimitLayer::new(max_body_bytes))
+        // 客户端带 `Accept-Encoding: gzip` 时压缩响应，其余客户端不受影响
+        .layer(CompressionLayer::new())
+        .layer(middleware::from_fn(request_id::request_id))
+        .with_state(ms_state)
+}
+
+// --- Custom Error Type for Axum (same as in RC app) ---
+struct AppError {
+    status: StatusCode,
+    error: anyhow::Error,
+}
+
+impl AppError {
+    // 服务器繁忙，客户端可稍后重试
+    fn unavailable(err: impl Into<anyhow::Error>) -> Self {
+        Self {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            error: err.into(),
+        }
+    }
+
+    // 信封缺失或校验失败
+    fn bad_request(err: impl Into<anyhow::Error>) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            error: err.into(),
+        }
+    }
+
+    // 管理接口未开启或令牌不正确
+    fn forbidden(err: impl Into<anyhow::Error>) -> Self {
+        Self {
+            status: StatusCode::FORBIDDEN,
+            error: err.into(),
+        }
+    }
+
+    // 会话已超过 `session_ttl_secs`，需要重新认证
+    fn session_expired() -> Self {
+        Self {
+            status: StatusCode::GONE,
+            error: anyhow!("Session expired, authenticate again"),
+        }
+    }
+
+    // 请求在时间戳窗口内已被处理过
+    fn replayed() -> Self {
+        Self {
+            status: StatusCode::UNAUTHORIZED,
+            error: anyhow!("Replayed authentication request"),
+        }
+    }
+
+    // 错误链中有 AAKAError 时使用它的错误码，否则按状态码归类
+    fn code(&self) -> &'static str {
+        if let Some(err) = self
+            .error
+            .chain()
+            .find_map(|e| e.downcast_ref::<AAKAError>())
+        {
+            return err.code();
+        }
+        match self.status {
+            StatusCode::SERVICE_UNAVAILABLE => "ERR_UNAVAILABLE",
+            StatusCode::BAD_REQUEST => "ERR_BAD_REQUEST",
+            StatusCode::GONE => "ERR_SESSION_EXPIRED",
+            StatusCode::FORBIDDEN => "ERR_FORBIDDEN",
+            StatusCode::UNAUTHORIZED => "ERR_REPLAY",
+            _ => "ERR_INTERNAL",
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> axum::response::Response {
+        eprintln!("Error occurred: {:?}", self.error);
+        let mut body = serde_json::json!({
+            "code": self.code(),
+            // 与 `/auth/ws`、`/auth/batch` 的错误项相同，不加前缀
+            "message": self.error.to_string(),
+            "request_id": request_id::current(),
+        });
+        // 请求中无法解析的字段
+        if let Some(invalid) = self
+            .error
+            .chain()
+            .find_map(|e| e.downcast_ref::<InvalidField>())
+        {
+            body["field"] = invalid.field.into();
+        }
+        (
+            self.status, // Or map specific errors (e.g., Bad Request for deserialization)
+            Json(body),
+        )
+            .into_response()
+    }
+}
+
+impl<E> From<E> for AppError
+where
+    E: Into<anyhow::Error>,
+{
+    fn from(err: E) -> Self {
+        Self {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            error: err.into(),
+        }
+    }
+}
+
+#[test]
+fn foo() {
+    use anyhow::{Context, Result};
+    use tracing::{error, instrument};
+
+    fn read_data() -> Result<()> {
+        // 模拟一个底层错误
+        let err = std::io::Error::new(std::io::ErrorKind::NotFound, "File not found on disk");
+        Err(err).context("Failed to load critical data from source")
+    }
+
+    #[instrument] // `instrument` 会创建一个 span
+    fn process_request() -> Result<()> {
+        read_data().context("Could not process user request")
+    }
+
+    let err = process_request().unwrap_err();
+    // 使用 `%err` 而不是 `?err` 或 `err`
+    error!(error = %err, "A top-level operation failed");
+    assert_eq!(err.to_string(), "Could not process user request");
+}
+
+// Post a hex JSON auth request to the handler and parse the JSON response
+#[cfg(test)]
+async fn post_auth_json(
+    state: MsState,
+    request: &UserAuthRequest,
+) -> Result<serde_json::Value, AppError> {
+    let body = serde_json::json!({
+        "m_hex": ark_to_hex(&request.m)?,
+        "n": hex::encode(&request.n),
+        "sigma_hex": ark_to_hex(&request.sigma)?,
+        "timestamp": request.timestamp,
+        "suite_id": request.suite_id,
+    });
+    let resp = handle_auth_request(
+        State(state),
+        HeaderMap::new(),
+        Bytes::from(body.to_string()),
+    )
+    .await?;
+    let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+// MS state for tests: `my_server` holding `ssk`, every option at its default
+#[cfg(test)]
+fn test_state(params: SystemParameters, ssk: ServerSecretKey) -> MsState {
+    test_state_with(InnerMsState {
+        ms_id: b"my_server".to_vec(),
+        params,
+        ssk,
+        rng: StdRng::from_entropy(),
+        sk: None,
+    })
+}
+
+#[cfg(test)]
+fn test_state_with(inner: InnerMsState) -> MsState {
+    MsState {
+        inner: Arc::new(RwLock::new(inner)),
+        auth_permits: Arc::new(Semaphore::new(1)),
+        replay: Arc::new(MemoryReplayCache::default()),
+        metrics: Arc::default(),
+        require_envelope: false,
+        session_ttl: None,
+        max_auth_batch: default_max_auth_batch(),
+        point_validation: PointValidation::Strict,
+        auth_retries: None,
+        debug: Arc::default(),
+    }
+}
+
+#[test]
+#[allow(clippy::result_large_err)]
+fn test_env_overrides_config_file() {
+    figment::Jail::expect_with(|jail| {
+        jail.create_file(
+            "config.json",
+            r#"{ "ms_id": "my_server", "ms_addr": "127.0.0.1:4001", "rc_url": "http://127.0.0.1:5000" }"#,
+        )?;
+        jail.set_env("MS_MS_ADDR", "127.0.0.1:4101");
+        jail.set_env("MS_STATELESS", "true");
+
+        let config = load_config(&Cli::parse_from(["aaka_ms_server"])).unwrap();
+        assert_eq!(config.ms_addr, "127.0.0.1:4101");
+        assert_eq!(config.ms_id, "my_server");
+        assert!(config.stateless);
+
+        let cli = Cli::parse_from(["aaka_ms_server", "--set", "ms_id=other_server"]);
+        assert_eq!(load_config(&cli).unwrap().ms_id, "other_server");
+        Ok(())
+    });
+}
+
+#[tokio::test]
+async fn test_stateless_mode_writes_no_state_file() {
+    use axum::routing::get;
+    use ibc_aaka_scheme::{rc, user};
+
+    // Mock RC serving /params and /register/server
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let params_json = serde_json::json!({
+        "p_hex": ark_to_hex(&params.p).unwrap(),
+        "p_pub_hex": ark_to_hex(&params.p_pub).unwrap(),
+        "p_pub_hat_hex": ark_to_hex(&params.p_pub_hat).unwrap(),
+        "g_hex": ark_to_hex(&params.g).unwrap(),
+    });
+    let ssk = rc::register_server(&msk, b"my_server").unwrap();
+    let ssk_json = serde_json::json!({ "sid_ms_hex": ark_to_hex(&ssk.sid_ms).unwrap() });
+    let rc_app = Router::new()
+        .route("/params", get(move || async move { Json(params_json) }))
+        .route(
+            "/register/server",
+            post(move || async move { Json(ssk_json) }),
+        );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let rc_url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, rc_app).await.unwrap() });
+
+    let state_file = std::env::temp_dir().join(format!("ms_state_{}.json", std::process::id()));
+    let state_file = state_file.to_str().unwrap();
+    let inner = init_state(
+        &HttpRcClient::new(reqwest::Client::new(), rc_url),
+        b"my_server".to_vec(),
+        state_file,
+        true,
+        &RetryConfig::default(),
+    )
+    .await
+    .unwrap();
+    assert!(!std::path::Path::new(state_file).exists());
+
+    // The in-memory state is still able to authenticate a user
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let (request, user_state) =
+        user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+    let ms_state = test_state_with(inner);
+    let Ok(resp) = post_auth_json(ms_state, &request).await else {
+        panic!("authentication failed");
+    };
+
+    let server_response = ibc_aaka_scheme::ServerAuthResponse {
+        t: hex_to_ark(resp["response"]["t_hex"].as_str().unwrap()).unwrap(),
+        y: hex_to_ark(resp["response"]["y_hex"].as_str().unwrap()).unwrap(),
+        timestamp: resp["response"]["timestamp"].as_u64().unwrap(),
+    };
+    let sk = user::process_server_response(
+        &usk,
+        &user_state,
+        &server_response,
+        b"my_server",
+        &params,
+        32,
+    )
+    .unwrap();
+    assert_eq!(hex::encode(&sk.0), resp["session_key_hex"]);
+
+    // Both sides derive the same session ID from the handshake transcript
+    let transcript = ibc_aaka_scheme::Transcript::from_user(&user_state, &server_response);
+    assert_eq!(
+        hex::encode(session_id(&transcript).unwrap()),
+        resp["session_id_hex"]
+    );
+}
+
+#[tokio::test]
+async fn test_key_file_initializes_ms_without_rc() {
+    use ibc_aaka_scheme::user;
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let ssk = rc::register_server(&msk, b"my_server").unwrap();
+    let key_file = std::env::temp_dir().join(format!("ms_key_{}.bin", std::process::id()));
+    std::fs::write(&key_file, keyfile::seal(&params, &ssk, b"secret").unwrap()).unwrap();
+    let path = key_file.to_str().unwrap();
+
+    // No RC is running: the state comes entirely from the key file
+    let inner = load_key_file(b"my_server".to_vec(), path, Some("secret")).unwrap();
+    assert_eq!(inner.params, params);
+    assert_eq!(inner.ssk.sid_ms, ssk.sid_ms);
+
+    // Key issued for another ID, wrong or missing passphrase
+    assert!(load_key_file(b"other".to_vec(), path, Some("secret")).is_err());
+    assert!(load_key_file(b"my_server".to_vec(), path, Some("wrong")).is_err());
+    assert!(load_key_file(b"my_server".to_vec(), path, None).is_err());
+    std::fs::remove_file(&key_file).unwrap();
+
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let (request, _) =
+        user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+    let ms_state = test_state_with(inner);
+    assert!(post_auth_json(ms_state, &request).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_standalone_ms_completes_handshake_without_rc() {
+    use ibc_aaka_scheme::user;
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let ssk = rc::register_server(&msk, b"my_server").unwrap();
+    let params_hex = ark_to_hex(&params).unwrap();
+    let ssk_hex = ark_to_hex(&ssk).unwrap();
+
+    // Missing, malformed or mismatched injected values are refused at startup
+    assert!(load_standalone(b"my_server".to_vec(), None, Some(&ssk_hex)).is_err());
+    assert!(load_standalone(b"my_server".to_vec(), Some(&params_hex), None).is_err());
+    assert!(load_standalone(b"my_server".to_vec(), Some("zz"), Some(&ssk_hex)).is_err());
+    assert!(load_standalone(b"other".to_vec(), Some(&params_hex), Some(&ssk_hex)).is_err());
+    let (other_params, _) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let other_params_hex = ark_to_hex(&other_params).unwrap();
+    assert!(
+        load_standalone(
+            b"my_server".to_vec(),
+            Some(&other_params_hex),
+            Some(&ssk_hex)
+        )
+        .is_err()
+    );
+
+    // No RC is running: boot the HTTP server from the injected params and key alone
+    let inner = load_standalone(b"my_server".to_vec(), Some(&params_hex), Some(&ssk_hex)).unwrap();
+    let ms_state = test_state_with(inner);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/auth/initiate", listener.local_addr().unwrap());
+    let app = router(ms_state, default_max_body_bytes());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let (request, user_state) =
+        user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+    let resp: serde_json::Value = reqwest::Client::new()
+        .post(&url)
+        .json(&serde_json::json!({
+            "m_hex": ark_to_hex(&request.m).unwrap(),
+            "n": hex::encode(&request.n),
+            "sigma_hex": ark_to_hex(&request.sigma).unwrap(),
+            "timestamp": request.timestamp,
+            "suite_id": request.suite_id,
+        }))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let response = ServerAuthResponse {
+        t: hex_to_ark(resp["response"]["t_hex"].as_str().unwrap()).unwrap(),
+        y: hex_to_ark(resp["response"]["y_hex"].as_str().unwrap()).unwrap(),
+        timestamp: resp["response"]["timestamp"].as_u64().unwrap(),
+    };
+    let sk = user::process_server_response(&usk, &user_state, &response, b"my_server", &params, 32)
+        .unwrap();
+    assert_eq!(hex::encode(&sk.0), resp["session_key_hex"]);
+}
+
+#[tokio::test]
+async fn test_auth_requests_beyond_limit_get_503() {
+    use axum::response::IntoResponse;
+    use ibc_aaka_scheme::{rc, user};
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let ms_state = MsState {
+        auth_permits: Arc::new(Semaphore::new(2)),
+        ..test_state(
+            params.clone(),
+            rc::register_server(&msk, b"my_server").unwrap(),
+        )
+    };
+    let mut new_request = || {
+        user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng)
+            .unwrap()
+            .0
+    };
+
+    // Steady load within the limit succeeds
+    for _ in 0..3 {
+        let resp = post_auth_json(ms_state.clone(), &new_request()).await;
+        assert!(resp.is_ok());
+    }
+
+    // All workers busy: the request is rejected instead of queued
+    let busy = ms_state
+        .auth_permits
+        .clone()
+        .acquire_many_owned(2)
+        .await
+        .unwrap();
+    let Err(err) = post_auth_json(ms_state.clone(), &new_request()).await else {
+        panic!("request beyond the concurrency limit should be rejected");
+    };
+    assert_eq!(
+        err.into_response().status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+
+    drop(busy);
+    let resp = post_auth_json(ms_state, &new_request()).await;
+    assert!(resp.is_ok());
+}
+
+#[tokio::test]
+async fn test_encrypted_message_roundtrip_after_handshake() {
+    use ibc_aaka_scheme::{rc, user};
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let ms_state = test_state(
+        params.clone(),
+        rc::register_server(&msk, b"my_server").unwrap(),
+    );
+
+    // Key agreement
+    let (request, user_state) =
+        user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+    let Ok(resp) = post_auth_json(ms_state.clone(), &request).await else {
+        panic!("authentication failed");
+    };
+    let server_response = ibc_aaka_scheme::ServerAuthResponse {
+        t: hex_to_ark(resp["response"]["t_hex"].as_str().unwrap()).unwrap(),
+        y: hex_to_ark(resp["response"]["y_hex"].as_str().unwrap()).unwrap(),
+        timestamp: resp["response"]["timestamp"].as_u64().unwrap(),
+    };
+    let sk = user::process_server_response(
+        &usk,
+        &user_state,
+        &server_response,
+        b"my_server",
+        &params,
+        32,
+    )
+    .unwrap();
+
+    // The MS decrypts the message and echoes it back encrypted
+    let (text, nonce) = sk.seal(b"hello").unwrap();
+    let Ok(Json(reply)) =
+        handle_communicate(State(ms_state), Json(Ciphertext { text, nonce })).await
+    else {
+        panic!("communicate failed");
+    };
+    assert_ne!(reply.text, b"hello");
+    assert_eq!(sk.open(&reply.text, &reply.nonce).unwrap(), b"hello");
+}
+
+#[cfg(not(feature = "unsafe-log-keys"))]
+#[tokio::test]
+async fn test_auth_logs_contain_no_session_key() {
+    use ibc_aaka_scheme::{rc, user};
+    use std::sync::Mutex;
+
+    // 收集格式化后的日志输出
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let ms_state = test_state(
+        params.clone(),
+        rc::register_server(&msk, b"my_server").unwrap(),
+    );
+
+    let output = Captured::default();
+    let writer = output.clone();
+    let _guard = tracing::subscriber::set_default(
+        tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_max_level(tracing::Level::TRACE)
+            .finish(),
+    );
+
+    let (request, _) =
+        user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+    let Ok(resp) = post_auth_json(ms_state.clone(), &request).await else {
+        panic!("authentication failed");
+    };
+    // 重放的请求走失败分支
+    assert!(post_auth_json(ms_state, &request).await.is_err());
+
+    let logs = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+    let key_hex = resp["session_key_hex"].as_str().unwrap();
+    assert!(logs.contains("Authentication successful"), "{logs}");
+    assert!(
+        logs.contains(resp["session_id_hex"].as_str().unwrap()),
+        "{logs}"
+    );
+    assert!(!logs.contains(key_hex), "{logs}");
+}
+
+#[tokio::test]
+async fn test_session_expires_after_ttl() {
+    use ibc_aaka_scheme::{rc, user};
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let ms_state = MsState {
+        session_ttl: Some(Duration::from_millis(300)),
+        ..test_state(
+            params.clone(),
+            rc::register_server(&msk, b"my_server").unwrap(),
+        )
+    };
+
+    let (request, user_state) =
+        user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+    let Ok(resp) = post_auth_json(ms_state.clone(), &request).await else {
+        panic!("authentication failed");
+    };
+    let server_response = ibc_aaka_scheme::ServerAuthResponse {
+        t: hex_to_ark(resp["response"]["t_hex"].as_str().unwrap()).unwrap(),
+        y: hex_to_ark(resp["response"]["y_hex"].as_str().unwrap()).unwrap(),
+        timestamp: resp["response"]["timestamp"].as_u64().unwrap(),
+    };
+    let sk = user::process_server_response(
+        &usk,
+        &user_state,
+        &server_response,
+        b"my_server",
+        &params,
+        32,
+    )
+    .unwrap();
+    let communicate = |state: MsState| {
+        let (text, nonce) = sk.seal(b"hello").unwrap();
+        handle_communicate(State(state), Json(Ciphertext { text, nonce }))
+    };
+
+    // Within the TTL the session is usable
+    assert!(communicate(ms_state.clone()).await.is_ok());
+
+    // Past the TTL the lookup reports 410 Gone, before and after the sweep
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let Err(err) = communicate(ms_state.clone()).await else {
+        panic!("expired session was accepted");
+    };
+    assert_eq!(err.status, StatusCode::GONE);
+
+    assert!(ms_state.inner.write().sk.as_mut().unwrap().sweep());
+    assert!(matches!(ms_state.inner.read().sk, Some(Session::Expired)));
+    let Err(err) = communicate(ms_state).await else {
+        panic!("expired session was accepted");
+    };
+    assert_eq!(err.status, StatusCode::GONE);
+}
+
+#[tokio::test]
+async fn test_session_stats_track_active_and_evicted_sessions() {
+    use ibc_aaka_scheme::user;
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let ms_state = MsState {
+        session_ttl: Some(Duration::from_millis(300)),
+        ..test_state(
+            params.clone(),
+            rc::register_server(&msk, b"my_server").unwrap(),
+        )
+    };
+    let stats = || async { handle_session_stats(State(ms_state.clone())).await.0 };
+
+    let empty = stats().await;
+    assert_eq!((empty.active, empty.established_total), (0, 0));
+    assert_eq!(empty.oldest_age_secs, None);
+
+    for _ in 0..3 {
+        let (request, _) =
+            user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+        assert!(post_auth_json(ms_state.clone(), &request).await.is_ok());
+    }
+
+    // The store keeps the latest session, the two earlier ones were replaced
+    let after_auth = stats().await;
+    assert_eq!(after_auth.active, 1);
+    assert_eq!(after_auth.established_total, 3);
+    assert_eq!(after_auth.replaced_total, 2);
+    assert_eq!(after_auth.expired_total, 0);
+    assert!(after_auth.oldest_age_secs.unwrap() < 0.3);
+    assert_eq!(after_auth.oldest_age_secs, after_auth.newest_age_secs);
+    assert!(after_auth.evictions_per_min > 0.0);
+
+    let metrics = handle_metrics(State(ms_state.clone()))
+        .await
+        .into_response();
+    let metrics = axum::body::to_bytes(metrics.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let metrics = String::from_utf8(metrics.to_vec()).unwrap();
+    assert!(
+        metrics.contains(r#"aaka_ms_sessions_total{server="my_server",event="established"} 3"#)
+    );
+    assert!(metrics.contains(r#"aaka_ms_sessions_total{server="my_server",event="replaced"} 2"#));
+    assert!(metrics.contains(r#"aaka_ms_sessions_active{server="my_server"} 1"#));
+
+    // Past the TTL the session drops out of the active count, and the sweep counts the eviction
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let expired = stats().await;
+    assert_eq!(expired.active, 0);
+    assert_eq!(expired.oldest_age_secs, None);
+    sweep_session(&ms_state);
+    assert!(matches!(ms_state.inner.read().sk, Some(Session::Expired)));
+    let swept = stats().await;
+    assert_eq!((swept.active, swept.expired_total), (0, 1));
+    assert_eq!(swept.established_total, 3);
+}
+
+#[tokio::test]
+async fn test_replayed_auth_request_rejected() {
+    use axum::response::IntoResponse;
+    use ibc_aaka_scheme::{rc, user};
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let ms_state = test_state(
+        params.clone(),
+        rc::register_server(&msk, b"my_server").unwrap(),
+    );
+
+    let (request, _) =
+        user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+    assert!(post_auth_json(ms_state.clone(), &request).await.is_ok());
+
+    // The same request sent again within the timestamp window
+    let Err(err) = post_auth_json(ms_state.clone(), &request).await else {
+        panic!("replayed request should be rejected");
+    };
+    let resp = err.into_response();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["code"], "ERR_REPLAY");
+
+    // A fresh request from the same user is still accepted
+    let (request, _) =
+        user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+    assert!(post_auth_json(ms_state, &request).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_identical_retry_returns_original_response() {
+    use ibc_aaka_scheme::{rc, user};
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let ms_state = MsState {
+        auth_retries: Some(Arc::new(AuthRetryCache::new(Duration::from_secs(60)))),
+        ..test_state(
+            params.clone(),
+            rc::register_server(&msk, b"my_server").unwrap(),
+        )
+    };
+
+    let (request, user_state) =
+        user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+    let Ok(first) = post_auth_json(ms_state.clone(), &request).await else {
+        panic!("auth request should succeed");
+    };
+    // 响应丢失，客户端原样重发：得到相同的 Y、t，而不是新的 y
+    let Ok(retry) = post_auth_json(ms_state.clone(), &request).await else {
+        panic!("auth request should succeed");
+    };
+    for field in ["y_hex", "t_hex", "timestamp"] {
+        assert_eq!(
+            first["response"][field], retry["response"][field],
+            "{field}"
+        );
+    }
+    assert_eq!(first["session_id_hex"], retry["session_id_hex"]);
+
+    let response = ServerAuthResponse {
+        t: hex_to_ark(retry["response"]["t_hex"].as_str().unwrap()).unwrap(),
+        y: hex_to_ark(retry["response"]["y_hex"].as_str().unwrap()).unwrap(),
+        timestamp: retry["response"]["timestamp"].as_u64().unwrap(),
+    };
+    let sk = user::process_server_response(&usk, &user_state, &response, b"my_server", &params, 32)
+        .unwrap();
+    assert_eq!(
+        ms_state.inner.read().sk.as_ref().unwrap().key().unwrap().0,
+        sk.0
+    );
+
+    // 摘要相同但并非逐字节相同的请求不会拿到缓存的响应
+    let mut altered = request.clone();
+    altered.suite_id ^= 1;
+    assert_eq!(
+        request_nonce(&altered).unwrap(),
+        request_nonce(&request).unwrap()
+    );
+    assert!(post_auth_json(ms_state.clone(), &altered).await.is_err());
+}
+
+#[tokio::test]
+async fn test_auth_confirm_checks_both_directions() {
+    use axum::response::IntoResponse;
+    use ibc_aaka_scheme::{rc, user};
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let ms_state = test_state(
+        params.clone(),
+        rc::register_server(&msk, b"my_server").unwrap(),
+    );
+    let confirm = |session_id_hex: &str, confirm: &[u8]| {
+        handle_auth_confirm(
+            State(ms_state.clone()),
+            Json(ConfirmRequest {
+                session_id_hex: session_id_hex.to_string(),
+                confirm_hex: hex::encode(confirm),
+            }),
+        )
+    };
+
+    // 尚未认证
+    let Err(err) = confirm("00", &[0; 32]).await else {
+        panic!("confirmation without a session should fail");
+    };
+    assert_eq!(err.status, StatusCode::BAD_REQUEST);
+
+    let (request, user_state) =
+        user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+    let Ok(body) = post_auth_json(ms_state.clone(), &request).await else {
+        panic!("auth request should succeed");
+    };
+    let response = ServerAuthResponse {
+        t: hex_to_ark(body["response"]["t_hex"].as_str().unwrap()).unwrap(),
+        y: hex_to_ark(body["response"]["y_hex"].as_str().unwrap()).unwrap(),
+        timestamp: body["response"]["timestamp"].as_u64().unwrap(),
+    };
+    let sk = user::process_server_response(&usk, &user_state, &response, b"my_server", &params, 32)
+        .unwrap();
+    let session_id_hex = body["session_id_hex"].as_str().unwrap();
+
+    // 密钥一致：MS 接受用户的确认值，用户也接受 MS 返回的确认值
+    let Ok(Json(reply)) = confirm(
+        session_id_hex,
+        &build_confirm(&sk, ConfirmDirection::ClientToServer),
+    )
+    .await
+    else {
+        panic!("matching keys should confirm");
+    };
+    let server_confirm = hex::decode(&reply.confirm_hex).unwrap();
+    assert!(verify_confirm(&sk, ConfirmDirection::ServerToClient, &server_confirm).is_ok());
+
+    // 用另一把密钥计算的确认值被拒绝
+    let wrong = SessionKey(vec![0; 32]);
+    let Err(err) = confirm(
+        session_id_hex,
+        &build_confirm(&wrong, ConfirmDirection::ClientToServer),
+    )
+    .await
+    else {
+        panic!("mismatched keys should fail confirmation");
+    };
+    let resp = err.into_response();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["code"], "ERR_KEY_CONFIRMATION");
+    // MS 的确认值同样不能通过另一把密钥的校验
+    assert!(verify_confirm(&wrong, ConfirmDirection::ServerToClient, &server_confirm).is_err());
+}
+
+#[tokio::test]
+async fn test_cors_preflight_returns_configured_headers() {
+    use ibc_aaka_scheme::rc;
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let ms_state = test_state(params, rc::register_server(&msk, b"my_server").unwrap());
+    let cors: CorsConfig = serde_json::from_value(serde_json::json!({
+        "allowed_origins": ["*"],
+        "allowed_methods": ["POST"],
+    }))
+    .unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/auth/initiate", listener.local_addr().unwrap());
+    let app = router(ms_state, default_max_body_bytes()).layer(cors.layer().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    let resp = reqwest::Client::new()
+        .request(reqwest::Method::OPTIONS, &url)
+        .header(header::ORIGIN, "https://app.example.com")
+        .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+        .header(header::ACCESS_CONTROL_REQUEST_HEADERS, ENVELOPE_HEADER)
+        .send()
+        .await
+        .unwrap();
+    assert!(resp.status().is_success());
+    let headers = resp.headers();
+    assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "POST");
+    assert_eq!(
+        headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+        "content-type,x-aaka-envelope,x-aaka-point-encoding"
+    );
+}
+
+#[tokio::test]
+async fn test_oversized_auth_request_rejected_with_413() {
+    use ibc_aaka_scheme::{rc, user};
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let ms_state = test_state(
+        params.clone(),
+        rc::register_server(&msk, b"my_server").unwrap(),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/auth/initiate", listener.local_addr().unwrap());
+    let app = router(ms_state, default_max_body_bytes());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    let client = reqwest::Client::new();
+
+    // A legitimate request fits comfortably within the default limit
+    let (request, _) =
+        user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+    let mut body = serde_json::json!({
+        "m_hex": ark_to_hex(&request.m).unwrap(),
+        "n": hex::encode(&request.n),
+        "sigma_hex": ark_to_hex(&request.sigma).unwrap(),
+        "timestamp": request.timestamp,
+        "suite_id": request.suite_id,
+    });
+    let resp = client.post(&url).json(&body).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // A huge `N` is refused before the JSON is parsed
+    body["n"] = "00".repeat(default_max_body_bytes()).into();
+    let resp = client.post(&url).json(&body).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn test_tampered_envelope_rejected_before_processing() {
+    use axum::response::IntoResponse;
+    use ibc_aaka_scheme::{rc, user};
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let ms_state = MsState {
+        require_envelope: true,
+        ..test_state(
+            params.clone(),
+            rc::register_server(&msk, b"my_server").unwrap(),
+        )
+    };
+    let key = EnvelopeKey::derive(&params, b"my_server").unwrap();
+    let mut headers = HeaderMap::new();
+    headers.insert(ENVELOPE_HEADER, ENVELOPE_ALGORITHM.parse().unwrap());
+    let mut sealed_request = || {
+        let (request, _) =
+            user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+        let body = serde_json::json!({
+            "m_hex": ark_to_hex(&request.m).unwrap(),
+            "n": hex::encode(&request.n),
+            "sigma_hex": ark_to_hex(&request.sigma).unwrap(),
+            "timestamp": request.timestamp,
+        "suite_id": request.suite_id,
+        });
+        key.seal(body.to_string().as_bytes()).unwrap()
+    };
+
+    let resp = handle_auth_request(
+        State(ms_state.clone()),
+        headers.clone(),
+        sealed_request().into(),
+    )
+    .await;
+    assert!(resp.is_ok());
+
+    let mut tampered = sealed_request();
+    tampered[20] ^= 1;
+    let Err(err) = handle_auth_request(State(ms_state.clone()), headers, tampered.into()).await
+    else {
+        panic!("tampered envelope should be rejected");
+    };
+    assert_eq!(err.into_response().status(), StatusCode::BAD_REQUEST);
+
+    // Without the envelope the request is refused when envelopes are required
+    let Err(err) = post_auth_json(ms_state.clone(), &{
+        user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng)
+            .unwrap()
+            .0
+    })
+    .await
+    else {
+        panic!("plain request should be rejected");
+    };
+    assert_eq!(err.into_response().status(), StatusCode::BAD_REQUEST);
+
+    // Neither rejected request reached signature verification
+    assert_eq!(ms_state.metrics.get(AuthOutcome::Success), 1);
+    assert_eq!(ms_state.metrics.get(AuthOutcome::BadSignature), 0);
+    assert_eq!(ms_state.metrics.get(AuthOutcome::DeserializationError), 2);
+}
+
+#[tokio::test]
+async fn test_malformed_auth_fields_get_400_naming_the_field() {
+    use axum::response::IntoResponse;
+    use ibc_aaka_scheme::{rc, user};
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let ms_state = test_state(
+        params.clone(),
+        rc::register_server(&msk, b"my_server").unwrap(),
+    );
+    let (request, _) =
+        user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+    let valid = serde_json::json!({
+        "m_hex": ark_to_hex(&request.m).unwrap(),
+        "n": hex::encode(&request.n),
+        "sigma_hex": ark_to_hex(&request.sigma).unwrap(),
+        "timestamp": request.timestamp,
+        "suite_id": request.suite_id,
+    });
+    let post = |body: serde_json::Value| {
+        let state = ms_state.clone();
+        async move {
+            let Err(err) =
+                handle_auth_request(State(state), HeaderMap::new(), body.to_string().into()).await
+            else {
+                panic!("malformed request should be rejected");
+            };
+            let resp = err.into_response();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        }
+    };
+
+    // (字段, 错误的值)：非 hex、hex 但不是合法的点或标量、奇数长度
+    let cases = [
+        ("m_hex", "not hex"),
+        ("m_hex", "00"),
+        ("n", "zz"),
+        ("n", "abc"),
+        ("sigma_hex", "not hex"),
+        ("sigma_hex", "ff"),
+    ];
+    for (field, value) in cases {
+        let mut body = valid.clone();
+        body[field] = value.into();
+        let error = post(body).await;
+        assert_eq!(error["code"], "ERR_BAD_REQUEST", "{field}={value}");
+        assert_eq!(error["field"], field, "{field}={value}");
+        let message = error["message"].as_str().unwrap();
+        assert!(
+            message.starts_with(&format!("Invalid {field}: ")),
+            "{message}"
+        );
+    }
+
+    // 不是合法的 JSON 或缺少字段时没有具体的字段，但仍是 400
+    let error = post(serde_json::json!("not an object")).await;
+    assert_eq!(error["code"], "ERR_BAD_REQUEST");
+    assert!(error.get("field").is_none());
+    let mut body = valid.clone();
+    body.as_object_mut().unwrap().remove("n");
+    let error = post(body).await;
+    assert_eq!(error["code"], "ERR_BAD_REQUEST");
+
+    assert_eq!(ms_state.metrics.get(AuthOutcome::DeserializationError), 8);
+    assert_eq!(ms_state.metrics.get(AuthOutcome::BadSignature), 0);
+
+    // 原始请求仍然可以完成认证
+    assert!(post_auth_json(ms_state.clone(), &request).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_auth_handshake_over_websocket() {
+    use futures_util::{SinkExt, StreamExt};
+    use ibc_aaka_scheme::{rc, user};
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let ms_state = test_state(
+        params.clone(),
+        rc::register_server(&msk, b"my_server").unwrap(),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}/auth/ws", listener.local_addr().unwrap());
+    let app = router(ms_state.clone(), default_max_body_bytes());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+    let mut exchange = async |frame: String| -> WsFrame {
+        socket.send(WsMessage::text(frame)).await.unwrap();
+        let reply = socket.next().await.unwrap().unwrap();
+        serde_json::from_str(reply.to_text().unwrap()).unwrap()
+    };
+
+    let (request, user_state) =
+        user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+    let frame = WsFrame::AuthRequest(AuthRequestPayload {
+        m_hex: ark_to_hex(&request.m).unwrap(),
+        n: hex::encode(&request.n),
+        sigma_hex: ark_to_hex(&request.sigma).unwrap(),
+        timestamp: request.timestamp,
+        suite_id: request.suite_id,
+    });
+    let WsFrame::AuthResponse(payload) = exchange(serde_json::to_string(&frame).unwrap()).await
+    else {
+        panic!("expected an auth_response frame");
+    };
+    let response = ServerAuthResponse {
+        t: hex_to_ark(&payload.t_hex).unwrap(),
+        y: hex_to_ark(&payload.y_hex).unwrap(),
+        timestamp: payload.timestamp,
+    };
+    let sk = user::process_server_response(&usk, &user_state, &response, b"my_server", &params, 32)
+        .unwrap();
+    assert_eq!(
+        ms_state.inner.read().sk.as_ref().unwrap().key().unwrap().0,
+        sk.0
+    );
+
+    // Malformed frames get an error frame and leave the connection usable
+    let WsFrame::Error { .. } = exchange("not json".to_string()).await else {
+        panic!("expected an error frame");
+    };
+    let WsFrame::Error { message } = exchange(serde_json::to_string(&frame).unwrap()).await else {
+        panic!("replayed request should be rejected");
+    };
+    assert!(message.contains("Replayed"));
+}
+
+#[tokio::test]
+async fn test_tampered_signature_counted_as_bad_signature() {
+    use ibc_aaka_scheme::{ScalarField, rc, user};
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let ms_state = test_state(
+        params.clone(),
+        rc::register_server(&msk, b"my_server").unwrap(),
+    );
+
+    let (mut request, _) =
+        user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+    request.sigma += ScalarField::from(1u64);
+    assert!(post_auth_json(ms_state.clone(), &request).await.is_err());
+    assert_eq!(ms_state.metrics.get(AuthOutcome::BadSignature), 1);
+    assert_eq!(ms_state.metrics.get(AuthOutcome::Success), 0);
+
+    let resp = handle_metrics(State(ms_state)).await.into_response();
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains(r#"aaka_ms_auth_total{server="my_server",outcome="bad_signature"} 1"#));
+}
+
+#[tokio::test]
+async fn test_auth_batch_reports_per_entry_outcomes() {
+    use ibc_aaka_scheme::{ScalarField, rc, user};
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let ms_state = MsState {
+        max_auth_batch: 4,
+        ..test_state(
+            params.clone(),
+            rc::register_server(&msk, b"my_server").unwrap(),
+        )
+    };
+
+    let mut sessions = Vec::new();
+    let mut payloads = Vec::new();
+    for id in ["alice", "bob", "carol"] {
+        let usk = rc::register_user(&msk, id.as_bytes(), &mut rng).unwrap();
+        let (mut request, state) =
+            user::initiate_authentication(&usk, id.as_bytes(), b"my_server", &params, &mut rng)
+                .unwrap();
+        if id == "bob" {
+            request.sigma += ScalarField::from(1u64);
+        }
+        payloads.push(serde_json::json!({
+            "m_hex": ark_to_hex(&request.m).unwrap(),
+            "n": hex::encode(&request.n),
+            "sigma_hex": ark_to_hex(&request.sigma).unwrap(),
+            "timestamp": request.timestamp,
+            "suite_id": request.suite_id,
+        }));
+        sessions.push((usk, state));
+    }
+    // 无法解析的条目同样只影响自己
+    payloads.push(serde_json::json!({
+        "m_hex": "zz",
+        "n": "",
+        "sigma_hex": "",
+        "timestamp": 0,
+        "suite_id": 0,
+    }));
+
+    let post = |payloads: Vec<serde_json::Value>| {
+        let state = ms_state.clone();
+        async move {
+            let body = serde_json::json!({ "requests": payloads }).to_string();
+            handle_auth_batch(State(state), HeaderMap::new(), Bytes::from(body)).await
+        }
+    };
+    let Ok(Json(batch)) = post(payloads.clone()).await else {
+        panic!("batch request failed");
+    };
+    let results = serde_json::to_value(&batch.results).unwrap();
+    let statuses: Vec<&str> = results
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| r["status"].as_str().unwrap())
+        .collect();
+    assert_eq!(statuses, ["ok", "error", "ok", "error"]);
+    assert_eq!(results[1]["code"], "ERR_BAD_SIGNATURE");
+    assert_eq!(results[3]["code"], "ERR_BAD_REQUEST");
+    assert_eq!(ms_state.metrics.get(AuthOutcome::Success), 2);
+    assert_eq!(ms_state.metrics.get(AuthOutcome::BadSignature), 1);
+
+    // 成功的条目可以各自完成握手
+    for i in [0, 2] {
+        let (usk, state) = &sessions[i];
+        let response = ServerAuthResponse {
+            t: hex_to_ark(results[i]["response"]["t_hex"].as_str().unwrap()).unwrap(),
+            y: hex_to_point(results[i]["response"]["y_hex"].as_str().unwrap()).unwrap(),
+            timestamp: results[i]["response"]["timestamp"].as_u64().unwrap(),
+        };
+        let transcript = ibc_aaka_scheme::Transcript::from_user(state, &response);
+        assert_eq!(
+            hex::encode(session_id(&transcript).unwrap()),
+            results[i]["session_id_hex"]
+        );
+        user::process_server_response(usk, state, &response, b"my_server", &params, 32).unwrap();
+    }
+
+    // 重发同一批请求：已接受的条目被当作重放
+    let Ok(Json(replayed)) = post(payloads[..3].to_vec()).await else {
+        panic!("batch request failed");
+    };
+    let replayed = serde_json::to_value(&replayed.results).unwrap();
+    assert_eq!(replayed[0]["code"], "ERR_REPLAY");
+    assert_eq!(replayed[2]["code"], "ERR_REPLAY");
+
+    // 超出上限的批次整体拒绝
+    let oversized = vec![payloads[0].clone(); 5];
+    let Err(err) = post(oversized).await else {
+        panic!("oversized batch should be rejected");
+    };
+    assert_eq!(err.status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_auth_request_over_cbor() {
+    use ibc_aaka_scheme::{rc, user};
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let ms_state = test_state(
+        params.clone(),
+        rc::register_server(&msk, b"my_server").unwrap(),
+    );
+
+    let (request, user_state) =
+        user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+    let cbor_body = request.to_cbor().unwrap();
+    let json_body = serde_json::json!({
+        "m_hex": ark_to_hex(&request.m).unwrap(),
+        "n": hex::encode(&request.n),
+        "sigma_hex": ark_to_hex(&request.sigma).unwrap(),
+        "timestamp": request.timestamp,
+        "suite_id": request.suite_id,
+    })
+    .to_string();
+    assert!(cbor_body.len() < json_body.len());
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, CBOR_CONTENT_TYPE.parse().unwrap());
+    headers.insert(header::ACCEPT, CBOR_CONTENT_TYPE.parse().unwrap());
+    let Ok(resp) = handle_auth_request(State(ms_state), headers, Bytes::from(cbor_body)).await
+    else {
+        panic!("authentication failed");
+    };
+    assert_eq!(resp.headers()[header::CONTENT_TYPE], CBOR_CONTENT_TYPE);
+
+    let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let server_response = ServerAuthResponse::from_cbor(&bytes).unwrap();
+    user::process_server_response(
+        &usk,
+        &user_state,
+        &server_response,
+        b"my_server",
+        &params,
+        32,
+    )
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_uncompressed_points_negotiated_by_header() {
+    use ibc_aaka_scheme::{rc, user};
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let ms_state = test_state(
+        params.clone(),
+        rc::register_server(&msk, b"my_server").unwrap(),
+    );
+
+    let (request, user_state) =
+        user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, CBOR_CONTENT_TYPE.parse().unwrap());
+    headers.insert(header::ACCEPT, CBOR_CONTENT_TYPE.parse().unwrap());
+    headers.insert(POINT_ENCODING_HEADER, "uncompressed".parse().unwrap());
+    let body = Bytes::from(request.to_cbor_with(PointEncoding::Uncompressed).unwrap());
+    let Ok(resp) = handle_auth_request(State(ms_state.clone()), headers.clone(), body).await else {
+        panic!("authentication failed");
+    };
+
+    let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let server_response = ServerAuthResponse::from_cbor(&bytes).unwrap();
+    assert_eq!(
+        bytes,
+        server_response
+            .to_cbor_with(PointEncoding::Uncompressed)
+            .unwrap()
+    );
+    user::process_server_response(
+        &usk,
+        &user_state,
+        &server_response,
+        b"my_server",
+        &params,
+        32,
+    )
+    .unwrap();
+
+    // An unknown encoding is rejected before any protocol work
+    headers.insert(POINT_ENCODING_HEADER, "raw".parse().unwrap());
+    let body = Bytes::from(request.to_cbor().unwrap());
+    let Err(err) = handle_auth_request(State(ms_state), headers, body).await else {
+        panic!("unknown point encoding was accepted");
+    };
+    assert_eq!(err.status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_grpc_initiate_auth_completes_handshake() {
+    use ibc_aaka_scheme::{
+        ServerAuthResponse,
+        proto::{self, ms_client::MsClient},
+        rc, user,
+    };
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let ms_state = test_state(
+        params.clone(),
+        rc::register_server(&msk, b"my_server").unwrap(),
+    );
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(MsServer::new(MsService {
+                state: ms_state.clone(),
+            }))
+            .serve_with_incoming(tonic::transport::server::TcpIncoming::from(listener)),
+    );
+    let mut client = MsClient::connect(url).await.unwrap();
+
+    let (request, user_state) =
+        user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+    let response = client
+        .initiate_auth(proto::UserAuthRequest::try_from(&request).unwrap())
+        .await
+        .unwrap()
+        .into_inner();
+    let response = ServerAuthResponse::try_from(response).unwrap();
+
+    let sk = user::process_server_response(&usk, &user_state, &response, b"my_server", &params, 32)
+        .unwrap();
+    assert_eq!(
+        sk.0,
+        ms_state.inner.read().sk.as_ref().unwrap().key().unwrap().0
+    );
+
+    // A malformed point is rejected as an invalid argument
+    let status = client
+        .initiate_auth(proto::UserAuthRequest {
+            m: vec![0; 3],
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn test_required_envelope_enforced_on_grpc_and_websocket() {
+    use futures_util::{SinkExt, StreamExt};
+    use ibc_aaka_scheme::{
+        proto::{self, ms_client::MsClient},
+        rc, user,
+    };
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let ms_state = MsState {
+        require_envelope: true,
+        ..test_state(
+            params.clone(),
+            rc::register_server(&msk, b"my_server").unwrap(),
+        )
+    };
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(MsServer::new(MsService {
+                state: ms_state.clone(),
+            }))
+            .serve_with_incoming(tonic::transport::server::TcpIncoming::from(listener)),
+    );
+    let mut client = MsClient::connect(url).await.unwrap();
+    let (request, _) =
+        user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+    let status = client
+        .initiate_auth(proto::UserAuthRequest::try_from(&request).unwrap())
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    assert!(status.message().contains("envelope"));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}/auth/ws", listener.local_addr().unwrap());
+    let app = router(ms_state.clone(), default_max_body_bytes());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+    let (request, _) =
+        user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+    let frame = WsFrame::AuthRequest(AuthRequestPayload {
+        m_hex: ark_to_hex(&request.m).unwrap(),
+        n: hex::encode(&request.n),
+        sigma_hex: ark_to_hex(&request.sigma).unwrap(),
+        timestamp: request.timestamp,
+        suite_id: request.suite_id,
+    });
+    socket
+        .send(WsMessage::text(serde_json::to_string(&frame).unwrap()))
+        .await
+        .unwrap();
+    let reply = socket.next().await.unwrap().unwrap();
+    let WsFrame::Error { message } = serde_json::from_str(reply.to_text().unwrap()).unwrap() else {
+        panic!("expected an error frame");
+    };
+    assert!(message.contains("envelope"));
+
+    // Neither request reached signature verification
+    assert_eq!(ms_state.metrics.get(AuthOutcome::Success), 0);
+    assert_eq!(ms_state.metrics.get(AuthOutcome::DeserializationError), 2);
+    assert!(ms_state.inner.read().sk.is_none());
+}
+
+#[tokio::test]
+async fn test_params_rotation_picked_up_on_refresh() {
+    use axum::{extract::State as AxumState, routing::get};
+    use ibc_aaka_scheme::rc;
+
+    // Mock RC serving versioned params over CBOR; `None` simulates an outage
+    type Served = Arc<parking_lot::Mutex<Option<(SystemParameters, String)>>>;
+    async fn serve_params(AxumState(served): AxumState<Served>, headers: HeaderMap) -> Response {
+        let Some((params, etag)) = served.lock().clone() else {
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        };
+        if headers
+            .get(header::IF_NONE_MATCH)
+            .is_some_and(|value| value == etag.as_str())
+        {
+            return StatusCode::NOT_MODIFIED.into_response();
+        }
+        ([(header::ETAG, etag)], params.to_cbor().unwrap()).into_response()
+    }
+
+    let mut rng = StdRng::from_entropy();
+    let (params_a, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let (params_b, _) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let served: Served = Arc::new(parking_lot::Mutex::new(Some((
+        params_a.clone(),
+        "\"a\"".to_string(),
+    ))));
+    let rc_app = Router::new()
+        .route("/params", get(serve_params))
+        .with_state(served.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let rc_url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, rc_app).await.unwrap() });
+
+    let ms_state = test_state(
+        params_a.clone(),
+        rc::register_server(&msk, b"my_server").unwrap(),
+    );
+    let cache = ParamsCache::with_params(&rc_url, params_a.clone());
+    let client = reqwest::Client::new();
+
+    // Unchanged params, then a 304 on the second round
+    assert!(!cache.refresh(&client).await.unwrap());
+    assert!(!cache.refresh(&client).await.unwrap());
+
+    // The RC rotates its params
+    *served.lock() = Some((params_b.clone(), "\"b\"".to_string()));
+    refresh_params(&ms_state, &cache, &client).await;
+    assert_eq!(ms_state.inner.read().params, params_b);
+
+    // RC outage: the last-known-good params are kept
+    *served.lock() = None;
+    assert!(cache.refresh(&client).await.is_err());
+    refresh_params(&ms_state, &cache, &client).await;
+    assert_eq!(ms_state.inner.read().params, params_b);
+}
+
+#[tokio::test]
+async fn test_startup_waits_for_rc_to_come_online() {
+    use axum::routing::get;
+    use ibc_aaka_scheme::rc;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    // Mock RC that is unavailable for the first two attempts
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let params_json = serde_json::json!({
+        "p_hex": ark_to_hex(&params.p).unwrap(),
+        "p_pub_hex": ark_to_hex(&params.p_pub).unwrap(),
+        "p_pub_hat_hex": ark_to_hex(&params.p_pub_hat).unwrap(),
+        "g_hex": ark_to_hex(&params.g).unwrap(),
+    });
+    let ssk = rc::register_server(&msk, b"my_server").unwrap();
+    let ssk_json = serde_json::json!({ "sid_ms_hex": ark_to_hex(&ssk.sid_ms).unwrap() });
+    let attempts = Arc::new(AtomicU32::new(0));
+    let counter = attempts.clone();
+    let rc_app = Router::new()
+        .route(
+            "/params",
+            get(move || async move {
+                if counter.fetch_add(1, Ordering::SeqCst) < 2 {
+                    return StatusCode::SERVICE_UNAVAILABLE.into_response();
+                }
+                Json(params_json).into_response()
+            }),
+        )
+        .route(
+            "/register/server",
+            post(move || async move { Json(ssk_json) }),
+        );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let rc_url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, rc_app).await.unwrap() });
+
+    let client = HttpRcClient::new(reqwest::Client::new(), rc_url);
+    let retry = RetryConfig {
+        attempts: 3,
+        backoff_ms: 10,
+    };
+    let inner = register_with_retry(&client, b"my_server".to_vec(), &retry)
+        .await
+        .unwrap();
+    assert_eq!(inner.params, params);
+    assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+    // Giving up once the attempts are exhausted
+    attempts.store(0, Ordering::SeqCst);
+    let retry = RetryConfig {
+        attempts: 2,
+        backoff_ms: 10,
+    };
+    let Err(err) = register_with_retry(&client, b"my_server".to_vec(), &retry).await else {
+        panic!("registration should give up after two attempts");
+    };
+    assert!(err.to_string().contains("after 2 attempts"));
+}
+
+#[tokio::test]
+async fn test_self_test_rejects_mismatched_config() {
+    use ibc_aaka_scheme::{rc, rc_client::MockRc};
+
+    let mut rng = StdRng::from_entropy();
+    let mock = MockRc::new(&mut rng).unwrap();
+    let params = mock.params().clone();
+    let state = |ms_id: &str, params: &SystemParameters, key_id: &[u8]| InnerMsState {
+        ms_id: ms_id.as_bytes().to_vec(),
+        params: params.clone(),
+        ssk: rc::register_server(mock.msk(), key_id).unwrap(),
+        rng: StdRng::from_entropy(),
+        sk: None,
+    };
+
+    let consistent = state("my_server", &params, b"my_server");
+    assert!(self_test(&mock, &consistent).await.is_ok());
+
+    // 私钥属于另一个 ID，例如拷贝了其他节点的 ms_state.json
+    let wrong_key = state("my_server", &params, b"other_server");
+    let err = self_test(&mock, &wrong_key).await.unwrap_err();
+    assert!(err.to_string().contains("Self-test failed"), "{err:#}");
+
+    // 系统参数来自另一个 RC
+    let (other_params, _) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let wrong_params = state("my_server", &other_params, b"my_server");
+    let err = self_test(&mock, &wrong_params).await.unwrap_err();
+    assert!(err.to_string().contains("Self-test failed"), "{err:#}");
+}
+
+#[tokio::test]
+async fn test_startup_detects_key_from_previous_rc_setup() {
+    use ibc_aaka_scheme::{rc, rc_client::MockRc};
+
+    let mut rng = StdRng::from_entropy();
+    let mock = MockRc::new(&mut rng).unwrap();
+    let current = InnerMsState {
+        ms_id: b"my_server".to_vec(),
+        params: mock.params().clone(),
+        ssk: rc::register_server(mock.msk(), b"my_server").unwrap(),
+        rng: StdRng::from_entropy(),
+        sk: None,
+    };
+    assert!(check_key_against_rc(&mock, &current).await.is_ok());
+
+    // RC 重新 /setup 之前保存的参数与私钥：两者彼此一致，但与 RC 当前的参数不符
+    let (old_params, old_msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let stale = InnerMsState {
+        params: old_params,
+        ssk: rc::register_server(&old_msk, b"my_server").unwrap(),
+        ..current
+    };
+    let err = check_key_against_rc(&mock, &stale).await.unwrap_err();
+    let msg = format!("{err:#}");
+    assert!(msg.contains("different master key"), "{msg}");
+    assert!(msg.contains("re-registers with the RC"), "{msg}");
+    assert!(msg.contains(&mock.params().fingerprint()), "{msg}");
+
+    // RC 不可达时无法校验，照常启动
+    mock.fail_next(1);
+    assert!(check_key_against_rc(&mock, &stale).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_state_file_reused_across_restarts() {
+    use ibc_aaka_scheme::rc_client::MockRc;
+
+    let mock = MockRc::new(&mut StdRng::from_entropy()).unwrap();
+    let state_file =
+        std::env::temp_dir().join(format!("ms_state_reuse_{}.json", std::process::id()));
+    let state_file = state_file.to_str().unwrap();
+    let retry = RetryConfig::default();
+    let load = || init_state(&mock, b"my_server".to_vec(), state_file, false, &retry);
+
+    // The first start registers and saves the state, the second one only checks it against the RC
+    let first = load().await.unwrap();
+    assert_eq!(mock.calls(), 2);
+    let second = load().await.unwrap();
+    assert_eq!(mock.calls(), 3);
+    assert_eq!(second.params, first.params);
+    assert_eq!(second.ssk, first.ssk);
+
+    // A corrupted file is replaced by a fresh registration
+    std::fs::write(state_file, "not json").unwrap();
+    load().await.unwrap();
+    assert_eq!(mock.calls(), 5);
+    assert!(InnerMsState::load_from_file(state_file).is_ok());
+    std::fs::remove_file(state_file).unwrap();
+}
+
+#[tokio::test]
+async fn test_binary_ms_id_registers_and_authenticates() {
+    use ibc_aaka_scheme::{rc_client::MockRc, user};
+
+    let ms_id = b"ms\x00\x80";
+    let config: Config = serde_json::from_value(serde_json::json!({
+        "ms_id": hex::encode(ms_id),
+        "ms_id_encoding": "hex",
+        "ms_addr": "127.0.0.1:0",
+        "rc_url": "http://127.0.0.1:5000",
+    }))
+    .unwrap();
+    let mut rng = StdRng::from_entropy();
+    let mock = MockRc::new(&mut rng).unwrap();
+    let state_file =
+        std::env::temp_dir().join(format!("ms_state_binary_{}.json", std::process::id()));
+    let state_file = state_file.to_str().unwrap();
+    let retry = RetryConfig::default();
+
+    // The key is issued for the decoded bytes, and they survive the state file
+    init_state(
+        &mock,
+        config.ms_id_bytes().unwrap(),
+        state_file,
+        false,
+        &retry,
+    )
+    .await
+    .unwrap();
+    let inner = init_state(
+        &mock,
+        config.ms_id_bytes().unwrap(),
+        state_file,
+        false,
+        &retry,
+    )
+    .await
+    .unwrap();
+    std::fs::remove_file(state_file).unwrap();
+    assert_eq!(inner.ms_id, ms_id);
+
+    let ms_state = test_state_with(inner);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/auth/initiate", listener.local_addr().unwrap());
+    let app = router(ms_state, default_max_body_bytes());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    let user_id = b"dev\x00\xff";
+    let usk = mock.register_user(user_id).await.unwrap();
+    let params = mock.params().clone();
+    let (request, user_state) =
+        user::initiate_authentication(&usk, user_id, ms_id, &params, &mut rng).unwrap();
+    let resp: serde_json::Value = reqwest::Client::new()
+        .post(&url)
+        .json(&serde_json::json!({
+            "m_hex": ark_to_hex(&request.m).unwrap(),
+            "n": hex::encode(&request.n),
+            "sigma_hex": ark_to_hex(&request.sigma).unwrap(),
+            "timestamp": request.timestamp,
+            "suite_id": request.suite_id,
+        }))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let response = ServerAuthResponse {
+        t: hex_to_ark(resp["response"]["t_hex"].as_str().unwrap()).unwrap(),
+        y: hex_to_ark(resp["response"]["y_hex"].as_str().unwrap()).unwrap(),
+        timestamp: resp["response"]["timestamp"].as_u64().unwrap(),
+    };
+    let sk =
+        user::process_server_response(&usk, &user_state, &response, ms_id, &params, 32).unwrap();
+    assert_eq!(hex::encode(&sk.0), resp["session_key_hex"]);
+}
+
+#[tokio::test]
+async fn test_debug_state_is_admin_gated_and_sanitized() {
+    use ibc_aaka_scheme::user;
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let ssk = rc::register_server(&msk, b"my_server").unwrap();
+    let rc_app = Router::new().route("/ready", get(|| async { StatusCode::OK }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let rc_url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, rc_app).await.unwrap() });
+
+    let debug = |admin_token: Option<&str>| {
+        Arc::new(DebugConfig {
+            admin_token: admin_token.map(str::to_string),
+            rc_url: Some(rc_url.clone()),
+        })
+    };
+    let ms_state = MsState {
+        auth_retries: Some(Arc::new(AuthRetryCache::new(Duration::from_secs(60)))),
+        debug: debug(Some("s3cret")),
+        ..test_state(params.clone(), ssk.clone())
+    };
+    let (request, _) =
+        user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+    let Ok(auth) = post_auth_json(ms_state.clone(), &request).await else {
+        panic!("authentication failed");
+    };
+
+    let serve = |ms_state: MsState| async move {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = router(ms_state, default_max_body_bytes());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        addr
+    };
+    let addr = serve(ms_state.clone()).await;
+    let client = reqwest::Client::new();
+    let get = |addr: std::net::SocketAddr, token: Option<&str>| {
+        let mut request = client.get(format!("http://{addr}/debug/state"));
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        request.send()
+    };
+
+    let resp = get(addr, Some("s3cret")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = resp.text().await.unwrap();
+    let state: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(state["ms_id"], "my_server");
+    assert_eq!(state["build"]["package"], "aaka_ms_server");
+    assert_eq!(state["params_fingerprint"], params.fingerprint());
+    assert_eq!(state["ssk_loaded"], true);
+    assert_eq!(state["ssk_fingerprint"], ssk.fingerprint());
+    assert_eq!(state["sessions"]["active"], 1);
+    assert_eq!(state["replay_cache_entries"], 1);
+    assert_eq!(state["auth_retry_cache_entries"], 1);
+    assert_eq!(state["rc"]["url"], rc_url);
+    assert_eq!(state["rc"]["reachable"], true);
+    assert_eq!(state["rc"]["ready"], true);
+
+    // 私钥、会话密钥与会话 ID 都不出现在响应中
+    for secret in [
+        ark_to_hex(&ssk).unwrap(),
+        ark_to_hex(&ssk.sid_ms).unwrap(),
+        auth["session_key_hex"].as_str().unwrap().to_string(),
+        auth["session_id_hex"].as_str().unwrap().to_string(),
+    ] {
+        assert!(!body.contains(&secret), "{body}");
+    }
+
+    // 没有或错误的令牌；未配置令牌时接口关闭
+    for token in [None, Some("guess")] {
+        let resp = get(addr, token).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+    let disabled = serve(MsState {
+        debug: debug(None),
+        ..ms_state
+    })
+    .await;
+    let resp = get(disabled, Some("s3cret")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["code"], "ERR_FORBIDDEN");
+}
+
+#[tokio::test]
+async fn test_gzip_capable_clients_get_compressed_responses() {
+    use std::io::Read;
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let ms_state = test_state(params, rc::register_server(&msk, b"my_server").unwrap());
+    let app = router(ms_state, default_max_body_bytes());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    let client = reqwest::Client::new();
+    let url = format!("http://{addr}/metrics");
+
+    // 不声明 Accept-Encoding 的客户端收到原样的响应
+    let plain = client.get(&url).send().await.unwrap();
+    assert!(plain.headers().get(header::CONTENT_ENCODING).is_none());
+    let plain = plain.text().await.unwrap();
+
+    let resp = client
+        .get(&url)
+        .header(header::ACCEPT_ENCODING, "gzip")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.headers()[header::CONTENT_ENCODING], "gzip");
+    let compressed = resp.bytes().await.unwrap();
+    let mut text = String::new();
+    flate2::read::GzDecoder::new(&compressed[..])
+        .read_to_string(&mut text)
+        .unwrap();
+    assert!(compressed.len() < text.len());
+    assert_eq!(text, plain);
 }
@@ -0,0 +1,188 @@
+// Authentication and session counters exported in the Prometheus text format on `/metrics`
+use ibc_aaka_scheme::AAKAError;
+use serde::Serialize;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthOutcome {
+    Success,
+    BadSignature,
+    StaleTimestamp,
+    Replay,
+    DeserializationError,
+    Other,
+}
+
+impl AuthOutcome {
+    const ALL: [AuthOutcome; 6] = [
+        AuthOutcome::Success,
+        AuthOutcome::BadSignature,
+        AuthOutcome::StaleTimestamp,
+        AuthOutcome::Replay,
+        AuthOutcome::DeserializationError,
+        AuthOutcome::Other,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            AuthOutcome::Success => "success",
+            AuthOutcome::BadSignature => "bad_signature",
+            AuthOutcome::StaleTimestamp => "stale_timestamp",
+            AuthOutcome::Replay => "replay",
+            AuthOutcome::DeserializationError => "deserialization_error",
+            AuthOutcome::Other => "other",
+        }
+    }
+}
+
+impl From<&AAKAError> for AuthOutcome {
+    fn from(err: &AAKAError) -> Self {
+        match err {
+            AAKAError::SignatureVerificationFailed => AuthOutcome::BadSignature,
+            AAKAError::InvalidTimestamp => AuthOutcome::StaleTimestamp,
+            // Malformed N (or N encrypted for another server ID), or points failing the
+            // curve/subgroup checks
+            AAKAError::Deserialization(_)
+            | AAKAError::InvalidInput(_)
+            | AAKAError::ServerIdentityMismatch(_) => AuthOutcome::DeserializationError,
+            _ => AuthOutcome::Other,
+        }
+    }
+}
+
+// What happened to a session in the MS session store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEvent {
+    Established,
+    // Evicted by a newer handshake while still valid
+    Replaced,
+    // Evicted after its TTL passed
+    Expired,
+}
+
+impl SessionEvent {
+    const ALL: [SessionEvent; 3] = [
+        SessionEvent::Established,
+        SessionEvent::Replaced,
+        SessionEvent::Expired,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SessionEvent::Established => "established",
+            SessionEvent::Replaced => "replaced",
+            SessionEvent::Expired => "expired",
+        }
+    }
+}
+
+// Snapshot of the session store served on `/sessions/stats`, for capacity planning
+#[derive(Debug, Serialize)]
+pub struct SessionStats {
+    pub active: usize,
+    pub oldest_age_secs: Option<f64>,
+    pub newest_age_secs: Option<f64>,
+    pub established_total: u64,
+    pub replaced_total: u64,
+    pub expired_total: u64,
+    // Replaced plus expired sessions per minute since the MS started
+    pub evictions_per_min: f64,
+}
+
+pub struct AuthMetrics {
+    counts: [AtomicU64; AuthOutcome::ALL.len()],
+    sessions: [AtomicU64; SessionEvent::ALL.len()],
+    started_at: Instant,
+}
+
+impl Default for AuthMetrics {
+    fn default() -> Self {
+        Self {
+            counts: Default::default(),
+            sessions: Default::default(),
+            started_at: Instant::now(),
+        }
+    }
+}
+
+impl AuthMetrics {
+    pub fn record(&self, outcome: AuthOutcome) {
+        self.counts[outcome as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, outcome: AuthOutcome) -> u64 {
+        self.counts[outcome as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn record_session(&self, event: SessionEvent) {
+        self.sessions[event as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn session_count(&self, event: SessionEvent) -> u64 {
+        self.sessions[event as usize].load(Ordering::Relaxed)
+    }
+
+    // Combine the session counters with the ages of the sessions currently in the store
+    pub fn session_stats(&self, ages: &[Duration]) -> SessionStats {
+        let evicted =
+            self.session_count(SessionEvent::Replaced) + self.session_count(SessionEvent::Expired);
+        let minutes = self.started_at.elapsed().as_secs_f64() / 60.0;
+        SessionStats {
+            active: ages.len(),
+            oldest_age_secs: ages.iter().max().map(Duration::as_secs_f64),
+            newest_age_secs: ages.iter().min().map(Duration::as_secs_f64),
+            established_total: self.session_count(SessionEvent::Established),
+            replaced_total: self.session_count(SessionEvent::Replaced),
+            expired_total: self.session_count(SessionEvent::Expired),
+            evictions_per_min: if minutes > 0.0 {
+                evicted as f64 / minutes
+            } else {
+                0.0
+            },
+        }
+    }
+
+    // Render the counters in the Prometheus text exposition format
+    pub fn render(&self, ms_id: &str, sessions: &SessionStats) -> String {
+        let mut out = String::from(
+            "# HELP aaka_ms_auth_total Authentication requests handled by the MS, by outcome.\n\
+             # TYPE aaka_ms_auth_total counter\n",
+        );
+        let server = ms_id.replace('\\', "\\\\").replace('"', "\\\"");
+        for outcome in AuthOutcome::ALL {
+            let _ = writeln!(
+                out,
+                "aaka_ms_auth_total{{server=\"{server}\",outcome=\"{}\"}} {}",
+                outcome.label(),
+                self.get(outcome)
+            );
+        }
+
+        out.push_str(
+            "# HELP aaka_ms_sessions_total Sessions established and evicted by the MS, by event.\n\
+             # TYPE aaka_ms_sessions_total counter\n",
+        );
+        for event in SessionEvent::ALL {
+            let _ = writeln!(
+                out,
+                "aaka_ms_sessions_total{{server=\"{server}\",event=\"{}\"}} {}",
+                event.label(),
+                self.session_count(event)
+            );
+        }
+        let _ = write!(
+            out,
+            "# HELP aaka_ms_sessions_active Sessions currently usable on the MS.\n\
+             # TYPE aaka_ms_sessions_active gauge\n\
+             aaka_ms_sessions_active{{server=\"{server}\"}} {}\n\
+             # HELP aaka_ms_session_oldest_age_seconds Age of the oldest active session.\n\
+             # TYPE aaka_ms_session_oldest_age_seconds gauge\n\
+             aaka_ms_session_oldest_age_seconds{{server=\"{server}\"}} {}\n",
+            sessions.active,
+            sessions.oldest_age_secs.unwrap_or(0.0)
+        );
+        out
+    }
+}
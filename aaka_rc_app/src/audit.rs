@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::Subscriber;
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+use tracing_subscriber::{Layer, filter::filter_fn, fmt, registry::LookupSpan};
+
+/// 审计事件使用的 tracing target，只有该 target 下的事件会被写入审计日志。
+pub const AUDIT_TARGET: &str = "audit";
+
+/// 审计日志文件。每次 flush 都会调用 `sync_data`，保证写入落盘。
+struct AuditFile(File);
+
+impl Write for AuditFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        self.0.sync_data()
+    }
+}
+
+/// 以追加模式打开审计日志，并返回一个非阻塞 writer。
+///
+/// 写入由后台线程完成（每批写入后 flush），因此不会阻塞请求处理路径。
+/// 调用者需要持有返回的 `WorkerGuard`，以便在退出时写完剩余的记录。
+pub fn open(path: impl AsRef<Path>) -> io::Result<(NonBlocking, WorkerGuard)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(tracing_appender::non_blocking(AuditFile(file)))
+}
+
+/// 创建一个只接收 `AUDIT_TARGET` 事件、以 JSON Lines 格式写入 `writer` 的 layer。
+pub fn layer<S>(writer: NonBlocking) -> impl Layer<S> + Send + Sync + 'static
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fmt::layer()
+        .json()
+        .with_current_span(false)
+        .with_span_list(false)
+        .with_writer(writer)
+        .with_filter(filter_fn(|meta| meta.target() == AUDIT_TARGET))
+}
+
+/// 一条审计记录。只记录 ID 的 SHA3-256 摘要，避免审计日志本身泄露身份。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub event: String,
+    pub kind: String,
+    pub id_hash: String,
+    pub node: String,
+    pub timestamp: u64,
+}
+
+impl AuditRecord {
+    /// 一次密钥签发
+    pub fn registration(kind: &str, id: &[u8], node: &str) -> Self {
+        Self {
+            event: "registration".to_string(),
+            kind: kind.to_string(),
+            id_hash: hex::encode(Sha3_256::digest(id)),
+            node: node.to_string(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// 记录一次密钥签发到审计日志，并返回该记录，供调用者写入 [`crate::store::RcStore`]
+pub fn record_registration(kind: &str, id: &[u8], node: &str) -> AuditRecord {
+    let record = AuditRecord::registration(kind, id, node);
+    tracing::info!(
+        target: AUDIT_TARGET,
+        event = %record.event,
+        kind = %record.kind,
+        id_hash = %record.id_hash,
+        node = %record.node,
+        timestamp = record.timestamp,
+        "credential issued"
+    );
+    record
+}
@@ -0,0 +1,99 @@
+//! RC 的 gRPC 接口。每个方法都复用对应的 HTTP handler，两种传输共享同一套逻辑（分片、审计、追踪）。
+use super::{
+    AdminScope, AppError, RcState, RegisterRequest, SetupQuery, authorize_admin, register_server,
+    register_user, require_ready, setup_system,
+};
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+};
+use ibc_aaka_scheme::proto::{self, rc_server::Rc};
+use tonic::{Request, Response, Status};
+
+pub use ibc_aaka_scheme::proto::rc_server::RcServer;
+
+pub struct RcService {
+    pub state: RcState,
+}
+
+impl From<AppError> for Status {
+    fn from(err: AppError) -> Self {
+        let message = err.error.to_string();
+        match err.status {
+            StatusCode::SERVICE_UNAVAILABLE => Status::unavailable(message),
+            StatusCode::UNAUTHORIZED => Status::unauthenticated(message),
+            StatusCode::FORBIDDEN => Status::permission_denied(message),
+            _ => Status::internal(message),
+        }
+    }
+}
+
+fn decode_hex(hex_str: &str) -> Result<Vec<u8>, Status> {
+    hex::decode(hex_str).map_err(|e| Status::internal(format!("Hex decoding failed: {e}")))
+}
+
+impl RcService {
+    async fn params(&self) -> Result<proto::SystemParameters, Status> {
+        let state_read = self.state.inner.read().await;
+        let (_, params) =
+            require_ready(&state_read).map_err(|e| Status::failed_precondition(e.to_string()))?;
+        proto::SystemParameters::try_from(params).map_err(|e| Status::internal(e.to_string()))
+    }
+}
+
+#[tonic::async_trait]
+impl Rc for RcService {
+    // 与 HTTP 的 `/setup` 相同，按 `authorization` 元数据中的管理令牌鉴权
+    async fn setup(
+        &self,
+        request: Request<proto::Empty>,
+    ) -> Result<Response<proto::SystemParameters>, Status> {
+        let authorization = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok());
+        authorize_admin(&self.state, AdminScope::Operator, authorization).await?;
+        let mut headers = HeaderMap::new();
+        if let Some(value) = authorization.and_then(|value| HeaderValue::from_str(value).ok()) {
+            headers.insert(header::AUTHORIZATION, value);
+        }
+        let _ = setup_system(
+            State(self.state.clone()),
+            headers,
+            Query(SetupQuery::default()),
+        )
+        .await?;
+        Ok(Response::new(self.params().await?))
+    }
+
+    async fn get_params(
+        &self,
+        _request: Request<proto::Empty>,
+    ) -> Result<Response<proto::SystemParameters>, Status> {
+        Ok(Response::new(self.params().await?))
+    }
+
+    async fn register_user(
+        &self,
+        request: Request<proto::RegisterRequest>,
+    ) -> Result<Response<proto::UserSecretKey>, Status> {
+        let payload = RegisterRequest::utf8(request.into_inner().id);
+        let Json(resp) = register_user(State(self.state.clone()), Json(payload)).await?;
+        Ok(Response::new(proto::UserSecretKey {
+            r_u: decode_hex(&resp.r_u_hex)?,
+            sid_u: decode_hex(&resp.sid_u_hex)?,
+        }))
+    }
+
+    async fn register_server(
+        &self,
+        request: Request<proto::RegisterRequest>,
+    ) -> Result<Response<proto::ServerSecretKey>, Status> {
+        let payload = RegisterRequest::utf8(request.into_inner().id);
+        let Json(resp) = register_server(State(self.state.clone()), Json(payload)).await?;
+        Ok(Response::new(proto::ServerSecretKey {
+            sid_ms: decode_hex(&resp.sid_ms_hex)?,
+        }))
+    }
+}
@@ -1,2 +1,4 @@
+pub mod audit;
+pub mod store;
 pub mod telemetry;
 pub mod util;
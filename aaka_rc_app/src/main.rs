@@ -1,47 +1,235 @@
 use aaka_rc_app::{
-    telemetry::init_subscriber,
-    util::{collect_shares, distribute_shares},
+    audit,
+    store::{FileStore, MemoryStore, RcStore, ShareKey, StoredParams},
+    telemetry::{LogConfig, init_subscriber},
+    util::{
+        CircuitBreakers, PeerStatus, broadcast_nodes, broadcast_params, collect_shares,
+        distribute_shares, peer_client, probe_peers, probe_quorum,
+    },
 };
 use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
-use ark_std::rand::{SeedableRng, rngs::StdRng};
 use axum::{
     Router,
-    extract::State,
-    http::StatusCode,
-    response::{IntoResponse, Json},
+    extract::{Path, Query, Request, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Json, Response},
     routing::{get, post},
 };
-use blahaj::{Share, Sharks};
+use base64::prelude::*;
+use clap::Parser;
 use dotenvy::dotenv;
-use eyre::{Result, anyhow, bail};
-use figment::{
-    Figment,
-    providers::{self, Format},
-};
+use eyre::{Result, anyhow};
+use grpc::{RcServer, RcService};
 use ibc_aaka_scheme::{
+    AAKAError,
+    EpochShare,
     MasterSecretKey, // Import core types and rc functions
+    NotReady,
+    ServerSecretKey,
+    ShareEnvelope,
     SystemParameters,
+    UserSecretKey,
+    config,
+    cors::CorsConfig,
+    hash_utils::{DeploymentInfo, Xof},
+    id::{EncodedId, IdEncoding},
     rc,
+    request_id,
+    suite::DEFAULT_KEY_LEN,
+    wire::{CBOR_CONTENT_TYPE, Cbor},
 };
-use rand::thread_rng;
-use reqwest::Client;
+use rand::{RngCore, thread_rng};
 // Use RwLock for interior mutability of state
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, env, str::FromStr, sync::Arc};
-use tokio::sync::RwLock;
-use tower_http::trace::TraceLayer;
-use tracing::{Level, debug, info, instrument, warn};
-use tracing_error::ErrorLayer;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use sha3::{Digest, Sha3_256};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{Mutex, RwLock};
+use tower_http::{compression::CompressionLayer, limit::RequestBodyLimitLayer, trace::TraceLayer};
+use tracing::{debug, info, instrument, warn};
+
+mod grpc;
+
+/// 命令行参数
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+struct Cli {
+    /// 配置文件（JSON、TOML 或 YAML，按扩展名识别），不存在时只使用 `RC_` 前缀的环境变量与默认值
+    #[arg(long, default_value = "config.json")]
+    config: PathBuf,
+    /// 覆盖配置项（可重复），优先级高于配置文件和环境变量，例如 `--set threshold=2`
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    overrides: Vec<String>,
+}
+
+/// 按 配置文件 → `RC_*` 环境变量 → `--set` 的顺序加载配置
+fn load_config(cli: &Cli) -> Result<RcConfig> {
+    Ok(config::load(&cli.config, "RC_", &cli.overrides)?)
+}
 
 #[derive(Deserialize)]
 struct RcConfig {
     addr: String,
     nodes: Vec<String>,
     threshold: usize,
+    /// 审计日志文件路径（JSON Lines），未设置时不记录审计日志
+    #[serde(default)]
+    audit_log: Option<String>,
+    /// 日志输出配置（目录、滚动周期），默认写入 stdout
+    #[serde(default)]
+    log: LogConfig,
+    /// gRPC 服务监听地址，未设置时只提供 HTTP 接口
+    #[serde(default)]
+    grpc_addr: Option<String>,
+    /// 请求体大小上限（字节），超出时返回 413
+    #[serde(default = "default_max_body_bytes")]
+    max_body_bytes: usize,
+    /// `/setup` 生成的参数中 `h2` 使用的 XOF（`shake128` 或 `shake256`，默认 `shake256`）
+    #[serde(default)]
+    xof: Xof,
+    /// `/setup` 生成的参数中的部署标识（见 `ibc_aaka_scheme::hash_utils::DeploymentInfo`），
+    /// 折入会话密钥，使不同部署的握手即使输入相同也得到不同的密钥；未设置时为空
+    #[serde(default)]
+    deployment_info: Option<String>,
+    /// 允许浏览器跨域访问的配置，未设置时不返回 CORS 头（只允许同源访问）
+    #[serde(default)]
+    cors: Option<CorsConfig>,
+    /// `/register/user` 按 `Idempotency-Key` 缓存响应的时长（秒）
+    #[serde(default = "default_idempotency_ttl_secs")]
+    idempotency_ttl_secs: u64,
+    /// `Idempotency-Key` 缓存的条目数上限，已满时淘汰最早过期的条目
+    #[serde(default = "default_idempotency_max_entries")]
+    idempotency_max_entries: usize,
+    /// 查询签发记录的接口（如 `/registered/{id}`）要求的 `Authorization: Bearer <token>`，
+    /// 与 `admin_token_hash` 都未设置时这些接口一律返回 403
+    #[serde(default)]
+    admin_token: Option<String>,
+    /// 管理令牌的 Argon2 哈希（PHC 字符串，如 `$argon2id$v=19$...`），配置文件中不必保存令牌原文。
+    /// 设置后 `/setup`、`/rotate_master`、`/membership` 也要求管理令牌，见 [`AdminScope`]
+    #[serde(default)]
+    admin_token_hash: Option<String>,
+    /// 节点之间接口（如 `/set_params`、`/set_nodes`）要求的 `Authorization: Bearer <token>`，所有节点配置相同的值。
+    /// 多节点部署必须设置；未设置时这些接口一律返回 403
+    #[serde(default)]
+    peer_token: Option<String>,
+    /// 持久化 share、签发记录与审计记录的目录，未设置时只保存在内存中（重启即丢失）
+    #[serde(default)]
+    store_dir: Option<String>,
+    /// 加密 `store_dir` 中 share 的节点密钥（32 字节，十六进制）的来源，未设置时 share 以明文保存
+    #[serde(default)]
+    share_key: Option<ShareKeySource>,
+    /// `/register/users` 一次最多注册的用户数，超出时返回 400
+    #[serde(default = "default_max_registration_batch")]
+    max_registration_batch: usize,
+    /// `/register/users` 并行签发私钥的线程数，默认等于 CPU 核数
+    #[serde(default)]
+    registration_threads: Option<usize>,
+}
+
+/// 节点密钥的来源，例如 `{ "env": "RC_SHARE_KEY" }` 或 `{ "file": "/run/secrets/rc_share_key" }`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+enum ShareKeySource {
+    /// 从环境变量读取
+    Env(String),
+    /// 从文件读取，文件不能位于 `store_dir` 中
+    File(PathBuf),
+}
+
+impl ShareKeySource {
+    fn load(&self) -> Result<ShareKey> {
+        match self {
+            ShareKeySource::Env(var) => ShareKey::from_hex(
+                &std::env::var(var).map_err(|e| anyhow!("Failed to read share key ${var}: {e}"))?,
+            ),
+            ShareKeySource::File(path) => ShareKey::from_hex(
+                &std::fs::read_to_string(path)
+                    .map_err(|e| anyhow!("Failed to read share key file {path:?}: {e}"))?,
+            ),
+        }
+    }
+}
+
+fn default_idempotency_ttl_secs() -> u64 {
+    600
+}
+
+fn default_idempotency_max_entries() -> usize {
+    10_000
+}
+
+fn default_max_registration_batch() -> usize {
+    256
+}
+
+// 最大的合法请求是 `/set_share` 与 `/set_nodes`，都只有几百字节
+fn default_max_body_bytes() -> usize {
+    64 * 1024
 }
 
 impl RcConfig {
+    /// 检查门限与节点列表是否一致，避免到第一次注册时才发现无法恢复 msk
+    fn validate(&self) -> Result<()> {
+        if self.threshold == 0 || self.threshold > self.nodes.len() {
+            return Err(anyhow!(
+                "Invalid threshold {}: must be between 1 and the number of nodes ({})",
+                self.threshold,
+                self.nodes.len()
+            ));
+        }
+        if !self.nodes.contains(&self.addr) {
+            return Err(anyhow!(
+                "This node's address {} is not listed in nodes {:?}",
+                self.addr,
+                self.nodes
+            ));
+        }
+        match (&self.share_key, &self.store_dir) {
+            (Some(_), None) => return Err(anyhow!("share_key requires store_dir")),
+            (Some(ShareKeySource::File(path)), Some(dir)) => {
+                // 与 share 放在一起的密钥起不到保护作用
+                let dir = std::fs::canonicalize(dir).unwrap_or_else(|_| PathBuf::from(dir));
+                let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+                if path.starts_with(&dir) {
+                    return Err(anyhow!(
+                        "share_key file {path:?} must not be stored inside store_dir {dir:?}"
+                    ));
+                }
+            }
+            _ => {}
+        }
+        if let Some(hash) = &self.admin_token_hash {
+            PasswordHash::new(hash).map_err(|e| anyhow!("Invalid admin_token_hash: {e}"))?;
+        }
+        if !self.is_single_node() && self.peer_token.is_none() {
+            return Err(anyhow!(
+                "peer_token is required when the cluster has more than one node"
+            ));
+        }
+        self.deployment()?;
+        Ok(())
+    }
+
+    fn deployment(&self) -> Result<DeploymentInfo> {
+        Ok(DeploymentInfo::new(
+            self.deployment_info.clone().unwrap_or_default(),
+        )?)
+    }
+
+    /// 节点列表只有本节点且门限为 1：主密钥由唯一的 share 直接恢复，不需要分发或收集 share
+    fn is_single_node(&self) -> bool {
+        self.threshold == 1 && self.nodes.len() == 1
+    }
+
     fn peers(&self) -> Vec<String> {
         self.nodes
             .iter()
@@ -56,40 +244,184 @@ impl RcConfig {
 #[derive(Clone)]
 struct RcState {
     inner: Arc<RwLock<InnerRcState>>,
+    // 对其他节点 share 请求的熔断器
+    breakers: Arc<CircuitBreakers>,
+    // 已完成的 /setup 次数，不需要加锁即可读取，用于识别并发的 /setup
+    setups: Arc<AtomicU64>,
+    // 按 `Idempotency-Key` 缓存的 /register/user 响应
+    idempotency: Arc<Mutex<HashMap<String, IdempotentRegistration>>>,
+    // 让本节点上的 /membership 依次进行，不占用状态锁
+    membership: Arc<Mutex<()>>,
+    // share、签发记录（ID 的 SHA3-256 哈希及签发时的参数版本）与审计记录的存储
+    store: Arc<dyn RcStore>,
+}
+
+struct IdempotentRegistration {
+    id: Vec<u8>,
+    // 签发完成前为 None；同一个键的并发请求在这把锁上排队，不影响其他键
+    response: Arc<Mutex<Option<UserRegistrationResponse>>>,
+    expires_at: Instant,
 }
 
 struct InnerRcState {
     params: Option<SystemParameters>,
-    share: Option<Share>,
+    // 每次 /setup 加一，0 表示尚未初始化
+    params_version: u64,
+    // 被最近一次 /setup 或 /rotate_master 取代的参数指纹
+    superseded: Option<String>,
+    share: Option<EpochShare>,
+    // 最近一次 /membership 或 /set_nodes 设置、配置文件尚未跟上的节点列表。
+    // SIGHUP 重新加载时配置文件必须与之一致，否则会悄悄回退到旧的节点集合
+    reshared_nodes: Option<Vec<String>>,
     config: RcConfig,
 }
 
 impl RcState {
     fn new(config: RcConfig) -> Result<Self> {
+        // 打开存储前检查，避免用放错位置的密钥加密已有的 share
+        config.validate()?;
+        let store: Arc<dyn RcStore> = match &config.store_dir {
+            Some(dir) => {
+                let share_key = config.share_key.as_ref().map(ShareKeySource::load);
+                Arc::new(FileStore::open_with_share_key(dir, share_key.transpose()?)?)
+            }
+            None => Arc::new(MemoryStore::default()),
+        };
+        Self::with_store(config, store)
+    }
+
+    /// 使用给定的存储，并从中恢复本节点的 share 与系统参数
+    fn with_store(config: RcConfig, store: Arc<dyn RcStore>) -> Result<Self> {
+        config.validate()?;
+
+        let share = store.load_share()?;
+        if share.is_some() {
+            info!("Restored this node's share from the store");
+        }
+        let (params, params_version, superseded) = match store.load_params()? {
+            Some(stored) => {
+                info!(
+                    "Restored system parameters from the store (fingerprint {}, version {})",
+                    stored.params.fingerprint(),
+                    stored.version
+                );
+                (Some(stored.params), stored.version, stored.superseded)
+            }
+            None => (None, 0, None),
+        };
         let initial_state = InnerRcState {
-            params: None,
-            share: None,
+            params,
+            params_version,
+            superseded,
+            share,
+            reshared_nodes: None,
             config,
         };
 
         Ok(Self {
             inner: Arc::new(RwLock::new(initial_state)),
+            breakers: Arc::default(),
+            setups: Arc::default(),
+            idempotency: Arc::default(),
+            membership: Arc::default(),
+            store,
         })
     }
+
+    // 先写入存储再替换内存中的 share，写入失败时保持原状
+    fn replace_share(&self, inner: &mut InnerRcState, share: Option<EpochShare>) -> Result<()> {
+        self.store.save_share(share.as_ref())?;
+        inner.share = share;
+        Ok(())
+    }
+
+    // 与 `replace_share` 相同，先写入存储再替换内存中的参数、版本号与被取代的指纹
+    fn replace_params(&self, inner: &mut InnerRcState, stored: StoredParams) -> Result<()> {
+        self.store.save_params(&stored)?;
+        inner.params = Some(stored.params);
+        inner.params_version = stored.version;
+        inner.superseded = stored.superseded;
+        Ok(())
+    }
+
+    // 只保存 ID 的哈希，与审计日志一致
+    fn record_registered(&self, kind: &str, id: &[u8], inner: &InnerRcState) -> Result<()> {
+        let record = audit::record_registration(kind, id, &inner.config.addr);
+        self.store
+            .save_registration(&record.id_hash, inner.params_version)?;
+        self.store.append_audit(&record)
+    }
+
+    // 轮换主密钥后，此前签发的私钥失效，不再算作已注册
+    fn is_registered(&self, id: &[u8], params_version: u64) -> Result<bool> {
+        let id_hash = hex::encode(Sha3_256::digest(id));
+        Ok(self.store.registration(&id_hash)? == Some(params_version))
+    }
 }
 
 // --- Request/Response Payloads ---
 
+/// `{"id": ..., "id_encoding": ...}`，二进制 ID（含 NUL 或非 UTF-8 字节）用 `hex` 或 `base64` 传输
+type RegisterRequest = EncodedId;
+
+#[derive(Debug, Deserialize)]
+struct BatchRegisterRequest {
+    ids: Vec<String>,
+    /// 所有 `ids` 共用的编码
+    #[serde(default)]
+    id_encoding: IdEncoding,
+}
+
+// 按请求中的编码取出 ID 字节，无法解码时返回 400
+fn id_bytes(id: &EncodedId) -> Result<Vec<u8>, AppError> {
+    id.bytes().map_err(AppError::bad_request)
+}
+
 #[derive(Debug, Deserialize)]
-struct RegisterRequest {
-    id: String, // User or Server ID as string
+struct PseudonymRequest {
+    /// 用户的真实 ID，不会出现在响应中
+    #[serde(flatten)]
+    id: EncodedId,
+    /// 由客户端选择，通常每次会话换一个；同一 epoch 总是得到同一个假名与私钥
+    epoch: u64,
 }
 
 // Use hex encoding for serialized points/scalars in JSON for better readability/transfer
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 struct UserRegistrationResponse {
     r_u_hex: String,
     sid_u_hex: String,
+    /// 供客户端不获取系统参数即可校验私钥的校验值，见 `rc::UserKeyCheck`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    check: Option<UserKeyCheckResponse>,
+}
+
+#[derive(Serialize)]
+struct BatchRegistrationResponse {
+    /// 与请求中的 `ids` 一一对应
+    keys: Vec<UserRegistrationResponse>,
+}
+
+#[derive(Serialize)]
+struct PseudonymRegistrationResponse {
+    /// 客户端认证时使用的 ID
+    pseudonym: String,
+    #[serde(flatten)]
+    key: UserRegistrationResponse,
+}
+
+#[derive(Serialize, Clone)]
+struct UserKeyCheckResponse {
+    h_u_hex: String,
+    p_pub_hex: String,
+    /// 签发私钥时的系统参数指纹，客户端之后可与 `/params` 比对
+    params_fingerprint: String,
+}
+
+#[derive(Serialize)]
+struct ServerBootstrapResponse {
+    params: SystemParametersResponse,
+    sid_ms_hex: String,
 }
 
 #[derive(Serialize)]
@@ -97,12 +429,40 @@ struct ServerRegistrationResponse {
     sid_ms_hex: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct MembershipChange {
+    #[serde(default)]
+    add: Vec<String>,
+    #[serde(default)]
+    remove: Vec<String>,
+}
+
 #[derive(Serialize)]
+struct MembershipResponse {
+    nodes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
 struct SystemParametersResponse {
     p_hex: String,
     p_pub_hex: String,
     p_pub_hat_hex: String,
     g_hex: String,
+    // 缺省为默认 XOF，兼容不带此字段的旧响应
+    #[serde(default)]
+    xof: Xof,
+    /// 部署标识，为空时省略
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    deployment_info: String,
+    /// 默认 32 字节会话密钥对应的算法套件编号（见 `ibc_aaka_scheme::suite`），
+    /// 用户的认证请求带有同样的编号，MS 据此拒绝不一致的套件
+    #[serde(default)]
+    suite_id: u16,
+    /// 单调递增的参数版本号，每次 `/setup` 加一
+    params_version: u64,
+    /// 被当前参数取代的旧参数指纹，用旧参数签发的私钥均已失效
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    supersedes: Option<String>,
 }
 
 // --- Utility Functions ---
@@ -116,8 +476,6 @@ fn ark_to_hex<T: CanonicalSerialize>(item: &T) -> Result<String> {
 }
 
 // Helper to deserialize arkworks types from hex string
-// Not strictly needed for RC responses, but useful pattern
-#[allow(dead_code)] // Allow unused for now
 fn hex_to_ark<T: CanonicalDeserialize>(hex_str: &str) -> Result<T> {
     let bytes = hex::decode(hex_str).map_err(|e| anyhow!("Hex decoding failed: {}", e))?;
     T::deserialize_compressed(&bytes[..]).map_err(|e| anyhow!("Deserialization failed: {}", e))
@@ -126,83 +484,483 @@ fn hex_to_ark<T: CanonicalDeserialize>(hex_str: &str) -> Result<T> {
 // --- Axum Handlers ---
 
 // Handler for GET /params
-// Returns the system public parameters
+// Returns the system public parameters, as CBOR when `Accept: application/cbor`
 async fn get_params(
     State(state): State<RcState>,
-) -> Result<Json<SystemParametersResponse>, AppError> {
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
     debug!("Calling get_params handler");
 
     let state_read = state.inner.read().await;
-    // Since setup runs at start, params should always exist unless setup failed initially
-    if let Some(params) = &state_read.params {
-        let response = SystemParametersResponse {
-            p_hex: ark_to_hex(&params.p)?,
-            p_pub_hex: ark_to_hex(&params.p_pub)?,
-            p_pub_hat_hex: ark_to_hex(&params.p_pub_hat)?,
-            g_hex: ark_to_hex(&params.g)?,
-        };
-        Ok(Json(response))
-    } else {
-        Err(AppError(anyhow!(
-            "RC should be initialized first by calling /setup endpoint before /get_params."
-        )))
+    let (_, params) = require_ready(&state_read)?;
+    // 参数的版本号：CBOR 编码的 SHA3-256，客户端据此判断参数是否轮换
+    let etag = format!("\"{}\"", hex::encode(Sha3_256::digest(params.to_cbor()?)));
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value == etag);
+    if not_modified {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+
+    let accepts_cbor = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains(CBOR_CONTENT_TYPE));
+    if accepts_cbor {
+        return Ok((
+            [
+                (header::CONTENT_TYPE, CBOR_CONTENT_TYPE.to_string()),
+                (header::ETAG, etag),
+            ],
+            params.to_cbor()?,
+        )
+            .into_response());
     }
+
+    let response = params_response(
+        params,
+        state_read.params_version,
+        state_read.superseded.clone(),
+    )?;
+    Ok(([(header::ETAG, etag)], Json(response)).into_response())
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct SetupQuery {
+    /// 调用者已知的参数版本号，只有当前版本与之相同时才重新生成参数（首次初始化为 0）
+    expected_version: Option<u64>,
+    /// 只生成并返回参数，不保存、不分发 share，用于在预发布环境检查配置
+    #[serde(default)]
+    dry_run: bool,
+    /// `expected_version` 与当前版本不一致时返回 409，而不是已有的参数（`/rotate_master` 使用）
+    #[serde(default)]
+    conflict_on_mismatch: bool,
 }
 
 // Handler for POST /setup
-// Initializes the system parameters and master key (only once)
+// Initializes the system parameters and master key.
+// 只有地址最小的节点（协调者）生成主密钥，其他节点把请求转发给它，避免并发的 /setup 各自
+// 生成主密钥、分发互相冲突的 share。
 async fn setup_system(
     State(state): State<RcState>,
+    headers: HeaderMap,
+    Query(query): Query<SetupQuery>,
 ) -> Result<Json<SystemParametersResponse>, AppError> {
+    if query.dry_run {
+        return dry_run_setup(&state).await;
+    }
+
+    let (addr, coordinator) = {
+        let config = &state.inner.read().await.config;
+        let coordinator = config.nodes.iter().min().unwrap_or(&config.addr).clone();
+        (config.addr.clone(), coordinator)
+    };
+    if coordinator != addr {
+        // 转发期间不能持有锁：协调者分发 share 时会调用本节点的 /set_share。
+        // 调用者的 `expected_version` 原样转发：本节点的版本号可能落后于协调者，不能代替调用者填写
+        let authorization = headers.get(header::AUTHORIZATION);
+        return proxy_setup(&state, &coordinator, &query, authorization).await;
+    }
+
+    let seen = state.setups.load(Ordering::SeqCst);
     let mut state_write = state.inner.write().await;
+
+    // 参数在调用者不知情时已经生成（等待写锁期间另一个 /setup 完成，或转发来的请求晚于
+    // 本节点的 /setup）：直接返回已有的参数
+    let superseded = match query.expected_version {
+        Some(expected) => expected != state_write.params_version,
+        None => state.setups.load(Ordering::SeqCst) != seen,
+    };
+    if superseded && let Some(params) = &state_write.params {
+        if query.conflict_on_mismatch {
+            return Err(AppError::conflict(anyhow!(
+                "System parameters changed concurrently (now version {}), nothing was rotated",
+                state_write.params_version
+            )));
+        }
+        info!("Concurrent /setup already completed, returning the established parameters");
+        return Ok(Json(params_response(
+            params,
+            state_write.params_version,
+            state_write.superseded.clone(),
+        )?));
+    }
+
     let nodes_count = state_write.config.nodes.len();
 
     // 生成主密钥，但这只是临时的，节点本身不存储msk
-    let (params, msk) = rc::gen_parameter_and_msk(&mut thread_rng())?; // Use anyhow context
+    let (mut params, msk) = rc::gen_parameter_and_msk(&mut thread_rng())?; // Use anyhow context
+    params.xof = state_write.config.xof;
+    params.deployment = state_write.config.deployment()?;
     let mut shares = msk.into_shares(state_write.config.threshold, nodes_count);
 
-    let response = SystemParametersResponse {
+    let superseded = state_write
+        .params
+        .as_ref()
+        .map(SystemParameters::fingerprint);
+    let response = params_response(&params, state_write.params_version + 1, superseded.clone())?;
+
+    let own_share = shares.pop(); // 为当前节点分配一个 share
+    let peers = state_write.config.peers();
+    let threshold = state_write.config.threshold;
+    // 单节点模式下没有其他节点需要 share 和系统参数。
+    // share 达到门限后才保存新的参数并计入 `setups`：分发失败时保留原有的参数与 share，
+    // 等待中的 /setup 不会把这次失败当作已完成
+    let single_node = state_write.config.is_single_node();
+    if !single_node {
+        let peer_token = state_write.config.peer_token.as_deref();
+        distribute_shares(&shares, &peers, &state.breakers, threshold, peer_token)
+            .await
+            .map_err(AppError::unavailable)?;
+    }
+
+    info!(
+        "System parameters initialized (fingerprint {}, version {})",
+        params.fingerprint(),
+        state_write.params_version + 1
+    );
+    // 先持久化 share 与参数，都写入成功后才一起替换内存中的四个字段。
+    // 写入失败时内存中保持原有的参数与 share，不会出现新参数配旧 share 的状态
+    let stored = StoredParams {
+        params,
+        version: state_write.params_version + 1,
+        superseded,
+    };
+    state.store.save_share(own_share.as_ref())?;
+    state.store.save_params(&stored)?;
+    state_write.share = own_share;
+    state_write.params = Some(stored.params);
+    state_write.params_version = stored.version;
+    state_write.superseded = stored.superseded;
+    state.setups.fetch_add(1, Ordering::SeqCst);
+
+    if !single_node {
+        let peer_token = state_write.config.peer_token.as_deref();
+        broadcast_params(&response, &peers, threshold, peer_token)
+            .await
+            .map_err(AppError::unavailable)?;
+    }
+
+    Ok(Json(response))
+}
+
+// `/setup?dry_run=true`：在本节点按当前配置生成参数并返回，主密钥随即丢弃。
+// 不转发给协调者，不修改状态，也不联系其他节点
+async fn dry_run_setup(state: &RcState) -> Result<Json<SystemParametersResponse>, AppError> {
+    let state_read = state.inner.read().await;
+    let (mut params, _) = rc::gen_parameter_and_msk(&mut thread_rng())?;
+    params.xof = state_read.config.xof;
+    params.deployment = state_read.config.deployment()?;
+    info!(
+        "Dry-run /setup generated parameters (fingerprint {}), nothing was stored or distributed",
+        params.fingerprint()
+    );
+    Ok(Json(params_response(
+        &params,
+        state_read.params_version + 1,
+        state_read
+            .params
+            .as_ref()
+            .map(SystemParameters::fingerprint),
+    )?))
+}
+
+// Handler for POST /rotate_master
+// 轮换主密钥：在协调者上重新生成主密钥与系统参数、分发新 epoch 的 share 并推送新参数，
+// 版本号加一，旧参数的指纹记录在 `supersedes` 中。
+// 用旧主密钥签发的所有用户与服务器私钥随之失效，需要重新注册。
+async fn rotate_master(
+    State(state): State<RcState>,
+    headers: HeaderMap,
+) -> Result<Json<SystemParametersResponse>, AppError> {
+    let version = {
+        let state_read = state.inner.read().await;
+        require_ready(&state_read)?;
+        state_read.params_version
+    };
+    warn!("Rotating the master key (params version {version}), all issued keys become invalid");
+    setup_system(
+        State(state),
+        headers,
+        Query(SetupQuery {
+            expected_version: Some(version),
+            dry_run: false,
+            conflict_on_mismatch: true,
+        }),
+    )
+    .await
+}
+
+// 把 /setup 转发给协调者，并在本地保存它返回的系统参数。
+// 调用者的管理令牌一并转发：协调者配置了 `admin_token_hash` 时同样需要校验
+async fn proxy_setup(
+    state: &RcState,
+    coordinator: &str,
+    query: &SetupQuery,
+    authorization: Option<&HeaderValue>,
+) -> Result<Json<SystemParametersResponse>, AppError> {
+    debug!("Forwarding /setup to coordinator {coordinator}");
+    let mut request =
+        peer_client(SETUP_PROXY_TIMEOUT, None).get(format!("http://{coordinator}/setup"));
+    if let Some(expected_version) = query.expected_version {
+        request = request.query(&[("expected_version", expected_version)]);
+    }
+    if query.conflict_on_mismatch {
+        request = request.query(&[("conflict_on_mismatch", true)]);
+    }
+    if let Some(authorization) = authorization {
+        request = request.header(header::AUTHORIZATION, authorization);
+    }
+    let unavailable = |e| AppError::unavailable(anyhow!("Setup coordinator {coordinator}: {e}"));
+    let response = request.send().await.map_err(unavailable)?;
+    // 协调者上的版本冲突原样返回给调用者，其余错误按暂时不可用处理
+    if response.status() == StatusCode::CONFLICT {
+        let message = response.text().await.unwrap_or_default();
+        return Err(AppError::conflict(anyhow!(
+            "Setup coordinator {coordinator}: {message}"
+        )));
+    }
+    let response: SystemParametersResponse = response
+        .error_for_status()
+        .map_err(unavailable)?
+        .json()
+        .await?;
+
+    let params = params_from_response(&response)?;
+    info!(
+        "System parameters received from coordinator {coordinator} (fingerprint {}, version {})",
+        params.fingerprint(),
+        response.params_version
+    );
+
+    let stored = StoredParams {
+        params,
+        version: response.params_version,
+        superseded: response.supersedes.clone(),
+    };
+    state.replace_params(&mut *state.inner.write().await, stored)?;
+    Ok(Json(response))
+}
+
+fn params_from_response(response: &SystemParametersResponse) -> Result<SystemParameters> {
+    let params = SystemParameters {
+        p: hex_to_ark(&response.p_hex)?,
+        p_pub: hex_to_ark(&response.p_pub_hex)?,
+        p_pub_hat: hex_to_ark(&response.p_pub_hat_hex)?,
+        g: hex_to_ark(&response.g_hex)?,
+        xof: response.xof,
+        deployment: DeploymentInfo::new(response.deployment_info.clone())?,
+    };
+    params.validate_strict()?;
+    Ok(params)
+}
+
+fn params_response(
+    params: &SystemParameters,
+    version: u64,
+    supersedes: Option<String>,
+) -> Result<SystemParametersResponse> {
+    Ok(SystemParametersResponse {
         p_hex: ark_to_hex(&params.p)?,
         p_pub_hex: ark_to_hex(&params.p_pub)?,
         p_pub_hat_hex: ark_to_hex(&params.p_pub_hat)?,
         g_hex: ark_to_hex(&params.g)?,
+        xof: params.xof,
+        deployment_info: params.deployment.as_str().to_string(),
+        suite_id: params.suite(DEFAULT_KEY_LEN)?.id(),
+        params_version: version,
+        supersedes,
+    })
+}
+
+// 客户端为一次注册生成的唯一键，超时后带着同一个键重试不会得到第二把私钥
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+// Handler for POST /register/user
+// 带有 `Idempotency-Key` 时，在 `idempotency_ttl_secs` 内用同一个键重复请求返回第一次的响应，
+// 不会为同一个 ID 签发新的私钥（新私钥的 Ru 不同，会让之前签发的私钥被客户端覆盖）。
+// 缓存只保存在处理请求的节点上，重试需要发往同一个节点
+async fn register_user_idempotent(
+    State(state): State<RcState>,
+    headers: HeaderMap,
+    Json(payload): Json<RegisterRequest>,
+) -> Result<Json<UserRegistrationResponse>, AppError> {
+    let Some(key) = headers.get(IDEMPOTENCY_KEY) else {
+        return register_user(State(state), Json(payload)).await;
     };
+    let key = key
+        .to_str()
+        .map_err(|_| AppError::bad_request(anyhow!("Idempotency-Key must be visible ASCII")))?
+        .to_string();
 
-    state_write.params = Some(params);
-    state_write.share = Some(shares.pop().unwrap()); // 为当前节点分配一个 share
+    let id = id_bytes(&payload)?;
+    let (ttl, max_entries) = {
+        let config = &state.inner.read().await.config;
+        (
+            Duration::from_secs(config.idempotency_ttl_secs),
+            config.idempotency_max_entries,
+        )
+    };
 
-    distribute_shares(&shares, &state_write.config.peers()).await?;
+    // 全局锁只在查找或插入条目时持有，签发私钥期间只锁住这个键
+    let slot = {
+        let mut cache = state.idempotency.lock().await;
+        let now = Instant::now();
+        cache.retain(|_, entry| entry.expires_at > now);
+        match cache.get(&key) {
+            Some(entry) if entry.id != id => {
+                return Err(AppError::bad_request(anyhow!(
+                    "Idempotency-Key was already used for another ID"
+                )));
+            }
+            Some(entry) => entry.response.clone(),
+            None => {
+                if cache.len() >= max_entries
+                    && let Some(oldest) = cache
+                        .iter()
+                        .min_by_key(|(_, entry)| entry.expires_at)
+                        .map(|(key, _)| key.clone())
+                {
+                    cache.remove(&oldest);
+                }
+                let slot = Arc::new(Mutex::new(None));
+                cache.insert(
+                    key.clone(),
+                    IdempotentRegistration {
+                        id,
+                        response: slot.clone(),
+                        expires_at: now + ttl,
+                    },
+                );
+                slot
+            }
+        }
+    };
 
+    // 同一个键的并发请求只签发一次；签发失败时下一个请求重新尝试
+    let mut cached = slot.lock().await;
+    if let Some(response) = &*cached {
+        debug!("Returning the cached registration for Idempotency-Key {key}");
+        return Ok(Json(response.clone()));
+    }
+    let Json(response) = register_user(State(state.clone()), Json(payload)).await?;
+    *cached = Some(response.clone());
     Ok(Json(response))
 }
 
-// Handler for POST /register/user
+#[instrument(name = "register", skip_all, fields(kind = "user"))]
 async fn register_user(
     State(state): State<RcState>,
     Json(payload): Json<RegisterRequest>,
 ) -> Result<Json<UserRegistrationResponse>, AppError> {
-    let mut state_write = state.inner.write().await;
+    // 收集 share 期间只持有读锁：其他节点的 /ready 与 /get_share 也需要读取本节点的状态
+    let state_read = state.inner.read().await;
+
+    let (_, params) = require_ready(&state_read)?;
 
-    let Some(share) = &state_write.share else {
-        return Err(AppError(anyhow!(
-            "RC must be initialized first by calling /setup endpoint before user registration."
+    let msk = recover_msk(&state, &state_read).await?;
+    let user_id = id_bytes(&payload)?;
+    let user_id_bytes = &user_id[..];
+    let usk = rc::register_user(&msk, user_id_bytes, &mut thread_rng())?;
+    state.record_registered("user", user_id_bytes, &state_read)?;
+
+    Ok(Json(user_registration_response(
+        params,
+        user_id_bytes,
+        &usk,
+    )?))
+}
+
+// Handler for POST /register/users
+// 批量注册用户（例如一次发放一批设备）：主密钥只恢复一次，各用户的私钥在
+// `registration_threads` 个线程上并行签发（见 `rc::register_users`），响应按请求顺序排列
+#[instrument(name = "register", skip_all, fields(kind = "user_batch"))]
+async fn register_users(
+    State(state): State<RcState>,
+    Json(payload): Json<BatchRegisterRequest>,
+) -> Result<Json<BatchRegistrationResponse>, AppError> {
+    // 收集 share 与签发整批私钥期间只持有读锁，不阻塞本节点的 /params、/ready 与 /get_share
+    let state_read = state.inner.read().await;
+
+    let (_, params) = require_ready(&state_read)?;
+    let config = &state_read.config;
+    if payload.ids.len() > config.max_registration_batch {
+        return Err(AppError::bad_request(anyhow!(
+            "Batch of {} users exceeds the limit of {}",
+            payload.ids.len(),
+            config.max_registration_batch
         )));
-    };
+    }
+    let ids = payload
+        .ids
+        .iter()
+        .map(|id| payload.id_encoding.decode(id))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(AppError::bad_request)?;
+    let threads = config.registration_threads.unwrap_or_else(|| {
+        std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+    });
 
-    let shares = collect_shares(share.clone(), &state_write.config.peers()).await?;
-    let msk = MasterSecretKey::from_shares(shares, state_write.config.threshold)?;
-    let mut rng = thread_rng();
-    let user_id_bytes = payload.id.as_bytes();
-    let usk = rc::register_user(&msk, user_id_bytes, &mut rng)?;
+    let msk = recover_msk(&state, &state_read).await?;
+    let mut seed = [0u8; 32];
+    thread_rng().fill_bytes(&mut seed);
+    let (ids, keys) = tokio::task::spawn_blocking(move || {
+        let keys = rc::register_users(&msk, &ids, seed, threads);
+        (ids, keys)
+    })
+    .await?;
+    let keys = keys?;
 
-    let response = UserRegistrationResponse {
+    let mut responses = Vec::with_capacity(keys.len());
+    for (id, usk) in ids.iter().zip(&keys) {
+        state.record_registered("user", id, &state_read)?;
+        responses.push(user_registration_response(params, id, usk)?);
+    }
+    Ok(Json(BatchRegistrationResponse { keys: responses }))
+}
+
+// Handler for POST /register/pseudonym
+// 为用户签发某个 epoch 的假名私钥：MS 只看到假名，不同 epoch 的假名无法关联；
+// RC 持有 msk，可以为候选 ID 重新计算假名完成去匿名化
+#[instrument(name = "register", skip_all, fields(kind = "pseudonym"))]
+async fn register_pseudonym(
+    State(state): State<RcState>,
+    Json(payload): Json<PseudonymRequest>,
+) -> Result<Json<PseudonymRegistrationResponse>, AppError> {
+    let state_read = state.inner.read().await;
+
+    let (_, params) = require_ready(&state_read)?;
+
+    let msk = recover_msk(&state, &state_read).await?;
+    let base_id = id_bytes(&payload.id)?;
+    let (pseudonym, usk) = rc::register_pseudonym(&msk, &base_id, payload.epoch)?;
+    state.record_registered("pseudonym", pseudonym.as_bytes(), &state_read)?;
+
+    let key = user_registration_response(params, pseudonym.as_bytes(), &usk)?;
+    Ok(Json(PseudonymRegistrationResponse { pseudonym, key }))
+}
+
+fn user_registration_response(
+    params: &SystemParameters,
+    id: &[u8],
+    usk: &UserSecretKey,
+) -> Result<UserRegistrationResponse, AppError> {
+    let check = rc::UserKeyCheck::new(params, id, usk)?;
+
+    Ok(UserRegistrationResponse {
         r_u_hex: ark_to_hex(&usk.r_u)?,
         sid_u_hex: ark_to_hex(&usk.sid_u)?, // Serialize ScalarField
-    };
-    Ok(Json(response))
+        check: Some(UserKeyCheckResponse {
+            h_u_hex: ark_to_hex(&check.h_u)?,
+            p_pub_hex: ark_to_hex(&check.p_pub)?,
+            params_fingerprint: params.fingerprint(),
+        }),
+    })
 }
 
 // Handler for POST /register/server
+#[instrument(name = "register", skip_all, fields(kind = "server"))]
 async fn register_server(
     State(state): State<RcState>,
     Json(payload): Json<RegisterRequest>,
@@ -210,83 +968,777 @@ async fn register_server(
     debug!("Calling register_server handler. payload: {:?}", payload);
 
     let state_read = state.inner.read().await; // Read lock might be enough if RNG state isn't mutated often
+    let ssk = issue_server_key(&state, &state_read, &id_bytes(&payload)?).await?;
 
-    let Some(share) = &state_read.share else {
-        return Err(AppError(anyhow!(
-            "RC must be initialized first by calling /setup endpoint before server registration."
-        )));
+    let response = ServerRegistrationResponse {
+        // **Ensure ServerSecretKey contains G2Point and it serializes correctly**
+        sid_ms_hex: ark_to_hex(&ssk.sid_ms)?, // Serialize G2Point
     };
+    Ok(Json(response))
+}
+
+// 恢复主密钥：单节点模式直接使用本地的 share，否则先确认足够多的节点可达再收集 share
+async fn recover_msk(
+    state: &RcState,
+    state_read: &InnerRcState,
+) -> Result<MasterSecretKey, AppError> {
+    let (share, _) = require_ready(state_read)?;
+    let config = &state_read.config;
+    if config.is_single_node() {
+        return Ok(MasterSecretKey::from_shares(vec![share.clone()], 1)?);
+    }
+
+    ensure_peers_reachable(config, &state.breakers).await?;
+    let peer_token = config.peer_token.as_deref();
+    let shares = collect_shares(
+        share.clone(),
+        &config.peers(),
+        &state.breakers,
+        config.threshold,
+        peer_token,
+    )
+    .await?;
+    ensure_quorum(&shares, config.threshold)?;
+    Ok(MasterSecretKey::from_shares(shares, config.threshold)?)
+}
 
-    let shares = collect_shares(share.clone(), &state_read.config.peers()).await?;
-    let msk = MasterSecretKey::from_shares(shares, state_read.config.threshold)?;
-    let server_id_bytes = payload.id.as_bytes();
+// 恢复主密钥并签发服务器私钥
+async fn issue_server_key(
+    state: &RcState,
+    state_read: &InnerRcState,
+    server_id_bytes: &[u8],
+) -> Result<ServerSecretKey, AppError> {
+    let msk = recover_msk(state, state_read).await?;
     // **Ensure register_server uses the corrected G2 logic**
     let ssk = rc::register_server(&msk, server_id_bytes)?;
+    state.record_registered("server", server_id_bytes, state_read)?;
+    Ok(ssk)
+}
 
-    let response = ServerRegistrationResponse {
-        // **Ensure ServerSecretKey contains G2Point and it serializes correctly**
-        sid_ms_hex: ark_to_hex(&ssk.sid_ms)?, // Serialize G2Point
+#[derive(Deserialize)]
+struct RegisteredQuery {
+    /// 路径中 ID 的编码，见 [`IdEncoding`]
+    #[serde(default)]
+    encoding: IdEncoding,
+}
+
+#[derive(Serialize)]
+struct RegisteredResponse {
+    id: String,
+    registered: bool,
+}
+
+// Handler for GET /registered/{id}
+// 查询 ID 在当前系统参数下是否已签发私钥（用户或服务器），已签发返回 200，否则 404。
+// 注册状态属于敏感信息，需要管理令牌。记录只保存在签发私钥的节点上
+async fn registered(
+    State(state): State<RcState>,
+    Path(id): Path<String>,
+    Query(query): Query<RegisteredQuery>,
+) -> Result<Json<RegisteredResponse>, AppError> {
+    let version = state.inner.read().await.params_version;
+    let id_bytes = query.encoding.decode(&id).map_err(AppError::bad_request)?;
+    if !state.is_registered(&id_bytes, version)? {
+        return Err(AppError {
+            status: StatusCode::NOT_FOUND,
+            error: anyhow!("No credential issued for this ID"),
+        });
+    }
+    Ok(Json(RegisteredResponse {
+        id,
+        registered: true,
+    }))
+}
+
+// `GET /registrations` 每页默认与最多返回的记录数
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+#[derive(Debug, Deserialize)]
+struct RegistrationsQuery {
+    /// 上一页响应中的 `next_cursor`，未设置时从头开始
+    cursor: Option<String>,
+    /// 每页的记录数，超过 [`MAX_PAGE_SIZE`] 时按上限处理
+    limit: Option<usize>,
+    /// `ndjson` 时以流的形式返回全部记录（每行一条），忽略 `cursor` 与 `limit`
+    format: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RegistrationEntry {
+    id_hash: String,
+    params_version: u64,
+    /// 是否在当前系统参数下签发，轮换主密钥前签发的私钥已失效
+    current: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RegistrationsPage {
+    registrations: Vec<RegistrationEntry>,
+    /// 还有更多记录时用于请求下一页，调用方不应解析其内容
+    next_cursor: Option<String>,
+}
+
+// 游标编码上一页最后一条记录的 ID 哈希
+fn encode_cursor(id_hash: &str) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(id_hash)
+}
+
+fn decode_cursor(cursor: &str) -> Result<String, AppError> {
+    BASE64_URL_SAFE_NO_PAD
+        .decode(cursor)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or_else(|| AppError::bad_request(anyhow!("Invalid cursor")))
+}
+
+// Handler for GET /registrations
+// 按 ID 哈希的顺序分页列出签发记录（只有哈希，RC 不保存 ID 原文），需要管理令牌。
+// `format=ndjson` 时逐页读取存储并以 NDJSON 流返回全部记录，用于导出
+async fn list_registrations(
+    State(state): State<RcState>,
+    Query(query): Query<RegistrationsQuery>,
+) -> Result<Response, AppError> {
+    let version = state.inner.read().await.params_version;
+    let entry = move |(id_hash, params_version): (String, u64)| RegistrationEntry {
+        id_hash,
+        params_version,
+        current: params_version == version,
     };
-    Ok(Json(response))
+
+    match query.format.as_deref() {
+        None | Some("json") => {}
+        Some("ndjson") => {
+            // 流的状态为下一页的起点：`Some(None)` 从头开始，`None` 表示已读完
+            let store = state.store.clone();
+            let pages = futures_util::stream::try_unfold(Some(None), move |after| {
+                let store = store.clone();
+                async move {
+                    let Some(after) = after else {
+                        return Ok(None);
+                    };
+                    let page = store
+                        .registrations(after.as_deref(), MAX_PAGE_SIZE)
+                        .map_err(|e| std::io::Error::other(e.to_string()))?;
+                    let next = (page.len() == MAX_PAGE_SIZE)
+                        .then(|| page.last().map(|(id, _)| id.clone()));
+                    let mut lines = Vec::new();
+                    for record in page {
+                        serde_json::to_writer(&mut lines, &entry(record))?;
+                        lines.push(b'\n');
+                    }
+                    Ok::<_, std::io::Error>(Some((lines, next)))
+                }
+            });
+            return Ok((
+                [(header::CONTENT_TYPE, NDJSON_CONTENT_TYPE)],
+                axum::body::Body::from_stream(pages),
+            )
+                .into_response());
+        }
+        Some(other) => {
+            return Err(AppError::bad_request(anyhow!(
+                "Unsupported format {other:?}, expected json or ndjson"
+            )));
+        }
+    }
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let after = query.cursor.as_deref().map(decode_cursor).transpose()?;
+    // 多取一条判断是否还有下一页
+    let mut records = state.store.registrations(after.as_deref(), limit + 1)?;
+    let next_cursor = (records.len() > limit).then(|| {
+        records.truncate(limit);
+        encode_cursor(&records[limit - 1].0)
+    });
+    Ok(Json(RegistrationsPage {
+        registrations: records.into_iter().map(entry).collect(),
+        next_cursor,
+    })
+    .into_response())
+}
+
+// --- Admin Authentication ---
+
+/// 管理接口与节点之间接口的权限，注册与 `/params` 等用户接口不受影响
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AdminScope {
+    /// 查询签发记录（`/registered/{id}`、`/registrations`）：
+    /// 未配置 `admin_token` 与 `admin_token_hash` 时一律返回 403
+    Registry,
+    /// 生成、轮换主密钥与调整集群成员（`/setup`、`/rotate_master`、`/membership`）：
+    /// 只有配置了 `admin_token_hash` 时才要求管理令牌，否则保持开放，兼容已有部署
+    Operator,
+    /// 只由其他节点调用的接口（`/set_share`、`/get_share`、`/set_params`、`/set_nodes`）：
+    /// 要求 `peer_token`，未配置时一律返回 403
+    Peer,
+}
+
+// 中间件：按 `scope` 校验 `Authorization: Bearer <token>`
+async fn admin_auth(
+    State((state, scope)): State<(RcState, AdminScope)>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    authorize_admin(&state, scope, authorization.as_deref()).await?;
+    Ok(next.run(request).await)
+}
+
+// 令牌与 `admin_token_hash`（Argon2）或 `admin_token`（比较两者的哈希，耗时与令牌内容无关）
+// 任一匹配即通过
+async fn authorize_admin(
+    state: &RcState,
+    scope: AdminScope,
+    authorization: Option<&str>,
+) -> Result<(), AppError> {
+    let (token, token_hash, peer_token) = {
+        let config = &state.inner.read().await.config;
+        (
+            config.admin_token.clone(),
+            config.admin_token_hash.clone(),
+            config.peer_token.clone(),
+        )
+    };
+    let unauthorized = || AppError {
+        status: StatusCode::UNAUTHORIZED,
+        error: anyhow!("Missing or invalid admin token"),
+    };
+    let provided = authorization.and_then(|value| value.strip_prefix("Bearer "));
+    let matches =
+        |expected: &str, provided: &str| Sha3_256::digest(provided) == Sha3_256::digest(expected);
+
+    if scope == AdminScope::Peer {
+        let Some(peer_token) = peer_token else {
+            return Err(AppError {
+                status: StatusCode::FORBIDDEN,
+                error: anyhow!("Peer endpoints are disabled, set peer_token to enable them"),
+            });
+        };
+        return match provided {
+            Some(provided) if matches(&peer_token, provided) => Ok(()),
+            _ => Err(AppError {
+                status: StatusCode::UNAUTHORIZED,
+                error: anyhow!("Missing or invalid peer token"),
+            }),
+        };
+    }
+
+    if token_hash.is_none() {
+        match scope {
+            AdminScope::Operator => return Ok(()),
+            AdminScope::Registry if token.is_none() => {
+                return Err(AppError {
+                    status: StatusCode::FORBIDDEN,
+                    error: anyhow!(
+                        "Admin endpoints are disabled, set admin_token_hash to enable them"
+                    ),
+                });
+            }
+            AdminScope::Registry | AdminScope::Peer => {}
+        }
+    }
+
+    let provided = provided.ok_or_else(unauthorized)?.to_string();
+    if token.is_some_and(|expected| matches(&expected, &provided)) {
+        return Ok(());
+    }
+    let Some(token_hash) = token_hash else {
+        return Err(unauthorized());
+    };
+    // Argon2 校验需要几十毫秒的 CPU 时间，不占用异步运行时的线程
+    let verified = tokio::task::spawn_blocking(move || {
+        let hash =
+            PasswordHash::new(&token_hash).map_err(|e| anyhow!("Invalid admin_token_hash: {e}"))?;
+        Ok::<_, eyre::Error>(
+            Argon2::default()
+                .verify_password(provided.as_bytes(), &hash)
+                .is_ok(),
+        )
+    })
+    .await??;
+    if verified {
+        Ok(())
+    } else {
+        Err(unauthorized())
+    }
+}
+
+// Handler for POST /bootstrap/server
+// 一次返回系统参数与新签发的服务器私钥：新 MS 不必先后调用 /params 与 /register/server，
+// 两者在同一把锁下读取，不会因为中间发生参数轮换而不一致。
+// SIDms 只取决于主密钥与 ID，对同一个 ID 重复调用返回相同的私钥，因此允许重复签发。
+#[instrument(name = "register", skip_all, fields(kind = "server"))]
+async fn bootstrap_server(
+    State(state): State<RcState>,
+    Json(payload): Json<RegisterRequest>,
+) -> Result<Json<ServerBootstrapResponse>, AppError> {
+    debug!("Calling bootstrap_server handler. payload: {:?}", payload);
+
+    let state_read = state.inner.read().await;
+    let id = id_bytes(&payload)?;
+    let ssk = issue_server_key(&state, &state_read, &id).await?;
+    let (_, params) = require_ready(&state_read)?;
+    rc::verify_server_key(params, &id, &ssk)?;
+
+    Ok(Json(ServerBootstrapResponse {
+        params: params_response(
+            params,
+            state_read.params_version,
+            state_read.superseded.clone(),
+        )?,
+        sid_ms_hex: ark_to_hex(&ssk.sid_ms)?,
+    }))
 }
 
 // Handler for POST /set_shares
 async fn set_share(
     State(state): State<RcState>,
-    Json(share): Json<Vec<u8>>,
+    Json(share): Json<ShareEnvelope>,
 ) -> Result<(), AppError> {
-    debug!("Calling set_shares handler. share: {:?}", share);
+    debug!("Calling set_shares handler. share index: {}", share.index);
 
     let mut state_write = state.inner.write().await;
 
-    state_write.share = Some(
-        Share::try_from(share.as_slice())
-            .map_err(|e| AppError(anyhow!("Failed to deserialize share: {}", e)))?,
-    );
+    let share =
+        EpochShare::try_from(share).map_err(|e| anyhow!("Failed to deserialize share: {}", e))?;
+    // 补发的 share 可能晚于下一轮分发到达，不能用它覆盖更新的 share
+    if let Some(current) = &state_write.share
+        && share.epoch < current.epoch
+    {
+        return Err(AppError::conflict(anyhow!(
+            "Share epoch {:016x} is older than the held epoch {:016x}",
+            share.epoch,
+            current.epoch
+        )));
+    }
+    state.replace_share(&mut state_write, Some(share))?;
     Ok(())
 }
 
 // Handler for GET /get_shares
-async fn get_share(State(state): State<RcState>) -> Result<Json<Vec<u8>>, AppError> {
+async fn get_share(State(state): State<RcState>) -> Result<Json<ShareEnvelope>, AppError> {
     debug!("Calling get_shares handler");
 
     let state_read = state.inner.read().await;
     let Some(share) = &state_read.share else {
-        return Err(AppError(eyre::anyhow!(
-            "No share available. Ensure /set_share was called first."
-        )));
+        return Err(
+            eyre::anyhow!("No share available. Ensure /set_share was called first.").into(),
+        );
     };
 
-    Ok(Json(share.into()))
+    Ok(Json(ShareEnvelope::from(share)))
 }
 
-// --- Main Application Setup ---
+// Handler for GET /ready
+// 持有系统参数和 share 时返回 200，供其他节点在注册前确认门限可达
+async fn ready(State(state): State<RcState>) -> StatusCode {
+    if require_ready(&*state.inner.read().await).is_ok() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    dotenv().ok();
+#[derive(Serialize)]
+struct DebugState {
+    addr: String,
+    build: BuildInfo,
+    nodes: Vec<String>,
+    threshold: usize,
+    single_node: bool,
+    params: Option<DebugParams>,
+    // 只说明是否持有 share 及其 epoch 与索引，不包含 share 的值
+    share_loaded: bool,
+    share_epoch: Option<String>,
+    share_index: Option<u8>,
+    idempotency_cache_entries: usize,
+    peers: Vec<DebugPeer>,
+}
 
-    let (sink, _guard) = tracing_appender::non_blocking(std::io::stdout());
-    init_subscriber(sink);
+#[derive(Serialize)]
+struct BuildInfo {
+    package: &'static str,
+    version: &'static str,
+}
 
-    let config = Figment::new()
-        .merge(providers::Json::file("config.json"))
-        .merge(providers::Env::prefixed("RC_"))
-        .extract::<RcConfig>()?;
-    let self_addr = config.addr.clone();
+#[derive(Serialize)]
+struct DebugParams {
+    fingerprint: String,
+    version: u64,
+    supersedes: Option<String>,
+}
 
-    let rc_state = RcState::new(config)?;
+#[derive(Serialize)]
+struct DebugPeer {
+    addr: String,
+    status: PeerStatus,
+    circuit_open: bool,
+}
 
-    // Build Axum app
-    let app = Router::new()
+// Handler for GET /debug/state
+// 供现场排查使用的节点状态：参数指纹、是否持有 share、缓存大小与各节点的可达性，
+// 不包含主密钥、share 或任何已签发的私钥
+async fn debug_state(State(state): State<RcState>) -> Json<DebugState> {
+    let (mut debug, peers) = {
+        let state_read = state.inner.read().await;
+        let config = &state_read.config;
+        let debug = DebugState {
+            addr: config.addr.clone(),
+            build: BuildInfo {
+                package: env!("CARGO_PKG_NAME"),
+                version: env!("CARGO_PKG_VERSION"),
+            },
+            nodes: config.nodes.clone(),
+            threshold: config.threshold,
+            single_node: config.is_single_node(),
+            params: state_read.params.as_ref().map(|params| DebugParams {
+                fingerprint: params.fingerprint(),
+                version: state_read.params_version,
+                supersedes: state_read.superseded.clone(),
+            }),
+            share_loaded: state_read.share.is_some(),
+            share_epoch: state_read
+                .share
+                .as_ref()
+                .map(|share| format!("{:016x}", share.epoch)),
+            share_index: state_read.share.as_ref().map(|share| share.share.x.0),
+            idempotency_cache_entries: state.idempotency.lock().await.len(),
+            peers: Vec::new(),
+        };
+        (debug, config.peers())
+    };
+    // 探测期间不持有锁
+    let statuses = probe_peers(&peers).await;
+    debug.peers = peers
+        .into_iter()
+        .zip(statuses)
+        .map(|(addr, status)| DebugPeer {
+            circuit_open: state.breakers.is_open(&addr),
+            addr,
+            status,
+        })
+        .collect();
+    Json(debug)
+}
+
+// Handler for POST /set_params
+// 保存协调者在 /setup 或 /membership 后推送的系统参数
+async fn set_params(
+    State(state): State<RcState>,
+    Json(response): Json<SystemParametersResponse>,
+) -> Result<(), AppError> {
+    let params = params_from_response(&response)?;
+    debug!(
+        "Received system parameters (fingerprint {}, version {})",
+        params.fingerprint(),
+        response.params_version
+    );
+
+    let stored = StoredParams {
+        params,
+        version: response.params_version,
+        superseded: response.supersedes.clone(),
+    };
+    state.replace_params(&mut *state.inner.write().await, stored)?;
+    Ok(())
+}
+
+// Handler for POST /membership
+// Adds/removes nodes and reshares the same msk to the new node set
+async fn change_membership(
+    State(state): State<RcState>,
+    Json(change): Json<MembershipChange>,
+) -> Result<Json<MembershipResponse>, AppError> {
+    debug!("Calling change_membership handler. change: {:?}", change);
+
+    // 同一节点上的成员变更依次进行。收集与分发 share 期间不持有状态锁，
+    // 响应缓慢的节点不会阻塞 /ready 与注册
+    let _membership = state.membership.lock().await;
+    let (share, params, addr, old_nodes, threshold, peer_token) = {
+        let state_read = state.inner.read().await;
+        let (share, params) = require_ready(&state_read)?;
+        let params = params_response(
+            params,
+            state_read.params_version,
+            state_read.superseded.clone(),
+        )?;
+        let config = &state_read.config;
+        (
+            share.clone(),
+            params,
+            config.addr.clone(),
+            config.nodes.clone(),
+            config.threshold,
+            config.peer_token.clone(),
+        )
+    };
+    let peer_token = peer_token.as_deref();
+
+    if change.remove.contains(&addr) {
+        return Err(anyhow!(
+            "A node cannot remove itself; send the request to another member instead."
+        )
+        .into());
+    }
+
+    let mut nodes: Vec<String> = old_nodes
+        .iter()
+        .filter(|node| !change.remove.contains(node))
+        .cloned()
+        .collect();
+    for node in change.add {
+        if !nodes.contains(&node) {
+            nodes.push(node);
+        }
+    }
+    if threshold > nodes.len() {
+        return Err(anyhow!(
+            "Threshold {} exceeds the new node count {}",
+            threshold,
+            nodes.len()
+        )
+        .into());
+    }
+    let removed: Vec<String> = old_nodes
+        .iter()
+        .filter(|node| !nodes.contains(node))
+        .cloned()
+        .collect();
+
+    // 用旧的节点集合恢复 msk，再为新的节点集合重新生成 share。
+    // 新的 share 来自新的随机多项式，因此被移除节点手中的旧 share 无法再与之组合。
+    let old_peers: Vec<String> = old_nodes
+        .iter()
+        .filter(|&node| node != &addr)
+        .cloned()
+        .collect();
+    let epoch = share.epoch;
+    let shares = collect_shares(share, &old_peers, &state.breakers, threshold, peer_token).await?;
+    ensure_quorum(&shares, threshold)?;
+    let msk = MasterSecretKey::from_shares(shares, threshold)?;
+    let mut new_shares = msk.into_shares(threshold, nodes.len());
+    let own_share = new_shares.pop(); // 为当前节点分配一个 share
+    let peers: Vec<String> = nodes
+        .iter()
+        .filter(|&node| node != &addr)
+        .cloned()
+        .collect();
+
+    // 新的 share 达到门限后才替换本节点的 share 与节点列表；分发失败时本节点仍持有旧 epoch 的
+    // share，旧的节点集合仍可恢复 msk
+    distribute_shares(&new_shares, &peers, &state.breakers, threshold, peer_token)
+        .await
+        .map_err(AppError::unavailable)?;
+    {
+        let mut state_write = state.inner.write().await;
+        // 网络阶段中 /setup、/set_share 或 /set_nodes 改变了本节点的 share 或节点列表
+        if state_write.share.as_ref().map(|share| share.epoch) != Some(epoch)
+            || state_write.config.nodes != old_nodes
+        {
+            return Err(AppError::conflict(anyhow!(
+                "The share or node list changed during the membership change, \
+                 this node kept the concurrent result"
+            )));
+        }
+        state_write.reshared_nodes = Some(nodes.clone());
+        state_write.config.nodes = nodes.clone();
+        state.replace_share(&mut state_write, own_share)?;
+    }
+
+    broadcast_params(&params, &peers, threshold, peer_token)
+        .await
+        .map_err(AppError::unavailable)?;
+    broadcast_nodes(&nodes, &peers, peer_token).await;
+    broadcast_nodes(&nodes, &removed, peer_token).await;
+
+    info!("Membership changed, nodes: {:?}", nodes);
+
+    Ok(Json(MembershipResponse { nodes }))
+}
+
+// Handler for POST /set_nodes
+async fn set_nodes(
+    State(state): State<RcState>,
+    Json(nodes): Json<Vec<String>>,
+) -> Result<(), AppError> {
+    debug!("Calling set_nodes handler. nodes: {:?}", nodes);
+
+    let mut state_write = state.inner.write().await;
+    if !nodes.contains(&state_write.config.addr) {
+        warn!("This node was removed from the cluster, dropping its share");
+        state.replace_share(&mut state_write, None)?;
+    }
+    state_write.reshared_nodes = Some(nodes.clone());
+    state_write.config.nodes = nodes;
+    Ok(())
+}
+
+// --- Config Reload ---
+
+// 用重新读取的配置更新节点列表，保留当前的 share 与 params。
+// 门限和本节点地址不能通过重新加载修改（门限变化需要重新分发 share）；
+// /membership 改变节点集合后，配置文件更新为新的集合之前也拒绝重新加载。
+async fn reload_nodes(state: &RcState, config: RcConfig) -> Result<()> {
+    config.validate()?;
+
+    let mut state_write = state.inner.write().await;
+    if config.threshold != state_write.config.threshold {
+        return Err(anyhow!(
+            "Refusing to reload: threshold changed from {} to {}, reshare through /membership instead",
+            state_write.config.threshold,
+            config.threshold
+        ));
+    }
+    if config.addr != state_write.config.addr {
+        return Err(anyhow!(
+            "Refusing to reload: this node's address changed from {} to {}",
+            state_write.config.addr,
+            config.addr
+        ));
+    }
+    if let Some(reshared) = &state_write.reshared_nodes {
+        let sorted = |nodes: &[String]| {
+            let mut nodes = nodes.to_vec();
+            nodes.sort();
+            nodes
+        };
+        if sorted(reshared) != sorted(&config.nodes) {
+            return Err(anyhow!(
+                "Refusing to reload: nodes {:?} differ from {:?} set by the last membership change, update the config file first",
+                config.nodes,
+                reshared
+            ));
+        }
+    }
+
+    info!("Reloaded nodes: {:?}", config.nodes);
+    // 配置文件已与成员变更的结果一致，之后以配置文件为准
+    state_write.reshared_nodes = None;
+    state_write.config.nodes = config.nodes;
+    Ok(())
+}
+
+// 收到 SIGHUP 时重新加载配置文件中的节点列表
+#[cfg(unix)]
+fn reload_on_sighup(state: RcState, cli: Cli) -> Result<tokio::task::JoinHandle<()>> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    // 在返回前注册信号处理，之后到达的 SIGHUP 都不会被遗漏
+    let mut hangup = signal(SignalKind::hangup())?;
+    Ok(tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("Received SIGHUP, reloading {}", cli.config.display());
+            let result = match load_config(&cli) {
+                Ok(config) => reload_nodes(&state, config).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                warn!("Failed to reload config: {e}");
+            }
+        }
+    }))
+}
+
+// --- Main Application Setup ---
+
+fn router(rc_state: RcState, max_body_bytes: usize) -> Router {
+    let admin = |scope| middleware::from_fn_with_state((rc_state.clone(), scope), admin_auth);
+    let operator = Router::new()
         .route("/setup", get(setup_system)) // Endpoint to initialize
-        .route("/params", get(get_params)) // Endpoint to get public params
-        .route("/register/user", post(register_user)) // Endpoint for user registration
-        .route("/register/server", post(register_server)) // Endpoint for server registration
+        .route("/rotate_master", post(rotate_master))
+        .route("/membership", post(change_membership))
+        .route_layer(admin(AdminScope::Operator));
+    let peer = Router::new()
         .route("/set_share", post(set_share))
         .route("/get_share", get(get_share))
+        .route("/set_params", post(set_params))
+        .route("/set_nodes", post(set_nodes))
+        .route_layer(admin(AdminScope::Peer));
+    let registry = Router::new()
+        .route("/registered/{id}", get(registered))
+        .route("/registrations", get(list_registrations))
+        .route("/debug/state", get(debug_state))
+        .route_layer(admin(AdminScope::Registry));
+
+    Router::new()
+        .route("/params", get(get_params)) // Endpoint to get public params
+        .route("/register/user", post(register_user_idempotent)) // Endpoint for user registration
+        .route("/register/users", post(register_users))
+        .route("/register/server", post(register_server)) // Endpoint for server registration
+        .route("/register/pseudonym", post(register_pseudonym))
+        .route("/bootstrap/server", post(bootstrap_server))
+        .merge(operator)
+        .merge(registry)
+        .merge(peer)
+        .route("/ready", get(ready))
+        .layer(RequestBodyLimitLayer::new(max_body_bytes))
+        // 客户端带 `Accept-Encoding: gzip` 时压缩响应，其余客户端不受影响
+        .layer(CompressionLayer::new())
         .layer(TraceLayer::new_for_http())
-        .with_state(rc_state); // Share the state with handlers
+        .layer(middleware::from_fn(request_id::request_id))
+        .with_state(rc_state) // Share the state with handlers
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenv().ok();
+
+    let cli = Cli::parse();
+    let config = load_config(&cli)?;
+
+    let (audit_sink, _audit_guard) = match &config.audit_log {
+        Some(path) => {
+            let (writer, guard) = audit::open(path)?;
+            (Some(writer), Some(guard))
+        }
+        None => (None, None),
+    };
+    let _guard = init_subscriber(&config.log, audit_sink)?;
+
+    let self_addr = config.addr.clone();
+    let grpc_addr = config.grpc_addr.clone();
+    let max_body_bytes = config.max_body_bytes;
+    let cors = config.cors.as_ref().map(CorsConfig::layer).transpose()?;
+
+    if config.is_single_node() {
+        info!("Running in single-node mode, shares are never exchanged with other nodes");
+    }
+    let rc_state = RcState::new(config)?;
+
+    #[cfg(unix)]
+    reload_on_sighup(rc_state.clone(), cli)?;
+
+    if let Some(grpc_addr) = grpc_addr {
+        let service = RcServer::new(RcService {
+            state: rc_state.clone(),
+        });
+        let grpc_addr = grpc_addr.parse()?;
+        println!("RC gRPC server listening on {grpc_addr}");
+        tokio::spawn(async move {
+            if let Err(e) = tonic::transport::Server::builder()
+                .add_service(service)
+                .serve(grpc_addr)
+                .await
+            {
+                warn!("gRPC server stopped: {e}");
+            }
+        });
+    }
+
+    // Build Axum app
+    let mut app = router(rc_state, max_body_bytes);
+    if let Some(cors) = cors {
+        app = app.layer(cors);
+    }
 
     // Run the server
     let listener = tokio::net::TcpListener::bind(&self_addr).await?; // Use listen_addr
@@ -298,16 +1750,77 @@ async fn main() -> Result<()> {
 
 // --- Custom Error Type for Axum ---
 // Make Axum return proper errors using anyhow for simplicity
-struct AppError(eyre::Error);
+#[derive(Debug)]
+struct AppError {
+    status: StatusCode,
+    error: eyre::Error,
+}
+
+impl AppError {
+    // 暂时无法处理请求（例如可用节点不足），客户端可稍后重试
+    fn unavailable(err: impl Into<eyre::Error>) -> Self {
+        Self {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            error: err.into(),
+        }
+    }
+
+    // 请求基于的状态已被并发的请求改变，调用者需要重新读取后再决定是否重试
+    fn conflict(err: impl Into<eyre::Error>) -> Self {
+        Self {
+            status: StatusCode::CONFLICT,
+            error: err.into(),
+        }
+    }
+
+    // 请求本身不合法
+    fn bad_request(err: impl Into<eyre::Error>) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            error: err.into(),
+        }
+    }
+
+    // 错误链中有 AAKAError 时使用它的错误码，否则按状态码归类
+    fn code(&self) -> &'static str {
+        match self
+            .error
+            .chain()
+            .find_map(|e| e.downcast_ref::<AAKAError>())
+        {
+            Some(err) => err.code(),
+            None if self.status == StatusCode::SERVICE_UNAVAILABLE => "ERR_UNAVAILABLE",
+            None if self.status == StatusCode::BAD_REQUEST => "ERR_BAD_REQUEST",
+            None if self.status == StatusCode::UNAUTHORIZED => "ERR_UNAUTHORIZED",
+            None if self.status == StatusCode::FORBIDDEN => "ERR_FORBIDDEN",
+            None if self.status == StatusCode::NOT_FOUND => "ERR_NOT_FOUND",
+            None if self.status == StatusCode::CONFLICT => "ERR_CONFLICT",
+            None => "ERR_INTERNAL",
+        }
+    }
+}
 
 impl IntoResponse for AppError {
     fn into_response(self) -> axum::response::Response {
-        eprintln!("Error occurred: {:?}", self.0); // Log the full error details
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Internal Server Error: {}", self.0), // Simplified user message
-        )
-            .into_response()
+        eprintln!("Error occurred: {:?}", self.error); // Log the full error details
+        // 中间件之外（如直接调用 handler）没有请求 ID，此时为 null
+        let body = serde_json::json!({
+            "code": self.code(),
+            "message": format!(
+                "{}: {}",
+                self.status.canonical_reason().unwrap_or("Error"),
+                self.error
+            ), // Simplified user message
+            "request_id": request_id::current(),
+        });
+        let mut response = (self.status, Json(body)).into_response();
+        // 503 表示稍后重试即可，告诉客户端轮询的间隔
+        if self.status == StatusCode::SERVICE_UNAVAILABLE {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, RETRY_AFTER_SECS.into());
+        }
+        response
     }
 }
 
@@ -316,6 +1829,2448 @@ where
     E: Into<eyre::Error>,
 {
     fn from(err: E) -> Self {
-        Self(err.into())
+        let error = err.into();
+        // 尚未初始化不是服务器故障，客户端应稍后重试
+        let status = match error.downcast_ref::<AAKAError>() {
+            Some(AAKAError::NotInitialized(_)) => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        Self { status, error }
+    }
+}
+
+// 503 响应的 `Retry-After`（秒）
+const RETRY_AFTER_SECS: u32 = 5;
+// 转发 /setup 的超时：协调者要先把 share 和系统参数分发给所有节点才会响应
+const SETUP_PROXY_TIMEOUT: Duration = Duration::from_secs(60);
+
+// 收集 share 前确认足够多的节点可达，不可达时直接返回 503，不必等待完整的收集与重试
+async fn ensure_peers_reachable(
+    config: &RcConfig,
+    breakers: &CircuitBreakers,
+) -> Result<(), AppError> {
+    let peers = config.peers();
+    // 本节点自己持有一份 share
+    let needed = config.threshold.saturating_sub(1);
+    let ready = probe_quorum(&peers, needed, breakers).await;
+    if ready < needed {
+        return Err(AAKAError::NotInitialized(NotReady::PeersUnreachable { ready, needed }).into());
+    }
+    Ok(())
+}
+
+// 注册与 /params 都要求本节点已有系统参数并持有 share
+fn require_ready(state: &InnerRcState) -> Result<(&EpochShare, &SystemParameters), AAKAError> {
+    let params = state
+        .params
+        .as_ref()
+        .ok_or(AAKAError::NotInitialized(NotReady::NoParams))?;
+    let share = state
+        .share
+        .as_ref()
+        .ok_or(AAKAError::NotInitialized(NotReady::NoShare))?;
+    Ok((share, params))
+}
+
+// 恢复 msk 前确认收集到的 share 数量达到门限
+fn ensure_quorum(shares: &[EpochShare], threshold: usize) -> Result<(), AppError> {
+    if shares.len() < threshold {
+        return Err(AppError::unavailable(AAKAError::Other(format!(
+            "insufficient shares: got {}, need {}",
+            shares.len(),
+            threshold
+        ))));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    // 单节点（threshold = 1）的 RC 状态，注册时不需要联系其他节点
+    async fn single_node_state() -> RcState {
+        let config = RcConfig {
+            peer_token: None,
+            ..config_with("127.0.0.1:0", &["127.0.0.1:0"], 1)
+        };
+        let state = RcState::new(config).unwrap();
+
+        let (params, msk) = rc::gen_parameter_and_msk(&mut thread_rng()).unwrap();
+        let mut state_write = state.inner.write().await;
+        state_write.params = Some(params);
+        state_write.share = msk.into_shares(1, 1).pop();
+        drop(state_write);
+
+        state
+    }
+
+    // 在随机端口上启动一组 RC 节点，返回各节点地址
+    async fn spawn_cluster(nodes_per_config: Vec<Vec<usize>>, threshold: usize) -> Vec<String> {
+        let mut listeners = Vec::new();
+        for _ in 0..nodes_per_config.len() {
+            listeners.push(tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap());
+        }
+        let addrs: Vec<String> = listeners
+            .iter()
+            .map(|l| l.local_addr().unwrap().to_string())
+            .collect();
+
+        for (listener, members) in listeners.into_iter().zip(nodes_per_config) {
+            let addr = listener.local_addr().unwrap().to_string();
+            let members: Vec<&str> = members.iter().map(|&i| addrs[i].as_str()).collect();
+            let config = config_with(&addr, &members, threshold);
+            let app = router(RcState::new(config).unwrap(), default_max_body_bytes());
+            tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        }
+
+        addrs
+    }
+
+    // 校验 SIDu * P == Ru + h0(IDu || Ru) * Ppub，即注册时恢复出的 msk 是正确的
+    async fn assert_user_key_valid(client: &reqwest::Client, params_node: &str, rc_node: &str) {
+        use ibc_aaka_scheme::{G1Point, ScalarField, hash_utils};
+
+        let params: serde_json::Value = client
+            .get(format!("http://{params_node}/params"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let user: serde_json::Value = client
+            .post(format!("http://{rc_node}/register/user"))
+            .json(&serde_json::json!({ "id": "alice" }))
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+
+        let p: G1Point = hex_to_ark(params["p_hex"].as_str().unwrap()).unwrap();
+        let p_pub: G1Point = hex_to_ark(params["p_pub_hex"].as_str().unwrap()).unwrap();
+        let r_u: G1Point = hex_to_ark(user["r_u_hex"].as_str().unwrap()).unwrap();
+        let sid_u: ScalarField = hex_to_ark(user["sid_u_hex"].as_str().unwrap()).unwrap();
+        let h_0 = hash_utils::h0(b"alice", &r_u).unwrap();
+        assert_eq!(p * sid_u, r_u + p_pub * h_0);
+    }
+
+    // 测试用的节点配置，其余字段取默认值；节点间请求带 `peer-secret` 令牌
+    fn config_with(addr: &str, nodes: &[&str], threshold: usize) -> RcConfig {
+        RcConfig {
+            addr: addr.to_string(),
+            nodes: nodes.iter().map(|node| node.to_string()).collect(),
+            threshold,
+            audit_log: None,
+            log: LogConfig::default(),
+            grpc_addr: None,
+            max_body_bytes: default_max_body_bytes(),
+            xof: Xof::default(),
+            cors: None,
+            idempotency_ttl_secs: default_idempotency_ttl_secs(),
+            idempotency_max_entries: default_idempotency_max_entries(),
+            admin_token: None,
+            admin_token_hash: None,
+            peer_token: Some("peer-secret".to_string()),
+            store_dir: None,
+            share_key: None,
+            deployment_info: None,
+            max_registration_batch: default_max_registration_batch(),
+            registration_threads: None,
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_sighup_picks_up_new_peer() {
+        let path = std::env::temp_dir().join(format!("rc_reload_{}.json", std::process::id()));
+        let write_config = |nodes: &[&str], threshold: usize| {
+            let config = serde_json::json!({
+                "addr": "127.0.0.1:5000",
+                "nodes": nodes,
+                "threshold": threshold,
+                "peer_token": "peer-secret",
+            });
+            std::fs::write(&path, config.to_string()).unwrap();
+        };
+        write_config(&["127.0.0.1:5000", "127.0.0.1:5001"], 1);
+
+        let cli = Cli::parse_from(["aaka_rc_app", "--config", path.to_str().unwrap()]);
+        let state = RcState::new(load_config(&cli).unwrap()).unwrap();
+        let (_, msk) = rc::gen_parameter_and_msk(&mut thread_rng()).unwrap();
+        state.inner.write().await.share = msk.into_shares(1, 1).pop();
+        reload_on_sighup(state.clone(), cli).unwrap();
+
+        let send_sighup = || {
+            let status = std::process::Command::new("kill")
+                .args(["-HUP", &std::process::id().to_string()])
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        let wait_for_nodes = |len: usize| {
+            let state = state.clone();
+            async move {
+                for _ in 0..100 {
+                    if state.inner.read().await.config.nodes.len() == len {
+                        return;
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                }
+                panic!("nodes were not reloaded");
+            }
+        };
+
+        write_config(&["127.0.0.1:5000", "127.0.0.1:5001", "127.0.0.1:5002"], 1);
+        send_sighup();
+        wait_for_nodes(3).await;
+        {
+            let state_read = state.inner.read().await;
+            assert_eq!(
+                state_read.config.peers(),
+                ["127.0.0.1:5001", "127.0.0.1:5002"]
+            );
+            assert!(state_read.share.is_some());
+        }
+
+        // 修改门限的配置被拒绝，节点列表保持不变
+        write_config(&["127.0.0.1:5000", "127.0.0.1:5001"], 2);
+        assert!(
+            reload_nodes(
+                &state,
+                load_config(&Cli::parse_from([
+                    "aaka_rc_app",
+                    "--config",
+                    path.to_str().unwrap()
+                ]))
+                .unwrap()
+            )
+            .await
+            .is_err()
+        );
+        assert_eq!(state.inner.read().await.config.nodes.len(), 3);
+
+        // /membership 改变节点集合后，仍列出旧集合的配置文件不会让节点列表回退
+        let reload = || async {
+            let cli = Cli::parse_from(["aaka_rc_app", "--config", path.to_str().unwrap()]);
+            reload_nodes(&state, load_config(&cli).unwrap()).await
+        };
+        let reshared = vec!["127.0.0.1:5000".to_string(), "127.0.0.1:5003".to_string()];
+        set_nodes(State(state.clone()), Json(reshared.clone()))
+            .await
+            .unwrap();
+        write_config(&["127.0.0.1:5000", "127.0.0.1:5001", "127.0.0.1:5002"], 1);
+        let err = reload().await.unwrap_err();
+        assert!(err.to_string().contains("last membership change"), "{err}");
+        assert_eq!(state.inner.read().await.config.nodes, reshared);
+
+        // 配置文件更新为新的集合后照常重新加载
+        write_config(&["127.0.0.1:5003", "127.0.0.1:5000"], 1);
+        reload().await.unwrap();
+        write_config(&["127.0.0.1:5000", "127.0.0.1:5003", "127.0.0.1:5004"], 1);
+        reload().await.unwrap();
+        assert_eq!(state.inner.read().await.config.nodes.len(), 3);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[allow(clippy::result_large_err)]
+    fn test_env_overrides_config_file() {
+        figment::Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.json",
+                r#"{ "addr": "127.0.0.1:5000", "nodes": ["127.0.0.1:5000"], "threshold": 2 }"#,
+            )?;
+            jail.set_env("RC_THRESHOLD", "1");
+
+            let config = load_config(&Cli::parse_from(["aaka_rc_app"])).unwrap();
+            assert_eq!(config.threshold, 1);
+            assert_eq!(config.addr, "127.0.0.1:5000");
+
+            let cli = Cli::parse_from(["aaka_rc_app", "--set", "addr=127.0.0.1:5001"]);
+            assert_eq!(load_config(&cli).unwrap().addr, "127.0.0.1:5001");
+            Ok(())
+        });
+    }
+
+    #[tokio::test]
+    async fn test_batch_user_registration() {
+        let state = single_node_state().await;
+        let params = state.inner.read().await.params.clone().unwrap();
+        state.inner.write().await.config.registration_threads = Some(3);
+        let register = |ids: Vec<String>, id_encoding: IdEncoding| {
+            register_users(
+                State(state.clone()),
+                Json(BatchRegisterRequest { ids, id_encoding }),
+            )
+        };
+
+        let ids: Vec<String> = (0..7).map(|i| format!("device-{i}")).collect();
+        let Ok(Json(resp)) = register(ids.clone(), IdEncoding::Utf8).await else {
+            panic!("batch registration failed");
+        };
+        assert_eq!(resp.keys.len(), ids.len());
+        for (id, key) in ids.iter().zip(&resp.keys) {
+            let usk = UserSecretKey {
+                r_u: hex_to_ark(&key.r_u_hex).unwrap(),
+                sid_u: hex_to_ark(&key.sid_u_hex).unwrap(),
+            };
+            rc::verify_user_key(&params, id.as_bytes(), &usk).unwrap();
+            assert!(state.is_registered(id.as_bytes(), 0).unwrap());
+        }
+
+        // 二进制 ID 按 `id_encoding` 解码；超出上限或 ID 无法解码时整批拒绝
+        let Ok(Json(resp)) = register(vec![hex::encode(b"dev\x00\xff")], IdEncoding::Hex).await
+        else {
+            panic!("batch registration of a hex ID failed");
+        };
+        assert_eq!(resp.keys.len(), 1);
+        let too_many = vec!["x".to_string(); default_max_registration_batch() + 1];
+        let Err(err) = register(too_many, IdEncoding::Utf8).await else {
+            panic!("oversized batch was accepted");
+        };
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        let Err(err) = register(vec!["zz".to_string()], IdEncoding::Hex).await else {
+            panic!("invalid hex ID was accepted");
+        };
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_gzip_capable_clients_get_compressed_responses() {
+        use std::io::Read;
+
+        let state = single_node_state().await;
+        let app = router(state, default_max_body_bytes());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let client = reqwest::Client::new();
+
+        // 不声明 Accept-Encoding 的客户端收到原样的响应
+        let plain = client
+            .get(format!("http://{addr}/params"))
+            .send()
+            .await
+            .unwrap();
+        assert!(plain.headers().get(header::CONTENT_ENCODING).is_none());
+        let plain: serde_json::Value = plain.json().await.unwrap();
+
+        let ids: Vec<String> = (0..16).map(|i| format!("device-{i}")).collect();
+        for request in [
+            client.get(format!("http://{addr}/params")),
+            client
+                .post(format!("http://{addr}/register/users"))
+                .json(&serde_json::json!({ "ids": ids })),
+        ] {
+            let resp = request
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers()[header::CONTENT_ENCODING], "gzip");
+            let compressed = resp.bytes().await.unwrap();
+            let mut json = String::new();
+            flate2::read::GzDecoder::new(&compressed[..])
+                .read_to_string(&mut json)
+                .unwrap();
+            assert!(compressed.len() < json.len());
+            let body: serde_json::Value = serde_json::from_str(&json).unwrap();
+            if body.get("keys").is_some() {
+                assert_eq!(body["keys"].as_array().unwrap().len(), ids.len());
+            } else {
+                assert_eq!(body, plain);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_params_over_cbor() {
+        let state = single_node_state().await;
+        let expected = state.inner.read().await.params.clone().unwrap();
+
+        let json = get_params(State(state.clone()), HeaderMap::new())
+            .await
+            .unwrap_or_else(|_| panic!("get_params failed"));
+        let json = axum::body::to_bytes(json.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, CBOR_CONTENT_TYPE.parse().unwrap());
+        let cbor = get_params(State(state), headers)
+            .await
+            .unwrap_or_else(|_| panic!("get_params failed"));
+        assert_eq!(cbor.headers()[header::CONTENT_TYPE], CBOR_CONTENT_TYPE);
+        let cbor = axum::body::to_bytes(cbor.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        assert_eq!(SystemParameters::from_cbor(&cbor).unwrap(), expected);
+        assert!(cbor.len() < json.len());
+    }
+
+    #[tokio::test]
+    async fn test_params_not_modified_for_matching_etag() {
+        let state = single_node_state().await;
+
+        let resp = get_params(State(state.clone()), HeaderMap::new())
+            .await
+            .unwrap_or_else(|_| panic!("get_params failed"));
+        let etag = resp.headers()[header::ETAG].clone();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, etag);
+        let resp = get_params(State(state.clone()), headers.clone())
+            .await
+            .unwrap_or_else(|_| panic!("get_params failed"));
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+        // 参数轮换后 ETag 不再匹配
+        let (params, _) = rc::gen_parameter_and_msk(&mut thread_rng()).unwrap();
+        state.inner.write().await.params = Some(params);
+        let resp = get_params(State(state), headers)
+            .await
+            .unwrap_or_else(|_| panic!("get_params failed"));
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_params_version_increments_on_setup() {
+        async fn fetch(state: &RcState, headers: HeaderMap) -> Response {
+            get_params(State(state.clone()), headers)
+                .await
+                .unwrap_or_else(|_| panic!("get_params failed"))
+        }
+        async fn version(resp: Response) -> u64 {
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()["params_version"]
+                .as_u64()
+                .unwrap()
+        }
+
+        let state = RcState::new(config_with("127.0.0.1:0", &["127.0.0.1:0"], 1)).unwrap();
+        let Ok(Json(setup)) = setup_system(
+            State(state.clone()),
+            HeaderMap::new(),
+            Query(SetupQuery::default()),
+        )
+        .await
+        else {
+            panic!("setup failed");
+        };
+        assert_eq!(setup.params_version, 1);
+
+        let resp = fetch(&state, HeaderMap::new()).await;
+        let etag = resp.headers()[header::ETAG].clone();
+        assert_eq!(version(resp).await, 1);
+
+        // 参数未变化时返回 304
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, etag);
+        let resp = fetch(&state, headers.clone()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+        // 再次 /setup 后版本号递增，旧的 ETag 不再匹配
+        let Ok(Json(setup)) = setup_system(
+            State(state.clone()),
+            HeaderMap::new(),
+            Query(SetupQuery::default()),
+        )
+        .await
+        else {
+            panic!("setup failed");
+        };
+        assert_eq!(setup.params_version, 2);
+        let resp = fetch(&state, headers).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(version(resp).await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_require_ready_reports_each_missing_piece() {
+        let state = RcState::new(config_with("127.0.0.1:0", &["127.0.0.1:0"], 1)).unwrap();
+        let not_ready = |reason| Err(AAKAError::NotInitialized(reason));
+        assert_eq!(
+            require_ready(&*state.inner.read().await).map(|_| ()),
+            not_ready(NotReady::NoParams)
+        );
+
+        // 收到了参数但没有 share（例如已被移出集群的节点）
+        let (params, msk) = rc::gen_parameter_and_msk(&mut thread_rng()).unwrap();
+        state.inner.write().await.params = Some(params.clone());
+        assert_eq!(
+            require_ready(&*state.inner.read().await).map(|_| ()),
+            not_ready(NotReady::NoShare)
+        );
+
+        state.inner.write().await.share = msk.into_shares(1, 1).pop();
+        let state_read = state.inner.read().await;
+        let (_, ready_params) = require_ready(&state_read).unwrap();
+        assert_eq!(ready_params, &params);
+    }
+
+    #[tokio::test]
+    async fn test_pre_setup_calls_return_503_with_retry_after() {
+        let state = RcState::new(config_with("127.0.0.1:0", &["127.0.0.1:0"], 1)).unwrap();
+        let app = router(state, default_max_body_bytes());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let client = reqwest::Client::new();
+        let body = serde_json::json!({ "id": "alice" });
+        for request in [
+            client.get(format!("http://{addr}/params")),
+            client
+                .post(format!("http://{addr}/register/user"))
+                .json(&body),
+            client
+                .post(format!("http://{addr}/register/server"))
+                .json(&body),
+        ] {
+            let resp = request.send().await.unwrap();
+            assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(resp.headers()[header::RETRY_AFTER], "5");
+            let body: serde_json::Value = resp.json().await.unwrap();
+            assert_eq!(body["code"], "ERR_NOT_INITIALIZED");
+            assert!(
+                body["message"]
+                    .as_str()
+                    .unwrap()
+                    .contains("Not initialized")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_response_carries_logged_request_id() {
+        use std::sync::Mutex as StdMutex;
+        use tracing::field::{Field, Visit};
+        use tracing_subscriber::{Layer, layer::Context, prelude::*};
+
+        // 记录每个 `request` span 的 request_id 字段
+        struct RequestIds(Arc<StdMutex<Vec<String>>>);
+        impl Visit for RequestIds {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                if field.name() == "request_id" {
+                    self.0.lock().unwrap().push(format!("{value:?}"));
+                }
+            }
+        }
+        impl<S: tracing::Subscriber> Layer<S> for RequestIds {
+            fn on_new_span(
+                &self,
+                attrs: &tracing::span::Attributes<'_>,
+                _: &tracing::span::Id,
+                _: Context<'_, S>,
+            ) {
+                if attrs.metadata().name() == "request" {
+                    attrs.record(&mut RequestIds(self.0.clone()));
+                }
+            }
+        }
+
+        let ids = Arc::new(StdMutex::new(Vec::new()));
+        // 单线程运行时，服务端任务与测试在同一线程上，线程默认 subscriber 对两者都生效
+        let _guard = tracing_subscriber::registry()
+            .with(RequestIds(ids.clone()))
+            .set_default();
+
+        let state = RcState::new(config_with("127.0.0.1:0", &["127.0.0.1:0"], 1)).unwrap();
+        let app = router(state, default_max_body_bytes());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let resp = reqwest::get(format!("http://{addr}/params")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let header_id = resp.headers()[request_id::REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(body["code"], "ERR_NOT_INITIALIZED");
+        assert_eq!(body["request_id"], header_id.as_str());
+        assert!(uuid::Uuid::parse_str(&header_id).is_ok());
+        assert_eq!(*ids.lock().unwrap(), [header_id]);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_setup_yields_one_parameter_set() {
+        let addrs = spawn_cluster(vec![vec![0, 1, 2]; 3], 2).await;
+        let client = reqwest::Client::new();
+        let setup = |addr: String| {
+            let client = client.clone();
+            async move {
+                client
+                    .get(format!("http://{addr}/setup?expected_version=0"))
+                    .send()
+                    .await
+                    .unwrap()
+                    .error_for_status()
+                    .unwrap()
+                    .json::<serde_json::Value>()
+                    .await
+                    .unwrap()
+            }
+        };
+
+        // 两个节点同时收到首次初始化的 /setup；转发给协调者的请求可能晚于另一个 /setup 完成，
+        // 调用者用 `expected_version=0` 表明只在尚未初始化时生成参数
+        let (first, second) = tokio::join!(setup(addrs[0].clone()), setup(addrs[1].clone()));
+        assert_eq!(first["p_pub_hex"], second["p_pub_hex"]);
+        assert_eq!(first["params_version"], 1);
+        assert_eq!(second["params_version"], 1);
+
+        // 所有节点的 share 来自同一个主密钥：任意节点注册的用户密钥都与参数一致
+        for addr in &addrs {
+            assert_user_key_valid(&client, &addrs[0], addr).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_setup_keeps_callers_expected_version() {
+        use axum::extract::RawQuery;
+        use std::sync::Mutex as StdMutex;
+
+        // 记录收到的查询参数的协调者，地址小于本节点
+        let queries = Arc::new(StdMutex::new(Vec::new()));
+        let (params, _) = rc::gen_parameter_and_msk(&mut thread_rng()).unwrap();
+        let response = serde_json::to_value(params_response(&params, 7, None).unwrap()).unwrap();
+        let coordinator_app = Router::new().route(
+            "/setup",
+            get({
+                let queries = queries.clone();
+                move |RawQuery(query): RawQuery| {
+                    queries.lock().unwrap().push(query);
+                    let response = response.clone();
+                    async move { Json(response) }
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let coordinator = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move { axum::serve(listener, coordinator_app).await.unwrap() });
+
+        let state = RcState::new(config_with(
+            "127.0.0.9:1",
+            &[&coordinator, "127.0.0.9:1"],
+            2,
+        ))
+        .unwrap();
+        for expected_version in [None, Some(3)] {
+            let query = SetupQuery {
+                expected_version,
+                ..Default::default()
+            };
+            let Json(response) = setup_system(State(state.clone()), HeaderMap::new(), Query(query))
+                .await
+                .unwrap();
+            assert_eq!(response.params_version, 7);
+        }
+
+        // 本节点的版本号（0）不会代替调用者填写
+        assert_eq!(
+            *queries.lock().unwrap(),
+            [None, Some("expected_version=3".to_string())]
+        );
+        assert_eq!(state.inner.read().await.params_version, 7);
+    }
+
+    #[tokio::test]
+    async fn test_failed_setup_keeps_previous_state() {
+        // 本节点是协调者，另一个节点没有服务监听，share 达不到门限
+        let dead = {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let state = RcState::new(config_with("127.0.0.1:0", &["127.0.0.1:0", &dead], 2)).unwrap();
+
+        let Err(err) = setup_system(
+            State(state.clone()),
+            HeaderMap::new(),
+            Query(SetupQuery::default()),
+        )
+        .await
+        else {
+            panic!("setup should fail without a quorum");
+        };
+        assert_eq!(err.status, StatusCode::SERVICE_UNAVAILABLE);
+
+        let state_read = state.inner.read().await;
+        assert!(state_read.params.is_none());
+        assert!(state_read.share.is_none());
+        assert_eq!(state_read.params_version, 0);
+        assert_eq!(state.setups.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_setup_leaves_state_untouched() {
+        // 本节点不是协调者，且另一个节点不可达：dry run 既不转发也不分发 share
+        let state = RcState::new(config_with(
+            "127.0.0.1:2",
+            &["127.0.0.1:1", "127.0.0.1:2"],
+            2,
+        ))
+        .unwrap();
+        let query = SetupQuery {
+            dry_run: true,
+            ..Default::default()
+        };
+        let Json(response) = setup_system(State(state.clone()), HeaderMap::new(), Query(query))
+            .await
+            .unwrap();
+
+        let params = params_from_response(&response).unwrap();
+        assert_eq!(params.xof, Xof::default());
+        assert_eq!(response.params_version, 1);
+
+        let state_read = state.inner.read().await;
+        assert!(state_read.params.is_none());
+        assert!(state_read.share.is_none());
+        assert_eq!(state_read.params_version, 0);
+        assert_eq!(state.setups.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_setup_carries_configured_deployment_info() {
+        let mut config = config_with("127.0.0.1:2", &["127.0.0.1:2"], 1);
+        config.deployment_info = Some("x".repeat(256));
+        assert!(RcState::new(config).is_err());
+
+        let mut config = config_with("127.0.0.1:2", &["127.0.0.1:2"], 1);
+        config.deployment_info = Some("staging".to_string());
+        let state = RcState::new(config).unwrap();
+        let Json(response) = setup_system(
+            State(state.clone()),
+            HeaderMap::new(),
+            Query(SetupQuery::default()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.deployment_info, "staging");
+        let params = params_from_response(&response).unwrap();
+        assert_eq!(params.deployment.as_str(), "staging");
+
+        // `/params` 返回同一个标识，MS 与用户据此派生会话密钥
+        let resp = get_params(State(state), HeaderMap::new())
+            .await
+            .unwrap_or_else(|_| panic!("get_params failed"));
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["deployment_info"], "staging");
+    }
+
+    #[tokio::test]
+    async fn test_rotate_master_invalidates_issued_keys() {
+        let state = single_node_state().await;
+        state.inner.write().await.params_version = 1;
+        let old_params = state.inner.read().await.params.clone().unwrap();
+
+        let payload = RegisterRequest::utf8("alice");
+        let Json(registered) = register_user(State(state.clone()), Json(payload))
+            .await
+            .unwrap();
+        let usk = ibc_aaka_scheme::UserSecretKey {
+            r_u: hex_to_ark(&registered.r_u_hex).unwrap(),
+            sid_u: hex_to_ark(&registered.sid_u_hex).unwrap(),
+        };
+        rc::verify_user_key(&old_params, b"alice", &usk).unwrap();
+
+        let Json(response) = rotate_master(State(state.clone()), HeaderMap::new())
+            .await
+            .unwrap();
+        assert_eq!(response.params_version, 2);
+        assert_eq!(response.supersedes, Some(old_params.fingerprint()));
+
+        // /params 公布的是新参数，旧私钥在新参数下校验失败
+        let new_params = params_from_response(&response).unwrap();
+        assert_ne!(new_params.fingerprint(), old_params.fingerprint());
+        assert!(rc::verify_user_key(&new_params, b"alice", &usk).is_err());
+        let state_read = state.inner.read().await;
+        assert_eq!(state_read.params.as_ref(), Some(&new_params));
+        assert_eq!(state_read.superseded, Some(old_params.fingerprint()));
+    }
+
+    #[tokio::test]
+    async fn test_rotation_racing_a_setup_returns_409() {
+        // rotate_master 读到版本 1 后，并发的 /setup 已把版本推进到 2
+        let state = single_node_state().await;
+        state.inner.write().await.params_version = 2;
+        let params = state.inner.read().await.params.clone();
+        let query = SetupQuery {
+            expected_version: Some(1),
+            conflict_on_mismatch: true,
+            ..Default::default()
+        };
+        let Err(err) = setup_system(State(state.clone()), HeaderMap::new(), Query(query)).await
+        else {
+            panic!("a stale rotation should conflict");
+        };
+        assert_eq!(err.status, StatusCode::CONFLICT);
+        assert_eq!(err.code(), "ERR_CONFLICT");
+        assert_eq!(state.inner.read().await.params, params);
+
+        // 转发给协调者的请求同样返回 409
+        let addrs = spawn_cluster(vec![vec![0, 1]; 2], 2).await;
+        let coordinator = addrs.iter().min().unwrap();
+        let other = addrs.iter().max().unwrap();
+        let client = reqwest::Client::new();
+        client
+            .get(format!("http://{coordinator}/setup"))
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+        let resp = client
+            .get(format!(
+                "http://{other}/setup?expected_version=0&conflict_on_mismatch=true"
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let resp: serde_json::Value = client
+            .post(format!("http://{other}/rotate_master"))
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(resp["params_version"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_server_returns_params_and_key() {
+        let state = single_node_state().await;
+        state.inner.write().await.params_version = 1;
+        let params = state.inner.read().await.params.clone().unwrap();
+
+        let bootstrap = |id: &str| {
+            let payload = RegisterRequest::utf8(id);
+            bootstrap_server(State(state.clone()), Json(payload))
+        };
+        let Json(response) = bootstrap("my_server").await.unwrap();
+        assert_eq!(response.params.params_version, 1);
+        assert_eq!(params_from_response(&response.params).unwrap(), params);
+        let ssk = ServerSecretKey {
+            sid_ms: hex_to_ark(&response.sid_ms_hex).unwrap(),
+        };
+        rc::verify_server_key(&params, b"my_server", &ssk).unwrap();
+
+        // 重复调用得到相同的私钥
+        let Json(again) = bootstrap("my_server").await.unwrap();
+        assert_eq!(again.sid_ms_hex, response.sid_ms_hex);
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key_returns_original_registration() {
+        let state = single_node_state().await;
+        let register = |key: Option<&str>, id: &str| {
+            let mut headers = HeaderMap::new();
+            if let Some(key) = key {
+                headers.insert(IDEMPOTENCY_KEY, key.parse().unwrap());
+            }
+            let payload = RegisterRequest::utf8(id);
+            register_user_idempotent(State(state.clone()), headers, Json(payload))
+        };
+
+        // 同一个键重复请求得到相同的私钥
+        let Json(first) = register(Some("retry-1"), "alice").await.unwrap();
+        let Json(retried) = register(Some("retry-1"), "alice").await.unwrap();
+        assert_eq!(retried.r_u_hex, first.r_u_hex);
+        assert_eq!(retried.sid_u_hex, first.sid_u_hex);
+
+        // 不同的键或不带键时重新签发
+        let Json(other) = register(Some("retry-2"), "alice").await.unwrap();
+        assert_ne!(other.r_u_hex, first.r_u_hex);
+        let Json(plain) = register(None, "alice").await.unwrap();
+        assert_ne!(plain.r_u_hex, first.r_u_hex);
+
+        // 同一个键不能用于其他 ID
+        let Err(err) = register(Some("retry-1"), "bob").await else {
+            panic!("an Idempotency-Key must not be reused for another ID");
+        };
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_cache_is_bounded_and_serializes_per_key() {
+        let state = single_node_state().await;
+        state.inner.write().await.config.idempotency_max_entries = 2;
+        let register = |key: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(IDEMPOTENCY_KEY, key.parse().unwrap());
+            register_user_idempotent(
+                State(state.clone()),
+                headers,
+                Json(RegisterRequest::utf8("alice")),
+            )
+        };
+
+        // 同一个键的并发请求只签发一次
+        let (first, second) = tokio::join!(register("key-1"), register("key-1"));
+        assert_eq!(first.unwrap().r_u_hex, second.unwrap().r_u_hex);
+
+        for key in ["key-2", "key-3", "key-4"] {
+            assert!(register(key).await.is_ok());
+        }
+        let cache = state.idempotency.lock().await;
+        assert_eq!(cache.len(), 2);
+        // 最早过期的条目先被淘汰
+        assert!(cache.contains_key("key-3") && cache.contains_key("key-4"));
+    }
+
+    #[tokio::test]
+    async fn test_register_user_returns_valid_check() {
+        let state = single_node_state().await;
+        let params = state.inner.read().await.params.clone().unwrap();
+
+        let payload = RegisterRequest::utf8("alice");
+        let Json(registered) = register_user(State(state), Json(payload)).await.unwrap();
+        let usk = ibc_aaka_scheme::UserSecretKey {
+            r_u: hex_to_ark(&registered.r_u_hex).unwrap(),
+            sid_u: hex_to_ark(&registered.sid_u_hex).unwrap(),
+        };
+        let check = registered.check.unwrap();
+        assert_eq!(check.params_fingerprint, params.fingerprint());
+
+        // 只用响应中的内容完成校验，不需要系统参数
+        let check = rc::UserKeyCheck {
+            h_u: hex_to_ark(&check.h_u_hex).unwrap(),
+            p_pub: hex_to_ark(&check.p_pub_hex).unwrap(),
+        };
+        check.verify(b"alice", &usk).unwrap();
+        assert!(check.verify(b"bob", &usk).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_single_node_mode_makes_no_peer_calls() {
+        use std::sync::atomic::AtomicUsize;
+
+        // 本节点在配置中的地址只统计连接数，HTTP 接口在另一个端口上提供：
+        // 发往任何已配置节点（包括自身）的请求都会被计数
+        let node = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let node_addr = node.local_addr().unwrap().to_string();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        tokio::spawn(async move {
+            while node.accept().await.is_ok() {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        let config = config_with(&node_addr, &[&node_addr], 1);
+        assert!(config.is_single_node());
+        let state = RcState::new(config).unwrap();
+        let app = router(state, default_max_body_bytes());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let client = reqwest::Client::new();
+        let params: SystemParametersResponse = client
+            .get(format!("http://{addr}/setup"))
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let params = params_from_response(&params).unwrap();
+        let body = serde_json::json!({ "id": "alice" });
+        let registered: serde_json::Value = client
+            .post(format!("http://{addr}/register/user"))
+            .json(&body)
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let usk = ibc_aaka_scheme::UserSecretKey {
+            r_u: hex_to_ark(registered["r_u_hex"].as_str().unwrap()).unwrap(),
+            sid_u: hex_to_ark(registered["sid_u_hex"].as_str().unwrap()).unwrap(),
+        };
+        rc::verify_user_key(&params, b"alice", &usk).unwrap();
+
+        assert_eq!(connections.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_registered_reports_issued_ids() {
+        let state = single_node_state().await;
+        state.inner.write().await.config.admin_token = Some("s3cret".to_string());
+        let request = |id: &str| Json(RegisterRequest::utf8(id));
+        assert!(
+            register_user(State(state.clone()), request("alice"))
+                .await
+                .is_ok()
+        );
+        assert!(
+            register_server(State(state.clone()), request("edge-1"))
+                .await
+                .is_ok()
+        );
+
+        let app = router(state.clone(), default_max_body_bytes());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let client = reqwest::Client::new();
+        let query = |id: &str, token: Option<&str>| {
+            let mut request = client.get(format!("http://{addr}/registered/{id}"));
+            if let Some(token) = token {
+                request = request.bearer_auth(token);
+            }
+            request.send()
+        };
+
+        for id in ["alice", "edge-1"] {
+            let resp = query(id, Some("s3cret")).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body: serde_json::Value = resp.json().await.unwrap();
+            assert_eq!(body["id"], id);
+            assert_eq!(body["registered"], true);
+        }
+        let resp = query("bob", Some("s3cret")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(body["code"], "ERR_NOT_FOUND");
+
+        // 没有或错误的令牌
+        assert_eq!(
+            query("alice", None).await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            query("alice", Some("guess")).await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        // 参数轮换后此前签发的私钥失效
+        state.inner.write().await.params_version += 1;
+        assert_eq!(
+            query("alice", Some("s3cret")).await.unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+
+        // 未配置令牌时管理接口关闭
+        state.inner.write().await.config.admin_token = None;
+        assert_eq!(
+            query("alice", Some("s3cret")).await.unwrap().status(),
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn test_debug_state_is_admin_gated_and_sanitized() {
+        let state = single_node_state().await;
+        let live_peer = {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let app = Router::new().route("/ready", get(|| async { StatusCode::OK }));
+            tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+            addr
+        };
+        let dead_peer = "127.0.0.1:1".to_string();
+        let (share, params) = {
+            let mut state_write = state.inner.write().await;
+            state_write.config.admin_token = Some("s3cret".to_string());
+            state_write.config.nodes.push(live_peer.clone());
+            state_write.config.nodes.push(dead_peer.clone());
+            (
+                state_write.share.clone().unwrap(),
+                state_write.params.clone().unwrap(),
+            )
+        };
+        for _ in 0..3 {
+            state.breakers.record_failure(&dead_peer);
+        }
+
+        let app = router(state.clone(), default_max_body_bytes());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let client = reqwest::Client::new();
+        let query = |token: Option<&str>| {
+            let mut request = client.get(format!("http://{addr}/debug/state"));
+            if let Some(token) = token {
+                request = request.bearer_auth(token);
+            }
+            request.send()
+        };
+
+        let resp = query(Some("s3cret")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.text().await.unwrap();
+        let debug: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(debug["build"]["package"], "aaka_rc_app");
+        assert_eq!(debug["threshold"], 1);
+        assert_eq!(debug["params"]["fingerprint"], params.fingerprint());
+        assert_eq!(debug["params"]["version"], 0);
+        assert_eq!(debug["share_loaded"], true);
+        assert_eq!(debug["share_epoch"], format!("{:016x}", share.epoch));
+        assert_eq!(debug["idempotency_cache_entries"], 0);
+        assert_eq!(
+            debug["peers"],
+            serde_json::json!([
+                { "addr": live_peer, "status": "ready", "circuit_open": false },
+                { "addr": dead_peer, "status": "unreachable", "circuit_open": true },
+            ])
+        );
+
+        // share 与由它恢复的主密钥都不出现在响应中
+        let msk = MasterSecretKey::from_shares(vec![share.clone()], 1).unwrap();
+        for secret in [
+            hex::encode(ShareEnvelope::from(&share).body),
+            ark_to_hex(&msk.s).unwrap(),
+            ark_to_hex(&msk.s_hat).unwrap(),
+        ] {
+            assert!(!body.contains(&secret), "{body}");
+        }
+
+        // 没有或错误的令牌；未配置令牌时接口关闭
+        assert_eq!(
+            query(None).await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            query(Some("guess")).await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+        state.inner.write().await.config.admin_token = None;
+        assert_eq!(
+            query(Some("s3cret")).await.unwrap().status(),
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_routes_require_argon2_admin_token() {
+        use argon2::{PasswordHasher, password_hash::SaltString};
+
+        let state = single_node_state().await;
+        let salt = SaltString::encode_b64(b"admin-token-salt").unwrap();
+        let hash = Argon2::default()
+            .hash_password(b"op-s3cret", &salt)
+            .unwrap()
+            .to_string();
+        state.inner.write().await.config.admin_token_hash = Some(hash);
+
+        let app = router(state.clone(), default_max_body_bytes());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let client = reqwest::Client::new();
+        let status = |method: reqwest::Method, path: &str, token: Option<&str>| {
+            let mut request = client.request(method, format!("http://{addr}{path}"));
+            if let Some(token) = token {
+                request = request.bearer_auth(token);
+            }
+            async move { request.send().await.unwrap().status() }
+        };
+
+        let admin_routes = [
+            (reqwest::Method::GET, "/setup?dry_run=true"),
+            (reqwest::Method::GET, "/registered/alice"),
+            (reqwest::Method::GET, "/registrations"),
+            (reqwest::Method::POST, "/rotate_master"),
+        ];
+        for (method, path) in &admin_routes {
+            for token in [None, Some("guess")] {
+                assert_eq!(
+                    status(method.clone(), path, token).await,
+                    StatusCode::UNAUTHORIZED,
+                    "{path} with {token:?}"
+                );
+            }
+        }
+        assert_eq!(
+            state.inner.read().await.params_version,
+            0,
+            "rejected rotation must not run"
+        );
+
+        assert_eq!(
+            status(
+                reqwest::Method::GET,
+                "/setup?dry_run=true",
+                Some("op-s3cret")
+            )
+            .await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(reqwest::Method::GET, "/registrations", Some("op-s3cret")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(reqwest::Method::POST, "/rotate_master", Some("op-s3cret")).await,
+            StatusCode::OK
+        );
+        assert_eq!(state.inner.read().await.params_version, 1);
+
+        // 用户接口不需要管理令牌
+        assert_eq!(
+            status(reqwest::Method::GET, "/params", None).await,
+            StatusCode::OK
+        );
+        let resp = client
+            .post(format!("http://{addr}/register/user"))
+            .json(&serde_json::json!({ "id": "alice" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // 未配置哈希时运维接口保持开放，查询接口关闭
+        state.inner.write().await.config.admin_token_hash = None;
+        assert_eq!(
+            status(reqwest::Method::GET, "/setup?dry_run=true", None).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(reqwest::Method::GET, "/registrations", Some("op-s3cret")).await,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn test_registrations_paginate_without_gaps_or_duplicates() {
+        let state = single_node_state().await;
+        state.inner.write().await.config.admin_token = Some("s3cret".to_string());
+        let hash = |i: u32| hex::encode(Sha3_256::digest(format!("user-{i}")));
+        for i in 0..250 {
+            state.store.save_registration(&hash(i), 0).unwrap();
+        }
+
+        let app = router(state.clone(), default_max_body_bytes());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let client = reqwest::Client::new();
+        let get = |query: String| {
+            client
+                .get(format!("http://{addr}/registrations?{query}"))
+                .bearer_auth("s3cret")
+                .send()
+        };
+
+        let mut seen = Vec::new();
+        let mut cursor: Option<String> = None;
+        let mut added = 250;
+        loop {
+            let query = match &cursor {
+                Some(cursor) => format!("limit=7&cursor={cursor}"),
+                None => "limit=7".to_string(),
+            };
+            let page: RegistrationsPage = get(query).await.unwrap().json().await.unwrap();
+            assert!(page.registrations.len() <= 7);
+            seen.extend(page.registrations.into_iter().map(|entry| entry.id_hash));
+            // 翻页期间继续有新的签发
+            state.store.save_registration(&hash(added), 0).unwrap();
+            added += 1;
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+
+        // 已有的记录恰好出现一次，新增的记录至多出现一次，整体按顺序
+        let unique: std::collections::BTreeSet<_> = seen.iter().cloned().collect();
+        assert_eq!(unique.len(), seen.len());
+        assert!(seen.windows(2).all(|pair| pair[0] < pair[1]));
+        for i in 0..250 {
+            assert!(unique.contains(&hash(i)), "user-{i} missing");
+        }
+
+        // NDJSON 导出全部记录
+        let resp = get("format=ndjson".to_string()).await.unwrap();
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], NDJSON_CONTENT_TYPE);
+        let body = resp.text().await.unwrap();
+        let exported: Vec<RegistrationEntry> = body
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(exported.len(), added as usize);
+        assert!(exported.iter().all(|entry| entry.current));
+
+        // 页大小限制在 1..=MAX_PAGE_SIZE，游标不合法时返回 400
+        let page: RegistrationsPage = get("limit=0".to_string())
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(page.registrations.len(), 1);
+        assert_eq!(
+            get("cursor=!!!".to_string()).await.unwrap().status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handlers_persist_through_store() {
+        let store = Arc::new(MemoryStore::default());
+        let config = config_with("127.0.0.1:0", &["127.0.0.1:0"], 1);
+        let state = RcState::with_store(config, store.clone()).unwrap();
+        assert!(state.inner.read().await.share.is_none());
+
+        // /setup 与 /set_share 都会写入本节点的 share
+        assert!(
+            setup_system(
+                State(state.clone()),
+                HeaderMap::new(),
+                Query(SetupQuery::default())
+            )
+            .await
+            .is_ok()
+        );
+        let stored = store.load_share().unwrap().unwrap();
+        assert_eq!(
+            ShareEnvelope::from(&stored),
+            ShareEnvelope::from(state.inner.read().await.share.as_ref().unwrap())
+        );
+        let (_, msk) = rc::gen_parameter_and_msk(&mut thread_rng()).unwrap();
+        let replacement = msk.into_shares(1, 1).pop().unwrap();
+        set_share(
+            State(state.clone()),
+            Json(ShareEnvelope::from(&replacement)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            ShareEnvelope::from(&store.load_share().unwrap().unwrap()),
+            ShareEnvelope::from(&replacement)
+        );
+
+        // 签发私钥时写入签发记录与审计记录
+        let request = |id: &str| Json(RegisterRequest::utf8(id));
+        assert!(
+            register_user(State(state.clone()), request("alice"))
+                .await
+                .is_ok()
+        );
+        assert!(
+            register_server(State(state.clone()), request("edge-1"))
+                .await
+                .is_ok()
+        );
+        let records = store.audit_records();
+        assert_eq!(
+            records
+                .iter()
+                .map(|r| (r.kind.as_str(), r.id_hash.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("user", hex::encode(Sha3_256::digest(b"alice"))),
+                ("server", hex::encode(Sha3_256::digest(b"edge-1"))),
+            ]
+        );
+        assert_eq!(store.registration(&records[0].id_hash).unwrap(), Some(1));
+        assert!(state.is_registered(b"alice", 1).unwrap());
+
+        // 新的 RcState 从同一存储恢复 share 与参数版本，签发记录仍然有效
+        let restarted = RcState::with_store(
+            config_with("127.0.0.1:0", &["127.0.0.1:0"], 1),
+            store.clone(),
+        )
+        .unwrap();
+        let version = restarted.inner.read().await.params_version;
+        assert_eq!(version, 1);
+        assert!(restarted.inner.read().await.share.is_some());
+        assert!(restarted.is_registered(b"edge-1", version).unwrap());
+
+        // 被移出集群的节点删除 share
+        set_nodes(State(state.clone()), Json(vec!["127.0.0.1:1".to_string()]))
+            .await
+            .unwrap();
+        assert!(store.load_share().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_restart_restores_params_from_file_store() {
+        let dir = std::env::temp_dir().join(format!("rc_restart_{}", std::process::id()));
+        let config = || RcConfig {
+            store_dir: Some(dir.to_string_lossy().into_owned()),
+            ..config_with("127.0.0.1:0", &["127.0.0.1:0"], 1)
+        };
+        let setup = |state: RcState| async move {
+            setup_system(State(state), HeaderMap::new(), Query(SetupQuery::default()))
+                .await
+                .unwrap()
+                .0
+        };
+
+        let state = RcState::new(config()).unwrap();
+        setup(state.clone()).await;
+        let second = setup(state.clone()).await;
+        assert!(
+            register_user(State(state.clone()), Json(RegisterRequest::utf8("alice")))
+                .await
+                .is_ok()
+        );
+        let fingerprint = state
+            .inner
+            .read()
+            .await
+            .params
+            .as_ref()
+            .unwrap()
+            .fingerprint();
+        drop(state);
+
+        // 重启后无需重新 /setup 即可就绪，参数、版本号与被取代的指纹都与重启前一致
+        let restarted = RcState::new(config()).unwrap();
+        assert_eq!(ready(State(restarted.clone())).await, StatusCode::OK);
+        {
+            let state_read = restarted.inner.read().await;
+            assert_eq!(
+                state_read.params.as_ref().unwrap().fingerprint(),
+                fingerprint
+            );
+            assert_eq!(state_read.params_version, 2);
+            assert_eq!(state_read.superseded, second.supersedes);
+        }
+        assert!(restarted.is_registered(b"alice", 2).unwrap());
+        let response = registered(
+            State(restarted.clone()),
+            Path("alice".to_string()),
+            Query(RegisteredQuery {
+                encoding: IdEncoding::default(),
+            }),
+        )
+        .await
+        .unwrap();
+        assert!(response.registered);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_register_pseudonym_issues_per_epoch_keys() {
+        let state = single_node_state().await;
+        let request = |epoch| {
+            Json(PseudonymRequest {
+                id: EncodedId::utf8("alice"),
+                epoch,
+            })
+        };
+        let Json(first) = register_pseudonym(State(state.clone()), request(1))
+            .await
+            .unwrap();
+        let Json(second) = register_pseudonym(State(state.clone()), request(2))
+            .await
+            .unwrap();
+        assert_ne!(first.pseudonym, second.pseudonym);
+        assert!(!first.pseudonym.contains("alice"));
+
+        let params = state.inner.read().await.params.clone().unwrap();
+        for response in [&first, &second] {
+            let usk = UserSecretKey {
+                r_u: CanonicalDeserialize::deserialize_compressed(
+                    &hex::decode(&response.key.r_u_hex).unwrap()[..],
+                )
+                .unwrap(),
+                sid_u: CanonicalDeserialize::deserialize_compressed(
+                    &hex::decode(&response.key.sid_u_hex).unwrap()[..],
+                )
+                .unwrap(),
+            };
+            assert!(rc::verify_user_key(&params, response.pseudonym.as_bytes(), &usk).is_ok());
+            // 签发记录使用假名，真实 ID 不算作已注册
+            assert!(
+                state
+                    .is_registered(response.pseudonym.as_bytes(), 0)
+                    .unwrap()
+            );
+        }
+        assert!(!state.is_registered(b"alice", 0).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_binary_ids_register_over_http() {
+        use ibc_aaka_scheme::ServerSecretKey;
+
+        let state = single_node_state().await;
+        state.inner.write().await.config.admin_token = Some("s3cret".to_string());
+        let app = router(state.clone(), default_max_body_bytes());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let client = reqwest::Client::new();
+
+        // 含 NUL 与非 UTF-8 字节的 ID
+        let user_id = b"dev\x00\xff\xfe-01";
+        let server_id = b"ms\x00\x80";
+        let user_resp: serde_json::Value = client
+            .post(format!("http://{addr}/register/user"))
+            .json(&serde_json::json!({ "id": hex::encode(user_id), "id_encoding": "hex" }))
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let server_resp: serde_json::Value = client
+            .post(format!("http://{addr}/register/server"))
+            .json(&serde_json::json!({
+                "id": BASE64_STANDARD.encode(server_id),
+                "id_encoding": "base64",
+            }))
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let hex_field = |value: &serde_json::Value, field: &str| {
+            hex::decode(value[field].as_str().unwrap()).unwrap()
+        };
+        let usk = UserSecretKey {
+            r_u: CanonicalDeserialize::deserialize_compressed(
+                &hex_field(&user_resp, "r_u_hex")[..],
+            )
+            .unwrap(),
+            sid_u: CanonicalDeserialize::deserialize_compressed(
+                &hex_field(&user_resp, "sid_u_hex")[..],
+            )
+            .unwrap(),
+        };
+        let ssk = ServerSecretKey {
+            sid_ms: CanonicalDeserialize::deserialize_compressed(
+                &hex_field(&server_resp, "sid_ms_hex")[..],
+            )
+            .unwrap(),
+        };
+
+        // 私钥绑定解码后的字节，而不是传输用的字符串
+        let params = state.inner.read().await.params.clone().unwrap();
+        rc::verify_user_key(&params, user_id, &usk).unwrap();
+        rc::verify_server_key(&params, server_id, &ssk).unwrap();
+        assert!(rc::verify_server_key(&params, b"6d730080", &ssk).is_err());
+
+        // 查询接口按 `encoding` 解码路径中的 ID
+        let resp = client
+            .get(format!(
+                "http://{addr}/registered/{}?encoding=hex",
+                hex::encode(user_id)
+            ))
+            .bearer_auth("s3cret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // 编码不合法时返回 400
+        let resp = client
+            .post(format!("http://{addr}/register/user"))
+            .json(&serde_json::json!({ "id": "not hex", "id_encoding": "hex" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_grpc_setup_register_and_handshake() {
+        use ibc_aaka_scheme::{
+            ServerSecretKey, UserSecretKey,
+            proto::{self, rc_client::RcClient},
+            server, user,
+        };
+
+        let state = RcState::new(config_with("127.0.0.1:0", &["127.0.0.1:0"], 1)).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(RcServer::new(RcService { state }))
+                .serve_with_incoming(tonic::transport::server::TcpIncoming::from(listener)),
+        );
+        let mut client = RcClient::connect(url).await.unwrap();
+
+        let status = client.get_params(proto::Empty {}).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+
+        let params = client.setup(proto::Empty {}).await.unwrap().into_inner();
+        let fetched = client
+            .get_params(proto::Empty {})
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(fetched, params);
+        let params = SystemParameters::try_from(params).unwrap();
+
+        let usk = UserSecretKey::try_from(
+            client
+                .register_user(proto::RegisterRequest {
+                    id: "alice".to_string(),
+                })
+                .await
+                .unwrap()
+                .into_inner(),
+        )
+        .unwrap();
+        let ssk = ServerSecretKey::try_from(
+            client
+                .register_server(proto::RegisterRequest {
+                    id: "my_server".to_string(),
+                })
+                .await
+                .unwrap()
+                .into_inner(),
+        )
+        .unwrap();
+
+        // 用 gRPC 签发的密钥完成一次握手
+        let mut rng = thread_rng();
+        let (request, user_state) =
+            user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+        let (response, server_key) =
+            server::process_user_request(&ssk, &request, b"my_server", &params, &mut rng, 32)
+                .unwrap();
+        let user_key =
+            user::process_server_response(&usk, &user_state, &response, b"my_server", &params, 32)
+                .unwrap();
+        assert_eq!(user_key.0, server_key.0);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_body_rejected_with_413() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = router(single_node_state().await, 1024);
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let client = reqwest::Client::new();
+        let resp = client
+            .post(format!("http://{addr}/register/user"))
+            .json(&vec![0u8; 1024])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // 限制以内的请求照常处理
+        let resp = client
+            .post(format!("http://{addr}/register/user"))
+            .json(&serde_json::json!({ "id": "alice" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_cors_preflight_returns_configured_headers() {
+        let cors: CorsConfig = serde_json::from_value(serde_json::json!({
+            "allowed_origins": ["https://app.example.com"],
+        }))
+        .unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = router(single_node_state().await, default_max_body_bytes())
+            .layer(cors.layer().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let client = reqwest::Client::new();
+        let preflight = |origin: &'static str| {
+            client
+                .request(
+                    reqwest::Method::OPTIONS,
+                    format!("http://{addr}/register/user"),
+                )
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+                .send()
+        };
+
+        let resp = preflight("https://app.example.com").await.unwrap();
+        assert!(resp.status().is_success());
+        let headers = resp.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_METHODS], "GET,POST");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "content-type,x-aaka-envelope,x-aaka-point-encoding"
+        );
+
+        // 未列出的来源得不到 CORS 头，浏览器会拦截响应
+        let resp = preflight("https://evil.example.com").await.unwrap();
+        assert!(
+            !resp
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failing_peer_skipped_once_breaker_opens() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+
+        // 始终返回 500 的节点，记录收到的请求数
+        let hits = Arc::new(AtomicUsize::new(0));
+        let peer_app = Router::new().route(
+            "/get_share",
+            get({
+                let hits = hits.clone();
+                move || async move {
+                    hits.fetch_add(1, Ordering::SeqCst);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peers = vec![listener.local_addr().unwrap().to_string()];
+        tokio::spawn(async move { axum::serve(listener, peer_app).await.unwrap() });
+
+        let (_, msk) = rc::gen_parameter_and_msk(&mut thread_rng()).unwrap();
+        let share = msk.into_shares(1, 1).pop().unwrap();
+        let breakers = CircuitBreakers::new(2, Duration::from_secs(2));
+
+        // 每次收集都会重试 3 次
+        for _ in 0..2 {
+            let shares = collect_shares(share.clone(), &peers, &breakers, 1, None)
+                .await
+                .unwrap();
+            assert_eq!(shares.len(), 1);
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 6);
+
+        // 熔断器打开后不再请求该节点
+        for _ in 0..3 {
+            collect_shares(share.clone(), &peers, &breakers, 1, None)
+                .await
+                .unwrap();
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 6);
+
+        // 冷却结束后半开，只放行一次探测，失败后重新打开
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        collect_shares(share.clone(), &peers, &breakers, 1, None)
+            .await
+            .unwrap();
+        collect_shares(share.clone(), &peers, &breakers, 1, None)
+            .await
+            .unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 9);
+    }
+
+    #[tokio::test]
+    async fn test_unparseable_share_counts_as_failure() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+
+        // 返回 200 但响应体不是 share 的节点
+        let hits = Arc::new(AtomicUsize::new(0));
+        let peer_app = Router::new().route(
+            "/get_share",
+            get({
+                let hits = hits.clone();
+                move || async move {
+                    hits.fetch_add(1, Ordering::SeqCst);
+                    "not a share"
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peers = vec![listener.local_addr().unwrap().to_string()];
+        tokio::spawn(async move { axum::serve(listener, peer_app).await.unwrap() });
+
+        let (_, msk) = rc::gen_parameter_and_msk(&mut thread_rng()).unwrap();
+        let share = msk.into_shares(1, 1).pop().unwrap();
+        let breakers = CircuitBreakers::new(2, Duration::from_secs(30));
+
+        for _ in 0..3 {
+            let shares = collect_shares(share.clone(), &peers, &breakers, 1, None)
+                .await
+                .unwrap();
+            assert_eq!(shares.len(), 1);
+        }
+        // 两次解析失败后熔断器打开，第三次不再请求
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        assert!(breakers.is_open(&peers[0]));
+    }
+
+    #[tokio::test]
+    async fn test_stale_local_share_does_not_block_reconstruction() {
+        let (_, msk) = rc::gen_parameter_and_msk(&mut thread_rng()).unwrap();
+        let stale = msk.clone().into_shares(2, 3).pop().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let fresh = msk.clone().into_shares(2, 3);
+
+        // 两个持有新一轮 share 的节点；本节点错过了这次拆分，仍持有旧 epoch 的 share
+        let mut peers = Vec::new();
+        for share in &fresh[..2] {
+            let envelope = ShareEnvelope::from(share);
+            let app = Router::new().route(
+                "/get_share",
+                get(move || async move { Json(envelope.clone()) }),
+            );
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            peers.push(listener.local_addr().unwrap().to_string());
+            tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        }
+
+        let shares = collect_shares(stale, &peers, &CircuitBreakers::default(), 2, None)
+            .await
+            .unwrap();
+        assert_eq!(shares.len(), 2);
+        assert!(shares.iter().all(|share| share.epoch == fresh[0].epoch));
+        assert_eq!(MasterSecretKey::from_shares(shares, 2).unwrap(), msk);
+    }
+
+    #[test]
+    fn test_share_envelope_json_roundtrip_reconstructs() {
+        let (_, msk) = rc::gen_parameter_and_msk(&mut thread_rng()).unwrap();
+        let shares = msk.clone().into_shares(2, 3);
+
+        // 经过 JSON 往返后索引保持不变，任意两份都能恢复出 msk
+        let decoded: Vec<EpochShare> = shares
+            .iter()
+            .map(|share| {
+                let json = serde_json::to_string(&ShareEnvelope::from(share)).unwrap();
+                let envelope: ShareEnvelope = serde_json::from_str(&json).unwrap();
+                assert_eq!(envelope.index, share.share.x.0);
+                assert_eq!(envelope.epoch, share.epoch);
+                EpochShare::try_from(envelope).unwrap()
+            })
+            .collect();
+        let indices: Vec<u8> = decoded.iter().map(|share| share.share.x.0).collect();
+        assert_eq!(indices, [1, 2, 3]);
+        for pair in [[0, 1], [0, 2], [1, 2]] {
+            let subset = pair.iter().map(|&i| decoded[i].clone()).collect();
+            assert_eq!(MasterSecretKey::from_shares(subset, 2).unwrap(), msk);
+        }
+
+        // 索引为 0 或长度不对的 share 被拒绝
+        let mut envelope = ShareEnvelope::from(&shares[0]);
+        envelope.index = 0;
+        assert!(EpochShare::try_from(envelope).is_err());
+        let mut envelope = ShareEnvelope::from(&shares[0]);
+        envelope.body.pop();
+        assert!(EpochShare::try_from(envelope).is_err());
+    }
+
+    #[test]
+    fn test_threshold_larger_than_node_count_rejected() {
+        let config = config_with("127.0.0.1:3001", &["127.0.0.1:3001", "127.0.0.1:3002"], 3);
+        let Err(err) = RcState::new(config) else {
+            panic!("threshold 3 with 2 nodes should be rejected");
+        };
+        assert!(err.to_string().contains("Invalid threshold 3"));
+
+        let config = config_with("127.0.0.1:3001", &["127.0.0.1:3001"], 0);
+        assert!(RcState::new(config).is_err());
+    }
+
+    #[test]
+    fn test_multi_node_config_requires_peer_token() {
+        let mut config = config_with("127.0.0.1:3001", &["127.0.0.1:3001", "127.0.0.1:3002"], 2);
+        config.peer_token = None;
+        let Err(err) = RcState::new(config) else {
+            panic!("a cluster without a peer token should be rejected");
+        };
+        assert!(err.to_string().contains("peer_token is required"));
+    }
+
+    #[tokio::test]
+    async fn test_peer_routes_require_peer_token() {
+        let state = single_node_state().await;
+        let app = router(state.clone(), default_max_body_bytes());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let client = reqwest::Client::new();
+        let params: serde_json::Value = client
+            .get(format!("http://{addr}/params"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let nodes = serde_json::json!(state.inner.read().await.config.nodes);
+        let routes = [("/set_params", &params), ("/set_nodes", &nodes)];
+        let status = |path: &str, body: &serde_json::Value, token: Option<&str>| {
+            let mut request = client.post(format!("http://{addr}{path}")).json(body);
+            if let Some(token) = token {
+                request = request.bearer_auth(token);
+            }
+            async move { request.send().await.unwrap().status() }
+        };
+
+        // 未配置 peer_token 时节点之间的接口关闭
+        for (path, body) in routes {
+            assert_eq!(
+                status(path, body, Some("guess")).await,
+                StatusCode::FORBIDDEN,
+                "{path}"
+            );
+        }
+
+        state.inner.write().await.config.peer_token = Some("peer-secret".to_string());
+        for (path, body) in routes {
+            for token in [None, Some("guess")] {
+                assert_eq!(
+                    status(path, body, token).await,
+                    StatusCode::UNAUTHORIZED,
+                    "{path} with {token:?}"
+                );
+            }
+            assert_eq!(
+                status(path, body, Some("peer-secret")).await,
+                StatusCode::OK,
+                "{path}"
+            );
+        }
+        assert!(state.inner.read().await.share.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_share_routes_require_peer_token() {
+        let state = single_node_state().await;
+        let app = router(state.clone(), default_max_body_bytes());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let client = reqwest::Client::new();
+        let get_share = |token: Option<&str>| {
+            let mut request = client.get(format!("http://{addr}/get_share"));
+            if let Some(token) = token {
+                request = request.bearer_auth(token);
+            }
+            async move { request.send().await.unwrap().status() }
+        };
+
+        // 未配置 peer_token 时任何人都拿不到 share
+        assert_eq!(get_share(None).await, StatusCode::FORBIDDEN);
+
+        state.inner.write().await.config.peer_token = Some("peer-secret".to_string());
+        assert_eq!(get_share(None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(get_share(Some("guess")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(get_share(Some("peer-secret")).await, StatusCode::OK);
+
+        // 没有令牌时也不能写入伪造的 share
+        let share: ShareEnvelope = client
+            .get(format!("http://{addr}/get_share"))
+            .bearer_auth("peer-secret")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let status = client
+            .post(format!("http://{addr}/set_share"))
+            .json(&share)
+            .send()
+            .await
+            .unwrap()
+            .status();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_params_broadcast_tolerates_unreachable_peers() {
+        // 一个正常接收参数的节点，一个没有服务监听的地址
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let healthy = listener.local_addr().unwrap().to_string();
+        let app = Router::new().route("/set_params", post(|| async { StatusCode::OK }));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let dead = {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let peers = vec![dead, healthy];
+        let params = serde_json::json!({});
+
+        // 不可达的节点不再中断推送，确认数达到门限即成功
+        assert_eq!(broadcast_params(&params, &peers, 2, None).await.unwrap(), 1);
+        let err = broadcast_params(&params, &peers, 3, None)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("insufficient params acknowledgements: got 2, need 3")
+        );
+    }
+
+    #[test]
+    fn test_self_address_not_in_nodes_rejected() {
+        let config = config_with("127.0.0.1:3001", &["127.0.0.1:3002", "127.0.0.1:3003"], 2);
+        let Err(err) = RcState::new(config) else {
+            panic!("a node missing from its own node list should be rejected");
+        };
+        assert!(err.to_string().contains("is not listed in nodes"));
+    }
+
+    #[tokio::test]
+    async fn test_membership_add_node_participates_in_reconstruction() {
+        // 节点 0、1 组成初始集群，节点 2 尚未加入（它的配置中已列出目标集群）
+        let addrs = spawn_cluster(vec![vec![0, 1], vec![0, 1], vec![0, 1, 2]], 2).await;
+        let client = reqwest::Client::new();
+
+        client
+            .get(format!("http://{}/setup", addrs[0]))
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+
+        let resp: serde_json::Value = client
+            .post(format!("http://{}/membership", addrs[0]))
+            .json(&serde_json::json!({ "add": [addrs[2]] }))
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(resp["nodes"].as_array().unwrap().len(), 3);
+
+        // 新节点可以收集 share 并完成注册
+        assert_user_key_valid(&client, &addrs[0], &addrs[2]).await;
+
+        // 移除节点 1 后，剩余的节点 0、2 仍可恢复同一个 msk
+        client
+            .post(format!("http://{}/membership", addrs[2]))
+            .json(&serde_json::json!({ "remove": [addrs[1]] }))
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+        assert_user_key_valid(&client, &addrs[0], &addrs[0]).await;
+
+        // 被移除的节点不再持有 share
+        let status = client
+            .get(format!("http://{}/get_share", addrs[1]))
+            .bearer_auth("peer-secret")
+            .send()
+            .await
+            .unwrap()
+            .status();
+        assert!(!status.is_success());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_registrations_on_two_nodes_succeed() {
+        let addrs = spawn_cluster(vec![vec![0, 1], vec![0, 1]], 2).await;
+        let client = reqwest::Client::new();
+        client
+            .get(format!("http://{}/setup", addrs[0]))
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+
+        // 两个节点同时注册时都要探测并读取对方的 share，不能互相等待对方的锁
+        let register = |addr: &str, id: &str| {
+            client
+                .post(format!("http://{addr}/register/user"))
+                .json(&serde_json::json!({ "id": id }))
+                .send()
+        };
+        for _ in 0..5 {
+            let (a, b) = tokio::join!(register(&addrs[0], "alice"), register(&addrs[1], "bob"));
+            assert_eq!(a.unwrap().status(), StatusCode::OK);
+            assert_eq!(b.unwrap().status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_membership_change_keeps_current_share() {
+        let addrs = spawn_cluster(vec![vec![0, 1], vec![0, 1]], 2).await;
+        let client = reqwest::Client::new();
+        client
+            .get(format!("http://{}/setup", addrs[0]))
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+        let share = |addr: String| {
+            let request = client
+                .get(format!("http://{addr}/get_share"))
+                .bearer_auth("peer-secret");
+            async move {
+                let envelope: ShareEnvelope = request.send().await.unwrap().json().await.unwrap();
+                (envelope.epoch, envelope.index)
+            }
+        };
+        let before = share(addrs[0].clone()).await;
+
+        // 替换节点 1 为一个没有服务监听的地址：新的 share 达不到门限
+        let dead = {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let resp = client
+            .post(format!("http://{}/membership", addrs[0]))
+            .json(&serde_json::json!({ "add": [dead], "remove": [addrs[1]] }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // 节点 0 仍持有旧的 share，旧的节点集合仍可完成注册
+        assert_eq!(share(addrs[0].clone()).await, before);
+        assert_user_key_valid(&client, &addrs[0], &addrs[0]).await;
+    }
+
+    #[tokio::test]
+    async fn test_membership_change_does_not_block_readers() {
+        use std::time::Duration;
+
+        // 接受连接但从不响应的新节点：分发 share 要等到请求超时
+        let (hung_peer, _listener) = {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            (listener.local_addr().unwrap().to_string(), listener)
+        };
+        let state = single_node_state().await;
+        let change = MembershipChange {
+            add: vec![hung_peer],
+            remove: Vec::new(),
+        };
+        let membership = tokio::spawn(change_membership(State(state.clone()), Json(change)));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!membership.is_finished());
+
+        // 网络阶段不持有状态锁，/ready 与注册照常进行
+        let read = tokio::time::timeout(Duration::from_millis(100), state.inner.read()).await;
+        assert!(read.is_ok());
+        drop(read);
+        assert_eq!(ready(State(state.clone())).await, StatusCode::OK);
+        membership.abort();
+    }
+
+    #[tokio::test]
+    async fn test_share_distribution_succeeds_once_quorum_acknowledges() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::{Duration, Instant};
+
+        // 4 个响应较慢但正常接收 share 的节点，2 个始终返回 500 的节点
+        let received = Arc::new(AtomicUsize::new(0));
+        let mut peers = Vec::new();
+        for healthy in [true, false, true, true, false, true] {
+            let received = received.clone();
+            let app = Router::new().route(
+                "/set_share",
+                post(move || async move {
+                    if !healthy {
+                        return StatusCode::INTERNAL_SERVER_ERROR;
+                    }
+                    tokio::time::sleep(Duration::from_millis(400)).await;
+                    received.fetch_add(1, Ordering::SeqCst);
+                    StatusCode::OK
+                }),
+            );
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            peers.push(listener.local_addr().unwrap().to_string());
+            tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        }
+
+        let (_, msk) = rc::gen_parameter_and_msk(&mut thread_rng()).unwrap();
+        let mut shares = msk.into_shares(4, 7);
+        shares.pop(); // 本节点的 share
+
+        // 并发发送：逐个发送时仅正常节点就需要 1.6 秒
+        let start = Instant::now();
+        let acknowledged = distribute_shares(&shares, &peers, &Arc::default(), 4, None)
+            .await
+            .unwrap();
+        assert_eq!(acknowledged, 4);
+        assert_eq!(received.load(Ordering::SeqCst), 4);
+        assert!(
+            start.elapsed() < Duration::from_millis(1200),
+            "{:?}",
+            start.elapsed()
+        );
+
+        // 确认的节点加上本节点不足门限时失败，并指出失败的节点
+        let err = distribute_shares(&shares, &peers, &Arc::default(), 6, None)
+            .await
+            .unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("got 5, need 6"), "{msg}");
+        assert!(msg.contains(&peers[1]) && msg.contains(&peers[4]), "{msg}");
+    }
+
+    #[tokio::test]
+    async fn test_missed_share_is_repaired_in_background() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+
+        // 分发时（含重试）一直返回 503，之后恢复的节点
+        let hits = Arc::new(AtomicUsize::new(0));
+        let received = Arc::new(Mutex::new(None));
+        let app = Router::new().route(
+            "/set_share",
+            post({
+                let (hits, received) = (hits.clone(), received.clone());
+                move |Json(envelope): Json<ShareEnvelope>| async move {
+                    if hits.fetch_add(1, Ordering::SeqCst) < 3 {
+                        return StatusCode::SERVICE_UNAVAILABLE;
+                    }
+                    *received.lock().await = Some(envelope.epoch);
+                    StatusCode::OK
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peers = vec![listener.local_addr().unwrap().to_string()];
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let (_, msk) = rc::gen_parameter_and_msk(&mut thread_rng()).unwrap();
+        let mut shares = msk.into_shares(1, 2);
+        let epoch = shares.pop().unwrap().epoch;
+
+        // 本节点自己就达到门限，分发成功，但节点没有确认
+        let acknowledged = distribute_shares(&shares, &peers, &Arc::default(), 1, None)
+            .await
+            .unwrap();
+        assert_eq!(acknowledged, 0);
+        assert_eq!(*received.lock().await, None);
+
+        // 节点恢复后在后台补上这一轮的 share
+        for _ in 0..50 {
+            if received.lock().await.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(*received.lock().await, Some(epoch));
+    }
+
+    #[tokio::test]
+    async fn test_older_share_rejected_with_409() {
+        let (_, msk) = rc::gen_parameter_and_msk(&mut thread_rng()).unwrap();
+        let older = msk.into_shares(1, 1).pop().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        // 之后拆分的 share 更新，例如补发的 share 晚于下一轮分发到达
+        let state = single_node_state().await;
+        let current = state.inner.read().await.share.clone().unwrap();
+        assert!(older.epoch < current.epoch);
+        let err = set_share(State(state.clone()), Json(ShareEnvelope::from(&older)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::CONFLICT);
+        assert_eq!(
+            state.inner.read().await.share.as_ref().unwrap().epoch,
+            current.epoch
+        );
+    }
+
+    #[tokio::test]
+    async fn test_registration_with_peers_down_reports_unreachable_quorum() {
+        // 绑定后立即释放，得到一个没有服务监听的地址
+        let dead_peer = {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let state = single_node_state().await;
+        {
+            let mut state_write = state.inner.write().await;
+            state_write.config.nodes.push(dead_peer);
+            state_write.config.threshold = 2;
+        }
+
+        let payload = RegisterRequest::utf8("alice");
+        let Err(err) = register_user(State(state), Json(payload)).await else {
+            panic!("registration should fail without enough shares");
+        };
+
+        // 节点宕机在收集 share 之前的 /ready 探测中就会发现
+        assert_eq!(err.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            err.error.downcast_ref::<AAKAError>(),
+            Some(&AAKAError::NotInitialized(NotReady::PeersUnreachable {
+                ready: 0,
+                needed: 1
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_registration_fails_fast_when_quorum_unreachable() {
+        use std::time::{Duration, Instant};
+
+        // 接受连接但从不响应的节点：完整的 share 收集会一直等待
+        let (hung_peer, _listener) = {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            (listener.local_addr().unwrap().to_string(), listener)
+        };
+        let state = single_node_state().await;
+        {
+            let mut state_write = state.inner.write().await;
+            state_write.config.nodes.push(hung_peer);
+            state_write.config.threshold = 2;
+        }
+
+        let payload = RegisterRequest::utf8("server");
+        let started = Instant::now();
+        let Err(err) = register_server(State(state), Json(payload)).await else {
+            panic!("registration should fail without a reachable quorum");
+        };
+
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(err.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            err.error.downcast_ref::<AAKAError>(),
+            Some(&AAKAError::NotInitialized(NotReady::PeersUnreachable {
+                ready: 0,
+                needed: 1
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_peer_requests_time_out_on_hung_peers() {
+        use std::time::Duration;
+
+        // 接受连接但从不响应的节点
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/get_share", listener.local_addr().unwrap());
+
+        let started = Instant::now();
+        let err = peer_client(Duration::from_millis(200), None)
+            .get(url)
+            .send()
+            .await
+            .unwrap_err();
+        assert!(err.is_timeout());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_quorum_probe_skips_open_breakers() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+
+        let hits = Arc::new(AtomicUsize::new(0));
+        let peer_app = Router::new().route(
+            "/ready",
+            get({
+                let hits = hits.clone();
+                move || async move {
+                    hits.fetch_add(1, Ordering::SeqCst);
+                    StatusCode::OK
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peers = vec![listener.local_addr().unwrap().to_string()];
+        tokio::spawn(async move { axum::serve(listener, peer_app).await.unwrap() });
+
+        let breakers = CircuitBreakers::new(1, Duration::from_secs(30));
+        assert_eq!(probe_quorum(&peers, 1, &breakers).await, 1);
+
+        // 熔断中的节点即使可达也拿不到 share，不探测也不计入
+        breakers.record_failure(&peers[0]);
+        assert_eq!(probe_quorum(&peers, 1, &breakers).await, 0);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[cfg(feature = "otlp")]
+    #[tokio::test]
+    async fn test_registration_exports_otlp_span() {
+        use opentelemetry::Value;
+        use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber =
+            tracing_subscriber::registry().with(aaka_rc_app::telemetry::otlp_layer(&provider));
+        let default_guard = tracing::subscriber::set_default(subscriber);
+
+        let state = single_node_state().await;
+        let payload = RegisterRequest::utf8("alice");
+        let _ = register_user(State(state), Json(payload)).await.unwrap();
+        drop(default_guard);
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let span = spans
+            .iter()
+            .find(|span| span.name == "register")
+            .expect("register span should be exported");
+        let kind = span
+            .attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == "kind")
+            .map(|kv| kv.value.clone());
+        assert_eq!(kind, Some(Value::from("user")));
+    }
+
+    #[tokio::test]
+    async fn test_registration_writes_audit_entry() {
+        let path = std::env::temp_dir().join(format!("rc_audit_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let (writer, guard) = audit::open(&path).unwrap();
+        let subscriber = tracing_subscriber::registry().with(audit::layer(writer));
+        let default_guard = tracing::subscriber::set_default(subscriber);
+
+        let state = single_node_state().await;
+        let payload = RegisterRequest::utf8("alice");
+        let _ = register_user(State(state), Json(payload)).await.unwrap();
+
+        // 释放 guard 以确保后台线程写完所有记录
+        drop(default_guard);
+        drop(guard);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 1, "expected exactly one audit entry");
+
+        let entry: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        let fields = &entry["fields"];
+        assert_eq!(entry["target"], audit::AUDIT_TARGET);
+        assert_eq!(fields["event"], "registration");
+        assert_eq!(fields["kind"], "user");
+        assert_eq!(fields["node"], "127.0.0.1:0");
+        assert!(fields["timestamp"].as_u64().unwrap() > 0);
+        // 只记录 ID 的摘要，不记录明文
+        assert_eq!(fields["id_hash"].as_str().unwrap().len(), 64);
+        assert!(!content.contains("alice"));
     }
 }
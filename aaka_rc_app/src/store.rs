@@ -0,0 +1,453 @@
+//! RC 需要持久化的数据：本节点的 share 与系统参数、签发记录（registry）与审计记录。
+//!
+//! 处理请求的代码只通过 [`RcStore`] 访问这些数据，部署方式不同时替换实现即可
+//! （例如容器中改用数据库）。自带两种实现：
+//! - [`FileStore`]：保存在一个目录下的文件中，配置 `store_dir` 时使用；
+//! - [`MemoryStore`]：只保存在内存中，进程退出即丢失，未配置 `store_dir` 时使用。
+use crate::audit::AuditRecord;
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use chacha20poly1305::{
+    AeadCore, ChaCha20Poly1305, Key, KeyInit, Nonce,
+    aead::{Aead, OsRng, Payload},
+};
+use eyre::{Result, WrapErr, eyre};
+use ibc_aaka_scheme::{EpochShare, ShareEnvelope, SystemParameters};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// RC 的持久化存储。方法都是同步的，实现应当只做短小的读写。
+pub trait RcStore: Send + Sync {
+    /// 读取本节点保存的 share
+    fn load_share(&self) -> Result<Option<EpochShare>>;
+    /// 保存本节点的 share，`None` 表示删除（例如本节点被移出集群）
+    fn save_share(&self, share: Option<&EpochShare>) -> Result<()>;
+    /// 读取本节点保存的系统参数
+    fn load_params(&self) -> Result<Option<StoredParams>>;
+    /// 保存本节点的系统参数，与 share 一起在重启后恢复
+    fn save_params(&self, params: &StoredParams) -> Result<()>;
+    /// 记录某个 ID（SHA3-256 哈希）在 `params_version` 版本的参数下签发了私钥
+    fn save_registration(&self, id_hash: &str, params_version: u64) -> Result<()>;
+    /// 最近一次为该 ID 签发私钥时的参数版本
+    fn registration(&self, id_hash: &str) -> Result<Option<u64>>;
+    /// 按 ID 哈希升序返回排在 `after` 之后的至多 `limit` 条签发记录，用于分页遍历。
+    /// 以哈希而不是位置作为游标，遍历期间新增的记录不会导致已返回的记录重复或被跳过
+    fn registrations(&self, after: Option<&str>, limit: usize) -> Result<Vec<(String, u64)>>;
+    /// 追加一条审计记录
+    fn append_audit(&self, record: &AuditRecord) -> Result<()>;
+}
+
+/// 本节点当前的系统参数及其版本
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredParams {
+    pub params: SystemParameters,
+    /// 每次 `/setup` 加一，签发记录按这个版本判断是否仍然有效
+    pub version: u64,
+    /// 被这组参数取代的旧参数指纹
+    pub superseded: Option<String>,
+}
+
+/// 只保存在内存中的存储
+#[derive(Default)]
+pub struct MemoryStore {
+    share: Mutex<Option<EpochShare>>,
+    params: Mutex<Option<StoredParams>>,
+    registry: Mutex<BTreeMap<String, u64>>,
+    audit: Mutex<Vec<AuditRecord>>,
+}
+
+impl MemoryStore {
+    /// 已追加的审计记录
+    pub fn audit_records(&self) -> Vec<AuditRecord> {
+        self.audit.lock().unwrap().clone()
+    }
+}
+
+impl RcStore for MemoryStore {
+    fn load_share(&self) -> Result<Option<EpochShare>> {
+        Ok(self.share.lock().unwrap().clone())
+    }
+
+    fn save_share(&self, share: Option<&EpochShare>) -> Result<()> {
+        *self.share.lock().unwrap() = share.cloned();
+        Ok(())
+    }
+
+    fn load_params(&self) -> Result<Option<StoredParams>> {
+        Ok(self.params.lock().unwrap().clone())
+    }
+
+    fn save_params(&self, params: &StoredParams) -> Result<()> {
+        *self.params.lock().unwrap() = Some(params.clone());
+        Ok(())
+    }
+
+    fn save_registration(&self, id_hash: &str, params_version: u64) -> Result<()> {
+        self.registry
+            .lock()
+            .unwrap()
+            .insert(id_hash.to_string(), params_version);
+        Ok(())
+    }
+
+    fn registration(&self, id_hash: &str) -> Result<Option<u64>> {
+        Ok(self.registry.lock().unwrap().get(id_hash).copied())
+    }
+
+    fn registrations(&self, after: Option<&str>, limit: usize) -> Result<Vec<(String, u64)>> {
+        Ok(page(&self.registry.lock().unwrap(), after, limit))
+    }
+
+    fn append_audit(&self, record: &AuditRecord) -> Result<()> {
+        self.audit.lock().unwrap().push(record.clone());
+        Ok(())
+    }
+}
+
+fn page(registry: &BTreeMap<String, u64>, after: Option<&str>, limit: usize) -> Vec<(String, u64)> {
+    let start = match after {
+        Some(after) => Bound::Excluded(after),
+        None => Bound::Unbounded,
+    };
+    registry
+        .range::<str, _>((start, Bound::Unbounded))
+        .take(limit)
+        .map(|(id_hash, version)| (id_hash.clone(), *version))
+        .collect()
+}
+
+const SHARE_FILE: &str = "share.json";
+const SEALED_SHARE_FILE: &str = "share.sealed";
+const PARAMS_FILE: &str = "params.json";
+const REGISTRY_FILE: &str = "registry.json";
+const AUDIT_FILE: &str = "audit.jsonl";
+
+const SEALED_SHARE_MAGIC: &[u8] = b"AAKA-SHARE1";
+const NONCE_LEN: usize = 12;
+
+/// 加密 share 文件的节点密钥（32 字节），不能与 share 保存在同一个目录中。
+///
+/// 加密后的文件为 `MAGIC || nonce (12 字节) || ChaCha20-Poly1305 密文`，MAGIC 作为关联数据，
+/// 明文与未加密时的 `share.json` 相同。
+pub struct ShareKey(Key);
+
+impl ShareKey {
+    /// 解析 64 个十六进制字符表示的密钥，忽略首尾空白
+    pub fn from_hex(hex_key: &str) -> Result<Self> {
+        let bytes = hex::decode(hex_key.trim()).wrap_err("Share key is not valid hex")?;
+        if bytes.len() != 32 {
+            return Err(eyre!("Share key must be 32 bytes, got {}", bytes.len()));
+        }
+        Ok(Self(*Key::from_slice(&bytes)))
+    }
+
+    fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: plaintext,
+            aad: SEALED_SHARE_MAGIC,
+        };
+        let ciphertext = ChaCha20Poly1305::new(&self.0)
+            .encrypt(&nonce, payload)
+            .map_err(|e| eyre!("Failed to seal share: {e}"))?;
+        Ok([SEALED_SHARE_MAGIC, nonce.as_slice(), &ciphertext].concat())
+    }
+
+    fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        let body = sealed
+            .strip_prefix(SEALED_SHARE_MAGIC)
+            .filter(|body| body.len() > NONCE_LEN)
+            .ok_or_else(|| eyre!("Corrupted sealed share file"))?;
+        let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: SEALED_SHARE_MAGIC,
+        };
+        ChaCha20Poly1305::new(&self.0)
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| eyre!("Failed to decrypt share file, wrong share key or tampered file"))
+    }
+}
+
+/// 保存在目录 `dir` 下的存储：
+/// - `share.json`：本节点的 share（明文，文件权限为 0600）；配置了 [`ShareKey`] 时改为
+///   加密的 `share.sealed`，打开时已有的 `share.json` 会被加密后删除；
+/// - `params.json`：系统参数（压缩编码的十六进制）、版本号与被取代的参数指纹；
+/// - `registry.json`：ID 哈希到参数版本的映射，每次签发时整体重写；
+/// - `audit.jsonl`：审计记录，每行一条 JSON，写入后 `sync_data`。
+pub struct FileStore {
+    dir: PathBuf,
+    share_key: Option<ShareKey>,
+    // registry.json 的内存副本，同时串行化对它的重写
+    registry: Mutex<BTreeMap<String, u64>>,
+    audit: Mutex<File>,
+}
+
+impl FileStore {
+    /// 打开（必要时创建）目录 `dir`，读取已有的签发记录
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_share_key(dir, None)
+    }
+
+    /// 与 [`FileStore::open`] 相同，`share_key` 不为 `None` 时加密保存 share
+    pub fn open_with_share_key(dir: impl AsRef<Path>, share_key: Option<ShareKey>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).wrap_err_with(|| format!("Failed to create store {dir:?}"))?;
+        let registry = match fs::read(dir.join(REGISTRY_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes).wrap_err("Corrupted registry file")?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e).wrap_err("Failed to read registry file"),
+        };
+        let audit = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(AUDIT_FILE))
+            .wrap_err("Failed to open audit file")?;
+        let store = Self {
+            dir,
+            share_key,
+            registry: Mutex::new(registry),
+            audit: Mutex::new(audit),
+        };
+        store.migrate_plaintext_share()?;
+        Ok(store)
+    }
+
+    // 启用加密前保存的明文 share 改为加密保存
+    fn migrate_plaintext_share(&self) -> Result<()> {
+        if self.share_key.is_none() {
+            return Ok(());
+        }
+        let Some(bytes) = read_optional(&self.dir.join(SHARE_FILE))? else {
+            return Ok(());
+        };
+        let share = parse_share(&bytes)?;
+        self.save_share(Some(&share))?;
+        remove_optional(&self.dir.join(SHARE_FILE))
+    }
+
+    // 先写临时文件再改名，进程中途退出时不会留下写了一半的文件
+    fn write_atomically(&self, name: &str, bytes: &[u8]) -> Result<()> {
+        let path = self.dir.join(name);
+        let tmp = self.dir.join(format!("{name}.tmp"));
+        let mut options = OpenOptions::new();
+        options.create(true).write(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options
+            .open(&tmp)
+            .wrap_err_with(|| format!("Failed to write {tmp:?}"))?;
+        file.write_all(bytes)?;
+        file.sync_data()?;
+        fs::rename(&tmp, &path).wrap_err_with(|| format!("Failed to replace {path:?}"))?;
+        Ok(())
+    }
+}
+
+fn read_optional(path: &Path) -> Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).wrap_err_with(|| format!("Failed to read {path:?}")),
+    }
+}
+
+fn remove_optional(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).wrap_err_with(|| format!("Failed to remove {path:?}"))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn parse_share(bytes: &[u8]) -> Result<EpochShare> {
+    let envelope: ShareEnvelope = serde_json::from_slice(bytes).wrap_err("Corrupted share file")?;
+    Ok(EpochShare::try_from(envelope)?)
+}
+
+// params.json 的内容
+#[derive(Serialize, Deserialize)]
+struct ParamsFile {
+    params_hex: String,
+    version: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    superseded: Option<String>,
+}
+
+impl ParamsFile {
+    fn new(stored: &StoredParams) -> Result<Self> {
+        let mut bytes = Vec::new();
+        stored.params.serialize_compressed(&mut bytes)?;
+        Ok(Self {
+            params_hex: hex::encode(bytes),
+            version: stored.version,
+            superseded: stored.superseded.clone(),
+        })
+    }
+
+    fn parse(bytes: &[u8]) -> Result<StoredParams> {
+        let file: Self = serde_json::from_slice(bytes).wrap_err("Corrupted params file")?;
+        let bytes = hex::decode(&file.params_hex).wrap_err("Corrupted params file")?;
+        let params = SystemParameters::deserialize_compressed(bytes.as_slice())
+            .wrap_err("Corrupted params file")?;
+        Ok(StoredParams {
+            params,
+            version: file.version,
+            superseded: file.superseded,
+        })
+    }
+}
+
+impl RcStore for FileStore {
+    fn load_share(&self) -> Result<Option<EpochShare>> {
+        let sealed = read_optional(&self.dir.join(SEALED_SHARE_FILE))?;
+        match (&self.share_key, sealed) {
+            (Some(key), Some(sealed)) => Ok(Some(parse_share(&key.open(&sealed)?)?)),
+            (None, Some(_)) => Err(eyre!(
+                "The share in {:?} is encrypted, configure share_key to load it",
+                self.dir
+            )),
+            (_, None) => read_optional(&self.dir.join(SHARE_FILE))?
+                .map(|bytes| parse_share(&bytes))
+                .transpose(),
+        }
+    }
+
+    fn save_share(&self, share: Option<&EpochShare>) -> Result<()> {
+        match share {
+            Some(share) => {
+                let plaintext = serde_json::to_vec(&ShareEnvelope::from(share))?;
+                match &self.share_key {
+                    Some(key) => self.write_atomically(SEALED_SHARE_FILE, &key.seal(&plaintext)?),
+                    None => self.write_atomically(SHARE_FILE, &plaintext),
+                }
+            }
+            None => {
+                remove_optional(&self.dir.join(SHARE_FILE))?;
+                remove_optional(&self.dir.join(SEALED_SHARE_FILE))
+            }
+        }
+    }
+
+    fn load_params(&self) -> Result<Option<StoredParams>> {
+        read_optional(&self.dir.join(PARAMS_FILE))?
+            .map(|bytes| ParamsFile::parse(&bytes))
+            .transpose()
+    }
+
+    fn save_params(&self, params: &StoredParams) -> Result<()> {
+        self.write_atomically(PARAMS_FILE, &serde_json::to_vec(&ParamsFile::new(params)?)?)
+    }
+
+    fn save_registration(&self, id_hash: &str, params_version: u64) -> Result<()> {
+        let mut registry = self.registry.lock().unwrap();
+        registry.insert(id_hash.to_string(), params_version);
+        self.write_atomically(REGISTRY_FILE, &serde_json::to_vec(&*registry)?)
+    }
+
+    fn registration(&self, id_hash: &str) -> Result<Option<u64>> {
+        Ok(self.registry.lock().unwrap().get(id_hash).copied())
+    }
+
+    fn registrations(&self, after: Option<&str>, limit: usize) -> Result<Vec<(String, u64)>> {
+        Ok(page(&self.registry.lock().unwrap(), after, limit))
+    }
+
+    fn append_audit(&self, record: &AuditRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut file = self.audit.lock().unwrap();
+        file.write_all(&line)?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ibc_aaka_scheme::rc;
+    use rand::thread_rng;
+
+    #[test]
+    fn test_file_store_survives_reopen() {
+        let dir = std::env::temp_dir().join(format!("rc_store_{}", std::process::id()));
+        let (params, msk) = rc::gen_parameter_and_msk(&mut thread_rng()).unwrap();
+        let share = msk.into_shares(1, 1).pop().unwrap();
+        let record = AuditRecord::registration("user", b"alice", "127.0.0.1:3000");
+        let stored = StoredParams {
+            params,
+            version: 3,
+            superseded: Some("old".to_string()),
+        };
+
+        let store = FileStore::open(&dir).unwrap();
+        assert!(store.load_share().unwrap().is_none());
+        assert!(store.load_params().unwrap().is_none());
+        store.save_share(Some(&share)).unwrap();
+        store.save_params(&stored).unwrap();
+        store.save_registration(&record.id_hash, 3).unwrap();
+        store.append_audit(&record).unwrap();
+        drop(store);
+
+        let store = FileStore::open(&dir).unwrap();
+        let loaded = store.load_share().unwrap().unwrap();
+        assert_eq!(ShareEnvelope::from(&loaded), ShareEnvelope::from(&share));
+        assert_eq!(store.load_params().unwrap(), Some(stored));
+        assert_eq!(store.registration(&record.id_hash).unwrap(), Some(3));
+        assert_eq!(store.registration("unknown").unwrap(), None);
+        let audit = fs::read_to_string(dir.join(AUDIT_FILE)).unwrap();
+        let logged: AuditRecord = serde_json::from_str(audit.lines().next().unwrap()).unwrap();
+        assert_eq!(logged, record);
+
+        store.save_share(None).unwrap();
+        assert!(store.load_share().unwrap().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_share_encrypted_at_rest() {
+        let dir = std::env::temp_dir().join(format!("rc_sealed_store_{}", std::process::id()));
+        let (_, msk) = rc::gen_parameter_and_msk(&mut thread_rng()).unwrap();
+        let share = msk.into_shares(1, 1).pop().unwrap();
+        let plaintext = serde_json::to_vec(&ShareEnvelope::from(&share)).unwrap();
+        let body = ShareEnvelope::from(&share).body;
+        let key = || ShareKey::from_hex(&"11".repeat(32)).unwrap();
+
+        // 启用加密前保存的明文 share 在打开时被加密，明文文件随即删除
+        FileStore::open(&dir)
+            .unwrap()
+            .save_share(Some(&share))
+            .unwrap();
+        let store = FileStore::open_with_share_key(&dir, Some(key())).unwrap();
+        assert!(!dir.join(SHARE_FILE).exists());
+        let sealed = fs::read(dir.join(SEALED_SHARE_FILE)).unwrap();
+        assert_ne!(sealed, plaintext);
+        assert!(!sealed.windows(body.len()).any(|window| window == body));
+        drop(store);
+
+        // 正确的密钥恢复出原来的 share；错误的密钥或缺少密钥时拒绝加载
+        let store = FileStore::open_with_share_key(&dir, Some(key())).unwrap();
+        let loaded = store.load_share().unwrap().unwrap();
+        assert_eq!(ShareEnvelope::from(&loaded), ShareEnvelope::from(&share));
+        let wrong = ShareKey::from_hex(&"22".repeat(32)).unwrap();
+        let err = FileStore::open_with_share_key(&dir, Some(wrong))
+            .unwrap()
+            .load_share()
+            .unwrap_err();
+        assert!(err.to_string().contains("wrong share key"), "{err}");
+        assert!(FileStore::open(&dir).unwrap().load_share().is_err());
+        assert!(ShareKey::from_hex("abcd").is_err());
+
+        store.save_share(None).unwrap();
+        assert!(!dir.join(SEALED_SHARE_FILE).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
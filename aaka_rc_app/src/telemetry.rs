@@ -1,50 +1,235 @@
-use tracing_error::ErrorLayer;
-use tracing_subscriber::{
-    EnvFilter,
-    fmt::{self, MakeWriter},
-    prelude::*,
+use crate::audit;
+use eyre::Result;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use tracing_appender::{
+    non_blocking::{NonBlocking, WorkerGuard},
+    rolling::{RollingFileAppender, Rotation},
 };
+use tracing_error::ErrorLayer;
+use tracing_subscriber::{EnvFilter, fmt, prelude::*};
+#[cfg(feature = "otlp")]
+use tracing_subscriber::{Layer, registry::LookupSpan};
 
-/// 初始化一个 subscriber，它将日志以 JSON 格式异步写入到指定的 `sink`。
-///
-/// # Arguments
-/// * `sink` - 一个实现了 `MakeWriter` 的目标，例如 `std::io::stdout` 或 `non_blocking_writer`。
-pub fn init_subscriber<W>(sink: W)
+/// 日志文件的滚动周期
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+impl From<LogRotation> for Rotation {
+    fn from(rotation: LogRotation) -> Self {
+        match rotation {
+            LogRotation::Minutely => Rotation::MINUTELY,
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        }
+    }
+}
+
+/// 日志输出配置。未设置 `dir` 时写入 stdout。
+#[derive(Debug, Clone, Deserialize)]
+pub struct LogConfig {
+    /// 日志目录，文件名为 `{file_prefix}.{日期}`
+    #[serde(default)]
+    pub dir: Option<PathBuf>,
+    #[serde(default = "default_file_prefix")]
+    pub file_prefix: String,
+    #[serde(default)]
+    pub rotation: LogRotation,
+    /// 最多保留的日志文件数，超出后删除最旧的文件；未设置时不删除
+    #[serde(default)]
+    pub max_files: Option<usize>,
+}
+
+fn default_file_prefix() -> String {
+    "aaka_rc.log".to_string()
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            file_prefix: default_file_prefix(),
+            rotation: LogRotation::default(),
+            max_files: None,
+        }
+    }
+}
+
+/// 按照配置在 `dir` 中创建一个滚动日志文件 writer。
+pub fn rolling_appender(config: &LogConfig, dir: &Path) -> Result<RollingFileAppender> {
+    let mut builder = RollingFileAppender::builder()
+        .rotation(config.rotation.into())
+        .filename_prefix(&config.file_prefix);
+    if let Some(max_files) = config.max_files {
+        builder = builder.max_log_files(max_files);
+    }
+    Ok(builder.build(dir)?)
+}
+
+/// OTLP 导出时上报的服务名
+#[cfg(feature = "otlp")]
+const SERVICE_NAME: &str = "aaka_rc_app";
+
+/// 使用给定的 `TracerProvider` 创建一个将 span 导出到 OpenTelemetry 的 layer。
+#[cfg(feature = "otlp")]
+pub fn otlp_layer<S>(
+    provider: &opentelemetry_sdk::trace::SdkTracerProvider,
+) -> impl Layer<S> + use<S>
 where
-    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
 {
-    // `EnvFilter` 从 `RUST_LOG` 环境变量读取日志级别配置。
-    let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("INFO"));
+    use opentelemetry::trace::TracerProvider as _;
 
-    #[cfg(feature = "debug")]
-    {
-        color_eyre::install().expect("Failed to install color_eyre");
+    tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME))
+}
 
-        let formatting_layer = fmt::layer().with_ansi(true).pretty().with_writer(sink);
+/// 启用 `otlp` feature 时，将 span 通过 OTLP/HTTP 导出到采集器。
+/// 采集器地址由标准环境变量 `OTEL_EXPORTER_OTLP_ENDPOINT` 配置（默认 `http://localhost:4318`）。
+///
+/// 返回的 provider 需要在退出时关闭（见 [`TelemetryGuard`]），否则批量导出器中尚未发送的 span 会丢失。
+#[cfg(feature = "otlp")]
+fn otlp_export_layer<S>() -> Result<(impl Layer<S>, opentelemetry_sdk::trace::SdkTracerProvider)>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    use opentelemetry_otlp::SpanExporter;
+    use opentelemetry_sdk::{Resource, trace::SdkTracerProvider};
 
-        let subscriber = tracing_subscriber::registry()
-            .with(env_filter)
-            .with(formatting_layer) // 便于机器分析
-            .with(ErrorLayer::default()); // 便于追踪错误信息
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .build()
+        .map_err(|e| eyre::eyre!("Failed to build OTLP span exporter: {e}"))?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+        .build();
+    let layer = otlp_layer(&provider);
+    opentelemetry::global::set_tracer_provider(provider.clone());
 
-        // 设置全局 subscriber
-        tracing::subscriber::set_global_default(subscriber)
-            .expect("Failed to set global tracing subscriber");
+    Ok((layer, provider))
+}
 
-        return;
+/// [`init_subscriber`] 返回的 guard，必须在程序运行期间保持存活。
+///
+/// drop 时先关闭 OTLP 导出（启用 `otlp` feature 时），把缓冲中的 span 推送给采集器，再写完缓冲中的日志。
+pub struct TelemetryGuard {
+    _log: WorkerGuard,
+    #[cfg(feature = "otlp")]
+    tracer_provider: opentelemetry_sdk::trace::SdkTracerProvider,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if let Err(e) = self.tracer_provider.shutdown() {
+            eprintln!("Failed to shut down the OTLP exporter: {e}");
+        }
     }
+}
 
-    // 创建 JSON 格式化层
+/// 初始化一个 subscriber，它将日志以 JSON 格式异步写入 stdout 或滚动日志文件。
+///
+/// 返回的 [`TelemetryGuard`] 必须在程序运行期间保持存活，drop 时会写完缓冲中的日志。
+///
+/// # Arguments
+/// * `log` - 日志输出配置，见 [`LogConfig`]。
+/// * `audit_sink` - 审计日志的 writer（见 [`audit::open`]），为 `None` 时不记录审计日志。
+pub fn init_subscriber(log: &LogConfig, audit_sink: Option<NonBlocking>) -> Result<TelemetryGuard> {
+    let (sink, guard) = match &log.dir {
+        Some(dir) => tracing_appender::non_blocking(rolling_appender(log, dir)?),
+        None => tracing_appender::non_blocking(std::io::stdout()),
+    };
+
+    // `EnvFilter` 从 `RUST_LOG` 环境变量读取日志级别配置。
+    // 审计事件始终开启，不受 `RUST_LOG` 影响。
+    let env_filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new("INFO"))
+        .add_directive(
+            format!("{}=info", audit::AUDIT_TARGET)
+                .parse()
+                .expect("valid audit directive"),
+        );
+
+    // 开启 `debug` feature 时输出便于阅读的彩色日志，否则输出 JSON 便于机器分析
+    #[cfg(feature = "debug")]
+    let formatting_layer = {
+        color_eyre::install().expect("Failed to install color_eyre");
+        fmt::layer().with_ansi(true).pretty().with_writer(sink)
+    };
+    #[cfg(not(feature = "debug"))]
     let formatting_layer = fmt::layer()
         .json() // <-- 1. 结构化 (JSON)
         .with_writer(sink); // <-- 2. 异步写入 (通过传入的 sink)
 
+    // 分布式追踪（`otlp` feature）
+    #[cfg(feature = "otlp")]
+    let (otlp_layer, tracer_provider) = otlp_export_layer()?;
+    #[cfg(not(feature = "otlp"))]
+    let otlp_layer = tracing_subscriber::layer::Identity::new();
+
     let subscriber = tracing_subscriber::registry()
         .with(env_filter)
-        .with(formatting_layer) // 便于机器分析
+        .with(formatting_layer)
+        .with(audit_sink.map(audit::layer)) // 审计日志
+        .with(otlp_layer)
         .with(ErrorLayer::default()); // 便于追踪错误信息
 
     // 设置全局 subscriber
     tracing::subscriber::set_global_default(subscriber)
         .expect("Failed to set global tracing subscriber");
+
+    Ok(TelemetryGuard {
+        _log: guard,
+        #[cfg(feature = "otlp")]
+        tracer_provider,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_rolling_appender_creates_rotated_files() {
+        let dir = std::env::temp_dir().join(format!("rc_logs_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let config = LogConfig {
+            dir: Some(dir.clone()),
+            file_prefix: "rc.log".to_string(),
+            rotation: LogRotation::Minutely,
+            max_files: Some(2),
+        };
+        let mut appender = rolling_appender(&config, &dir).unwrap();
+        writeln!(appender, "hello").unwrap();
+        appender.flush().unwrap();
+
+        let files: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // 按分钟滚动的文件名形如 rc.log.2024-01-01-12-30
+        assert_eq!(files.len(), 1);
+        let suffix = files[0].strip_prefix("rc.log.").unwrap();
+        assert_eq!(suffix.len(), "YYYY-MM-DD-HH-MM".len());
+    }
+
+    #[test]
+    fn test_log_config_defaults() {
+        let config: LogConfig = serde_json::from_str(r#"{ "dir": "logs" }"#).unwrap();
+        assert_eq!(config.rotation, LogRotation::Daily);
+        assert_eq!(config.file_prefix, "aaka_rc.log");
+        assert_eq!(config.max_files, None);
+    }
 }
@@ -1,61 +1,529 @@
-use blahaj::Share;
-use tracing::{instrument, warn};
+use futures_util::future::join_all;
+use ibc_aaka_scheme::{AAKAError, EpochShare, ShareEnvelope, backoff::Backoff};
+use reqwest::{
+    StatusCode,
+    header::{AUTHORIZATION, HeaderMap},
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::Semaphore;
+use tracing::{debug, info, warn};
 
+// 对其他节点的每次请求最多尝试的次数，以及重试退避的起点与上限
+const PEER_ATTEMPTS: u32 = 3;
+const PEER_BACKOFF_BASE: Duration = Duration::from_millis(100);
+const PEER_BACKOFF_CAP: Duration = Duration::from_secs(1);
+// 连接其他节点的超时，以及单次请求（含读取响应）的超时。接受连接却不响应的节点
+// 不会让调用者无限等待
+const PEER_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const PEER_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// 注册前探测节点 /ready 的超时，不重试
+const READY_PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+// 分发 share 时同时进行的请求数上限
+const MAX_PARALLEL_PEERS: usize = 16;
+// 分发成功后向未确认的节点补发 share 的轮数，以及两轮之间退避的起点与上限。
+// 退避的总时长超过熔断器的冷却时间，熔断中的节点恢复后仍能补上
+const REPAIR_ROUNDS: u32 = 8;
+const REPAIR_BACKOFF_BASE: Duration = Duration::from_secs(1);
+const REPAIR_BACKOFF_CAP: Duration = Duration::from_secs(30);
+
+/// 每个节点一个熔断器，避免反复请求已经宕机的节点。
+///
+/// 连续失败 `failure_threshold` 次后熔断器打开，`cooldown` 内对该节点的请求直接跳过；
+/// 冷却结束后进入半开状态，只放行一次探测请求：成功则关闭，失败则重新打开。
+pub struct CircuitBreakers {
+    failure_threshold: u32,
+    cooldown: Duration,
+    peers: Mutex<HashMap<String, Breaker>>,
+}
+
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+    probing: bool,
+}
+
+impl Default for CircuitBreakers {
+    fn default() -> Self {
+        Self::new(3, Duration::from_secs(30))
+    }
+}
+
+impl CircuitBreakers {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cooldown,
+            peers: Mutex::default(),
+        }
+    }
+
+    /// 是否允许向 `peer` 发起请求。半开状态下只有第一次调用返回 `true`。
+    pub fn allow(&self, peer: &str) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+        let Some(breaker) = peers.get_mut(peer) else {
+            return true;
+        };
+        match breaker.open_until {
+            None => true,
+            Some(until) if Instant::now() < until || breaker.probing => false,
+            Some(_) => {
+                breaker.probing = true;
+                true
+            }
+        }
+    }
+
+    /// `peer` 的熔断器是否处于打开状态（包括冷却结束、等待探测的半开状态），不影响熔断器本身
+    pub fn is_open(&self, peer: &str) -> bool {
+        self.peers
+            .lock()
+            .unwrap()
+            .get(peer)
+            .is_some_and(|breaker| breaker.open_until.is_some())
+    }
+
+    /// [`allow`](Self::allow) 是否会跳过 `peer`：冷却中，或半开状态下已有探测请求在进行。不影响熔断器本身
+    pub fn would_skip(&self, peer: &str) -> bool {
+        self.peers.lock().unwrap().get(peer).is_some_and(|breaker| {
+            breaker
+                .open_until
+                .is_some_and(|until| Instant::now() < until || breaker.probing)
+        })
+    }
+
+    pub fn record_success(&self, peer: &str) {
+        self.peers.lock().unwrap().remove(peer);
+    }
+
+    pub fn record_failure(&self, peer: &str) {
+        let mut peers = self.peers.lock().unwrap();
+        let breaker = peers.entry(peer.to_string()).or_default();
+        breaker.failures += 1;
+        if breaker.probing || breaker.failures >= self.failure_threshold {
+            // 只在第一次打开时记录，半开探测失败不再重复告警
+            if breaker.open_until.is_none() {
+                warn!(
+                    "Peer {peer} keeps failing, skipping it for {:?}",
+                    self.cooldown
+                );
+            }
+            breaker.open_until = Some(Instant::now() + self.cooldown);
+            breaker.probing = false;
+        }
+    }
+}
+
+/// 请求其他节点用的客户端，整个请求最长 `timeout`。
+/// 给出 `peer_token` 时每个请求都带上 `Authorization: Bearer <peer_token>`
+pub fn peer_client(timeout: Duration, peer_token: Option<&str>) -> reqwest::Client {
+    let mut headers = HeaderMap::new();
+    if let Some(value) = peer_token.and_then(|token| format!("Bearer {token}").parse().ok()) {
+        headers.insert(AUTHORIZATION, value);
+    }
+    reqwest::Client::builder()
+        .connect_timeout(PEER_CONNECT_TIMEOUT)
+        .timeout(timeout)
+        .default_headers(headers)
+        .build()
+        .unwrap_or_default()
+}
+
+/// 发送对其他节点的请求，连接失败或返回错误状态时按带抖动的指数退避重试
+async fn send_to_peer(
+    request: impl Fn() -> reqwest::RequestBuilder,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let mut backoff = Backoff::new(PEER_BACKOFF_BASE, PEER_BACKOFF_CAP);
+    let mut attempt = 1;
+    loop {
+        match request()
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+        {
+            Err(e) if attempt < PEER_ATTEMPTS => {
+                let delay = backoff.next_delay(&mut rand::thread_rng());
+                debug!("Peer request failed, retrying in {delay:?}: {e}");
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// 把 `shares[i]` 发给 `other_nodes[i]` 的 `/set_share`，同时最多 `MAX_PARALLEL_PEERS` 个请求，
+/// 大集群的耗时不再随节点数线性增长。
+///
+/// 单个节点失败（包括熔断中被跳过）只记录警告；确认收到的节点数加上调用者自己的 share
+/// 达到 `threshold` 即成功，返回确认收到的节点数，否则返回列出各节点失败原因的错误。
+/// 成功时在后台向未确认的节点补发它们的 share（见 [`repair_shares`]），
+/// 错过这次分发的节点不会一直持有无法再参与插值的旧 share。
 pub async fn distribute_shares(
-    shares: &[Share],
+    shares: &[EpochShare],
     other_nodes: &[String],
-) -> Result<(), reqwest::Error> {
+    breakers: &Arc<CircuitBreakers>,
+    threshold: usize,
+    peer_token: Option<&str>,
+) -> Result<usize, AAKAError> {
     debug_assert_eq!(
         shares.len(),
         other_nodes.len(),
         "Number of shares must match number of nodes",
     );
 
-    let client = reqwest::Client::new();
-    for (peer_addr, share) in other_nodes.iter().zip(shares.iter()) {
+    let client = &peer_client(PEER_REQUEST_TIMEOUT, peer_token);
+    let missed = &Mutex::new(Vec::new());
+    let acknowledged = fan_out("share", other_nodes, threshold, |i, peer_addr| async move {
+        let result = send_share(client, peer_addr, &shares[i], breakers).await;
+        if result.is_err() {
+            missed.lock().unwrap().push(i);
+        }
+        result
+    })
+    .await?;
+
+    let missed: Vec<(String, EpochShare)> = std::mem::take(&mut *missed.lock().unwrap())
+        .into_iter()
+        .map(|i| (other_nodes[i].clone(), shares[i].clone()))
+        .collect();
+    if !missed.is_empty() {
+        tokio::spawn(repair_shares(client.clone(), missed, breakers.clone()));
+    }
+    Ok(acknowledged)
+}
+
+/// 在后台按退避向 `missed` 中的节点补发 share，直到全部成功、节点以 409 拒绝（它已经持有
+/// 更新一轮的 share）或用完 `REPAIR_ROUNDS` 轮
+async fn repair_shares(
+    client: reqwest::Client,
+    mut missed: Vec<(String, EpochShare)>,
+    breakers: Arc<CircuitBreakers>,
+) {
+    let mut backoff = Backoff::new(REPAIR_BACKOFF_BASE, REPAIR_BACKOFF_CAP);
+    for _ in 0..REPAIR_ROUNDS {
+        let delay = backoff.next_delay(&mut rand::thread_rng());
+        tokio::time::sleep(delay).await;
+
+        let mut pending = Vec::new();
+        for (peer_addr, share) in missed {
+            if !breakers.allow(&peer_addr) {
+                pending.push((peer_addr, share));
+                continue;
+            }
+            let result = client
+                .post(format!("http://{peer_addr}/set_share"))
+                .json(&ShareEnvelope::from(&share))
+                .send()
+                .await
+                .and_then(|res| res.error_for_status());
+            match result {
+                Ok(_) => {
+                    breakers.record_success(&peer_addr);
+                    info!("Repaired the share of peer {peer_addr}");
+                }
+                Err(e) if e.status() == Some(StatusCode::CONFLICT) => {
+                    debug!("Peer {peer_addr} already holds a newer share, not repairing it");
+                }
+                Err(e) => {
+                    breakers.record_failure(&peer_addr);
+                    debug!("Failed to repair the share of peer {peer_addr}: {e}");
+                    pending.push((peer_addr, share));
+                }
+            }
+        }
+        if pending.is_empty() {
+            return;
+        }
+        missed = pending;
+    }
+    let peers: Vec<&str> = missed
+        .iter()
+        .map(|(peer_addr, _)| peer_addr.as_str())
+        .collect();
+    warn!("Giving up repairing the shares of peers {peers:?}");
+}
+
+/// 并发地对 `other_nodes` 中的每个节点执行 `send(i, peer_addr)`，同时最多 `MAX_PARALLEL_PEERS` 个。
+/// 失败只记录警告，确认的节点数加上调用者自己未达到 `threshold` 时返回错误
+async fn fan_out<'a, Fut>(
+    what: &str,
+    other_nodes: &'a [String],
+    threshold: usize,
+    send: impl Fn(usize, &'a str) -> Fut,
+) -> Result<usize, AAKAError>
+where
+    Fut: Future<Output = Result<(), String>>,
+{
+    let permits = Semaphore::new(MAX_PARALLEL_PEERS);
+    let results = join_all(other_nodes.iter().enumerate().map(|(i, peer_addr)| {
+        let (permits, send) = (&permits, &send);
+        async move {
+            let _permit = permits.acquire().await;
+            (peer_addr, send(i, peer_addr).await)
+        }
+    }))
+    .await;
+
+    let mut acknowledged = 0;
+    let mut failures = Vec::new();
+    for (peer_addr, result) in results {
+        match result {
+            Ok(()) => acknowledged += 1,
+            Err(e) => {
+                warn!("Failed to send {} to peer {}: {}", what, peer_addr, e);
+                failures.push(format!("{peer_addr}: {e}"));
+            }
+        }
+    }
+    if acknowledged + 1 < threshold {
+        return Err(AAKAError::Other(format!(
+            "insufficient {} acknowledgements: got {}, need {} ({})",
+            what,
+            acknowledged + 1,
+            threshold,
+            failures.join("; ")
+        )));
+    }
+    Ok(acknowledged)
+}
+
+async fn send_share(
+    client: &reqwest::Client,
+    peer_addr: &str,
+    share: &EpochShare,
+    breakers: &CircuitBreakers,
+) -> Result<(), String> {
+    if !breakers.allow(peer_addr) {
+        return Err("circuit open".to_string());
+    }
+    let request = || {
         client
             .post(format!("http://{peer_addr}/set_share"))
-            .json(&Vec::from(share))
-            .send()
-            .await?
-            .error_for_status()
-            .is_err()
-            .then(|| {
-                warn!("Failed to send share to peer {}", peer_addr);
-            });
+            .json(&ShareEnvelope::from(share))
+    };
+    match send_to_peer(request).await {
+        Ok(_) => {
+            breakers.record_success(peer_addr);
+            Ok(())
+        }
+        Err(e) => {
+            breakers.record_failure(peer_addr);
+            Err(e.to_string())
+        }
     }
-
-    Ok(())
 }
 
+/// 向 `other_nodes` 逐个收集 share，连同本节点的 `self_share` 按 epoch 分组，返回达到
+/// `threshold` 的最新一组。
+///
+/// 错过了最近一次拆分的节点（包括本节点自己）仍持有旧 epoch 的 share，这些 share 不参与插值。
+/// 没有任何一组达到门限时返回份数最多的一组，由调用者报告 share 不足。
 pub async fn collect_shares(
-    self_share: Share,
+    self_share: EpochShare,
     other_nodes: &[String],
-) -> Result<Vec<Share>, reqwest::Error> {
+    breakers: &CircuitBreakers,
+    threshold: usize,
+    peer_token: Option<&str>,
+) -> Result<Vec<EpochShare>, reqwest::Error> {
     let mut shares = vec![self_share];
 
-    let client = reqwest::Client::new();
+    let client = peer_client(PEER_REQUEST_TIMEOUT, peer_token);
     for peer_addr in other_nodes {
-        let Ok(res) = client
-            .get(format!("http://{peer_addr}/get_share"))
-            .send()
-            .await?
-            .error_for_status()
-        else {
-            warn!("Failed to get share from peer {}", peer_addr);
+        // 熔断中的节点直接跳过
+        if !breakers.allow(peer_addr) {
             continue;
+        }
+        // 不可达的节点只记录警告，是否满足门限由调用者判断
+        let res = match send_to_peer(|| client.get(format!("http://{peer_addr}/get_share"))).await {
+            Ok(res) => res,
+            Err(e) => {
+                breakers.record_failure(peer_addr);
+                warn!("Failed to get share from peer {}: {}", peer_addr, e);
+                continue;
+            }
+        };
+        // 返回了无法解析的响应体同样算作失败，避免熔断器把行为异常的节点当作健康节点
+        let share = match res.json::<ShareEnvelope>().await {
+            Ok(envelope) => EpochShare::try_from(envelope).map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
         };
+        let share = match share {
+            Ok(share) => share,
+            Err(e) => {
+                breakers.record_failure(peer_addr);
+                warn!("Failed to parse share from peer {}: {}", peer_addr, e);
+                continue;
+            }
+        };
+        breakers.record_success(peer_addr);
 
-        let share_bytes: Vec<u8> = res.json().await?;
-        if let Ok(share) = Share::try_from(share_bytes.as_slice()) {
-            shares.push(share);
+        let index = share.share.x.0;
+        if shares
+            .iter()
+            .any(|s| s.epoch == share.epoch && s.share.x.0 == index)
+        {
+            // 两个节点返回相同索引的 share 时插值结果是错误的，只保留先收到的
+            warn!(
+                "Peer {} returned a duplicate share index {}",
+                peer_addr, index
+            );
         } else {
+            shares.push(share);
+        }
+    }
+
+    Ok(select_epoch(shares, threshold))
+}
+
+/// 按 epoch 分组，返回达到 `threshold` 的 epoch 中最新的一组；都未达到时返回份数最多的一组
+fn select_epoch(shares: Vec<EpochShare>, threshold: usize) -> Vec<EpochShare> {
+    let mut by_epoch: BTreeMap<u64, Vec<EpochShare>> = BTreeMap::new();
+    for share in shares {
+        by_epoch.entry(share.epoch).or_default().push(share);
+    }
+    let selected = by_epoch
+        .iter()
+        .rev()
+        .find(|(_, group)| group.len() >= threshold)
+        .or_else(|| by_epoch.iter().rev().max_by_key(|(_, group)| group.len()))
+        .map(|(&epoch, _)| epoch);
+    let Some(selected) = selected else {
+        return Vec::new();
+    };
+    for (epoch, group) in &by_epoch {
+        if *epoch != selected {
+            // 尚未收到新一轮 share 的节点仍持有旧 epoch 的 share，不能参与插值
             warn!(
-                "Failed to parse share from peer {}: {:?}",
-                peer_addr, share_bytes
+                "Ignoring {} share(s) from epoch {:016x}, using epoch {:016x}",
+                group.len(),
+                epoch,
+                selected
             );
         }
     }
+    by_epoch.remove(&selected).unwrap_or_default()
+}
+
+/// 并发探测 `other_nodes` 的 `/ready`，确认至少 `needed` 个节点持有 share 并且可达。
+///
+/// 只发一次请求、不重试，已确认足够或剩余节点全部可达也不够时立即返回。熔断器会跳过的节点
+/// 随后也拿不到 share，不探测也不计入。返回确认可达的节点数，可能小于实际可达的数量。
+pub async fn probe_quorum(
+    other_nodes: &[String],
+    needed: usize,
+    breakers: &CircuitBreakers,
+) -> usize {
+    if needed == 0 {
+        return 0;
+    }
+    let client = peer_client(READY_PROBE_TIMEOUT, None);
+    let mut probes = tokio::task::JoinSet::new();
+    for peer_addr in other_nodes {
+        if breakers.would_skip(peer_addr) {
+            debug!("Peer {peer_addr} is behind an open circuit breaker, not probing it");
+            continue;
+        }
+        let request = client.get(format!("http://{peer_addr}/ready"));
+        let peer_addr = peer_addr.clone();
+        probes.spawn(async move {
+            let ready = request
+                .send()
+                .await
+                .is_ok_and(|res| res.status().is_success());
+            if !ready {
+                debug!("Peer {peer_addr} is not ready");
+            }
+            ready
+        });
+    }
+
+    let (mut ready, mut pending) = (0, probes.len());
+    while let Some(result) = probes.join_next().await {
+        pending -= 1;
+        ready += usize::from(result.unwrap_or(false));
+        if ready >= needed || ready + pending < needed {
+            break;
+        }
+    }
+    ready
+}
+
+/// 一次 `/ready` 探测的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerStatus {
+    Ready,
+    /// 可以连接，但尚未持有 share 或系统参数
+    NotReady,
+    Unreachable,
+}
+
+/// 并发探测 `other_nodes` 的 `/ready`，按相同的顺序返回结果。只发一次请求、不重试
+pub async fn probe_peers(other_nodes: &[String]) -> Vec<PeerStatus> {
+    let client = peer_client(READY_PROBE_TIMEOUT, None);
+    join_all(other_nodes.iter().map(|peer_addr| {
+        let request = client.get(format!("http://{peer_addr}/ready"));
+        async move {
+            match request.send().await {
+                Ok(res) if res.status().is_success() => PeerStatus::Ready,
+                Ok(_) => PeerStatus::NotReady,
+                Err(_) => PeerStatus::Unreachable,
+            }
+        }
+    }))
+    .await
+}
+
+/// 将系统参数推送给 `targets` 中的每个节点，使它们都能提供 `/params` 并处理注册。
+///
+/// 与 [`distribute_shares`] 相同：单个节点失败只记录警告，确认收到的节点数加上调用者自己
+/// 达到 `threshold` 即成功，返回确认收到的节点数。
+pub async fn broadcast_params(
+    params: &(impl Serialize + Sync),
+    targets: &[String],
+    threshold: usize,
+    peer_token: Option<&str>,
+) -> Result<usize, AAKAError> {
+    let client = &peer_client(PEER_REQUEST_TIMEOUT, peer_token);
+    fan_out("params", targets, threshold, |_, peer_addr| async move {
+        send_to_peer(|| {
+            client
+                .post(format!("http://{peer_addr}/set_params"))
+                .json(params)
+        })
+        .await
+        .map(drop)
+        .map_err(|e| e.to_string())
+    })
+    .await
+}
 
-    Ok(shares)
+/// 将新的节点列表推送给 `targets` 中的每个节点，返回确认收到的节点数。
+///
+/// 单个节点失败只记录警告：被移除的节点可能已经下线，不能让它中断其余节点的更新。
+pub async fn broadcast_nodes(
+    nodes: &[String],
+    targets: &[String],
+    peer_token: Option<&str>,
+) -> usize {
+    let client = &peer_client(PEER_REQUEST_TIMEOUT, peer_token);
+    // 门限为 0，不会返回错误
+    fan_out("node list", targets, 0, |_, peer_addr| async move {
+        send_to_peer(|| {
+            client
+                .post(format!("http://{peer_addr}/set_nodes"))
+                .json(nodes)
+        })
+        .await
+        .map(drop)
+        .map_err(|e| e.to_string())
+    })
+    .await
+    .unwrap_or_default()
 }
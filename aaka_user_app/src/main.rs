@@ -1,23 +1,32 @@
 use anyhow::{Context, Result, anyhow};
 use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
 use ark_std::rand::{SeedableRng, rngs::StdRng};
-use axum::{
-    Json, Router,
-    extract::State,
-    response::IntoResponse,
-    routing::{get, post},
-};
-use clap::Parser;
-use dotenvy::dotenv;
+use axum::{Json, Router, extract::State, response::IntoResponse, routing::post};
+use base64::prelude::*;
+use clap::{Parser, Subcommand, ValueEnum};
 use ibc_aaka_scheme::{
-    ServerAuthResponse, SessionKey, SystemParameters, UserSecretKey, decrypt, encrypt, user,
+    AAKAError, CheckedPoint, ServerAuthResponse, SessionKey, SystemParameters, Transcript,
+    UserSecretKey,
+    backoff::Backoff,
+    bundle::{Credential, ProvisioningBundle},
+    clock::ClockCheckConfig,
+    config,
+    confirm::{ConfirmDirection, build_confirm, verify_confirm},
+    deserialize_point,
+    envelope::{ENVELOPE_ALGORITHM, ENVELOPE_HEADER, EnvelopeKey},
+    http::HttpClientConfig,
+    id::IdEncoding,
+    params_cache::ParamsCache,
+    rc,
+    rc_client::{HttpRcClient, RcClient},
+    session_id, user,
 };
 use reqwest::{Client, StatusCode};
 use serde::{Deserialize, Serialize};
 use std::{
     fs,
-    path::PathBuf,
-    sync::{Arc, Mutex},
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 use tokio::sync::RwLock;
 use tracing::{error, info, warn}; // Add Serialize for saving UserKeyData // Add fs and PathBuf for file operations
@@ -44,32 +53,186 @@ struct Args {
     key_len: usize,
 }
 
+/// 命令行入口：不带子命令时完成认证后启动 `/send_message` 服务
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// 配置文件（JSON、TOML 或 YAML，按扩展名识别），不存在时只使用 `USER_` 前缀的环境变量与默认值
+    #[arg(long, global = true, default_value = "config.json")]
+    config: PathBuf,
+    /// 覆盖配置项（可重复），优先级高于配置文件和环境变量，例如 `--set user_id=alice`
+    #[arg(long = "set", global = true, value_name = "KEY=VALUE")]
+    overrides: Vec<String>,
+    /// 会话密钥的输出编码
+    #[arg(long, global = true, value_enum, default_value_t = KeyFormat::Hex)]
+    key_format: KeyFormat,
+    /// 把会话密钥写入该文件，`--key-format raw` 时必须指定。
+    /// 未指定时只有以 `unsafe-log-keys` feature 构建才会把密钥打印到日志
+    #[arg(long, global = true, required_if_eq("key_format", "raw"))]
+    key_out: Option<PathBuf>,
+    /// 离线发放的凭据包（见 `ibc_aaka_scheme::bundle`），指定时直接使用其中的系统参数、
+    /// 用户 ID 与私钥，不再向 RC 获取参数或注册
+    #[arg(long, global = true)]
+    bundle: Option<PathBuf>,
+    /// 加密凭据包的口令
+    #[arg(
+        long,
+        global = true,
+        env = "USER_BUNDLE_PASSPHRASE",
+        hide_env_values = true
+    )]
+    bundle_passphrase: Option<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum KeyFormat {
+    Hex,
+    Base64,
+    /// 原始字节
+    Raw,
+}
+
+impl KeyFormat {
+    fn encode(self, key: &SessionKey) -> Vec<u8> {
+        match self {
+            KeyFormat::Hex => hex::encode(&key.0).into_bytes(),
+            KeyFormat::Base64 => BASE64_STANDARD.encode(&key.0).into_bytes(),
+            KeyFormat::Raw => key.0.clone(),
+        }
+    }
+}
+
+// 按 `--key-format` 输出会话密钥：指定了 `--key-out` 时写入文件；
+// 否则只在开启 `unsafe-log-keys` 时打印到日志，默认只记录密钥长度
+fn output_session_key(cli: &Cli, key: &SessionKey) -> Result<()> {
+    match &cli.key_out {
+        Some(path) => {
+            fs::write(path, cli.key_format.encode(key))
+                .context(format!("Failed to write session key to {path:?}"))?;
+            info!("SUCCESS: Client Session key written to {:?}", path);
+        }
+        #[cfg(feature = "unsafe-log-keys")]
+        None => info!(
+            "SUCCESS: Client Session key is {}",
+            String::from_utf8_lossy(&cli.key_format.encode(key))
+        ),
+        #[cfg(not(feature = "unsafe-log-keys"))]
+        None => info!(
+            key_len = key.0.len(),
+            "SUCCESS: Client Session key established, pass --key-out to save it"
+        ),
+    }
+    Ok(())
+}
+
+// 由握手记录派生会话 ID 并与 MS 报告的 ID 比对，两者不一致说明双方看到的握手不同
+fn log_session_id(
+    state: &ibc_aaka_scheme::UserState,
+    response: &ServerAuthResponse,
+    ms_response: &MsAuthSuccessResponse,
+) -> Result<String> {
+    let id = hex::encode(session_id(&Transcript::from_user(state, response))?);
+    match &ms_response.session_id_hex {
+        Some(ms_id) if *ms_id != id => {
+            warn!("Session ID {id} differs from the one reported by the MS ({ms_id})")
+        }
+        _ => info!("Session ID is {id}"),
+    }
+    Ok(id)
+}
+
+/// 按 配置文件 → `USER_*` 环境变量 → `--set` 的顺序加载配置
+fn load_config(cli: &Cli) -> Result<Config> {
+    Ok(config::load(&cli.config, "USER_", &cli.overrides)?)
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// 完成认证后，用会话密钥加密发送一条消息给 MS，打印 MS 加密回显的内容后退出
+    SendMessage { message: String },
+    /// 不连接 MS，只检查 `key_file` 中保存的用户密钥与 RC 当前的系统参数是否一致，并打印密钥指纹
+    Verify,
+}
+
 #[derive(Debug, Deserialize)]
 struct Config {
     user_addr: String,
     ms_id: String,
+    /// `ms_id` 的编码，取值与 `user_id_encoding` 相同
+    #[serde(default)]
+    ms_id_encoding: IdEncoding,
     user_id: String,
+    /// `user_id` 的编码：`utf8`（默认）、`hex` 或 `base64`，后两者用于含 NUL 或非 UTF-8 字节的 ID
+    #[serde(default)]
+    user_id_encoding: IdEncoding,
     rc_url: String,
     ms_url: String,
     key_file: PathBuf,
     key_len: usize,
+    /// 无状态模式：不读写 `key_file`，每次启动都重新向 RC 注册
+    #[serde(default)]
+    stateless: bool,
+    /// 定期向 RC 检查系统参数是否轮换的间隔（秒），未设置时不刷新
+    #[serde(default)]
+    params_refresh_secs: Option<u64>,
+    /// 用系统参数派生的密钥把认证请求体封装在外层信封中发送（见 `ibc_aaka_scheme::envelope`）
+    #[serde(default)]
+    envelope: bool,
+    /// 向 MS 认证遇到暂时性错误时的重试
+    #[serde(default)]
+    auth_retry: RetryConfig,
+    /// 访问 RC 与 MS 的 HTTP 客户端的超时与连接池设置
+    #[serde(default)]
+    http: HttpClientConfig,
+    /// 每次启动向 RC 申请一个新的假名私钥（随机 epoch），用假名而不是 `user_id` 向 MS 认证，
+    /// MS 无法关联同一用户的不同会话；假名私钥不写入 `key_file`
+    #[serde(default)]
+    pseudonymous: bool,
+    /// 启动时与参考时间（NTP 服务器或其他节点的 `Date` 头）比较本地时钟，未设置时不检查
+    #[serde(default)]
+    clock_check: Option<ClockCheckConfig>,
+    /// 建立会话密钥后向 MS 的 `/auth/confirm` 交换确认值，确认双方得到了相同的密钥
+    #[serde(default)]
+    key_confirmation: bool,
 }
 
-// --- Data Structures for Communication (remain the same) ---
-#[derive(Deserialize, Debug)]
-struct RcSystemParametersResponse {
-    p_hex: String,
-    p_pub_hex: String,
-    p_pub_hat_hex: String,
-    g_hex: String,
+impl Config {
+    // 认证与校验私钥时使用的 ID 字节
+    fn user_id_bytes(&self) -> Result<Vec<u8>> {
+        self.user_id_encoding
+            .decode(&self.user_id)
+            .context("Invalid user_id")
+    }
+
+    // 服务器 ID（IDms）的字节，必须与 MS 的 `ms_id` 一致
+    fn ms_id_bytes(&self) -> Result<Vec<u8>> {
+        self.ms_id_encoding
+            .decode(&self.ms_id)
+            .context("Invalid ms_id")
+    }
 }
 
-#[derive(Deserialize, Debug, Serialize, Clone)] // Add Serialize, Clone for saving
-struct RcUserRegistrationResponse {
-    r_u_hex: String,
-    sid_u_hex: String,
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+struct RetryConfig {
+    /// 最多尝试的次数（包括第一次）
+    attempts: u32,
+    /// 第一次重试前的等待时间（毫秒），之后每次翻倍
+    backoff_ms: u64,
 }
 
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff_ms: 200,
+        }
+    }
+}
+
+// --- Data Structures for Communication (remain the same) ---
 #[derive(Deserialize, Debug)]
 struct MsAuthResponsePayload {
     t_hex: String,
@@ -82,13 +245,41 @@ struct MsAuthSuccessResponse {
     message: String,
     response: MsAuthResponsePayload,
     // session_key_hex: String, // From MS (DEMO ONLY)
+    #[serde(default)]
+    session_id_hex: Option<String>,
 }
 
 // --- Structure for storing user key data locally ---
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct UserKeyData {
     user_id: String, // Store ID for verification
-    key_info: RcUserRegistrationResponse,
+    key_info: StoredUserKey,
+}
+
+// 与 RC `/register/user` 响应的字段相同，较早写入的密钥文件中多出的 `check` 会被忽略
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct StoredUserKey {
+    r_u_hex: String,
+    sid_u_hex: String,
+}
+
+impl UserKeyData {
+    fn new(user_id: String, usk: &UserSecretKey) -> Result<Self> {
+        Ok(Self {
+            user_id,
+            key_info: StoredUserKey {
+                r_u_hex: ark_to_hex(&usk.r_u)?,
+                sid_u_hex: ark_to_hex(&usk.sid_u)?,
+            },
+        })
+    }
+
+    fn usk(&self) -> Result<UserSecretKey> {
+        Ok(UserSecretKey {
+            r_u: hex_to_point(&self.key_info.r_u_hex)?,
+            sid_u: hex_to_ark(&self.key_info.sid_u_hex)?,
+        })
+    }
 }
 
 // --- Utility Functions (remain the same) ---
@@ -98,6 +289,13 @@ fn hex_to_ark<T: CanonicalDeserialize>(hex_str: &str) -> Result<T> {
     T::deserialize_compressed(&bytes[..]).map_err(|e| anyhow!("Ark Deserialization failed: {}", e))
 }
 
+// Helper to deserialize a curve point from hex, rejecting identity/off-curve/off-subgroup points
+fn hex_to_point<P: CheckedPoint>(hex_str: &str) -> Result<P> {
+    let bytes = hex::decode(hex_str)
+        .map_err(|e| anyhow!("Hex decoding failed for '{}': {}", hex_str, e))?;
+    deserialize_point(&bytes).map_err(|e| anyhow!("Invalid curve point: {}", e))
+}
+
 // Helper to serialize arkworks types to hex string
 fn ark_to_hex<T: CanonicalSerialize>(item: &T) -> Result<String> {
     let mut buffer = Vec::new();
@@ -107,10 +305,7 @@ fn ark_to_hex<T: CanonicalSerialize>(item: &T) -> Result<String> {
 }
 
 // --- Function to load or register user key ---
-async fn load_or_register_user_key(
-    config: &Config,
-    client: &reqwest::Client,
-) -> Result<UserKeyData> {
+async fn load_or_register_user_key(config: &Config, rc: &impl RcClient) -> Result<UserKeyData> {
     // FIX:
     // if config.key_file.exists() {
     if false {
@@ -143,37 +338,19 @@ async fn load_or_register_user_key(
         "Registering user '{}' with RC at {}...",
         config.user_id, config.rc_url
     );
-    let register_url = format!("{}/register/user", config.rc_url);
-
-    #[derive(Serialize)]
-    struct RegisterPayload<'a> {
-        id: &'a str,
-    }
-    let payload = RegisterPayload {
-        id: &config.user_id,
-    };
-
-    let resp = client
-        .post(&register_url)
-        .json(&payload)
-        .send()
+    // RC 随私钥返回的校验值（若有）已在 `register_user` 中检查
+    let usk = rc
+        .register_user(&config.user_id_bytes()?)
         .await
-        .context(format!(
-            "Failed to send user registration request to RC: {register_url}",
-        ))?
-        .error_for_status()?;
-
-    let reg_resp: RcUserRegistrationResponse = resp
-        .json()
-        .await
-        .context("Failed to parse JSON user registration response from RC")?;
-
+        .context("Failed to register user with RC")?;
     info!("User registered successfully.");
 
-    let new_key_data = UserKeyData {
-        user_id: config.user_id.clone(),
-        key_info: reg_resp.clone(), // Clone response for saving
-    };
+    let new_key_data = UserKeyData::new(config.user_id.clone(), &usk)?;
+
+    if config.stateless {
+        info!("Stateless mode: user key kept in memory only.");
+        return Ok(new_key_data);
+    }
 
     // Attempt to save the new key data
     match serde_json::to_string_pretty(&new_key_data) {
@@ -190,75 +367,329 @@ async fn load_or_register_user_key(
     Ok(new_key_data) // Return the newly obtained key data
 }
 
+// 向 RC 申请 `epoch` 的假名私钥，返回的 `user_id` 为假名
+async fn register_pseudonym(
+    config: &Config,
+    rc: &impl RcClient,
+    epoch: u64,
+) -> Result<UserKeyData> {
+    let (pseudonym, usk) = rc
+        .register_pseudonym(&config.user_id_bytes()?, epoch)
+        .await
+        .context("Failed to register pseudonym with RC")?;
+    info!("Registered pseudonym {pseudonym} for this session.");
+    UserKeyData::new(pseudonym, &usk)
+}
+
+// Fetch and validate the current system parameters from the RC
+async fn fetch_params(rc: &impl RcClient) -> Result<SystemParameters> {
+    info!("Fetching system parameters from RC...");
+    let params = rc
+        .fetch_params()
+        .await
+        .context("Failed to fetch system parameters from RC")?;
+    info!(
+        "System parameters fetched successfully (fingerprint {}).",
+        params.fingerprint()
+    );
+    Ok(params)
+}
+
+// 读取凭据包，返回其中的系统参数、用户 ID 与私钥；解析时已校验私钥属于该 ID
+fn load_bundle(
+    path: &Path,
+    passphrase: Option<&str>,
+) -> Result<(SystemParameters, Vec<u8>, UserSecretKey)> {
+    let bytes = fs::read(path).context(format!("Failed to read bundle {path:?}"))?;
+    let bundle = if ProvisioningBundle::is_sealed(&bytes) {
+        let passphrase = passphrase.context("Bundle is encrypted, set USER_BUNDLE_PASSPHRASE")?;
+        ProvisioningBundle::open(&bytes, passphrase.as_bytes())
+    } else {
+        ProvisioningBundle::from_bytes(&bytes)
+    }
+    .context(format!("Invalid bundle {path:?}"))?;
+    match bundle.credential {
+        Credential::User { id, usk } => Ok((bundle.params, id, usk)),
+        Credential::Server { .. } => Err(anyhow!("Bundle {path:?} holds a server credential")),
+    }
+}
+
+/// 读取 `key_file` 中的用户密钥并在本地校验，返回密钥指纹
+fn verify_stored_key(config: &Config, params: &SystemParameters) -> Result<String> {
+    let content = fs::read_to_string(&config.key_file)
+        .context(format!("Failed to read key file: {:?}", config.key_file))?;
+    let stored_data: UserKeyData = serde_json::from_str(&content).context(format!(
+        "Failed to parse JSON from key file: {:?}",
+        config.key_file
+    ))?;
+    if stored_data.user_id != config.user_id {
+        return Err(anyhow!(
+            "Key file belongs to user '{}', not '{}'",
+            stored_data.user_id,
+            config.user_id
+        ));
+    }
+
+    let usk = stored_data.usk()?;
+    rc::verify_user_key(params, &config.user_id_bytes()?, &usk)?;
+    Ok(usk.fingerprint())
+}
+
 // --- Main Application Logic ---
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
+    let cli = Cli::parse();
 
-    let config: Config = serde_json::from_str(&std::fs::read_to_string("config.json")?)
-        .context("Failed to parse configuration from file")?;
+    let config = load_config(&cli).context("Failed to load configuration")?;
 
     // --- Initialize HTTP client ---
-    let client = reqwest::Client::new();
+    let client = config.http.build()?;
+    if let Some(clock_check) = &config.clock_check {
+        clock_check.run(&client).await.context(format!(
+            "Refusing to start, clock check against {} failed",
+            clock_check.reference
+        ))?;
+    }
 
-    // --- Step 1: Load/Fetch System Parameters ---
-    info!("Fetching system parameters from RC at {}...", config.rc_url);
-    let params_rc_url = format!("{}/params", config.rc_url);
+    let rc = HttpRcClient::new(client.clone(), &config.rc_url);
+    let (params, auth_id, usk) = match &cli.bundle {
+        // --- Steps 1-2 from the bundle, without the RC ---
+        Some(path) => {
+            let (params, auth_id, usk) = load_bundle(path, cli.bundle_passphrase.as_deref())?;
+            info!(
+                "Loaded bundle {path:?} for user '{}' (params {}).",
+                String::from_utf8_lossy(&auth_id),
+                params.fingerprint()
+            );
+            if let Some(Command::Verify) = cli.command {
+                println!(
+                    "Bundle for '{}' is valid (fingerprint {})",
+                    String::from_utf8_lossy(&auth_id),
+                    usk.fingerprint()
+                );
+                return Ok(());
+            }
+            (params, auth_id, usk)
+        }
+        None => {
+            // --- Step 1: Load/Fetch System Parameters ---
+            let params = fetch_params(&rc).await?;
+
+            if let Some(Command::Verify) = cli.command {
+                match verify_stored_key(&config, &params) {
+                    Ok(fingerprint) => {
+                        println!(
+                            "User key for '{}' is valid (fingerprint {fingerprint})",
+                            config.user_id
+                        );
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        println!("User key for '{}' is INVALID: {e:#}", config.user_id);
+                        std::process::exit(1);
+                    }
+                }
+            }
+
+            // --- Step 2: Load or Register User Key ---
+            let (auth_id, usk) = load_user_key(&config, &rc).await?;
+            (params, auth_id, usk)
+        }
+    };
+    info!("Using user key with fingerprint {}.", usk.fingerprint());
 
-    let params_resp: RcSystemParametersResponse = client
-        .get(&params_rc_url)
+    // --- Step 3-5: Authenticate with the MS, retrying with a fresh request ---
+    let mut rng = StdRng::from_entropy();
+    let Authenticated {
+        state: user_state,
+        response: server_response_data,
+        ms_response: success_resp,
+    } = authenticate_with_retry(&client, &config, &params, &auth_id, &usk, &mut rng).await?;
+
+    let user_session_key_result = user::process_server_response(
+        &usk,
+        &user_state,
+        &server_response_data,
+        &config.ms_id_bytes()?,
+        &params,
+        config.key_len,
+    );
+    let sk = match user_session_key_result {
+        Ok(key) => {
+            output_session_key(&cli, &key)?;
+            let session_id_hex = log_session_id(&user_state, &server_response_data, &success_resp)?;
+            if config.key_confirmation {
+                confirm_key(&client, &config.ms_url, &session_id_hex, &key).await?;
+                info!("Key confirmation with the MS succeeded.");
+            }
+            key
+        }
+        Err(e) => {
+            // ... (print error, exit 1) ...
+            error!("ERROR: Failed to process server response: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(Command::SendMessage { message }) = cli.command {
+        let reply = send_message(&client, &config.ms_url, &sk, &message).await?;
+        println!("MS replied: {reply}");
+        return Ok(());
+    }
+
+    let user_addr = config.user_addr.clone();
+    let refresh = config.params_refresh_secs.map(|secs| {
+        (
+            secs,
+            ParamsCache::with_params(&config.rc_url, params.clone()),
+        )
+    });
+    let user_state = UserState {
+        inner: Arc::new(RwLock::new(InnerUserState {
+            params,
+            config,
+            sk,
+            client: client.clone(),
+        })),
+    };
+
+    // --- Periodically pick up rotated system parameters ---
+    if let Some((secs, cache)) = refresh {
+        let state = user_state.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(secs));
+            loop {
+                interval.tick().await;
+                match cache.refresh(&client).await {
+                    Ok(true) => {
+                        if let Some(params) = cache.get() {
+                            let mut state_write = state.inner.write().await;
+                            info!(
+                                "System parameters changed on the RC, reloaded (fingerprint {} -> {}).",
+                                state_write.params.fingerprint(),
+                                params.fingerprint()
+                            );
+                            state_write.params = params;
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        warn!("Failed to refresh system parameters, keeping cached ones: {e}")
+                    }
+                }
+            }
+        });
+    }
+    let app = Router::new()
+        .route("/send_message", post(handle_send_message))
+        .with_state(user_state);
+
+    let listener = tokio::net::TcpListener::bind(&user_addr).await?;
+    println!("User Server listening on {}", listener.local_addr()?);
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ConfirmRequest<'a> {
+    session_id_hex: &'a str,
+    confirm_hex: String,
+}
+
+#[derive(Deserialize)]
+struct ConfirmResponse {
+    confirm_hex: String,
+}
+
+// 发送用户方向的确认值，并校验 MS 返回的确认值；任一方向不一致都说明双方的会话密钥不同
+async fn confirm_key(
+    client: &Client,
+    ms_url: &str,
+    session_id_hex: &str,
+    sk: &SessionKey,
+) -> Result<()> {
+    let request = ConfirmRequest {
+        session_id_hex,
+        confirm_hex: hex::encode(build_confirm(sk, ConfirmDirection::ClientToServer)),
+    };
+    let resp = client
+        .post(format!("{ms_url}/auth/confirm"))
+        .json(&request)
         .send()
         .await
-        .context(format!("Failed to get params from RC: {params_rc_url}"))?
-        .error_for_status()?
-        .json()
-        .await
-        .context("Failed to parse params JSON from RC")?;
-    info!("System parameters fetched successfully.");
-
-    let params = SystemParameters {
-        p: hex_to_ark(&params_resp.p_hex)?,
-        p_pub: hex_to_ark(&params_resp.p_pub_hex)?,
-        p_pub_hat: hex_to_ark(&params_resp.p_pub_hat_hex)?,
-        g: hex_to_ark(&params_resp.g_hex)?,
-    };
+        .context("Failed to send key confirmation to MS")?;
+    let status = resp.status();
+    if !status.is_success() {
+        let body = resp.text().await.unwrap_or_default();
+        return Err(anyhow!("MS rejected key confirmation ({status}): {body}"));
+    }
+    let resp: ConfirmResponse = resp.json().await?;
+    let confirm = hex::decode(&resp.confirm_hex).context("Invalid confirm_hex from MS")?;
+    verify_confirm(sk, ConfirmDirection::ServerToClient, &confirm)
+        .context("MS key confirmation value does not match")?;
+    Ok(())
+}
 
-    // --- Step 2: Load or Register User Key ---
+// 步骤 2：加载或向 RC 注册用户私钥，返回认证使用的 ID 与私钥
+async fn load_user_key(config: &Config, rc: &impl RcClient) -> Result<(Vec<u8>, UserSecretKey)> {
     info!(
         "Loading or registering user key for '{}' with RC at {}...",
         config.user_id, config.rc_url
     );
-    let user_key_data = load_or_register_user_key(&config, &client).await?;
+    let user_key_data = if config.pseudonymous {
+        register_pseudonym(config, rc, rand::random()).await?
+    } else {
+        load_or_register_user_key(config, rc).await?
+    };
+    // 假名总是 UTF-8 字符串；否则按 `user_id_encoding` 解码配置中的 ID
+    let auth_id = if config.pseudonymous {
+        user_key_data.user_id.as_bytes().to_vec()
+    } else {
+        config.user_id_bytes()?
+    };
 
     // Deserialize the loaded/fetched user key
-    let usk = UserSecretKey {
-        r_u: hex_to_ark(&user_key_data.key_info.r_u_hex)?,
-        sid_u: hex_to_ark(&user_key_data.key_info.sid_u_hex)?,
-    };
+    Ok((auth_id, user_key_data.usk()?))
+}
 
-    // --- Step 3: Initiate Authentication (Call Core Lib) ---
-    // (Logic remains the same, uses loaded usk and params)
-    let mut rng = StdRng::from_entropy();
-    let (request, user_state) = user::initiate_authentication(
-        &usk,
-        config.user_id.as_bytes(),
-        config.ms_id.as_bytes(),
-        &params,
-        &mut rng,
+// 一次成功认证得到的用户状态、服务器响应以及 MS 的原始响应
+struct Authenticated {
+    state: ibc_aaka_scheme::UserState,
+    response: ServerAuthResponse,
+    ms_response: MsAuthSuccessResponse,
+}
+
+// 生成一个新的认证请求并发送给 MS
+async fn authenticate(
+    client: &Client,
+    config: &Config,
+    params: &SystemParameters,
+    user_id: &[u8],
+    usk: &UserSecretKey,
+    rng: &mut StdRng,
+) -> Result<Authenticated> {
+    let (request, state) = user::initiate_authentication_with_key_len(
+        usk,
+        user_id,
+        &config.ms_id_bytes()?,
+        params,
+        config.key_len,
+        rng,
     )
     .context("Failed to initiate authentication")?;
 
     info!("Authentication request generated successfully.");
 
-    // --- Step 4: Send Request to MS (Serialize to JSON with hex) ---
-    // (Logic remains the same)
     #[derive(Serialize)]
     struct AuthRequestPayloadForSend {
         m_hex: String,
         n: String,
         sigma_hex: String,
         timestamp: u64,
+        suite_id: u16,
     }
 
     let request_payload = AuthRequestPayloadForSend {
@@ -266,70 +697,95 @@ async fn main() -> anyhow::Result<()> {
         n: hex::encode(&request.n),
         sigma_hex: ark_to_hex(&request.sigma)?,
         timestamp: request.timestamp,
+        suite_id: request.suite_id,
     };
 
     info!("Sending authentication request to MS...");
 
     let ms_auth_url = format!("{}/auth/initiate", config.ms_url);
-    let res = client
-        .post(&ms_auth_url)
-        .json(&request_payload)
-        .send()
-        .await?
-        .error_for_status()?;
+    let mut auth_request = client.post(&ms_auth_url);
+    if config.envelope {
+        let key = EnvelopeKey::derive(params, &config.ms_id_bytes()?)?;
+        auth_request = auth_request
+            .header(ENVELOPE_HEADER, ENVELOPE_ALGORITHM)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(key.seal(&serde_json::to_vec(&request_payload)?)?);
+    } else {
+        auth_request = auth_request.json(&request_payload);
+    }
+    let res = auth_request.send().await?.error_for_status()?;
 
     info!(
         "Authentication request sent successfully to MS at {}",
         ms_auth_url
     );
 
-    // --- Step 5: Process Response from MS ---
-    let success_resp: MsAuthSuccessResponse = res.json().await?; // Simplified
+    let ms_response: MsAuthSuccessResponse = res.json().await?;
     info!(
         "Received successful response from MS: {}",
-        success_resp.message
+        ms_response.message
     );
 
-    // Deserialize the inner response payload
-    let server_response_data = ServerAuthResponse {
-        t: hex_to_ark(&success_resp.response.t_hex)?,
-        y: hex_to_ark(&success_resp.response.y_hex)?,
-        timestamp: success_resp.response.timestamp,
-    };
-
-    let user_session_key_result = user::process_server_response(
-        &usk,
-        &user_state,
-        &server_response_data,
-        config.ms_id.as_bytes(),
-        &params,
-        config.key_len,
-    );
-    let sk = match user_session_key_result {
-        Ok(key) => {
-            info!("SUCCESS: Client Session key is {:?}", hex::encode(&key.0));
-            key
-        }
-        Err(e) => {
-            // ... (print error, exit 1) ...
-            error!("ERROR: Failed to process server response: {:?}", e);
-            std::process::exit(1);
-        }
+    let response = ServerAuthResponse {
+        t: hex_to_ark(&ms_response.response.t_hex)?,
+        y: hex_to_point(&ms_response.response.y_hex)?,
+        timestamp: ms_response.response.timestamp,
     };
+    Ok(Authenticated {
+        state,
+        response,
+        ms_response,
+    })
+}
 
-    let user_addr = config.user_addr.clone();
-    let user_state = UserState {
-        inner: Arc::new(RwLock::new(InnerUserState { params, config, sk })),
-    };
-    let app = Router::new()
-        .route("/send_message", post(handle_send_message))
-        .with_state(user_state);
+// 连接失败、超时、5xx 与 429 视为暂时性错误；其他 4xx（请求被拒绝、重放）重试也不会成功
+fn is_transient(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<reqwest::Error>().is_some_and(|e| {
+        e.is_connect()
+            || e.is_timeout()
+            || e.status().is_some_and(|status| {
+                status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+            })
+    })
+}
 
-    let listener = tokio::net::TcpListener::bind(&user_addr).await?;
-    println!("User Server listening on {}", listener.local_addr()?);
-    axum::serve(listener, app).await?;
+// 单次等待的上限
+const MAX_AUTH_BACKOFF: std::time::Duration = std::time::Duration::from_secs(5);
 
-    Ok(())
+// 认证请求带有时间戳，重发同一个请求可能已经过期（或被当作重放），
+// 因此每次重试都重新生成请求（新的 x 与时间戳）
+async fn authenticate_with_retry(
+    client: &Client,
+    config: &Config,
+    params: &SystemParameters,
+    user_id: &[u8],
+    usk: &UserSecretKey,
+    rng: &mut StdRng,
+) -> Result<Authenticated> {
+    let retry = &config.auth_retry;
+    let mut backoff = Backoff::new(
+        std::time::Duration::from_millis(retry.backoff_ms),
+        MAX_AUTH_BACKOFF,
+    );
+    let mut attempt = 1;
+    loop {
+        match authenticate(client, config, params, user_id, usk, rng).await {
+            Ok(authenticated) => return Ok(authenticated),
+            Err(e) if attempt < retry.attempts && is_transient(&e) => {
+                let delay = backoff.next_delay(rng);
+                warn!(
+                    attempt,
+                    max_attempts = retry.attempts,
+                    "Authentication failed, retrying with a new request in {delay:?}: {e:#}"
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                return Err(e.context(format!("Authentication failed after {attempt} attempts")));
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -342,6 +798,7 @@ struct InnerUserState {
     params: SystemParameters,
     config: Config,
     sk: SessionKey,
+    client: Client,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -355,36 +812,41 @@ struct Ciphertext {
     nonce: Vec<u8>,
 }
 
-async fn handle_send_message(
-    State(state): State<UserState>,
-    msg: String,
-) -> Result<Json<Msg>, AppError> {
-    let state_locked = state.inner.read().await;
-    let mut key = [0; 32];
-    key.clone_from_slice(&state_locked.sk.0[..32]);
-    let (ciphertext, nonce) =
-        encrypt(&key, msg.as_bytes()).map_err(|e| AppError(anyhow!(e.to_string())))?;
+// Encrypt `msg` under the session key, send it to the MS and decrypt its reply
+async fn send_message(client: &Client, ms_url: &str, sk: &SessionKey, msg: &str) -> Result<String> {
+    let (ciphertext, nonce) = sk.seal(msg.as_bytes())?;
 
     let payload = Ciphertext {
         text: ciphertext,
         nonce,
     };
 
-    let ms_url = &state_locked.config.ms_url;
-
-    let client = Client::new();
-    let resp = client
+    let cipheresp: Ciphertext = client
         .post(format!("{}/communicate", ms_url))
         .json(&payload)
         .send()
+        .await?
+        .error_for_status()?
+        .json()
         .await?;
 
-    let cipheresp: Ciphertext = resp.json().await?;
+    Ok(String::from_utf8(
+        sk.open(&cipheresp.text, &cipheresp.nonce)?,
+    )?)
+}
 
-    let msg = String::from_utf8(
-        decrypt(&key, &cipheresp.text, &cipheresp.nonce)
-            .map_err(|e| AppError(anyhow!(e.to_string())))?,
-    )?;
+async fn handle_send_message(
+    State(state): State<UserState>,
+    msg: String,
+) -> Result<Json<Msg>, AppError> {
+    let state_locked = state.inner.read().await;
+    let msg = send_message(
+        &state_locked.client,
+        &state_locked.config.ms_url,
+        &state_locked.sk,
+        &msg,
+    )
+    .await?;
 
     Ok(axum::Json(Msg { msg }))
 }
@@ -394,9 +856,15 @@ struct AppError(anyhow::Error);
 impl IntoResponse for AppError {
     fn into_response(self) -> axum::response::Response {
         eprintln!("Error occurred: {:?}", self.0);
+        let code = self
+            .0
+            .chain()
+            .find_map(|e| e.downcast_ref::<AAKAError>())
+            .map_or("ERR_INTERNAL", AAKAError::code);
+        let body = serde_json::json!({ "code": code, "message": format!("Error: {}", self.0) });
         (
             StatusCode::INTERNAL_SERVER_ERROR, // Or map specific errors (e.g., Bad Request for deserialization)
-            format!("Error: {}", self.0),
+            Json(body),
         )
             .into_response()
     }
@@ -410,3 +878,420 @@ where
         Self(err.into())
     }
 }
+
+// Config for tests: `alice` authenticating to `my_server` in stateless mode, every option at its default
+#[cfg(test)]
+fn test_config() -> Config {
+    Config {
+        user_addr: "127.0.0.1:0".to_string(),
+        ms_id: "my_server".to_string(),
+        ms_id_encoding: IdEncoding::default(),
+        user_id: "alice".to_string(),
+        user_id_encoding: IdEncoding::default(),
+        rc_url: String::new(),
+        ms_url: String::new(),
+        key_file: PathBuf::new(),
+        key_len: 32,
+        stateless: true,
+        params_refresh_secs: None,
+        envelope: false,
+        auth_retry: RetryConfig::default(),
+        http: HttpClientConfig::default(),
+        pseudonymous: false,
+        clock_check: None,
+        key_confirmation: false,
+    }
+}
+
+#[test]
+#[allow(clippy::result_large_err)]
+fn test_env_overrides_config_file() {
+    figment::Jail::expect_with(|jail| {
+        jail.create_file(
+            "config.json",
+            r#"{
+                "user_addr": "127.0.0.1:4002",
+                "ms_id": "my_server",
+                "user_id": "user",
+                "rc_url": "http://127.0.0.1:5000",
+                "ms_url": "http://127.0.0.1:4001",
+                "key_file": "user_key.json",
+                "key_len": 32
+            }"#,
+        )?;
+        jail.set_env("USER_USER_ID", "alice");
+        jail.set_env("USER_KEY_LEN", "16");
+
+        let config = load_config(&Cli::parse_from(["aaka_user_app"])).unwrap();
+        assert_eq!(config.user_id, "alice");
+        assert_eq!(config.key_len, 16);
+        assert_eq!(config.ms_id, "my_server");
+
+        let cli = Cli::parse_from([
+            "aaka_user_app",
+            "send-message",
+            "hi",
+            "--set",
+            "ms_id=other",
+        ]);
+        assert_eq!(load_config(&cli).unwrap().ms_id, "other");
+        Ok(())
+    });
+}
+
+#[tokio::test]
+async fn test_stateless_mode_writes_no_key_file() {
+    // Mock RC serving /register/user
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let check = rc::UserKeyCheck::new(&params, b"alice", &usk).unwrap();
+    let reg_json = serde_json::json!({
+        "r_u_hex": ark_to_hex(&usk.r_u).unwrap(),
+        "sid_u_hex": ark_to_hex(&usk.sid_u).unwrap(),
+        "check": {
+            "h_u_hex": ark_to_hex(&check.h_u).unwrap(),
+            "p_pub_hex": ark_to_hex(&check.p_pub).unwrap(),
+            "params_fingerprint": params.fingerprint(),
+        },
+    });
+    let rc_app = Router::new().route(
+        "/register/user",
+        post(move || async move { Json(reg_json) }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let rc_url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, rc_app).await.unwrap() });
+
+    let key_file = std::env::temp_dir().join(format!("user_key_{}.json", std::process::id()));
+    let config = Config {
+        rc_url,
+        key_file: key_file.clone(),
+        ..test_config()
+    };
+    let key_data =
+        load_or_register_user_key(&config, &HttpRcClient::new(Client::new(), &config.rc_url))
+            .await
+            .unwrap();
+    assert!(!key_file.exists());
+
+    // The in-memory key is still usable for authentication
+    let usk = key_data.usk().unwrap();
+    user::initiate_authentication(&usk, b"alice", b"my_server", &params, &mut rng).unwrap();
+}
+
+#[tokio::test]
+async fn test_auth_retry_regenerates_request() {
+    use ibc_aaka_scheme::{UserAuthRequest, server};
+    use std::sync::Mutex;
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let ssk = rc::register_server(&msk, b"my_server").unwrap();
+
+    // Mock MS: the first request is answered as stale (500), later ones are processed normally
+    let seen = Arc::new(Mutex::new(Vec::<serde_json::Value>::new()));
+    let ms_params = params.clone();
+    let ms_seen = seen.clone();
+    let ms_app = Router::new().route(
+        "/auth/initiate",
+        post(move |Json(body): Json<serde_json::Value>| async move {
+            let first = {
+                let mut seen = ms_seen.lock().unwrap();
+                seen.push(body.clone());
+                seen.len() == 1
+            };
+            if first {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Error: Timestamp is invalid or expired",
+                )
+                    .into_response();
+            }
+            let request = UserAuthRequest {
+                m: hex_to_point(body["m_hex"].as_str().unwrap()).unwrap(),
+                n: hex::decode(body["n"].as_str().unwrap()).unwrap(),
+                sigma: hex_to_ark(body["sigma_hex"].as_str().unwrap()).unwrap(),
+                timestamp: body["timestamp"].as_u64().unwrap(),
+                suite_id: body["suite_id"].as_u64().unwrap() as u16,
+            };
+            let (response, _) = server::process_user_request(
+                &ssk,
+                &request,
+                b"my_server",
+                &ms_params,
+                &mut StdRng::from_entropy(),
+                32,
+            )
+            .unwrap();
+            Json(serde_json::json!({
+                "message": "ok",
+                "response": {
+                    "t_hex": ark_to_hex(&response.t).unwrap(),
+                    "y_hex": ark_to_hex(&response.y).unwrap(),
+                    "timestamp": response.timestamp,
+                },
+            }))
+            .into_response()
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let ms_url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, ms_app).await.unwrap() });
+
+    let config = Config {
+        ms_url,
+        auth_retry: RetryConfig {
+            attempts: 3,
+            backoff_ms: 1,
+        },
+        ..test_config()
+    };
+    let authenticated =
+        authenticate_with_retry(&Client::new(), &config, &params, b"alice", &usk, &mut rng)
+            .await
+            .unwrap();
+    user::process_server_response(
+        &usk,
+        &authenticated.state,
+        &authenticated.response,
+        b"my_server",
+        &params,
+        32,
+    )
+    .unwrap();
+
+    // The second attempt carried a newly generated request, not a resend of the first
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 2);
+    assert_ne!(seen[0]["n"], seen[1]["n"]);
+    assert_ne!(seen[0]["sigma_hex"], seen[1]["sigma_hex"]);
+}
+
+#[tokio::test]
+async fn test_http_client_times_out_as_configured() {
+    use std::time::{Duration, Instant};
+
+    // Mock RC that never answers in time
+    let slow_app = Router::new().route(
+        "/params",
+        axum::routing::get(|| async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            "too late"
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let rc_url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, slow_app).await.unwrap() });
+
+    let client = HttpClientConfig {
+        timeout_ms: 200,
+        ..Default::default()
+    }
+    .build()
+    .unwrap();
+    let started = Instant::now();
+    let err = client
+        .get(format!("{rc_url}/params"))
+        .send()
+        .await
+        .unwrap_err();
+    assert!(err.is_timeout(), "{err:?}");
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    // The app-level call surfaces the timeout instead of stalling
+    assert!(
+        fetch_params(&HttpRcClient::new(client, rc_url))
+            .await
+            .is_err()
+    );
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
+
+#[test]
+fn test_verify_reports_corrupted_key_invalid() {
+    use ibc_aaka_scheme::ScalarField;
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+    let key_file =
+        std::env::temp_dir().join(format!("user_key_verify_{}.json", std::process::id()));
+    let config = Config {
+        key_file: key_file.clone(),
+        stateless: false,
+        ..test_config()
+    };
+    let write_key = |sid_u: ScalarField| {
+        let key_data = UserKeyData {
+            user_id: "alice".to_string(),
+            key_info: StoredUserKey {
+                r_u_hex: ark_to_hex(&usk.r_u).unwrap(),
+                sid_u_hex: ark_to_hex(&sid_u).unwrap(),
+            },
+        };
+        fs::write(&key_file, serde_json::to_string(&key_data).unwrap()).unwrap();
+    };
+
+    write_key(usk.sid_u);
+    assert_eq!(
+        verify_stored_key(&config, &params).unwrap(),
+        usk.fingerprint()
+    );
+
+    write_key(usk.sid_u + ScalarField::from(1u64));
+    let err = verify_stored_key(&config, &params).unwrap_err();
+    assert!(err.to_string().contains("inconsistent"));
+
+    fs::remove_file(&key_file).unwrap();
+}
+
+#[test]
+fn test_session_key_formats() {
+    let key = SessionKey(vec![0xde, 0xad, 0xbe, 0xef, 0x00, 0xff]);
+    assert_eq!(KeyFormat::Hex.encode(&key), b"deadbeef00ff");
+    assert_eq!(KeyFormat::Base64.encode(&key), b"3q2+7wD/");
+    assert_eq!(KeyFormat::Raw.encode(&key), key.0);
+
+    // raw 必须写入文件
+    assert!(Cli::try_parse_from(["aaka_user_app", "--key-format", "raw"]).is_err());
+    let key_out = std::env::temp_dir().join(format!("session_key_{}.bin", std::process::id()));
+    let cli = Cli::parse_from([
+        "aaka_user_app",
+        "--key-format",
+        "raw",
+        "--key-out",
+        key_out.to_str().unwrap(),
+    ]);
+    output_session_key(&cli, &key).unwrap();
+    assert_eq!(fs::read(&key_out).unwrap(), key.0);
+    fs::remove_file(&key_out).unwrap();
+
+    let cli = Cli::parse_from(["aaka_user_app"]);
+    assert_eq!(cli.key_format, KeyFormat::Hex);
+}
+
+#[test]
+fn test_bundle_replaces_param_fetch_and_registration() {
+    use ibc_aaka_scheme::server;
+
+    let mut rng = StdRng::from_entropy();
+    let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+    let bundle = ProvisioningBundle {
+        params: params.clone(),
+        credential: Credential::User {
+            id: b"alice".to_vec(),
+            usk: rc::register_user(&msk, b"alice", &mut rng).unwrap(),
+        },
+    };
+    let dir = std::env::temp_dir();
+    let plain = dir.join(format!("user_bundle_{}.bin", std::process::id()));
+    let sealed = dir.join(format!("user_bundle_sealed_{}.bin", std::process::id()));
+    fs::write(&plain, bundle.to_bytes().unwrap()).unwrap();
+    fs::write(&sealed, bundle.seal(b"secret").unwrap()).unwrap();
+
+    // 明文的包不需要口令，加密的包需要正确的口令
+    let (loaded_params, auth_id, usk) = load_bundle(&plain, None).unwrap();
+    assert_eq!(
+        (&loaded_params, auth_id.as_slice()),
+        (&params, &b"alice"[..])
+    );
+    assert!(load_bundle(&sealed, None).is_err());
+    assert!(load_bundle(&sealed, Some("wrong")).is_err());
+    let (_, _, sealed_usk) = load_bundle(&sealed, Some("secret")).unwrap();
+    assert_eq!(sealed_usk.fingerprint(), usk.fingerprint());
+
+    // 服务器凭据的包不能用于用户端
+    let server_bundle = ProvisioningBundle {
+        params: params.clone(),
+        credential: Credential::Server {
+            id: b"my_server".to_vec(),
+            ssk: rc::register_server(&msk, b"my_server").unwrap(),
+        },
+    };
+    fs::write(&plain, server_bundle.to_bytes().unwrap()).unwrap();
+    assert!(load_bundle(&plain, None).is_err());
+    fs::remove_file(&plain).unwrap();
+    fs::remove_file(&sealed).unwrap();
+
+    // 包中的内容足以完成握手
+    let Credential::Server { ssk, .. } = server_bundle.credential else {
+        unreachable!()
+    };
+    let (request, user_state) =
+        user::initiate_authentication(&usk, &auth_id, b"my_server", &loaded_params, &mut rng)
+            .unwrap();
+    let (response, server_key) =
+        server::process_user_request(&ssk, &request, b"my_server", &params, &mut rng, 32).unwrap();
+    let user_key = user::process_server_response(
+        &usk,
+        &user_state,
+        &response,
+        b"my_server",
+        &loaded_params,
+        32,
+    )
+    .unwrap();
+    assert_eq!(user_key.0, server_key.0);
+}
+
+#[tokio::test]
+async fn test_registration_and_auth_against_mock_rc() {
+    use ibc_aaka_scheme::{rc_client::MockRc, server};
+
+    let mut rng = StdRng::from_entropy();
+    let mock = MockRc::new(&mut rng).unwrap();
+    let key_file = std::env::temp_dir().join(format!("user_key_mock_{}.json", std::process::id()));
+    let config = Config {
+        user_id: hex::encode(b"dev\x00\xff"),
+        user_id_encoding: IdEncoding::Hex,
+        key_file: key_file.clone(),
+        stateless: false,
+        ..test_config()
+    };
+
+    // 注册后写入密钥文件，文件中的私钥可在本地校验
+    let params = fetch_params(&mock).await.unwrap();
+    let key_data = load_or_register_user_key(&config, &mock).await.unwrap();
+    let usk = key_data.usk().unwrap();
+    assert_eq!(
+        verify_stored_key(&config, &params).unwrap(),
+        usk.fingerprint()
+    );
+
+    // 用签发的私钥与同一 RC 签发的服务器私钥完成握手
+    let ssk = mock.register_server(b"my_server").await.unwrap();
+    let (request, user_state) =
+        user::initiate_authentication(&usk, b"dev\x00\xff", b"my_server", &params, &mut rng)
+            .unwrap();
+    let (response, server_key) =
+        server::process_user_request(&ssk, &request, b"my_server", &params, &mut rng, 32).unwrap();
+    let user_key =
+        user::process_server_response(&usk, &user_state, &response, b"my_server", &params, 32)
+            .unwrap();
+    assert_eq!(user_key.0, server_key.0);
+
+    // 假名私钥绑定假名而不是真实 ID
+    let pseudonym = register_pseudonym(&config, &mock, 7).await.unwrap();
+    assert!(pseudonym.user_id.starts_with("pn-"));
+    rc::verify_user_key(
+        &params,
+        pseudonym.user_id.as_bytes(),
+        &pseudonym.usk().unwrap(),
+    )
+    .unwrap();
+
+    // RC 不可用时报告注册失败
+    let calls = mock.calls();
+    mock.fail_next(1);
+    let err = load_or_register_user_key(&config, &mock).await.unwrap_err();
+    assert!(
+        err.to_string().contains("Failed to register user"),
+        "{err:#}"
+    );
+    assert_eq!(mock.calls(), calls + 1);
+    assert!(load_or_register_user_key(&config, &mock).await.is_ok());
+
+    fs::remove_file(&key_file).unwrap();
+}
@@ -0,0 +1,43 @@
+//! `h2`（密钥流）与 `h5`（会话密钥）在不同输出长度下的开销。
+//!
+//! 运行：`cargo bench -p ibc_aaka_scheme --bench kdf`
+use ark_std::UniformRand;
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use ibc_aaka_scheme::{G1Point, GtPoint, hash_utils, prelude::*};
+use rand::{SeedableRng, rngs::StdRng};
+use std::hint::black_box;
+
+const OUTPUT_LENS: [usize; 4] = [32, 64, 256, 1024];
+
+fn h2(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let g_x = GtPoint::rand(&mut rng);
+
+    let mut group = c.benchmark_group("h2");
+    for xof in [Xof::Shake128, Xof::Shake256] {
+        for len in OUTPUT_LENS {
+            group.bench_with_input(BenchmarkId::new(xof.as_str(), len), &len, |b, &len| {
+                b.iter(|| hash_utils::h2(black_box(&g_x), len, xof).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+fn h5(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let [k, x, y] = [(); 3].map(|_| G1Point::rand(&mut rng));
+
+    let mut group = c.benchmark_group("h5");
+    for len in OUTPUT_LENS {
+        group.bench_with_input(BenchmarkId::from_parameter(len), &len, |b, &len| {
+            b.iter(|| {
+                hash_utils::h5(black_box(&k), b"alice", b"server", &x, &y, len, &[], &[]).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, h2, h5);
+criterion_main!(benches);
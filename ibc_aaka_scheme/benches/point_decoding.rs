@@ -0,0 +1,62 @@
+//! 压缩与非压缩编码的点解码开销对比。
+//!
+//! 运行：`cargo bench -p ibc_aaka_scheme --bench point_decoding`
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use ibc_aaka_scheme::{
+    G1Point, G2Point, deserialize_point_with,
+    prelude::*,
+    wire::{PointEncoding, to_bytes_with},
+};
+use rand::{SeedableRng, rngs::StdRng};
+use std::hint::black_box;
+
+const ENCODINGS: [PointEncoding; 2] = [PointEncoding::Compressed, PointEncoding::Uncompressed];
+
+fn decode_points(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let (params, msk) = gen_parameter_and_msk(&mut rng).unwrap();
+    let ssk = register_server(&msk, b"server").unwrap();
+
+    let mut group = c.benchmark_group("decode_point");
+    for encoding in ENCODINGS {
+        let g1 = to_bytes_with(&params.p_pub, encoding).unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("g1", encoding.as_str()),
+            &g1,
+            |b, bytes| {
+                b.iter(|| deserialize_point_with::<G1Point>(black_box(bytes), encoding).unwrap())
+            },
+        );
+        let g2 = to_bytes_with(&ssk.sid_ms, encoding).unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("g2", encoding.as_str()),
+            &g2,
+            |b, bytes| {
+                b.iter(|| deserialize_point_with::<G2Point>(black_box(bytes), encoding).unwrap())
+            },
+        );
+    }
+    group.finish();
+}
+
+fn decode_request(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let (params, msk) = gen_parameter_and_msk(&mut rng).unwrap();
+    let usk = register_user(&msk, b"alice", &mut rng).unwrap();
+    let (request, _) =
+        initiate_authentication(&usk, b"alice", b"server", &params, &mut rng).unwrap();
+
+    let mut group = c.benchmark_group("decode_auth_request_cbor");
+    for encoding in ENCODINGS {
+        let bytes = request.to_cbor_with(encoding).unwrap();
+        group.bench_with_input(
+            BenchmarkId::from_parameter(encoding.as_str()),
+            &bytes,
+            |b, bytes| b.iter(|| UserAuthRequest::from_cbor(black_box(bytes)).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, decode_points, decode_request);
+criterion_main!(benches);
@@ -0,0 +1,36 @@
+//! 批量注册用户时，串行与多线程签发私钥的开销。
+//!
+//! 运行：`cargo bench -p ibc_aaka_scheme --bench registration`
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use ibc_aaka_scheme::rc;
+use rand::{SeedableRng, rngs::StdRng};
+use std::hint::black_box;
+
+const BATCH_LEN: usize = 256;
+
+fn register_users(c: &mut Criterion) {
+    let (_, msk) = rc::gen_parameter_and_msk(&mut StdRng::seed_from_u64(0)).unwrap();
+    let ids: Vec<Vec<u8>> = (0..BATCH_LEN)
+        .map(|i| format!("user-{i}").into_bytes())
+        .collect();
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+
+    let mut group = c.benchmark_group("register_users");
+    group.sample_size(10);
+    let mut thread_counts = vec![1, 2, 4, cores];
+    thread_counts.sort_unstable();
+    thread_counts.dedup();
+    for threads in thread_counts {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &threads,
+            |b, &threads| {
+                b.iter(|| rc::register_users(&msk, black_box(&ids), [0; 32], threads).unwrap())
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, register_users);
+criterion_main!(benches);
@@ -0,0 +1,14 @@
+fn main() {
+    // 只有启用 `grpc` feature 时才需要生成 protobuf 代码
+    #[cfg(feature = "grpc")]
+    {
+        // SAFETY: build 脚本是单线程的
+        unsafe {
+            std::env::set_var(
+                "PROTOC",
+                protoc_bin_vendored::protoc_bin_path().expect("vendored protoc"),
+            );
+        }
+        tonic_prost_build::compile_protos("proto/aaka.proto").expect("Failed to compile protos");
+    }
+}
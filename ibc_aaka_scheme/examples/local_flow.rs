@@ -0,0 +1,38 @@
+//! 在单个进程内跑通完整流程，不依赖任何网络服务：
+//! RC 生成系统参数并注册用户和服务器，用户与服务器完成一次握手，双方得到相同的会话密钥。
+//!
+//! 运行：`cargo run -p ibc_aaka_scheme --example local_flow`
+use ibc_aaka_scheme::prelude::*;
+use rand::thread_rng;
+
+const USER_ID: &[u8] = b"alice";
+const SERVER_ID: &[u8] = b"edge-server-1";
+const KEY_LEN: usize = 32;
+
+fn main() -> Result<(), AAKAError> {
+    let mut rng = thread_rng();
+
+    // RC：生成系统参数与主密钥，为用户和服务器签发私钥
+    let (params, msk) = gen_parameter_and_msk(&mut rng)?;
+    println!("params      {}", params.fingerprint());
+    let usk = register_user(&msk, USER_ID, &mut rng)?;
+    verify_user_key(&params, USER_ID, &usk)?;
+    let ssk = register_server(&msk, SERVER_ID)?;
+
+    // 用户 -> 服务器：认证请求
+    let (request, state) = initiate_authentication(&usk, USER_ID, SERVER_ID, &params, &mut rng)?;
+
+    // 服务器 -> 用户：认证响应，服务器得到会话密钥
+    let (response, server_key) =
+        process_user_request(&ssk, &request, SERVER_ID, &params, &mut rng, KEY_LEN)?;
+
+    // 用户校验服务器并得到会话密钥
+    let user_key = process_server_response(&usk, &state, &response, SERVER_ID, &params, KEY_LEN)?;
+
+    println!("user key    {}", hex::encode(&user_key.0));
+    println!("server key  {}", hex::encode(&server_key.0));
+    // 不一致时 panic，进程以非零状态退出
+    assert_eq!(user_key.0, server_key.0, "session keys differ");
+    println!("session keys match");
+    Ok(())
+}
@@ -0,0 +1,70 @@
+//! 带随机抖动的指数退避，用于 RC 节点之间以及 MS 启动时的重试。
+//!
+//! 采用 "full jitter"：第 n 次重试前等待 `[0, min(cap, base * 2^n)]` 内均匀随机的时长，
+//! 避免多个节点同时重启后按相同的节奏重试而互相压垮。
+use ark_std::rand::Rng;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    cap: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, cap: Duration) -> Self {
+        Self {
+            base,
+            cap,
+            attempt: 0,
+        }
+    }
+
+    /// 当前这次等待的上限：min(cap, base * 2^attempt)
+    pub fn ceiling(&self) -> Duration {
+        self.base
+            .checked_mul(1u32.checked_shl(self.attempt).unwrap_or(u32::MAX))
+            .map_or(self.cap, |delay| delay.min(self.cap))
+    }
+
+    /// 返回下一次重试前的等待时长，并把上限翻倍
+    pub fn next_delay<R: Rng + ?Sized>(&mut self, rng: &mut R) -> Duration {
+        let ceiling = self.ceiling();
+        self.attempt = self.attempt.saturating_add(1);
+        ceiling.mul_f64(rng.gen_range(0.0..=1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ark_std::rand::{SeedableRng, rngs::StdRng};
+
+    #[test]
+    fn test_backoff_delays_within_jittered_range() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let base = Duration::from_millis(100);
+        let cap = Duration::from_secs(1);
+        let mut backoff = Backoff::new(base, cap);
+
+        let delays: Vec<_> = (0..8).map(|_| backoff.next_delay(&mut rng)).collect();
+        for (n, delay) in delays.iter().enumerate() {
+            let ceiling = (base * 2u32.pow(n as u32)).min(cap);
+            assert!(*delay <= ceiling, "delay {n} = {delay:?} above {ceiling:?}");
+        }
+        assert_eq!(backoff.ceiling(), cap);
+
+        // 不同节点（不同的随机数）得到不同的等待时长，不会同步重试
+        let mut other = Backoff::new(base, cap);
+        let mut other_rng = StdRng::seed_from_u64(1);
+        let other_delays: Vec<_> = (0..8).map(|_| other.next_delay(&mut other_rng)).collect();
+        assert_ne!(delays, other_delays);
+
+        // 尝试次数很大时不会溢出
+        let mut backoff = Backoff::new(base, cap);
+        for _ in 0..100 {
+            assert!(backoff.next_delay(&mut rng) <= cap);
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! 离线发放用的凭据包：系统参数与一个用户或服务器的 ID 及私钥放在同一个文件中，
+//! 拿到文件即可完成认证，不需要访问 RC。
+//!
+//! 明文格式为 `MAGIC || CBOR 消息`，其中的参数与私钥使用原始字节编码；
+//! [`ProvisioningBundle::seal`] 以口令加密整个明文，格式同 [`crate::keyfile`]（不同的 MAGIC）。
+//! 解析时检查点与系统参数，并按包中的 ID 校验私钥与参数相符。
+use crate::{AAKAError, ServerSecretKey, SystemParameters, UserSecretKey, keyfile, rc, wire};
+use serde::{Deserialize, Serialize};
+
+const MAGIC: &[u8] = b"AAKA-BUNDLE1";
+const SEALED_MAGIC: &[u8] = b"AAKA-BUNDLE1-SEALED";
+
+/// 包中的凭据及其所属的 ID
+#[derive(Debug, Clone)]
+pub enum Credential {
+    User { id: Vec<u8>, usk: UserSecretKey },
+    Server { id: Vec<u8>, ssk: ServerSecretKey },
+}
+
+impl Credential {
+    pub fn id(&self) -> &[u8] {
+        match self {
+            Credential::User { id, .. } | Credential::Server { id, .. } => id,
+        }
+    }
+}
+
+/// 系统参数与一个凭据
+#[derive(Debug, Clone)]
+pub struct ProvisioningBundle {
+    pub params: SystemParameters,
+    pub credential: Credential,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum CredentialKind {
+    User,
+    Server,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BundleCbor {
+    #[serde(with = "serde_bytes")]
+    params: Vec<u8>,
+    kind: CredentialKind,
+    #[serde(with = "serde_bytes")]
+    id: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    key: Vec<u8>,
+}
+
+impl ProvisioningBundle {
+    /// 检查凭据是 RC 用这组参数为包中的 ID 签发的
+    pub fn verify(&self) -> Result<(), AAKAError> {
+        match &self.credential {
+            Credential::User { id, usk } => rc::verify_user_key(&self.params, id, usk),
+            Credential::Server { id, ssk } => rc::verify_server_key(&self.params, id, ssk),
+        }
+    }
+
+    /// 未加密的凭据包，私钥以明文保存，只应通过可信的渠道传递
+    pub fn to_bytes(&self) -> Result<Vec<u8>, AAKAError> {
+        let (kind, key) = match &self.credential {
+            Credential::User { usk, .. } => (CredentialKind::User, wire::to_bytes(usk)?),
+            Credential::Server { ssk, .. } => (CredentialKind::Server, wire::to_bytes(ssk)?),
+        };
+        let msg = wire::encode(&BundleCbor {
+            params: wire::to_bytes(&self.params)?,
+            kind,
+            id: self.credential.id().to_vec(),
+            key,
+        })?;
+        Ok([MAGIC, &msg].concat())
+    }
+
+    /// 解析 [`Self::to_bytes`] 的输出，并以 [`Self::verify`] 校验凭据
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, AAKAError> {
+        let msg = bytes.strip_prefix(MAGIC).ok_or_else(|| {
+            AAKAError::Deserialization("Not an AAKA provisioning bundle (bad magic)".to_string())
+        })?;
+        let msg: BundleCbor = wire::decode(msg)?;
+        let credential = match msg.kind {
+            CredentialKind::User => Credential::User {
+                id: msg.id,
+                usk: UserSecretKey::try_from(msg.key.as_slice())?,
+            },
+            CredentialKind::Server => Credential::Server {
+                id: msg.id,
+                ssk: ServerSecretKey::try_from(msg.key.as_slice())?,
+            },
+        };
+        let bundle = Self {
+            params: SystemParameters::try_from(msg.params.as_slice())?,
+            credential,
+        };
+        bundle.verify()?;
+        Ok(bundle)
+    }
+
+    /// 以 `passphrase` 加密的凭据包
+    pub fn seal(&self, passphrase: &[u8]) -> Result<Vec<u8>, AAKAError> {
+        keyfile::seal_with(SEALED_MAGIC, &self.to_bytes()?, passphrase)
+    }
+
+    /// 解密 [`Self::seal`] 的输出；口令错误或文件被篡改时返回错误
+    pub fn open(bytes: &[u8], passphrase: &[u8]) -> Result<Self, AAKAError> {
+        Self::from_bytes(&keyfile::open_with(SEALED_MAGIC, bytes, passphrase)?)
+    }
+
+    /// `bytes` 是否为加密的凭据包
+    pub fn is_sealed(bytes: &[u8]) -> bool {
+        bytes.starts_with(SEALED_MAGIC)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{server, user};
+    use ark_std::rand::{SeedableRng, rngs::StdRng};
+
+    #[test]
+    fn test_bundle_roundtrip_and_handshake() {
+        let mut rng = StdRng::from_entropy();
+        let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+        let ssk = rc::register_server(&msk, b"server").unwrap();
+        let user_bundle = ProvisioningBundle {
+            params: params.clone(),
+            credential: Credential::User {
+                id: b"alice".to_vec(),
+                usk,
+            },
+        };
+        let server_bundle = ProvisioningBundle {
+            params: params.clone(),
+            credential: Credential::Server {
+                id: b"server".to_vec(),
+                ssk,
+            },
+        };
+
+        // 明文与加密的包都能还原
+        let user_bytes = user_bundle.to_bytes().unwrap();
+        assert!(!ProvisioningBundle::is_sealed(&user_bytes));
+        let sealed = server_bundle.seal(b"passphrase").unwrap();
+        assert!(ProvisioningBundle::is_sealed(&sealed));
+        let user_bundle = ProvisioningBundle::from_bytes(&user_bytes).unwrap();
+        let server_bundle = ProvisioningBundle::open(&sealed, b"passphrase").unwrap();
+        assert_eq!(user_bundle.params, params);
+        assert_eq!(server_bundle.credential.id(), b"server");
+
+        // 只用两个包中的内容完成一次握手
+        let (Credential::User { id: user_id, usk }, Credential::Server { id: server_id, ssk }) =
+            (&user_bundle.credential, &server_bundle.credential)
+        else {
+            panic!("bundles hold the wrong credential kinds");
+        };
+        let (request, state) =
+            user::initiate_authentication(usk, user_id, server_id, &user_bundle.params, &mut rng)
+                .unwrap();
+        let (response, server_key) = server::process_user_request(
+            ssk,
+            &request,
+            server_id,
+            &server_bundle.params,
+            &mut rng,
+            32,
+        )
+        .unwrap();
+        let user_key =
+            user::process_server_response(usk, &state, &response, server_id, &params, 32).unwrap();
+        assert_eq!(user_key.0, server_key.0);
+
+        // 错误的口令、篡改的内容、与 ID 不符的私钥
+        assert!(ProvisioningBundle::open(&sealed, b"wrong").is_err());
+        let mut tampered = user_bytes.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(ProvisioningBundle::from_bytes(&tampered).is_err());
+        let mislabeled = ProvisioningBundle {
+            params,
+            credential: Credential::User {
+                id: b"mallory".to_vec(),
+                usk: usk.clone(),
+            },
+        };
+        assert!(ProvisioningBundle::from_bytes(&mislabeled.to_bytes().unwrap()).is_err());
+        assert!(ProvisioningBundle::from_bytes(&sealed).is_err());
+    }
+}
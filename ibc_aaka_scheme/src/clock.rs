@@ -0,0 +1,323 @@
+//! 启动时的时钟偏差检查。
+//!
+//! 认证请求与响应都带有时间戳，本地时钟与对端相差超过 [`crate::ALLOWED_SKEW_SECONDS`] 时，
+//! 所有握手都会以 `ERR_STALE_TS` 失败，从日志中很难看出原因。应用在启动时把本地时间与一个参考
+//! 时间比较，偏差超出 `max_skew_secs` 时输出醒目的警告，或按配置拒绝启动。
+//!
+//! 参考时间来源：
+//! - `ntp://host[:port]`：向 NTP 服务器发送一次 SNTP 请求（默认端口 123，IPv6 地址写作 `[::1]:123`）；
+//! - `http://...` 或 `https://...`：读取响应的 `Date` 头，例如另一个节点，精度为 1 秒。
+//!
+//! 需要启用 `clock-check` feature。
+use crate::{AAKAError, ALLOWED_SKEW_SECONDS};
+use serde::Deserialize;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// NTP 时间从 1900-01-01 起算
+const NTP_UNIX_OFFSET_SECS: u64 = 2_208_988_800;
+const NTP_DEFAULT_PORT: u16 = 123;
+const NTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClockCheckConfig {
+    /// 参考时间来源，见模块文档
+    pub reference: String,
+    /// 允许的最大偏差（秒），默认与时间戳的有效窗口相同
+    #[serde(default = "default_max_skew_secs")]
+    pub max_skew_secs: u64,
+    /// 偏差超出时拒绝启动，而不只是输出警告
+    #[serde(default)]
+    pub enforce: bool,
+}
+
+fn default_max_skew_secs() -> u64 {
+    ALLOWED_SKEW_SECONDS
+}
+
+/// 本地时钟相对参考时间的偏差
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockSkew {
+    /// 本地时间减参考时间（秒），正数表示本地时钟偏快
+    pub secs: i64,
+    pub max_secs: u64,
+}
+
+impl ClockSkew {
+    pub fn exceeded(&self) -> bool {
+        self.secs.unsigned_abs() > self.max_secs
+    }
+}
+
+impl ClockCheckConfig {
+    /// 读取参考时间并与本地时间比较。偏差超出且 `enforce` 时返回 [`AAKAError::ClockSkew`]，
+    /// 否则返回偏差，由调用方决定如何提示。参考时间无法获取时返回错误。
+    pub async fn check(&self, client: &reqwest::Client) -> Result<ClockSkew, AAKAError> {
+        let reference = reference_time(&self.reference, client).await?;
+        let skew = ClockSkew {
+            secs: skew_secs(SystemTime::now(), reference),
+            max_secs: self.max_skew_secs,
+        };
+        if skew.exceeded() && self.enforce {
+            return Err(AAKAError::ClockSkew {
+                skew_secs: skew.secs,
+                max_secs: skew.max_secs,
+            });
+        }
+        Ok(skew)
+    }
+
+    /// 应用启动时调用：偏差过大时输出醒目的警告，参考时间无法获取时只警告；
+    /// 只有偏差超出且 `enforce` 时返回错误，调用方应拒绝启动
+    pub async fn run(&self, client: &reqwest::Client) -> Result<(), AAKAError> {
+        match self.check(client).await {
+            Ok(skew) if skew.exceeded() => tracing::warn!(
+                skew_secs = skew.secs,
+                max_skew_secs = skew.max_secs,
+                reference = %self.reference,
+                "LOCAL CLOCK IS OFF: authentication requests will be rejected as stale, fix the system time"
+            ),
+            Ok(skew) => tracing::info!(
+                skew_secs = skew.secs,
+                reference = %self.reference,
+                "Clock check passed"
+            ),
+            Err(e @ AAKAError::ClockSkew { .. }) => return Err(e),
+            Err(e) => tracing::warn!(
+                reference = %self.reference,
+                "Clock check skipped, reference time unavailable: {e}"
+            ),
+        }
+        Ok(())
+    }
+}
+
+/// `local - reference`，以秒为单位
+pub fn skew_secs(local: SystemTime, reference: SystemTime) -> i64 {
+    match local.duration_since(reference) {
+        Ok(ahead) => ahead.as_secs() as i64,
+        Err(behind) => -(behind.duration().as_secs() as i64),
+    }
+}
+
+/// 从 `reference`（见模块文档）读取当前时间
+pub async fn reference_time(
+    reference: &str,
+    client: &reqwest::Client,
+) -> Result<SystemTime, AAKAError> {
+    if let Some(host) = reference.strip_prefix("ntp://") {
+        return sntp_time(host).await;
+    }
+    if reference.starts_with("http://") || reference.starts_with("https://") {
+        return http_date(reference, client).await;
+    }
+    Err(AAKAError::InvalidInput(format!(
+        "Unsupported clock reference {reference}, expected ntp://, http:// or https://"
+    )))
+}
+
+async fn http_date(url: &str, client: &reqwest::Client) -> Result<SystemTime, AAKAError> {
+    let resp = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| AAKAError::Other(format!("Failed to reach clock reference {url}: {e}")))?;
+    let date = resp
+        .headers()
+        .get(reqwest::header::DATE)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| AAKAError::Other(format!("Clock reference {url} sent no Date header")))?;
+    httpdate::parse_http_date(date)
+        .map_err(|e| AAKAError::Other(format!("Invalid Date header from {url}: {e}")))
+}
+
+// `host`、`host:port`、`1.2.3.4`、`::1` 或 `[::1]:port`，解析为一个地址
+async fn ntp_addr(host: &str) -> Result<SocketAddr, AAKAError> {
+    if let Ok(addr) = host.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    if let Ok(ip) = host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, NTP_DEFAULT_PORT));
+    }
+    let target = match host.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => host.to_string(),
+        _ => format!("{host}:{NTP_DEFAULT_PORT}"),
+    };
+    tokio::net::lookup_host(&target)
+        .await
+        .map_err(|e| AAKAError::Other(format!("Failed to resolve NTP server {host}: {e}")))?
+        .next()
+        .ok_or_else(|| AAKAError::Other(format!("NTP server {host} has no address")))
+}
+
+// 64 位 NTP 时间戳：32 位秒 + 32 位小数
+fn ntp_timestamp(time: SystemTime) -> [u8; 8] {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = (since_epoch.as_secs() + NTP_UNIX_OFFSET_SECS) as u32;
+    let fraction = ((u64::from(since_epoch.subsec_nanos()) << 32) / 1_000_000_000) as u32;
+    let mut bytes = [0u8; 8];
+    bytes[..4].copy_from_slice(&secs.to_be_bytes());
+    bytes[4..].copy_from_slice(&fraction.to_be_bytes());
+    bytes
+}
+
+async fn sntp_time(host: &str) -> Result<SystemTime, AAKAError> {
+    let io_err = |e: std::io::Error| AAKAError::Other(format!("SNTP query to {host} failed: {e}"));
+    let invalid =
+        |reason: &str| AAKAError::Other(format!("Invalid SNTP reply from {host}: {reason}"));
+    let addr = ntp_addr(host).await?;
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = tokio::net::UdpSocket::bind(local).await.map_err(io_err)?;
+    socket.connect(addr).await.map_err(io_err)?;
+
+    // LI = 0，版本 4，模式 3（客户端）；发送时间戳由服务器原样放回应答的 Originate Timestamp，
+    // 据此丢弃不属于本次请求的应答
+    let mut packet = [0u8; 48];
+    packet[0] = 0b00_100_011;
+    let transmit = ntp_timestamp(SystemTime::now());
+    packet[40..48].copy_from_slice(&transmit);
+    socket.send(&packet).await.map_err(io_err)?;
+    let len = tokio::time::timeout(NTP_TIMEOUT, socket.recv(&mut packet))
+        .await
+        .map_err(|_| AAKAError::Other(format!("SNTP query to {host} timed out")))?
+        .map_err(io_err)?;
+    if len < 48 {
+        return Err(invalid("too short"));
+    }
+    // 模式 4（服务器）；LI = 3 表示服务器自身未同步
+    if packet[0] & 0b111 != 4 {
+        return Err(invalid("not a server reply"));
+    }
+    if packet[0] >> 6 == 3 {
+        return Err(invalid("server clock is not synchronized"));
+    }
+    // 层级 0 是 Kiss-o'-Death（服务器拒绝服务），16 及以上表示未同步
+    if !(1..16).contains(&packet[1]) {
+        return Err(invalid(&format!("stratum {}", packet[1])));
+    }
+    if packet[24..32] != transmit {
+        return Err(invalid("originate timestamp does not match the request"));
+    }
+
+    // 发送时间戳（Transmit Timestamp）：32 位秒 + 32 位小数
+    let secs = u64::from(u32::from_be_bytes(packet[40..44].try_into().unwrap()));
+    let fraction = u64::from(u32::from_be_bytes(packet[44..48].try_into().unwrap()));
+    let unix_secs = secs
+        .checked_sub(NTP_UNIX_OFFSET_SECS)
+        .ok_or_else(|| invalid("carries no valid time"))?;
+    let nanos = (fraction * 1_000_000_000) >> 32;
+    Ok(UNIX_EPOCH + Duration::new(unix_secs, nanos as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // 在发送前修改应答
+    type Tamper = fn(&mut [u8; 48]);
+
+    // 应答 SNTP 请求的服务器，`bind` 的地址族不可用时返回 `None`
+    async fn fake_ntp_server(bind: &str, time: SystemTime, tamper: Tamper) -> Option<String> {
+        let ntp = tokio::net::UdpSocket::bind(bind).await.ok()?;
+        let reference = format!("ntp://{}", ntp.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut packet = [0u8; 48];
+            loop {
+                let (_, from) = ntp.recv_from(&mut packet).await.unwrap();
+                let mut reply = [0u8; 48];
+                reply[0] = 0b00_100_100;
+                reply[1] = 2;
+                reply[24..32].copy_from_slice(&packet[40..48]);
+                reply[40..48].copy_from_slice(&ntp_timestamp(time));
+                tamper(&mut reply);
+                ntp.send_to(&reply, from).await.unwrap();
+            }
+        });
+        Some(reference)
+    }
+
+    #[tokio::test]
+    async fn test_clock_check_reports_excessive_skew() {
+        // 模拟的参考时间比本地时间慢一小时：HTTP 节点的 `Date` 头与 SNTP 服务器的应答
+        let behind = SystemTime::now() - Duration::from_secs(3600);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http_reference = format!("http://{}/", listener.local_addr().unwrap());
+        let date = httpdate::fmt_http_date(behind);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request).await;
+                let response =
+                    format!("HTTP/1.1 200 OK\r\nDate: {date}\r\nContent-Length: 0\r\n\r\n");
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        let ntp_reference = fake_ntp_server("127.0.0.1:0", behind, |_| {})
+            .await
+            .unwrap();
+
+        let client = reqwest::Client::new();
+        for reference in [http_reference, ntp_reference] {
+            let mut config = ClockCheckConfig {
+                reference: reference.clone(),
+                max_skew_secs: 300,
+                enforce: false,
+            };
+            // 只警告时照常启动，并报告偏差
+            let skew = config.check(&client).await.unwrap();
+            assert!(skew.exceeded(), "{reference}");
+            assert!((3590..=3610).contains(&skew.secs), "{reference}: {skew:?}");
+            assert!(config.run(&client).await.is_ok());
+
+            // 要求强制检查时拒绝启动
+            config.enforce = true;
+            assert!(matches!(
+                config.run(&client).await,
+                Err(AAKAError::ClockSkew { max_secs: 300, .. })
+            ));
+
+            // 偏差在允许范围内时通过
+            config.max_skew_secs = 7200;
+            assert!(!config.check(&client).await.unwrap().exceeded());
+        }
+
+        // 参考时间不可用时不阻止启动
+        let unreachable = ClockCheckConfig {
+            reference: "http://127.0.0.1:1/".to_string(),
+            max_skew_secs: 300,
+            enforce: true,
+        };
+        assert!(unreachable.run(&client).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_sntp_rejects_invalid_replies() {
+        let now = SystemTime::now();
+        let tampered: [(Tamper, &str); 4] = [
+            (|reply| reply[0] = 0b00_100_011, "not a server reply"),
+            (|reply| reply[0] |= 0b11 << 6, "not synchronized"),
+            (|reply| reply[1] = 0, "stratum 0"),
+            (|reply| reply[24] ^= 1, "originate timestamp"),
+        ];
+        for (tamper, expected) in tampered {
+            let reference = fake_ntp_server("127.0.0.1:0", now, tamper).await.unwrap();
+            let err = reference_time(&reference, &reqwest::Client::new())
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains(expected), "{err}");
+        }
+
+        // IPv6 服务器（环境不支持 IPv6 时跳过）
+        if let Some(reference) = fake_ntp_server("[::1]:0", now, |_| {}).await {
+            let time = reference_time(&reference, &reqwest::Client::new())
+                .await
+                .unwrap();
+            assert_eq!(skew_secs(time, now), 0);
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! RC、MS 和用户应用共用的分层配置加载。
+//!
+//! 配置按 配置文件 → 带前缀的环境变量 → 命令行 `--set key=value` 的顺序合并，后者覆盖前者。
+//! 配置文件不存在时直接跳过，只使用环境变量与字段默认值。
+//!
+//! 配置文件可以是 JSON、TOML 或 YAML，按扩展名识别。
+use crate::AAKAError;
+use figment::{
+    Figment,
+    providers::{Env, Format, Json, Serialized, Toml, Yaml},
+    value::Value,
+};
+use serde::de::DeserializeOwned;
+use std::path::{Path, PathBuf};
+
+/// 支持的配置文件扩展名，按查找顺序排列
+const EXTENSIONS: [&str; 4] = ["json", "toml", "yaml", "yml"];
+
+// 给定的文件不存在时，依次尝试同名的其他格式（例如默认的 `config.json` 不存在时使用 `config.toml`）
+fn resolve(path: &Path) -> PathBuf {
+    if path.exists() {
+        return path.to_path_buf();
+    }
+    EXTENSIONS
+        .iter()
+        .map(|ext| path.with_extension(ext))
+        .find(|candidate| candidate.exists())
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+fn file_provider(path: &Path) -> Result<Figment, AAKAError> {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("json")
+        .to_ascii_lowercase();
+    Ok(match ext.as_str() {
+        "json" => Figment::from(Json::file(path)),
+        "toml" => Figment::from(Toml::file(path)),
+        "yaml" | "yml" => Figment::from(Yaml::file(path)),
+        _ => {
+            return Err(AAKAError::InvalidInput(format!(
+                "Unsupported config file extension {ext:?}, expected one of {EXTENSIONS:?}"
+            )));
+        }
+    })
+}
+
+/// 加载配置。
+///
+/// # Arguments
+/// * `path` - 配置文件路径，按扩展名识别 JSON（默认）、TOML 或 YAML；不存在时先尝试同名的
+///   其他格式，都不存在则跳过。
+/// * `env_prefix` - 环境变量前缀，例如 `"MS_"` 时 `MS_MS_ADDR` 覆盖 `ms_addr`。
+/// * `overrides` - 命令行给出的 `key=value`，`value` 会按 JSON/TOML 字面量解析（例如数组、数字），
+///   解析失败时作为字符串。
+pub fn load<T: DeserializeOwned>(
+    path: impl AsRef<Path>,
+    env_prefix: &str,
+    overrides: &[String],
+) -> Result<T, AAKAError> {
+    let mut figment = file_provider(&resolve(path.as_ref()))?.merge(Env::prefixed(env_prefix));
+    for entry in overrides {
+        let Some((key, value)) = entry.split_once('=') else {
+            return Err(AAKAError::InvalidInput(format!(
+                "Invalid config override {entry:?}, expected KEY=VALUE"
+            )));
+        };
+        let value: Value = value.parse().unwrap_or_else(|never| match never {});
+        figment = figment.merge(Serialized::default(key.trim(), value));
+    }
+
+    figment
+        .extract()
+        .map_err(|e| AAKAError::InvalidInput(format!("Invalid configuration: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Config {
+        addr: String,
+        #[serde(default)]
+        threshold: usize,
+        #[serde(default)]
+        nodes: Vec<String>,
+    }
+
+    #[test]
+    #[allow(clippy::result_large_err)] // `figment::Jail` 的错误类型
+    fn test_layers_override_in_order() {
+        figment::Jail::expect_with(|jail| {
+            jail.create_file("config.json", r#"{ "addr": "file", "threshold": 1 }"#)?;
+            jail.set_env("TEST_THRESHOLD", "2");
+
+            let config: Config = load("config.json", "TEST_", &[]).unwrap();
+            assert_eq!(config.addr, "file");
+            assert_eq!(config.threshold, 2);
+
+            let overrides = ["addr=cli".to_string(), "nodes=[\"a\", \"b\"]".to_string()];
+            let config: Config = load("config.json", "TEST_", &overrides).unwrap();
+            assert_eq!(
+                config,
+                Config {
+                    addr: "cli".to_string(),
+                    threshold: 2,
+                    nodes: vec!["a".to_string(), "b".to_string()],
+                }
+            );
+
+            // 缺少配置文件时使用环境变量
+            jail.set_env("TEST_ADDR", "env");
+            let config: Config = load("missing.json", "TEST_", &[]).unwrap();
+            assert_eq!(config.addr, "env");
+
+            assert!(load::<Config>("config.json", "TEST_", &["addr".to_string()]).is_err());
+            Ok(())
+        });
+    }
+
+    #[test]
+    #[allow(clippy::result_large_err)] // `figment::Jail` 的错误类型
+    fn test_json_toml_yaml_load_equivalently() {
+        figment::Jail::expect_with(|jail| {
+            jail.create_file(
+                "rc.json",
+                r#"{ "addr": "0.0.0.0:3001", "threshold": 2, "nodes": ["a", "b"] }"#,
+            )?;
+            jail.create_file(
+                "rc.toml",
+                "addr = \"0.0.0.0:3001\"\nthreshold = 2\nnodes = [\"a\", \"b\"]\n",
+            )?;
+            jail.create_file(
+                "rc.yaml",
+                "addr: \"0.0.0.0:3001\"\nthreshold: 2\nnodes:\n  - a\n  - b\n",
+            )?;
+
+            let expected = Config {
+                addr: "0.0.0.0:3001".to_string(),
+                threshold: 2,
+                nodes: vec!["a".to_string(), "b".to_string()],
+            };
+            for path in ["rc.json", "rc.toml", "rc.yaml"] {
+                let config: Config = load(path, "TEST_", &[]).unwrap();
+                assert_eq!(config, expected, "{path}");
+            }
+
+            // 默认的 JSON 文件不存在时使用同名的 TOML 文件
+            jail.create_file("only.toml", "addr = \"toml\"\n")?;
+            let config: Config = load("only.json", "TEST_", &[]).unwrap();
+            assert_eq!(config.addr, "toml");
+
+            jail.create_file("rc.ini", "addr = ini\n")?;
+            assert!(load::<Config>("rc.ini", "TEST_", &[]).is_err());
+            Ok(())
+        });
+    }
+}
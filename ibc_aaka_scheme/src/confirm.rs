@@ -0,0 +1,85 @@
+//! 可选的显式密钥确认：握手完成后双方各发送一个由会话密钥派生的确认值并校验对方的值，
+//! 使协议成为带密钥确认的 AKA。
+//!
+//! 确认值为 [`SessionKey::expand`]`("confirm-c2s")`（用户发给服务器）与
+//! `expand("confirm-s2c")`（服务器发给用户），两个方向使用不同的标签，任何一方都不能把
+//! 收到的值原样反射回去。确认值是单向哈希的输出，不暴露会话密钥，也与加密消息的子密钥相互独立。
+use crate::{AAKAError, SessionKey};
+
+/// 确认值的长度（字节）
+pub const CONFIRM_LEN: usize = 32;
+
+/// 确认值的方向
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfirmDirection {
+    /// 用户发给服务器
+    ClientToServer,
+    /// 服务器发给用户
+    ServerToClient,
+}
+
+impl ConfirmDirection {
+    fn label(self) -> &'static [u8] {
+        match self {
+            ConfirmDirection::ClientToServer => b"confirm-c2s",
+            ConfirmDirection::ServerToClient => b"confirm-s2c",
+        }
+    }
+}
+
+/// 计算 `direction` 方向的确认值
+pub fn build_confirm(key: &SessionKey, direction: ConfirmDirection) -> [u8; CONFIRM_LEN] {
+    key.expand(direction.label())
+}
+
+/// 校验对方发来的 `direction` 方向的确认值，比较耗时与内容无关。
+/// 不一致时返回 [`AAKAError::KeyConfirmationFailed`]，说明双方的会话密钥不同。
+pub fn verify_confirm(
+    key: &SessionKey,
+    direction: ConfirmDirection,
+    confirm: &[u8],
+) -> Result<(), AAKAError> {
+    let expected = build_confirm(key, direction);
+    let diff = expected
+        .iter()
+        .zip(confirm)
+        .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+    if confirm.len() != CONFIRM_LEN || diff != 0 {
+        return Err(AAKAError::KeyConfirmationFailed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_confirm_detects_mismatched_keys() {
+        let user_key = SessionKey(vec![7; 32]);
+        let server_key = SessionKey(vec![7; 32]);
+        let other_key = SessionKey(vec![8; 32]);
+
+        // 双方密钥一致时两个方向都通过
+        for direction in [
+            ConfirmDirection::ClientToServer,
+            ConfirmDirection::ServerToClient,
+        ] {
+            let confirm = build_confirm(&user_key, direction);
+            assert!(verify_confirm(&server_key, direction, &confirm).is_ok());
+            assert_eq!(
+                verify_confirm(&other_key, direction, &confirm),
+                Err(AAKAError::KeyConfirmationFailed)
+            );
+        }
+
+        // 方向不同的确认值不能互换，截断的确认值同样被拒绝
+        let c2s = build_confirm(&user_key, ConfirmDirection::ClientToServer);
+        assert_ne!(
+            c2s,
+            build_confirm(&user_key, ConfirmDirection::ServerToClient)
+        );
+        assert!(verify_confirm(&server_key, ConfirmDirection::ServerToClient, &c2s).is_err());
+        assert!(verify_confirm(&server_key, ConfirmDirection::ClientToServer, &c2s[..16]).is_err());
+    }
+}
@@ -0,0 +1,73 @@
+//! RC 与 MS 共用的跨域（CORS）配置，供浏览器中的前端直接调用。
+use crate::{
+    AAKAError, envelope::ENVELOPE_HEADER, request_id::REQUEST_ID_HEADER,
+    wire::POINT_ENCODING_HEADER,
+};
+use axum::http::{HeaderName, HeaderValue, Method, header};
+use serde::Deserialize;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CorsConfig {
+    /// 允许的来源，例如 `https://app.example.com`；`"*"` 表示任意来源
+    pub allowed_origins: Vec<String>,
+    /// 允许的方法，默认 GET 与 POST
+    #[serde(default = "default_cors_methods")]
+    pub allowed_methods: Vec<String>,
+    /// 允许的请求头，默认为 `content-type` 以及信封和点编码协商用的请求头
+    #[serde(default = "default_cors_headers")]
+    pub allowed_headers: Vec<String>,
+}
+
+fn default_cors_methods() -> Vec<String> {
+    vec!["GET".to_string(), "POST".to_string()]
+}
+
+fn default_cors_headers() -> Vec<String> {
+    [
+        header::CONTENT_TYPE.as_str(),
+        ENVELOPE_HEADER,
+        POINT_ENCODING_HEADER,
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn invalid(what: &str, value: &str) -> AAKAError {
+    AAKAError::InvalidInput(format!("Invalid CORS {what}: {value:?}"))
+}
+
+impl CorsConfig {
+    pub fn layer(&self) -> Result<CorsLayer, AAKAError> {
+        let origins = if self.allowed_origins.iter().any(|origin| origin == "*") {
+            AllowOrigin::any()
+        } else {
+            AllowOrigin::list(
+                self.allowed_origins
+                    .iter()
+                    .map(|origin| origin.parse().map_err(|_| invalid("origin", origin)))
+                    .collect::<Result<Vec<HeaderValue>, _>>()?,
+            )
+        };
+        let methods = self
+            .allowed_methods
+            .iter()
+            .map(|method| method.parse().map_err(|_| invalid("method", method)))
+            .collect::<Result<Vec<Method>, _>>()?;
+        let headers = self
+            .allowed_headers
+            .iter()
+            .map(|name| name.parse().map_err(|_| invalid("header", name)))
+            .collect::<Result<Vec<HeaderName>, _>>()?;
+
+        Ok(CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            // 客户端据此决定何时重试 503，并在报告问题时附上请求 ID
+            .expose_headers([
+                header::RETRY_AFTER,
+                HeaderName::from_static(REQUEST_ID_HEADER),
+            ]))
+    }
+}
@@ -0,0 +1,85 @@
+//! 认证请求 HTTP 请求体的外层 AEAD 信封（ChaCha20-Poly1305）。
+//!
+//! 信封密钥由系统参数与 MS 的 ID 派生，用户和 MS 无需额外交换密钥。
+//! 系统参数是公开的，信封只能防止不知道系统参数的中间代理读取或篡改请求体，
+//! 协议本身的安全性不依赖信封。
+//!
+//! 信封格式为 `nonce (12 字节) || 密文`，请求带 [`ENVELOPE_HEADER`] 头，`Content-Type` 仍描述内层请求体。
+use crate::{AAKAError, SystemParameters, wire};
+use chacha20poly1305::{
+    AeadCore, ChaCha20Poly1305, Key, KeyInit, Nonce,
+    aead::{Aead, OsRng},
+};
+use digest::Digest;
+
+/// 标记请求体为信封的请求头，取值为 [`ENVELOPE_ALGORITHM`]
+pub const ENVELOPE_HEADER: &str = "x-aaka-envelope";
+pub const ENVELOPE_ALGORITHM: &str = "chacha20poly1305";
+
+const NONCE_LEN: usize = 12;
+
+pub struct EnvelopeKey(Key);
+
+impl EnvelopeKey {
+    /// 信封密钥：SHA3-256("AAKA-envelope" || 系统参数的原始字节编码 || IDms)
+    pub fn derive(params: &SystemParameters, ms_id: &[u8]) -> Result<Self, AAKAError> {
+        let digest = sha3::Sha3_256::new()
+            .chain_update(b"AAKA-envelope")
+            .chain_update(wire::to_bytes(params)?)
+            .chain_update(ms_id)
+            .finalize();
+        Ok(Self(digest))
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, AAKAError> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = ChaCha20Poly1305::new(&self.0)
+            .encrypt(&nonce, plaintext)
+            .map_err(|e| AAKAError::CryptoError(format!("Failed to seal envelope: {e}")))?;
+
+        let mut envelope = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        envelope.extend_from_slice(&nonce);
+        envelope.extend_from_slice(&ciphertext);
+        Ok(envelope)
+    }
+
+    /// 解开信封；被篡改、截断或用其他密钥加密的信封都返回错误
+    pub fn open(&self, envelope: &[u8]) -> Result<Vec<u8>, AAKAError> {
+        if envelope.len() < NONCE_LEN {
+            return Err(AAKAError::Deserialization(format!(
+                "Envelope too short: {} bytes",
+                envelope.len()
+            )));
+        }
+        let (nonce, ciphertext) = envelope.split_at(NONCE_LEN);
+        ChaCha20Poly1305::new(&self.0)
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| AAKAError::CryptoError("Envelope authentication failed".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rc;
+    use ark_std::rand::{SeedableRng, rngs::StdRng};
+
+    #[test]
+    fn test_envelope_roundtrip_and_tampering() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let (params, _) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        let key = EnvelopeKey::derive(&params, b"server").unwrap();
+
+        let envelope = key.seal(b"request body").unwrap();
+        assert_eq!(key.open(&envelope).unwrap(), b"request body");
+
+        let mut tampered = envelope.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(key.open(&tampered).is_err());
+        assert!(key.open(&envelope[..NONCE_LEN - 1]).is_err());
+
+        // 发给其他 MS 的信封无法解开
+        let other = EnvelopeKey::derive(&params, b"other").unwrap();
+        assert!(other.open(&envelope).is_err());
+    }
+}
@@ -1,11 +1,230 @@
-use crate::{AAKAError, G1Point, GtPoint, ScalarField}; // Import types from lib.rs
+use crate::{AAKAError, G1AffinePoint, G1Point, GtPoint, ScalarField}; // Import types from lib.rs
+use ark_bls12_381::G2Affine;
 use ark_ec::CurveGroup; // Need this trait for point coordinates/serialization
 use ark_ff::PrimeField; // For field operations
 use ark_serialize::CanonicalSerialize; // For serializing points/field elements
 use ark_std::vec::Vec; // Use ark_std's Vec
 
+use ark_serialize::{
+    CanonicalDeserialize, Compress, Read, SerializationError, Valid, Validate, Write,
+};
 use digest::Digest; // Import Digest trait
-use sha3::Sha3_256; // Use SHA3-256 as the base hash function
+use serde::{Deserialize, Serialize};
+use sha3::{Sha3_256, Shake128, Shake256}; // Use SHA3-256 as the base hash function
+use std::str::FromStr;
+
+// --- Output Lengths ---
+
+/// Output length in bytes of the base hash (SHA3-256) behind `h5` and `h_sid`.
+/// `h5` produces longer outputs by concatenating further counter-tagged digests; `h2` squeezes
+/// its keystream from the XOF recorded in the system parameters.
+pub const HASH_OUTPUT_BYTES: usize = 32;
+
+/// Length in bytes of a serialized scalar, i.e. of the outputs of `h0`, `h1`, `h3` and `h4`.
+pub const SCALAR_BYTES: usize = 32;
+
+/// Number of uniform bytes `h0`, `h1`, `h3` and `h4` reduce mod q (see [`hash_to_scalar`]).
+///
+/// q is a 255-bit prime, so reducing a 32-byte digest maps some residues from two preimages and
+/// others from one; reducing 64 bytes leaves a statistical distance of about 2^-257 from uniform.
+#[cfg(not(feature = "legacy-hash-to-field"))]
+pub const HASH_TO_FIELD_BYTES: usize = 64;
+/// Number of bytes `h0`, `h1`, `h3` and `h4` reduce mod q: the `legacy-hash-to-field` feature
+/// keeps the original, slightly biased reduction of a single SHA3-256 digest.
+#[cfg(feature = "legacy-hash-to-field")]
+pub const HASH_TO_FIELD_BYTES: usize = HASH_OUTPUT_BYTES;
+
+/// Length in bytes of a compressed G1 point (48 on BLS12-381), e.g. `Ru` and `X` inside `N`.
+pub fn compressed_g1_len() -> usize {
+    G1AffinePoint::default().compressed_size()
+}
+
+/// Length in bytes of a compressed G2 point (96 on BLS12-381), e.g. `SIDms`.
+pub fn compressed_g2_len() -> usize {
+    G2Affine::default().compressed_size()
+}
+
+// --- Keystream XOF ---
+
+/// `h2` 生成密钥流所用的 XOF，记录在 [`crate::SystemParameters`] 中，用户与服务器必须一致，
+/// 否则双方得到的密钥流不同，服务器无法解开 `N`。
+///
+/// 默认为 SHAKE256（与基础哈希 SHA3-256 同为 256 位安全强度），也是第一个使用 XOF 的版本
+/// 所采用的算法；SHAKE128 更快，安全余量为 128 位。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Xof {
+    Shake128,
+    #[default]
+    Shake256,
+}
+
+impl Xof {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Xof::Shake128 => "shake128",
+            Xof::Shake256 => "shake256",
+        }
+    }
+
+    pub(crate) fn tag(self) -> u8 {
+        match self {
+            Xof::Shake128 => 1,
+            Xof::Shake256 => 2,
+        }
+    }
+}
+
+impl FromStr for Xof {
+    type Err = AAKAError;
+
+    fn from_str(s: &str) -> Result<Self, AAKAError> {
+        match s.trim() {
+            "shake128" => Ok(Xof::Shake128),
+            "shake256" => Ok(Xof::Shake256),
+            other => Err(AAKAError::InvalidInput(format!(
+                "Unknown XOF {other:?}, expected \"shake128\" or \"shake256\""
+            ))),
+        }
+    }
+}
+
+// 以单字节标签参与 `SystemParameters` 的规范序列化，因此也会反映在参数指纹中
+impl CanonicalSerialize for Xof {
+    fn serialize_with_mode<W: Write>(
+        &self,
+        writer: W,
+        compress: Compress,
+    ) -> Result<(), SerializationError> {
+        self.tag().serialize_with_mode(writer, compress)
+    }
+
+    fn serialized_size(&self, compress: Compress) -> usize {
+        self.tag().serialized_size(compress)
+    }
+}
+
+impl Valid for Xof {
+    fn check(&self) -> Result<(), SerializationError> {
+        Ok(())
+    }
+}
+
+impl CanonicalDeserialize for Xof {
+    fn deserialize_with_mode<R: Read>(
+        reader: R,
+        compress: Compress,
+        validate: Validate,
+    ) -> Result<Self, SerializationError> {
+        match u8::deserialize_with_mode(reader, compress, validate)? {
+            1 => Ok(Xof::Shake128),
+            2 => Ok(Xof::Shake256),
+            _ => Err(SerializationError::InvalidData),
+        }
+    }
+}
+
+// --- Deployment Separator ---
+
+/// [`DeploymentInfo`] 的最大长度（字节）
+pub const MAX_DEPLOYMENT_INFO_LEN: usize = 255;
+
+/// 部署标识（如 `"acme-prod-eu"`），记录在 [`crate::SystemParameters`] 中并折入 `h5`，
+/// 即使两个部署的握手输入完全相同，得到的会话密钥也不同。用户与服务器必须一致。
+///
+/// 与每个会话的 `context` 不同，它在 RC `/setup` 时确定，此后不变。空标识不参与
+/// 参数的序列化与 `h5`，参数编码、指纹与会话密钥都与引入该字段之前相同。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeploymentInfo(String);
+
+impl DeploymentInfo {
+    pub fn new(info: impl Into<String>) -> Result<Self, AAKAError> {
+        let info = info.into();
+        if info.len() > MAX_DEPLOYMENT_INFO_LEN {
+            return Err(AAKAError::InvalidInput(format!(
+                "Deployment info is {} bytes, at most {MAX_DEPLOYMENT_INFO_LEN} are allowed",
+                info.len()
+            )));
+        }
+        Ok(Self(info))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+// 非空时以单字节长度加 UTF-8 内容追加在参数编码的末尾；空标识不写入任何字节，
+// 因此旧的参数编码（到 XOF 标签为止）解析为空标识
+impl CanonicalSerialize for DeploymentInfo {
+    fn serialize_with_mode<W: Write>(
+        &self,
+        mut writer: W,
+        _compress: Compress,
+    ) -> Result<(), SerializationError> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let len = u8::try_from(self.0.len()).map_err(|_| SerializationError::InvalidData)?;
+        writer.write_all(&[len])?;
+        writer.write_all(self.as_bytes())?;
+        Ok(())
+    }
+
+    fn serialized_size(&self, _compress: Compress) -> usize {
+        if self.is_empty() { 0 } else { 1 + self.0.len() }
+    }
+}
+
+impl Valid for DeploymentInfo {
+    fn check(&self) -> Result<(), SerializationError> {
+        Ok(())
+    }
+}
+
+impl CanonicalDeserialize for DeploymentInfo {
+    fn deserialize_with_mode<R: Read>(
+        mut reader: R,
+        _compress: Compress,
+        _validate: Validate,
+    ) -> Result<Self, SerializationError> {
+        let mut len = [0u8; 1];
+        if reader.read(&mut len)? == 0 {
+            return Ok(Self::default());
+        }
+        // 空标识只能以不写入任何字节表示，保证编码唯一
+        if len[0] == 0 {
+            return Err(SerializationError::InvalidData);
+        }
+        let mut info = vec![0u8; usize::from(len[0])];
+        reader.read_exact(&mut info)?;
+        String::from_utf8(info)
+            .map(Self)
+            .map_err(|_| SerializationError::InvalidData)
+    }
+}
+
+// 依次吸收 `parts` 后挤出 `output_len` 字节
+fn squeeze<H: Default + digest::Update + digest::ExtendableOutput>(
+    parts: &[&[u8]],
+    output_len: usize,
+) -> Vec<u8> {
+    let mut hasher = H::default();
+    for part in parts {
+        hasher.update(part);
+    }
+    let mut out = vec![0u8; output_len];
+    hasher.finalize_xof_into(&mut out);
+    out
+}
 
 // --- Domain Separation Constants ---
 // Using unique prefixes for each hash function to ensure domain separation
@@ -15,6 +234,10 @@ const H2_DOMAIN_SEP: &[u8] = b"IBC_AAKA_H2";
 const H3_DOMAIN_SEP: &[u8] = b"IBC_AAKA_H3";
 const H4_DOMAIN_SEP: &[u8] = b"IBC_AAKA_H4";
 const H5_DOMAIN_SEP: &[u8] = b"IBC_AAKA_H5";
+const SID_DOMAIN_SEP: &[u8] = b"IBC_AAKA_SID";
+
+/// Length in bytes of a session ID, see [`h_sid`].
+pub const SESSION_ID_BYTES: usize = 16;
 
 // Helper function to serialize G1 points safely
 fn serialize_g1(point: &G1Point) -> Result<Vec<u8>, AAKAError> {
@@ -32,78 +255,72 @@ fn serialize_gt(point: &GtPoint) -> Result<Vec<u8>, AAKAError> {
     Ok(buffer)
 }
 
+// --- Hash-to-field ---
+
+/// `expand_message_xof` from RFC 9380 (section 5.3.2) with SHAKE256:
+/// `SHAKE256(msg || I2OSP(len, 2) || DST || I2OSP(len(DST), 1), len)`, where `msg` is the
+/// concatenation of `parts`.
+pub fn expand_message_xof(dst: &[u8], parts: &[&[u8]], len: usize) -> Result<Vec<u8>, AAKAError> {
+    let len_bytes = u16::try_from(len)
+        .map_err(|_| AAKAError::HashError(format!("expand_message_xof output too long: {len}")))?
+        .to_be_bytes();
+    let dst_len = [u8::try_from(dst.len())
+        .map_err(|_| AAKAError::HashError("expand_message_xof DST too long".to_string()))?];
+    let mut input = parts.to_vec();
+    input.extend([&len_bytes[..], dst, &dst_len[..]]);
+    Ok(squeeze::<Shake256>(&input, len))
+}
+
+/// Hash `parts` to a scalar under the domain separation tag `dst`, the `hash_to_field`
+/// construction of RFC 9380 with a single output element: expand to [`HASH_TO_FIELD_BYTES`]
+/// uniform bytes, then reduce mod q.
+#[cfg(not(feature = "legacy-hash-to-field"))]
+pub fn hash_to_scalar(dst: &[u8], parts: &[&[u8]]) -> Result<ScalarField, AAKAError> {
+    let uniform = expand_message_xof(dst, parts, HASH_TO_FIELD_BYTES)?;
+    Ok(ScalarField::from_be_bytes_mod_order(&uniform))
+}
+
+/// Hash `parts` to a scalar under the domain separation tag `dst`: the original reduction of
+/// `SHA3-256(dst || parts)` mod q, kept by the `legacy-hash-to-field` feature so that keys
+/// issued before the wide reduction keep working.
+#[cfg(feature = "legacy-hash-to-field")]
+pub fn hash_to_scalar(dst: &[u8], parts: &[&[u8]]) -> Result<ScalarField, AAKAError> {
+    let mut hasher = Sha3_256::new();
+    hasher.update(dst);
+    for part in parts {
+        hasher.update(part);
+    }
+    Ok(ScalarField::from_be_bytes_mod_order(
+        hasher.finalize().as_slice(),
+    ))
+}
+
 // --- Hash Function Implementations ---
 
 /// h0: {0,1}^* × G → Z_q^*
 /// Input: IDu || Ru
 pub fn h0(id_u: &[u8], r_u: &G1Point) -> Result<ScalarField, AAKAError> {
     let r_u_bytes = serialize_g1(r_u)?;
-
-    let mut hasher = Sha3_256::new();
-    hasher.update(H0_DOMAIN_SEP);
-    hasher.update(id_u);
-    hasher.update(&r_u_bytes);
-    let hash_output = hasher.finalize();
-
-    // Convert hash output bytes to a ScalarField element (mod q)
-    // Using from_be_bytes_mod_order ensures the result is in the field
-    Ok(ScalarField::from_be_bytes_mod_order(hash_output.as_slice()))
+    hash_to_scalar(H0_DOMAIN_SEP, &[id_u, &r_u_bytes])
 }
 
 /// h1: {0,1}^* → Z_q^*
 /// Input: IDms
 pub fn h1(id_ms: &[u8]) -> Result<ScalarField, AAKAError> {
-    let mut hasher = Sha3_256::new();
-    hasher.update(H1_DOMAIN_SEP);
-    hasher.update(id_ms);
-    let hash_output = hasher.finalize();
-
-    Ok(ScalarField::from_be_bytes_mod_order(hash_output.as_slice()))
+    hash_to_scalar(H1_DOMAIN_SEP, &[id_ms])
 }
 
 /// h2: GT → {0,1}^* × G × G (Output is raw bytes for XOR)
 /// Input: gx = g^x = e(P, P)^x
-/// Output length must match |IDu| + |Ru| + |X|
-pub fn h2(g_x: &GtPoint, output_len: usize) -> Result<Vec<u8>, AAKAError> {
+/// Output length must match |IDu| + |Ru| + |X|; the keystream is squeezed from `xof`.
+pub fn h2(g_x: &GtPoint, output_len: usize, xof: Xof) -> Result<Vec<u8>, AAKAError> {
     let gx_bytes = serialize_gt(g_x)?;
+    let parts: [&[u8]; 2] = [H2_DOMAIN_SEP, &gx_bytes];
 
-    let mut hasher = Sha3_256::new();
-    hasher.update(H2_DOMAIN_SEP);
-    hasher.update(&gx_bytes);
-    let hash_output = hasher.finalize(); // SHA3-256 outputs 32 bytes
-
-    // If required output length is different from hash output length,
-    // we might need a KDF or extend the hash. For simplicity,
-    // we'll require the hash output length to be sufficient or use a KDF-like approach.
-    // A simple (but not ideal) approach if len > 32: re-hash iteratively.
-    // A better approach: Use a KDF like HKDF or Blake2X.
-    // Let's use a simple iterative hashing if needed (demonstration purpose).
-    let mut result_bytes = Vec::with_capacity(output_len);
-    result_bytes.extend_from_slice(hash_output.as_slice());
-
-    let mut counter: u32 = 0;
-    while result_bytes.len() < output_len {
-        let mut hasher_ext = Sha3_256::new();
-        hasher_ext.update(H2_DOMAIN_SEP); // Keep domain separation consistent
-        hasher_ext.update(&gx_bytes);
-        hasher_ext.update(counter.to_be_bytes()); // Add counter to vary input
-        let next_hash = hasher_ext.finalize();
-        result_bytes.extend_from_slice(next_hash.as_slice());
-        counter += 1;
-        if counter > 100 {
-            // Safety break to prevent infinite loops
-            return Err(AAKAError::HashError(
-                "H2 output generation loop limit reached".to_string(),
-            ));
-        }
-    }
-
-    // Truncate to the exact required length
-    result_bytes.truncate(output_len);
-    Ok(result_bytes)
-
-    // Note: For production, consider using a dedicated XOF (e.g., SHAKE) or KDF (e.g., HKDF-Expand with SHA3-256).
-    // This iterative approach is less standard but works for demonstration.
+    Ok(match xof {
+        Xof::Shake128 => squeeze::<Shake128>(&parts, output_len),
+        Xof::Shake256 => squeeze::<Shake256>(&parts, output_len),
+    })
 }
 
 /// h3: {0,1}^* × G × G × {0,1}^* → Z_q^*
@@ -117,16 +334,7 @@ pub fn h3(
     let r_u_bytes = serialize_g1(r_u)?;
     let x_pub_bytes = serialize_g1(x_pub)?;
     let ts_bytes = timestamp.to_be_bytes();
-
-    let mut hasher = Sha3_256::new();
-    hasher.update(H3_DOMAIN_SEP);
-    hasher.update(id_u);
-    hasher.update(&r_u_bytes);
-    hasher.update(&x_pub_bytes);
-    hasher.update(ts_bytes);
-    let hash_output = hasher.finalize();
-
-    Ok(ScalarField::from_be_bytes_mod_order(hash_output.as_slice()))
+    hash_to_scalar(H3_DOMAIN_SEP, &[id_u, &r_u_bytes, &x_pub_bytes, &ts_bytes])
 }
 
 /// h4: {0,1}^* × {0,1}^* × G × G × {0,1}^* → Z_q^*
@@ -141,22 +349,22 @@ pub fn h4(
     let x_pub_bytes = serialize_g1(x_pub)?;
     let y_pub_bytes = serialize_g1(y_pub)?;
     let ts_bytes = timestamp.to_be_bytes();
-
-    let mut hasher = Sha3_256::new();
-    hasher.update(H4_DOMAIN_SEP);
-    hasher.update(id_u);
-    hasher.update(id_ms);
-    hasher.update(&x_pub_bytes);
-    hasher.update(&y_pub_bytes);
-    hasher.update(ts_bytes);
-    let hash_output = hasher.finalize();
-
-    Ok(ScalarField::from_be_bytes_mod_order(hash_output.as_slice()))
+    hash_to_scalar(
+        H4_DOMAIN_SEP,
+        &[id_u, id_ms, &x_pub_bytes, &y_pub_bytes, &ts_bytes],
+    )
 }
 
 /// h5: GT × {0,1}^* × {0,1}^* × G × G → {0,1}^k (Output is Session Key)
-/// Input: K = Kms-u = Ku-ms || IDu || IDms || X || Y
+/// Input: K = Kms-u = Ku-ms || IDu || IDms || X || Y [|| "D" || len(deployment) || deployment]
+/// [|| len(context) || context]
 /// k is the desired key length in bytes (e.g., 16 for AES-128, 32 for AES-256)
+///
+/// A non-empty `deployment` (the [`DeploymentInfo`] of the system parameters) separates keys
+/// derived in different deployments; a non-empty `context` (e.g. `b"voip"`) separates keys
+/// derived from the same handshake for different application protocols. Empty values give the
+/// same key as before either existed.
+#[allow(clippy::too_many_arguments)]
 pub fn h5(
     k_intermediate_g1: &G1Point, // <-- **Changed type to G1Point**
     id_u: &[u8],
@@ -164,11 +372,15 @@ pub fn h5(
     x_pub: &G1Point, // X = xP
     y_pub: &G1Point, // Y = yP
     key_len_bytes: usize,
+    deployment: &[u8],
+    context: &[u8],
 ) -> Result<Vec<u8>, AAKAError> {
     let k_bytes = serialize_g1(k_intermediate_g1)?; // <-- **Use serialize_g1**
     let x_pub_bytes = serialize_g1(x_pub)?;
     let y_pub_bytes = serialize_g1(y_pub)?;
 
+    // 公共输入只吸收一次，之后的每个分块从克隆的哈希状态继续，不再重复哈希整个输入；
+    // 输出与逐块重新计算 H5_DOMAIN_SEP || K || IDu || IDms || X || Y [|| counter] 完全相同
     let mut hasher = Sha3_256::new();
     hasher.update(H5_DOMAIN_SEP);
     hasher.update(&k_bytes); // Hash the G1 point bytes
@@ -176,21 +388,25 @@ pub fn h5(
     hasher.update(id_ms);
     hasher.update(&x_pub_bytes);
     hasher.update(&y_pub_bytes);
-    let hash_output = hasher.finalize(); // 32 bytes
+    if !deployment.is_empty() {
+        // context 的长度前缀以 0x00 开头，标签 "D" 保证两者不会混淆
+        hasher.update(b"D");
+        hasher.update((deployment.len() as u64).to_be_bytes());
+        hasher.update(deployment);
+    }
+    if !context.is_empty() {
+        // 带上长度前缀，避免 context 与后面的计数器拼接产生歧义
+        hasher.update((context.len() as u64).to_be_bytes());
+        hasher.update(context);
+    }
+    let hash_output = hasher.clone().finalize(); // 32 bytes
 
-    // KDF logic (iterative hash) remains the same for generating desired length
-    let mut result_bytes = Vec::with_capacity(key_len_bytes);
+    let mut result_bytes = Vec::with_capacity(key_len_bytes.max(HASH_OUTPUT_BYTES));
     result_bytes.extend_from_slice(hash_output.as_slice());
 
     let mut counter: u32 = 0;
     while result_bytes.len() < key_len_bytes {
-        let mut hasher_ext = Sha3_256::new();
-        hasher_ext.update(H5_DOMAIN_SEP);
-        hasher_ext.update(&k_bytes); // Re-use same base input
-        hasher_ext.update(id_u);
-        hasher_ext.update(id_ms);
-        hasher_ext.update(&x_pub_bytes);
-        hasher_ext.update(&y_pub_bytes);
+        let mut hasher_ext = hasher.clone();
         hasher_ext.update(counter.to_be_bytes()); // Vary input
         let next_hash = hasher_ext.finalize();
         result_bytes.extend_from_slice(next_hash.as_slice());
@@ -204,3 +420,28 @@ pub fn h5(
     result_bytes.truncate(key_len_bytes);
     Ok(result_bytes)
 }
+
+/// h_sid: {0,1}^* × {0,1}^* × G × G → {0,1}^128 (Output is the session ID)
+/// Input: IDu || IDms || X || Y
+/// Only public handshake values are hashed, so the ID can be logged and shared freely.
+pub fn h_sid(
+    id_u: &[u8],
+    id_ms: &[u8],
+    x_pub: &G1Point, // X = xP
+    y_pub: &G1Point, // Y = yP
+) -> Result<[u8; SESSION_ID_BYTES], AAKAError> {
+    let x_pub_bytes = serialize_g1(x_pub)?;
+    let y_pub_bytes = serialize_g1(y_pub)?;
+
+    let mut hasher = Sha3_256::new();
+    hasher.update(SID_DOMAIN_SEP);
+    hasher.update(id_u);
+    hasher.update(id_ms);
+    hasher.update(&x_pub_bytes);
+    hasher.update(&y_pub_bytes);
+    let hash_output = hasher.finalize();
+
+    let mut id = [0u8; SESSION_ID_BYTES];
+    id.copy_from_slice(&hash_output[..SESSION_ID_BYTES]);
+    Ok(id)
+}
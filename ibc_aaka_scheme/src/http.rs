@@ -0,0 +1,43 @@
+//! MS 和用户端共用的 HTTP 客户端配置。
+//!
+//! `reqwest::Client::new()` 不设置超时，RC 或 MS 卡住时调用方会无限期等待。应用在启动时按配置
+//! 构建一个 `reqwest::Client` 并在各处复用（内部共享连接池，克隆开销很小）。
+use crate::AAKAError;
+use serde::Deserialize;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HttpClientConfig {
+    /// 建立连接的超时（毫秒）
+    pub connect_timeout_ms: u64,
+    /// 整个请求（连接、发送与读取响应）的超时（毫秒）
+    pub timeout_ms: u64,
+    /// 空闲连接在连接池中保留的时间（秒）
+    pub pool_idle_timeout_secs: u64,
+    /// 每个主机最多保留的空闲连接数
+    pub pool_max_idle_per_host: usize,
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout_ms: 5_000,
+            timeout_ms: 30_000,
+            pool_idle_timeout_secs: 90,
+            pool_max_idle_per_host: 8,
+        }
+    }
+}
+
+impl HttpClientConfig {
+    pub fn build(&self) -> Result<reqwest::Client, AAKAError> {
+        reqwest::Client::builder()
+            .connect_timeout(Duration::from_millis(self.connect_timeout_ms))
+            .timeout(Duration::from_millis(self.timeout_ms))
+            .pool_idle_timeout(Duration::from_secs(self.pool_idle_timeout_secs))
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .build()
+            .map_err(|e| AAKAError::InvalidInput(format!("Invalid HTTP client configuration: {e}")))
+    }
+}
@@ -0,0 +1,122 @@
+//! 配置与 HTTP 接口中的 ID 编码。
+//!
+//! 库以字节处理用户与服务器 ID（`IDu`、`IDms`），但配置文件和 JSON 只能保存字符串。默认的 `utf8`
+//! 只能表示合法的 UTF-8 字符串，含 NUL 或非 UTF-8 字节的二进制 ID 用 `hex` 或 `base64` 编码。
+use crate::AAKAError;
+use base64::{Engine, prelude::BASE64_STANDARD};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// ID 字符串的编码
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdEncoding {
+    #[default]
+    Utf8,
+    Hex,
+    /// 标准字母表，带填充
+    Base64,
+}
+
+impl IdEncoding {
+    pub fn decode(self, id: &str) -> Result<Vec<u8>, AAKAError> {
+        match self {
+            IdEncoding::Utf8 => Ok(id.as_bytes().to_vec()),
+            IdEncoding::Hex => {
+                hex::decode(id).map_err(|e| AAKAError::InvalidInput(format!("Invalid hex ID: {e}")))
+            }
+            IdEncoding::Base64 => BASE64_STANDARD
+                .decode(id)
+                .map_err(|e| AAKAError::InvalidInput(format!("Invalid base64 ID: {e}"))),
+        }
+    }
+
+    /// 按本编码表示 `id`；`Utf8` 要求 `id` 是合法的 UTF-8
+    pub fn encode(self, id: &[u8]) -> Result<String, AAKAError> {
+        match self {
+            IdEncoding::Utf8 => String::from_utf8(id.to_vec()).map_err(|_| {
+                AAKAError::InvalidInput("ID is not valid UTF-8, use hex or base64".to_string())
+            }),
+            IdEncoding::Hex => Ok(hex::encode(id)),
+            IdEncoding::Base64 => Ok(BASE64_STANDARD.encode(id)),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            IdEncoding::Utf8 => "utf8",
+            IdEncoding::Hex => "hex",
+            IdEncoding::Base64 => "base64",
+        }
+    }
+}
+
+/// 一个 ID 字符串及其编码，对应 JSON 中的 `{"id": "...", "id_encoding": "..."}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncodedId {
+    pub id: String,
+    /// 省略时为 `utf8`
+    #[serde(default)]
+    pub id_encoding: IdEncoding,
+}
+
+impl EncodedId {
+    pub fn utf8(id: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            id_encoding: IdEncoding::Utf8,
+        }
+    }
+
+    /// 合法的 UTF-8 ID 原样保存，其余按 hex 编码
+    pub fn from_bytes(id: &[u8]) -> Self {
+        match std::str::from_utf8(id) {
+            Ok(id) => Self::utf8(id),
+            Err(_) => Self {
+                id: hex::encode(id),
+                id_encoding: IdEncoding::Hex,
+            },
+        }
+    }
+
+    pub fn bytes(&self) -> Result<Vec<u8>, AAKAError> {
+        self.id_encoding.decode(&self.id)
+    }
+}
+
+/// 用于日志：UTF-8 ID 原样输出，其余带上编码前缀，例如 `hex:6d7300`
+impl fmt::Display for EncodedId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.id_encoding {
+            IdEncoding::Utf8 => f.write_str(&self.id),
+            encoding => write!(f, "{}:{}", encoding.as_str(), self.id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoded_ids_roundtrip() {
+        let binary = b"ms\x00\xff";
+        for encoding in [IdEncoding::Hex, IdEncoding::Base64] {
+            let id = EncodedId {
+                id: encoding.encode(binary).unwrap(),
+                id_encoding: encoding,
+            };
+            assert_eq!(id.bytes().unwrap(), binary);
+        }
+        assert!(IdEncoding::Utf8.encode(binary).is_err());
+
+        let id = EncodedId::from_bytes(binary);
+        assert_eq!(id.to_string(), "hex:6d7300ff");
+        assert_eq!(id.bytes().unwrap(), binary);
+        assert_eq!(EncodedId::from_bytes(b"alice"), EncodedId::utf8("alice"));
+
+        // 省略 `id_encoding` 时按 UTF-8 解析
+        let id: EncodedId = serde_json::from_str(r#"{"id": "alice"}"#).unwrap();
+        assert_eq!(id.bytes().unwrap(), b"alice");
+    }
+}
@@ -0,0 +1,143 @@
+//! 预先签发的 MS 密钥文件，供无法访问 RC 的（隔离网络）部署离线加载。
+//!
+//! 文件中保存系统参数与 [`ServerSecretKey`]，以口令加密：
+//! `MAGIC || salt (16 字节) || nonce (12 字节) || ChaCha20-Poly1305 密文`，
+//! 加密密钥由 Argon2id(口令, salt) 派生。明文为两者原始字节编码组成的 CBOR 消息。
+//!
+//! 解密只能说明口令正确，加载方还应以 [`crate::rc::verify_server_key`] 校验密钥与自己的 ID 相符。
+//!
+//! [`crate::MasterSecretKey::export_encrypted`] 以同样的格式（不同的 MAGIC）加密主密钥。
+use crate::{AAKAError, ServerSecretKey, SystemParameters, wire};
+use argon2::Argon2;
+use chacha20poly1305::{
+    AeadCore, ChaCha20Poly1305, Key, KeyInit, Nonce,
+    aead::{Aead, OsRng, rand_core::RngCore},
+};
+use serde::{Deserialize, Serialize};
+
+const MAGIC: &[u8] = b"AAKA-KEY1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+#[derive(Serialize, Deserialize)]
+struct KeyFileCbor {
+    #[serde(with = "serde_bytes")]
+    params: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    ssk: Vec<u8>,
+}
+
+fn derive_key(passphrase: &[u8], salt: &[u8]) -> Result<Key, AAKAError> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase, salt, &mut key)
+        .map_err(|e| AAKAError::CryptoError(format!("Failed to derive key file key: {e}")))?;
+    Ok(key)
+}
+
+/// 以 `passphrase` 加密系统参数与服务器私钥，得到密钥文件的内容
+pub fn seal(
+    params: &SystemParameters,
+    ssk: &ServerSecretKey,
+    passphrase: &[u8],
+) -> Result<Vec<u8>, AAKAError> {
+    let plaintext = wire::encode(&KeyFileCbor {
+        params: wire::to_bytes(params)?,
+        ssk: wire::to_bytes(ssk)?,
+    })?;
+    seal_with(MAGIC, &plaintext, passphrase)
+}
+
+/// `magic || salt || nonce || 密文`，密钥由 Argon2id(口令, salt) 派生
+pub(crate) fn seal_with(
+    magic: &[u8],
+    plaintext: &[u8],
+    passphrase: &[u8],
+) -> Result<Vec<u8>, AAKAError> {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = ChaCha20Poly1305::new(&derive_key(passphrase, &salt)?)
+        .encrypt(&nonce, plaintext)
+        .map_err(|e| AAKAError::CryptoError(format!("Failed to seal key file: {e}")))?;
+
+    let mut out = Vec::with_capacity(magic.len() + SALT_LEN + NONCE_LEN + ciphertext.len());
+    out.extend_from_slice(magic);
+    out.extend_from_slice(&salt);
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+/// 解密 [`seal`] 产生的密钥文件；口令错误、文件被篡改或截断时返回错误。
+/// 其中的点经过曲线/子群检查，系统参数经过 [`SystemParameters::validate`]。
+pub fn open(
+    bytes: &[u8],
+    passphrase: &[u8],
+) -> Result<(SystemParameters, ServerSecretKey), AAKAError> {
+    let plaintext = open_with(MAGIC, bytes, passphrase)?;
+    let msg: KeyFileCbor = wire::decode(&plaintext)?;
+    Ok((
+        SystemParameters::try_from(msg.params.as_slice())?,
+        ServerSecretKey::try_from(msg.ssk.as_slice())?,
+    ))
+}
+
+/// 解密 [`seal_with`] 的输出，返回明文
+pub(crate) fn open_with(
+    magic: &[u8],
+    bytes: &[u8],
+    passphrase: &[u8],
+) -> Result<Vec<u8>, AAKAError> {
+    let body = bytes.strip_prefix(magic).ok_or_else(|| {
+        AAKAError::Deserialization("Not an AAKA key file (bad magic)".to_string())
+    })?;
+    if body.len() < SALT_LEN + NONCE_LEN {
+        return Err(AAKAError::Deserialization(format!(
+            "Key file too short: {} bytes",
+            bytes.len()
+        )));
+    }
+    let (salt, rest) = body.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    ChaCha20Poly1305::new(&derive_key(passphrase, salt)?)
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| {
+            AAKAError::CryptoError("Key file authentication failed (wrong passphrase?)".to_string())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rc;
+    use ark_std::rand::{SeedableRng, rngs::StdRng};
+
+    #[test]
+    fn test_key_file_roundtrip_and_verification() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        let ssk = rc::register_server(&msk, b"server").unwrap();
+
+        let bytes = seal(&params, &ssk, b"passphrase").unwrap();
+        let (loaded_params, loaded_ssk) = open(&bytes, b"passphrase").unwrap();
+        assert_eq!(loaded_params, params);
+        assert_eq!(loaded_ssk.sid_ms, ssk.sid_ms);
+        rc::verify_server_key(&loaded_params, b"server", &loaded_ssk).unwrap();
+
+        // 密钥属于其他 ID，或与另一组参数不匹配
+        assert!(rc::verify_server_key(&params, b"other", &ssk).is_err());
+        let (other_params, _) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        assert!(rc::verify_server_key(&other_params, b"server", &ssk).is_err());
+
+        assert!(matches!(
+            open(&bytes, b"wrong"),
+            Err(AAKAError::CryptoError(_))
+        ));
+        let mut tampered = bytes.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(open(&tampered, b"passphrase").is_err());
+        assert!(open(&bytes[..MAGIC.len() + 4], b"passphrase").is_err());
+        assert!(open(b"not a key file", b"passphrase").is_err());
+    }
+}
@@ -1,30 +1,52 @@
+pub mod backoff;
+pub mod bundle;
+#[cfg(feature = "clock-check")]
+pub mod clock;
+#[cfg(feature = "config")]
+pub mod config;
+pub mod confirm;
+#[cfg(feature = "server")]
+pub mod cors;
+pub mod envelope;
 pub mod hash_utils;
+pub mod http;
+pub mod id;
+pub mod keyfile;
+pub mod nonce;
+pub mod params_cache;
+pub mod prelude;
+#[cfg(feature = "grpc")]
+pub mod proto;
 pub mod rc; // Make the rc module public
+pub mod rc_client;
+pub mod replay;
+#[cfg(feature = "server")]
+pub mod request_id;
+pub mod secret;
 pub mod server;
+pub mod suite;
+#[cfg(test)]
+mod test_utils;
 pub mod user;
+pub mod wire;
 
 use aes_gcm::{AeadCore, Key};
-use ark_bls12_381::{
-    Bls12_381, Fr as BlsScalarField, FrConfig, G1Affine, G1Projective, G2Projective,
-};
-use ark_ec::pairing::PairingOutput;
-use ark_ec::{Group, pairing::Pairing}; // Need CurveGroup for zero(), Group for identity
-use ark_ff::{BigInt, Field, FpConfig, MontBackend, UniformRand}; // Need Field for inverse, UniformRand for random generation
-use ark_std::Zero;
-use ark_std::ops::Add;
-use ark_std::rand::prelude::*; // For random number generation (e.g., thread_rng) // Need Add trait
-// Add UniformRand for random generation
 use aes_gcm::{
     Aes256Gcm,
     Nonce, // Or `Aes128Gcm`
     aead::{Aead, KeyInit, OsRng},
 };
+use ark_bls12_381::{Bls12_381, Fr as BlsScalarField, G1Affine, G1Projective, G2Projective};
+use ark_ec::pairing::PairingOutput;
+use ark_ec::short_weierstrass::{Affine, Projective, SWCurveConfig};
+use ark_ff::{BigInt, FpConfig};
 use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
 use ark_std::vec::Vec;
 use blahaj::{Share, Sharks};
-use bytemuck::try_from_bytes;
-use rand::{SeedableRng, rngs::StdRng};
-use std::time::{SystemTime, UNIX_EPOCH}; // Add SystemTime imports here
+use hash_utils::{DeploymentInfo, Xof};
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+use wire::PointEncoding; // Add SystemTime imports here
 
 // Define type aliases for clarity
 pub type Curve = Bls12_381; // Our chosen curve
@@ -39,7 +61,7 @@ use serde::{Deserialize, Serialize};
 // --- Error Handling ---
 use thiserror::Error;
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone, PartialEq)]
 pub enum AAKAError {
     #[error("Serialization error: {0}")]
     Serialization(String),
@@ -53,14 +75,71 @@ pub enum AAKAError {
     SignatureVerificationFailed,
     #[error("Server response verification failed")]
     ServerResponseVerificationFailed,
+    /// 用户与服务器使用的服务器 ID (IDms) 不一致，通常是配置错误
+    #[error("Server identity mismatch: {0}")]
+    ServerIdentityMismatch(String),
     #[error("Input data invalid: {0}")]
     InvalidInput(String),
+    /// The component is up but not ready yet (e.g. the RC before `/setup`); retry later
+    #[error("Not initialized: {0}")]
+    NotInitialized(NotReady),
     #[error("Hash function error: {0}")]
     HashError(String),
+    /// 认证请求使用的算法套件（见 [`suite::Suite`]）不被服务器支持
+    #[error(
+        "Unsupported cipher suite {requested:#06x}, supported: {}",
+        suite::describe(.supported)
+    )]
+    UnsupportedSuite { requested: u16, supported: Vec<u16> },
+    /// 本地时钟与参考时间的偏差超出允许范围，见 `clock::ClockCheckConfig`
+    #[error(
+        "Local clock is off by {skew_secs}s from the reference, more than the allowed {max_secs}s"
+    )]
+    ClockSkew { skew_secs: i64, max_secs: u64 },
+    /// 对方的密钥确认值不一致，双方的会话密钥不同，见 [`confirm`]
+    #[error("Key confirmation failed")]
+    KeyConfirmationFailed,
     #[error("other error: {0}")]
     Other(String),
 }
 
+/// 尚未就绪的具体原因，见 [`AAKAError::NotInitialized`]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum NotReady {
+    /// 还没有系统参数（尚未 `/setup`，或本节点没有收到参数）
+    #[error("no system parameters, call /setup first")]
+    NoParams,
+    /// 本节点没有持有主密钥的 share
+    #[error("this node holds no master key share, call /setup first")]
+    NoShare,
+    /// 可达的节点数不足以达到门限
+    #[error("quorum unreachable: {ready} peers ready, need {needed}")]
+    PeersUnreachable { ready: usize, needed: usize },
+}
+
+impl AAKAError {
+    /// 稳定的机器可读错误码，随 HTTP 错误响应一起返回，客户端据此分支而不必解析错误信息。
+    /// 已有的错误码不会改变，新增变体时追加新的错误码。
+    pub fn code(&self) -> &'static str {
+        match self {
+            AAKAError::Serialization(_) => "ERR_SERIALIZATION",
+            AAKAError::Deserialization(_) => "ERR_DESERIALIZATION",
+            AAKAError::CryptoError(_) => "ERR_CRYPTO",
+            AAKAError::InvalidTimestamp => "ERR_STALE_TS",
+            AAKAError::SignatureVerificationFailed => "ERR_BAD_SIGNATURE",
+            AAKAError::ServerResponseVerificationFailed => "ERR_BAD_SERVER_RESPONSE",
+            AAKAError::ServerIdentityMismatch(_) => "ERR_SERVER_ID_MISMATCH",
+            AAKAError::InvalidInput(_) => "ERR_INVALID_INPUT",
+            AAKAError::NotInitialized(_) => "ERR_NOT_INITIALIZED",
+            AAKAError::HashError(_) => "ERR_HASH",
+            AAKAError::UnsupportedSuite { .. } => "ERR_UNSUPPORTED_SUITE",
+            AAKAError::ClockSkew { .. } => "ERR_CLOCK_SKEW",
+            AAKAError::KeyConfirmationFailed => "ERR_KEY_CONFIRMATION",
+            AAKAError::Other(_) => "ERR_OTHER",
+        }
+    }
+}
+
 // Helper to convert ark_serialize errors
 impl From<ark_serialize::SerializationError> for AAKAError {
     fn from(err: ark_serialize::SerializationError) -> Self {
@@ -77,11 +156,85 @@ pub struct SystemParameters {
     pub p_pub: G1Point,     // sP
     pub p_pub_hat: G1Point, // ŝP
     pub g: GtPoint,         // e(P, P)
-                            // Hash function identifiers/configs could be added here if needed
+    /// XOF used by `h2` for the keystream masking `N`
+    pub xof: Xof,
+    /// Per-deployment separator folded into `h5`, empty unless the RC was set up with one
+    pub deployment: DeploymentInfo,
+}
+
+impl SystemParameters {
+    pub fn fingerprint(&self) -> String {
+        fingerprint(self)
+    }
+
+    /// 这组参数与给定会话密钥长度对应的算法套件
+    pub fn suite(&self, key_len: usize) -> Result<suite::Suite, AAKAError> {
+        suite::Suite::new(self.xof, key_len)
+    }
+
+    /// 检查从网络或文件获得的公开参数是否自洽：P 为 G1 生成元，Ppub、Ppub_hat 非零，且 g = e(P, P2)。
+    /// 点本身是否在曲线/子群上已由反序列化检查。
+    pub fn validate(&self) -> Result<(), AAKAError> {
+        use ark_ec::{Group, pairing::Pairing};
+        use ark_std::Zero;
+
+        if self.p != G1Point::generator() {
+            return Err(AAKAError::InvalidInput(
+                "P is not the G1 generator".to_string(),
+            ));
+        }
+        if self.p_pub.is_zero() || self.p_pub_hat.is_zero() {
+            return Err(AAKAError::InvalidInput(
+                "Ppub and Ppub_hat must not be the identity".to_string(),
+            ));
+        }
+        if self.g != Curve::pairing(self.p, G2Point::generator()) {
+            return Err(AAKAError::InvalidInput("g != e(P, P2)".to_string()));
+        }
+        Ok(())
+    }
+
+    /// 在一次调用中检查所有字段，用于加载来源不一定经过反序列化检查的参数（例如在内存中构造）：
+    /// `p`、`p_pub`、`p_pub_hat` 不是无穷远点、在曲线上且在素数阶子群中，`p` 为 G1 生成元，
+    /// 并重新计算 `g = e(P, P2)` 比较。错误信息指出第一个不满足条件的字段。
+    pub fn validate_strict(&self) -> Result<(), AAKAError> {
+        use ark_ec::{CurveGroup, Group, pairing::Pairing};
+        use ark_std::Zero;
+
+        let invalid = |field: &str, reason: &str| {
+            Err(AAKAError::InvalidInput(format!(
+                "System parameter `{field}` {reason}"
+            )))
+        };
+        for (field, point) in [
+            ("p", &self.p),
+            ("p_pub", &self.p_pub),
+            ("p_pub_hat", &self.p_pub_hat),
+        ] {
+            if point.is_zero() {
+                return invalid(field, "is the identity");
+            }
+            let affine = point.into_affine();
+            if !affine.is_on_curve() {
+                return invalid(field, "is not on the curve");
+            }
+            if !affine.is_in_correct_subgroup_assuming_on_curve() {
+                return invalid(field, "is not in the prime-order subgroup");
+            }
+            if field == "p" && *point != G1Point::generator() {
+                return invalid(field, "is not the G1 generator");
+            }
+        }
+        if self.g != Curve::pairing(self.p, G2Point::generator()) {
+            return invalid("g", "does not equal e(P, P2)");
+        }
+        Ok(())
+    }
 }
 
 // Note: MasterSecretKey should be handled with extreme care and NOT be easily serialized/passed around.
 // We define it for completeness but won't derive Serialize/Deserialize directly.
+// The only way to serialize it is `export_encrypted`, which never yields the plaintext.
 #[derive(Debug, Clone, PartialEq)]
 pub struct MasterSecretKey {
     pub s: ScalarField,
@@ -89,17 +242,38 @@ pub struct MasterSecretKey {
 }
 
 impl MasterSecretKey {
-    pub fn into_shares(self, t: usize, n: usize) -> Vec<Share> {
+    /// 将 msk 拆分为 `n` 份、门限为 `t` 的 share，同一次拆分的 share 带有相同的 epoch
+    pub fn into_shares(self, t: usize, n: usize) -> Vec<EpochShare> {
         let sharks = Sharks(t as u8);
         let msk_bytes: [u8; 64] = bytemuck::cast([
             FpConfig::into_bigint(self.s).0,
             FpConfig::into_bigint(self.s_hat).0,
         ]);
+        // 高 48 位为拆分时的毫秒时间戳，低 16 位随机：后一次拆分的 epoch 更大
+        let millis = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let epoch = (millis << 16) | u64::from(rand::random::<u16>());
         let dealer = sharks.dealer(&msk_bytes);
-        dealer.take(n).collect::<Vec<_>>()
+        dealer
+            .take(n)
+            .map(|share| EpochShare { epoch, share })
+            .collect()
     }
 
-    pub fn from_shares(shares: Vec<Share>, t: usize) -> Result<Self, AAKAError> {
+    pub fn from_shares(shares: Vec<EpochShare>, t: usize) -> Result<Self, AAKAError> {
+        if let Some(first) = shares.first()
+            && let Some(other) = shares.iter().find(|share| share.epoch != first.epoch)
+        {
+            return Err(AAKAError::InvalidInput(format!(
+                "shares from different master key epochs ({:016x} and {:016x}) cannot be combined",
+                first.epoch, other.epoch
+            )));
+        }
+        let shares: Vec<Share> = shares.into_iter().map(|share| share.share).collect();
+        check_shares(&shares, t)?;
+
         let sharks = Sharks(t as u8);
         let bytes: [u8; 64] = sharks
             .recover(&shares)
@@ -113,6 +287,157 @@ impl MasterSecretKey {
 
         Ok(Self { s, s_hat })
     }
+
+    /// 以口令加密导出主密钥，供单节点部署离线冷备份；格式同 [`keyfile`]
+    /// （Argon2id 派生密钥，ChaCha20-Poly1305 加密），只是 MAGIC 不同。
+    /// 不提供明文的序列化方式。
+    pub fn export_encrypted(&self, passphrase: &[u8]) -> Result<Vec<u8>, AAKAError> {
+        let mut plaintext = wire::to_bytes(&self.s)?;
+        plaintext.extend(wire::to_bytes(&self.s_hat)?);
+        keyfile::seal_with(MSK_BACKUP_MAGIC, &plaintext, passphrase)
+    }
+
+    /// 解密 [`Self::export_encrypted`] 的输出；口令错误、数据被篡改或截断时返回错误
+    pub fn import_encrypted(bytes: &[u8], passphrase: &[u8]) -> Result<Self, AAKAError> {
+        let plaintext = keyfile::open_with(MSK_BACKUP_MAGIC, bytes, passphrase)?;
+        if plaintext.len() != MSK_BYTES {
+            return Err(AAKAError::Deserialization(format!(
+                "Master key backup holds {} bytes, expected {MSK_BYTES}",
+                plaintext.len()
+            )));
+        }
+        let (s, s_hat) = plaintext.split_at(MSK_BYTES / 2);
+        Ok(Self {
+            s: wire::from_bytes(s)?,
+            s_hat: wire::from_bytes(s_hat)?,
+        })
+    }
+}
+
+const MSK_BACKUP_MAGIC: &[u8] = b"AAKA-MSK1";
+
+/// A share tagged with the dealing it came from.
+///
+/// Every `into_shares` call (setup or reshare) draws a fresh epoch, so shares of an old
+/// dealing, even of the same msk, are never interpolated together with new ones: mixing them
+/// would silently yield a wrong master key. The epoch starts with the dealing time in
+/// milliseconds, so among the dealings of one cluster the larger epoch is the newer one.
+#[derive(Clone)]
+pub struct EpochShare {
+    pub epoch: u64,
+    pub share: Share,
+}
+
+// 只输出 epoch 与索引，不输出 share 的内容
+impl std::fmt::Debug for EpochShare {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EpochShare")
+            .field("epoch", &format_args!("{:016x}", self.epoch))
+            .field("index", &self.share.x.0)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Byte length of a serialized master key (`s || ŝ`), i.e. the length of every share body.
+const MSK_BYTES: usize = 64;
+
+/// Checks that a set of shares can be fed to `Sharks::recover` without silently producing a
+/// wrong key: the threshold must be representable, every share must carry a full master key
+/// body, and no two shares may claim the same index (x-coordinate).
+fn check_shares(shares: &[Share], t: usize) -> Result<(), AAKAError> {
+    if t == 0 || t > u8::MAX as usize {
+        return Err(AAKAError::InvalidInput(format!(
+            "threshold must be between 1 and {}, got {}",
+            u8::MAX,
+            t
+        )));
+    }
+
+    let mut seen = HashSet::with_capacity(shares.len());
+    for share in shares {
+        let index = share.x.0;
+        if index == 0 {
+            return Err(AAKAError::InvalidInput(
+                "share index 0 is reserved for the secret itself".to_string(),
+            ));
+        }
+        if share.y.len() != MSK_BYTES {
+            return Err(AAKAError::InvalidInput(format!(
+                "share with index {} has length {}, expected {}",
+                index,
+                share.y.len(),
+                MSK_BYTES
+            )));
+        }
+        if !seen.insert(index) {
+            return Err(AAKAError::InvalidInput(format!(
+                "duplicate share index {}",
+                index
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// A share as exchanged between RC nodes, with its index (x-coordinate) kept as its own field.
+///
+/// `Vec::from(&Share)` puts the index in the first byte, which any re-framing of the bytes
+/// (trimming, re-encoding) could silently shift and pair the body with the wrong x-coordinate
+/// during interpolation. Here the index travels explicitly and is checked on the way back.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShareEnvelope {
+    pub epoch: u64,
+    pub index: u8,
+    pub body: Vec<u8>,
+}
+
+impl From<&EpochShare> for ShareEnvelope {
+    fn from(share: &EpochShare) -> Self {
+        let bytes = Vec::from(&share.share);
+        Self {
+            epoch: share.epoch,
+            index: bytes[0],
+            body: bytes[1..].to_vec(),
+        }
+    }
+}
+
+impl TryFrom<ShareEnvelope> for EpochShare {
+    type Error = AAKAError;
+
+    fn try_from(envelope: ShareEnvelope) -> Result<Self, AAKAError> {
+        if envelope.index == 0 {
+            return Err(AAKAError::InvalidInput(
+                "share index 0 is reserved for the secret itself".to_string(),
+            ));
+        }
+        if envelope.body.len() != MSK_BYTES {
+            return Err(AAKAError::InvalidInput(format!(
+                "share with index {} has length {}, expected {}",
+                envelope.index,
+                envelope.body.len(),
+                MSK_BYTES
+            )));
+        }
+
+        let mut bytes = Vec::with_capacity(1 + MSK_BYTES);
+        bytes.push(envelope.index);
+        bytes.extend_from_slice(&envelope.body);
+        let share = Share::try_from(bytes.as_slice())
+            .map_err(|e| AAKAError::Deserialization(e.to_string()))?;
+        // 解析前后索引必须一致
+        if share.x.0 != envelope.index {
+            return Err(AAKAError::Deserialization(format!(
+                "share index changed from {} to {} while decoding",
+                envelope.index, share.x.0
+            )));
+        }
+        Ok(Self {
+            epoch: envelope.epoch,
+            share,
+        })
+    }
 }
 
 #[derive(Debug, Clone, CanonicalSerialize, CanonicalDeserialize, PartialEq)]
@@ -121,6 +446,12 @@ pub struct UserSecretKey {
     pub sid_u: ScalarField, // SIDu = ru + s * h0(IDu || Ru)
 }
 
+impl UserSecretKey {
+    pub fn fingerprint(&self) -> String {
+        fingerprint(self)
+    }
+}
+
 #[derive(Debug, Clone, CanonicalSerialize, CanonicalDeserialize, PartialEq)]
 pub struct ServerSecretKey {
     // SIDms = (1 / (ŝ + h1(IDms))) * P
@@ -128,15 +459,33 @@ pub struct ServerSecretKey {
     pub sid_ms: G2Point,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl ServerSecretKey {
+    pub fn fingerprint(&self) -> String {
+        fingerprint(self)
+    }
+}
+
+/// 指纹：原始字节编码的 SHA3-256 前 8 字节（16 个十六进制字符）。
+/// 日志中输出指纹而不是完整的十六进制，便于一眼比对 RC、MS 和用户使用的是否是同一份参数或密钥。
+fn fingerprint<T: CanonicalSerialize>(item: &T) -> String {
+    use digest::Digest;
+
+    // 序列化到 Vec 不会失败
+    let bytes = wire::to_bytes(item).expect("serializing into a Vec cannot fail");
+    hex::encode(&sha3::Sha3_256::digest(bytes)[..8])
+}
+
+#[derive(Debug, Clone, CanonicalSerialize, PartialEq)]
 pub struct UserAuthRequest {
     pub m: G1Point,
     pub n: Vec<u8>, // Encrypted/XORed data (IDu || Ru || X)
     pub sigma: ScalarField,
     pub timestamp: u64, // T_u
+    /// 用户使用的算法套件，见 [`suite::Suite::id`]
+    pub suite_id: u16,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, CanonicalSerialize, PartialEq)]
 pub struct ServerAuthResponse {
     pub t: ScalarField,
     pub y: G1Point,
@@ -147,6 +496,76 @@ pub struct ServerAuthResponse {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SessionKey(pub Vec<u8>); // Store as bytes
 
+impl SessionKey {
+    /// 消息加密子密钥使用的派生标签
+    pub const MESSAGE_LABEL: &'static [u8] = b"AAKA-message";
+
+    /// 从会话密钥派生一个 32 字节的子密钥：SHA3-256(len(label) || label || K)。
+    /// 不同的 `label` 得到相互独立的子密钥，避免会话密钥被直接用于多种用途。
+    pub fn expand(&self, label: &[u8]) -> [u8; 32] {
+        use digest::Digest;
+
+        sha3::Sha3_256::new()
+            .chain_update((label.len() as u64).to_be_bytes())
+            .chain_update(label)
+            .chain_update(&self.0)
+            .finalize()
+            .into()
+    }
+
+    /// 使用 [`Self::MESSAGE_LABEL`] 派生的子密钥以 AES-256-GCM 加密消息，返回 (密文, Nonce)。
+    pub fn seal(&self, plaintext: &[u8]) -> Result<(Vec<u8>, Vec<u8>), AAKAError> {
+        encrypt(&self.expand(Self::MESSAGE_LABEL), plaintext)
+            .map_err(|e| AAKAError::CryptoError(format!("Failed to seal message: {e}")))
+    }
+
+    /// 解密 [`Self::seal`] 产生的密文，密文被篡改或密钥不一致时返回错误。
+    pub fn open(&self, ciphertext: &[u8], nonce: &[u8]) -> Result<Vec<u8>, AAKAError> {
+        if nonce.len() != 12 {
+            return Err(AAKAError::InvalidInput(format!(
+                "Invalid nonce length {}, expected 12",
+                nonce.len()
+            )));
+        }
+        decrypt(&self.expand(Self::MESSAGE_LABEL), ciphertext, nonce)
+            .map_err(|e| AAKAError::CryptoError(format!("Failed to open message: {e}")))
+    }
+}
+
+/// 一次握手的公开记录 IDu || IDms || X || Y，用户与服务器得到的内容相同。
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transcript {
+    pub user_id: Vec<u8>,
+    pub server_id: Vec<u8>,
+    pub x: G1Point, // X = xP
+    pub y: G1Point, // Y = yP
+}
+
+impl Transcript {
+    /// 用户一侧的记录：发起认证时保存的状态加上服务器响应中的 Y
+    pub fn from_user(state: &UserState, response: &ServerAuthResponse) -> Self {
+        Self {
+            user_id: state.user_id.clone(),
+            server_id: state.server_id.clone(),
+            x: state.temp_x_pub,
+            y: response.y,
+        }
+    }
+}
+
+/// 由握手记录派生稳定的会话 ID，双方无需额外通信即可用它关联同一个会话。
+/// 只依赖公开值而不依赖会话密钥，可以出现在日志中。
+pub fn session_id(
+    transcript: &Transcript,
+) -> Result<[u8; hash_utils::SESSION_ID_BYTES], AAKAError> {
+    hash_utils::h_sid(
+        &transcript.user_id,
+        &transcript.server_id,
+        &transcript.x,
+        &transcript.y,
+    )
+}
+
 // Temporary state kept by the user between sending request and receiving response
 // We might need this later when implementing the user logic
 #[derive(Debug, Clone)]
@@ -155,27 +574,156 @@ pub struct UserState {
     pub temp_x_pub: G1Point, // X = xP
     pub user_id: Vec<u8>,    // User's ID
     pub r_u: G1Point,        // User's Ru
-                             // Store other relevant info if needed, e.g., target server_id
+    pub server_id: Vec<u8>,  // IDms the request was encrypted for
 }
+/// 反序列化曲线点时做哪些校验
+///
+/// `Lenient` 跳过子群检查（G1 上一次约 255 位的标量乘），只应在输入来自可信来源、
+/// 需要最大吞吐时使用：非子群中的点会让签名校验与会话密钥失去安全保证。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PointValidation {
+    /// 检查在曲线上、属于素数阶子群且不是无穷远点
+    #[default]
+    Strict,
+    /// 只检查在曲线上且不是无穷远点
+    Lenient,
+}
+
+/// 可以经过显式校验反序列化的曲线点（G1、G2 的射影坐标点），见 [`deserialize_point`]。
+pub trait CheckedPoint: Sized {
+    fn deserialize_checked(bytes: &[u8]) -> Result<Self, AAKAError> {
+        Self::deserialize_checked_with(bytes, PointEncoding::Compressed)
+    }
+
+    fn deserialize_checked_with(bytes: &[u8], encoding: PointEncoding) -> Result<Self, AAKAError> {
+        Self::deserialize_validated(bytes, encoding, PointValidation::Strict)
+    }
+
+    fn deserialize_validated(
+        bytes: &[u8],
+        encoding: PointEncoding,
+        validation: PointValidation,
+    ) -> Result<Self, AAKAError>;
+}
+
+impl<C: SWCurveConfig> CheckedPoint for Projective<C> {
+    fn deserialize_validated(
+        bytes: &[u8],
+        encoding: PointEncoding,
+        validation: PointValidation,
+    ) -> Result<Self, AAKAError> {
+        use ark_ec::AffineRepr;
+        use ark_serialize::Validate;
+
+        // 先不做校验地解析，再逐项显式检查，便于给出明确的错误原因
+        let point = Affine::<C>::deserialize_with_mode(bytes, encoding.into(), Validate::No)
+            .map_err(|e| AAKAError::Deserialization(e.to_string()))?;
+        if point.is_zero() {
+            return Err(AAKAError::InvalidInput("point is the identity".to_string()));
+        }
+        if !point.is_on_curve() {
+            return Err(AAKAError::InvalidInput(
+                "point is not on the curve".to_string(),
+            ));
+        }
+        if validation == PointValidation::Strict
+            && !point.is_in_correct_subgroup_assuming_on_curve()
+        {
+            return Err(AAKAError::InvalidInput(
+                "point is not in the prime-order subgroup".to_string(),
+            ));
+        }
+        Ok(point.into_group())
+    }
+}
+
+/// 反序列化一个压缩编码的曲线点，并检查它在曲线上、属于素数阶子群且不是无穷远点。
+///
+/// 所有来自网络或文件的点都应通过此函数解析，以防御 invalid-point / small-subgroup 攻击。
+pub fn deserialize_point<P: CheckedPoint>(bytes: &[u8]) -> Result<P, AAKAError> {
+    P::deserialize_checked(bytes)
+}
+
+/// 与 [`deserialize_point`] 相同，但按 `encoding` 指定的压缩或非压缩编码解析。
+pub fn deserialize_point_with<P: CheckedPoint>(
+    bytes: &[u8],
+    encoding: PointEncoding,
+) -> Result<P, AAKAError> {
+    P::deserialize_checked_with(bytes, encoding)
+}
+
+/// 反序列化一个压缩编码的曲线点，按 `validation` 决定是否检查子群，见 [`PointValidation`]。
+pub fn deserialize_point_validated<P: CheckedPoint>(
+    bytes: &[u8],
+    validation: PointValidation,
+) -> Result<P, AAKAError> {
+    P::deserialize_validated(bytes, PointEncoding::Compressed, validation)
+}
+
+/// 多标量乘法 Σ scalars[i]·bases[i]（Pippenger 算法），比逐项标量乘再相加更快。
+///
+/// `bases` 与 `scalars` 的长度必须相同。
+pub fn msm(bases: &[G1Point], scalars: &[ScalarField]) -> Result<G1Point, AAKAError> {
+    use ark_ec::{CurveGroup, VariableBaseMSM};
+
+    if bases.len() != scalars.len() {
+        return Err(AAKAError::InvalidInput(format!(
+            "MSM length mismatch: {} bases, {} scalars",
+            bases.len(),
+            scalars.len()
+        )));
+    }
+    let bases = G1Point::normalize_batch(bases);
+    G1Point::msm(&bases, scalars)
+        .map_err(|len| AAKAError::CryptoError(format!("MSM failed at {len} elements")))
+}
+
+/// 密码学步骤的 span guard：启用 `tracing` feature 时进入一个 INFO 级别的 span，
+/// 调用 [`Step::end`] 或 drop 时退出；未启用时为空操作。
+pub(crate) struct Step {
+    #[cfg(feature = "tracing")]
+    _span: tracing::span::EnteredSpan,
+}
+
+impl Step {
+    pub(crate) fn end(self) {}
+}
+
+/// 创建一个 [`Step`]，span 名称必须是字面量
+macro_rules! step {
+    ($name:literal) => {
+        $crate::Step {
+            #[cfg(feature = "tracing")]
+            _span: tracing::info_span!($name).entered(),
+        }
+    };
+}
+pub(crate) use step;
+
 /// Gets the current Unix timestamp in seconds.
-// Marked pub(crate) so it's accessible within the crate (e.g., from user.rs and server.rs)
-pub(crate) fn get_current_timestamp() -> Result<u64, AAKAError> {
+pub fn get_current_timestamp() -> Result<u64, AAKAError> {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .map(|d| d.as_secs())
         .map_err(|e| AAKAError::CryptoError(format!("System time error: {}", e)))
 }
 
+/// Allowed clock skew for request timestamps (5 minutes).
+pub const ALLOWED_SKEW_SECONDS: u64 = 300;
+
 /// Checks if a given timestamp is fresh within an allowed skew.
-pub(crate) fn is_timestamp_fresh(timestamp: u64) -> Result<bool, AAKAError> {
-    const ALLOWED_SKEW_SECONDS: u64 = 300; // Allow 5 minutes skew
+///
+/// The window is two-sided: `timestamp` may lag behind or run ahead of the local clock by at
+/// most [`ALLOWED_SKEW_SECONDS`], i.e. it must lie in `[now - skew, now + skew]`.
+/// A zero timestamp is an unset field, never a real send time, and is always rejected.
+pub fn is_timestamp_fresh(timestamp: u64) -> Result<bool, AAKAError> {
+    if timestamp == 0 {
+        return Ok(false);
+    }
     let current_ts = get_current_timestamp()?;
-    let diff = if current_ts >= timestamp {
-        current_ts - timestamp
-    } else {
-        timestamp - current_ts // Handle potential clock skew in both directions
-    };
-    Ok(diff <= ALLOWED_SKEW_SECONDS)
+    // abs_diff never underflows, whichever side of `current_ts` the timestamp is on
+    Ok(current_ts.abs_diff(timestamp) <= ALLOWED_SKEW_SECONDS)
 }
 
 // 加密函数
@@ -226,14 +774,15 @@ mod tests {
         server,
         user,
     };
-    use ark_std::rand::{SeedableRng, rngs::StdRng}; // For deterministic testing RNG
+    use ark_std::rand::{SeedableRng, rngs::StdRng}; // For the testing RNG
 
     use std::ops::Add; // Use vec macro
 
-    // Helper to create a deterministic RNG for tests
+    // Helper to create an RNG for tests
     fn test_rng() -> StdRng {
-        // Use a fixed seed for reproducible tests
-        StdRng::seed_from_u64(0u64)
+        // Not a fixed seed: the nonce repeat check is shared by the whole process, so
+        // tests running in parallel on one seed look like a cloned RNG and get rejected
+        StdRng::from_entropy()
     }
 
     // Helper to compare SessionKeys (assumes content equality is needed)
@@ -244,6 +793,137 @@ mod tests {
     }
     impl Eq for SessionKey {}
 
+    #[test]
+    fn test_hash_output_lengths_match_runtime_sizes() {
+        use hash_utils::{HASH_OUTPUT_BYTES, SCALAR_BYTES, compressed_g1_len, compressed_g2_len};
+        use sha3::{Digest, Sha3_256};
+
+        assert_eq!(Sha3_256::output_size(), HASH_OUTPUT_BYTES);
+        assert_eq!(ScalarField::from(7u64).compressed_size(), SCALAR_BYTES);
+
+        let mut rng = test_rng();
+        let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        let ssk = rc::register_server(&msk, b"server").unwrap();
+        assert_eq!(params.p.compressed_size(), compressed_g1_len());
+        assert_eq!(ssk.sid_ms.compressed_size(), compressed_g2_len());
+
+        // N = h2(g^x) XOR (IDu || Ru || X)
+        let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+        let (request, _) =
+            user::initiate_authentication(&usk, b"alice", b"server", &params, &mut rng).unwrap();
+        assert_eq!(request.n.len(), b"alice".len() + 2 * compressed_g1_len());
+    }
+
+    #[test]
+    #[cfg(not(feature = "legacy-hash-to-field"))]
+    fn test_hash_to_field_matches_reference() {
+        use hash_utils::{HASH_TO_FIELD_BYTES, expand_message_xof, hash_to_scalar};
+        use std::str::FromStr;
+
+        // RFC 9380 附录 K.6 中 expand_message_xof(SHAKE256) 的测试向量
+        let dst = b"QUUX-V01-CS02-with-expander-SHAKE256";
+        assert_eq!(
+            hex::encode(expand_message_xof(dst, &[], 0x20).unwrap()),
+            "2ffc05c48ed32b95d72e807f6eab9f7530dd1c2f013914c8fed38c5ccc15ad76"
+        );
+        assert_eq!(
+            hex::encode(expand_message_xof(dst, &[b"a", b"bc"], 0x20).unwrap()),
+            "b39e493867e2767216792abce1f2676c197c0692aed061560ead251821808e07"
+        );
+
+        // 64 字节按大端整数模 q 归约，参考值由独立实现（Python 整数运算）计算
+        assert_eq!(HASH_TO_FIELD_BYTES, 64);
+        let expected = ScalarField::from_str(
+            "10891681243325326341213136405889609678927960820491386973076586229933168890039",
+        )
+        .unwrap();
+        assert_eq!(hash_utils::h1(b"my_server").unwrap(), expected);
+        assert_eq!(
+            hash_to_scalar(b"IBC_AAKA_H1", &[b"my_", b"server"]).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_kdf_outputs_match_previous_implementation() {
+        use ark_ec::CurveGroup;
+        use ark_ff::UniformRand;
+        use sha3::{Digest, Sha3_256};
+
+        // 改为克隆哈希状态之前的 h5：每个分块都重新哈希完整输入
+        fn h5_iterative(parts: &[&[u8]], key_len: usize) -> Vec<u8> {
+            let block = |counter: Option<u32>| {
+                let mut hasher = Sha3_256::new();
+                hasher.update(b"IBC_AAKA_H5");
+                for part in parts {
+                    hasher.update(part);
+                }
+                if let Some(counter) = counter {
+                    hasher.update(counter.to_be_bytes());
+                }
+                hasher.finalize()
+            };
+            let mut out = block(None).to_vec();
+            let mut counter = 0;
+            while out.len() < key_len {
+                out.extend_from_slice(&block(Some(counter)));
+                counter += 1;
+            }
+            out.truncate(key_len);
+            out
+        }
+
+        let mut rng = test_rng();
+        let [k, x, y] = [(); 3].map(|_| G1Point::rand(&mut rng));
+        let [k_bytes, x_bytes, y_bytes] = [k, x, y].map(|p| {
+            let mut buf = Vec::new();
+            p.into_affine().serialize_compressed(&mut buf).unwrap();
+            buf
+        });
+        let parts: [&[u8]; 5] = [&k_bytes, b"alice", b"server", &x_bytes, &y_bytes];
+        for len in [1, 16, 32, 33, 64, 100, 256] {
+            let h5 = hash_utils::h5(&k, b"alice", b"server", &x, &y, len, &[], &[]).unwrap();
+            assert_eq!(h5, h5_iterative(&parts, len), "len {len}");
+        }
+
+        // h2 的密钥流来自 XOF：较短的输出是较长输出的前缀，长度不影响初始化开销
+        let g_x = GtPoint::rand(&mut rng);
+        let long = hash_utils::h2(&g_x, 1024, Xof::default()).unwrap();
+        for len in [32, 64, 256] {
+            assert_eq!(
+                hash_utils::h2(&g_x, len, Xof::default()).unwrap(),
+                long[..len]
+            );
+        }
+    }
+
+    #[test]
+    fn test_h2_known_answers() {
+        use ark_ec::{Group, pairing::Pairing};
+
+        // 固定输入 g^x = e(P, Q)（两个群的生成元），期望值即 SHAKE128/SHAKE256 对
+        // "IBC_AAKA_H2" || 压缩编码的 g^x 输出的前 64 字节。h2 的输出决定 N 的密文格式，
+        // 这些值改变意味着新旧版本的用户与 MS 无法互相解密 N
+        let g_x = Curve::pairing(G1Point::generator(), G2Point::generator());
+        let cases = [
+            (
+                Xof::Shake128,
+                "16299e169781f2991ef6b9363a0f4235d9f9fdd0cd62c47ac385569a91262a8912bb0a3db3b57f489e804afef4100f2d4a12817710cd7daa6d49ec5188423df7",
+            ),
+            (
+                Xof::Shake256,
+                "e7db9b3186922099e083e23454871c5e65ab2d392e1b2ba451abc5852f2e4308e354c8e0557dfa04d53e90637e0a0c9f1a53e2ea7ed5a968ed677120f9eed1f2",
+            ),
+        ];
+        for (xof, expected) in cases {
+            assert_eq!(
+                hex::encode(hash_utils::h2(&g_x, 64, xof).unwrap()),
+                expected,
+                "{xof:?}"
+            );
+        }
+    }
+
     #[test]
     fn test_full_protocol_flow_success() {
         let mut rng = test_rng();
@@ -316,6 +996,91 @@ mod tests {
         assert!(!user_session_key.0.is_empty()); // Ensure key is not empty
     }
 
+    #[test]
+    fn test_decrypted_request_parse_reports_malformed_lengths() {
+        use ark_ff::UniformRand;
+        use server::DecryptedRequest;
+
+        let mut rng = test_rng();
+        let (r_u, x) = (G1Point::rand(&mut rng), G1Point::rand(&mut rng));
+        let mut points = Vec::new();
+        r_u.serialize_compressed(&mut points).unwrap();
+        x.serialize_compressed(&mut points).unwrap();
+        let parse = |payload: &[u8]| match DecryptedRequest::parse(payload, PointValidation::Strict)
+        {
+            Err(AAKAError::Deserialization(msg)) => msg,
+            other => panic!("expected a deserialization error, got {other:?}"),
+        };
+
+        // 空的或不足以容纳两个点
+        assert!(parse(&[]).contains("too short"));
+        assert!(parse(&points[1..]).contains("too short"));
+        // 恰好两个点，没有用户 ID
+        assert!(parse(&points).contains("no user ID"));
+
+        let payload = [b"alice".as_slice(), &points].concat();
+        assert_eq!(
+            DecryptedRequest::parse(&payload, PointValidation::Strict).unwrap(),
+            DecryptedRequest {
+                id_u: b"alice".to_vec(),
+                r_u,
+                x
+            }
+        );
+
+        // 长度正确但点不合法时指出是哪一个点
+        let mut bad_r_u = payload.clone();
+        bad_r_u[5..53].fill(0xff);
+        assert!(parse(&bad_r_u).contains("Ru'"));
+        let mut bad_x = payload.clone();
+        bad_x[53..].fill(0xff);
+        assert!(parse(&bad_x).contains("X'"));
+    }
+
+    #[test]
+    fn test_shared_points_match_before_hashing() {
+        use ark_ff::UniformRand;
+
+        let mut rng = test_rng();
+        let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+        let ssk = rc::register_server(&msk, b"server").unwrap();
+
+        let (request, state) =
+            user::initiate_authentication(&usk, b"alice", b"server", &params, &mut rng).unwrap();
+        // 服务器处理请求时第一个随机数就是 y，用同一个 RNG 的副本恢复它
+        let y = ScalarField::rand(&mut rng.clone());
+        let (response, server_key) =
+            server::process_user_request(&ssk, &request, b"server", &params, &mut rng, 32).unwrap();
+        assert_eq!(params.p * y, response.y);
+
+        // Kms-u = y(tX' + Ru' + h0·Ppub)，Ku-ms = (SIDu + xt)Y，两者是同一个 G1 点
+        let h_0 = hash_utils::h0(b"alice", &usk.r_u).unwrap();
+        let k_ms_u =
+            server::compute_k_ms_u(&params, &y, response.t, &state.temp_x_pub, &usk.r_u, h_0)
+                .unwrap();
+        let k_u_ms = user::compute_k_u_ms(&usk, &state.x, response.t, &response.y);
+        assert_eq!(k_ms_u, k_u_ms);
+        assert!(!ark_std::Zero::is_zero(&k_u_ms));
+
+        // 双方的会话密钥都是 h5 作用在这个点上的结果
+        let expected = hash_utils::h5(
+            &k_u_ms,
+            b"alice",
+            b"server",
+            &state.temp_x_pub,
+            &response.y,
+            32,
+            &[],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(server_key.0, expected);
+        let user_key =
+            user::process_server_response(&usk, &state, &response, b"server", &params, 32).unwrap();
+        assert_eq!(user_key.0, expected);
+    }
+
     #[test]
     fn test_signature_verification_failure() {
         let mut rng = test_rng();
@@ -355,6 +1120,65 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_session_key_context_separates_keys() {
+        let mut rng = test_rng();
+        let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+        let ssk = rc::register_server(&msk, b"server").unwrap();
+        let (request, state) =
+            user::initiate_authentication(&usk, b"alice", b"server", &params, &mut rng).unwrap();
+
+        // 同一次握手：相同 context 的双方密钥一致，不同 context 得到不同的密钥
+        let (response, voip) = server::process_user_request_with_context(
+            &ssk, &request, b"server", &params, &mut rng, 32, b"voip",
+        )
+        .unwrap();
+        let user_key = |context: &[u8]| {
+            user::process_server_response_with_context(
+                &usk, &state, &response, b"server", &params, 32, context,
+            )
+            .unwrap()
+        };
+        assert_eq!(user_key(b"voip").0, voip.0);
+        assert_ne!(user_key(b"file").0, voip.0);
+
+        // 空 context 与不带 context 的接口一致
+        let plain =
+            user::process_server_response(&usk, &state, &response, b"server", &params, 32).unwrap();
+        assert_eq!(user_key(b"").0, plain.0);
+        assert_ne!(plain.0, voip.0);
+    }
+
+    #[test]
+    fn test_zero_scalars_rejected() {
+        use ark_ff::Field;
+
+        let mut rng = test_rng();
+        let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+        let ssk = rc::register_server(&msk, b"server").unwrap();
+        let (mut request, mut state) =
+            user::initiate_authentication(&usk, b"alice", b"server", &params, &mut rng).unwrap();
+        let (response, _) =
+            server::process_user_request(&ssk, &request, b"server", &params, &mut rng, 32).unwrap();
+
+        // N 被清空：若先做配对与解密，会报告 N 过短而不是 σ 非法
+        request.sigma = ScalarField::ZERO;
+        request.n.clear();
+        let err = server::process_user_request(&ssk, &request, b"server", &params, &mut rng, 32)
+            .unwrap_err();
+        assert!(
+            matches!(&err, AAKAError::InvalidInput(msg) if msg.contains("sigma")),
+            "{err:?}"
+        );
+
+        state.x = ScalarField::ZERO;
+        let err = user::process_server_response(&usk, &state, &response, b"server", &params, 32)
+            .unwrap_err();
+        assert!(matches!(err, AAKAError::InvalidInput(_)), "{err:?}");
+    }
+
     #[test]
     fn test_server_response_verification_failure() {
         let mut rng = test_rng();
@@ -439,8 +1263,7 @@ mod tests {
         // --- Modify the request to make its timestamp stale ---
         let mut stale_request = request.clone(); // Clone the original request
         let current_time = get_current_timestamp().unwrap(); // Get current time for reference
-        const ALLOWED_SKEW_SECONDS: u64 = 300; // Use the same constant as in is_timestamp_fresh
-        stale_request.timestamp = current_time.saturating_sub(ALLOWED_SKEW_SECONDS + 60); // Set timestamp to be clearly outside the window (e.g., 6 minutes ago)
+        stale_request.timestamp = current_time - (ALLOWED_SKEW_SECONDS + 60); // Set timestamp to be clearly outside the window (e.g., 6 minutes ago)
 
         // --- Server processes the *stale* request ---
         let server_result2 = server::process_user_request(
@@ -463,6 +1286,839 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_timestamp_freshness_bounds() {
+        let now = get_current_timestamp().unwrap();
+        assert!(is_timestamp_fresh(now).unwrap());
+        // 双向窗口的两端（留 1 秒余量，避免测试过程中跨秒）
+        assert!(is_timestamp_fresh(now - ALLOWED_SKEW_SECONDS + 1).unwrap());
+        assert!(is_timestamp_fresh(now + ALLOWED_SKEW_SECONDS).unwrap());
+        assert!(!is_timestamp_fresh(now - ALLOWED_SKEW_SECONDS - 1).unwrap());
+        assert!(!is_timestamp_fresh(now + ALLOWED_SKEW_SECONDS + 2).unwrap());
+
+        assert!(!is_timestamp_fresh(0).unwrap());
+        assert!(!is_timestamp_fresh(u64::MAX).unwrap());
+    }
+
+    #[test]
+    fn test_deserialize_point_rejects_invalid_points() {
+        use ark_bls12_381::{Fq, Fq2, G2Affine};
+        use ark_ec::Group;
+        use ark_std::{One, Zero};
+
+        fn to_bytes<T: CanonicalSerialize>(item: &T) -> Vec<u8> {
+            let mut buf = Vec::new();
+            item.serialize_compressed(&mut buf).unwrap();
+            buf
+        }
+
+        // 合法的点可以正常解析
+        let p = G1Point::generator() * ScalarField::from(7u64);
+        assert_eq!(deserialize_point::<G1Point>(&to_bytes(&p)).unwrap(), p);
+        let q = G2Point::generator() * ScalarField::from(7u64);
+        assert_eq!(deserialize_point::<G2Point>(&to_bytes(&q)).unwrap(), q);
+
+        // 无穷远点
+        assert!(deserialize_point::<G1Point>(&to_bytes(&G1Point::zero())).is_err());
+        assert!(deserialize_point::<G2Point>(&to_bytes(&G2Point::zero())).is_err());
+
+        // 在曲线上但不在素数阶子群中的点
+        let off_g1 = (1u64..)
+            .filter_map(|x| G1AffinePoint::get_point_from_x_unchecked(Fq::from(x), true))
+            .find(|point| !point.is_in_correct_subgroup_assuming_on_curve())
+            .unwrap();
+        assert!(off_g1.is_on_curve());
+        let Err(err) = deserialize_point::<G1Point>(&to_bytes(&off_g1)) else {
+            panic!("off-subgroup G1 point must be rejected");
+        };
+        assert!(err.to_string().contains("subgroup"));
+
+        let off_g2 = (1u64..)
+            .filter_map(|x| {
+                G2Affine::get_point_from_x_unchecked(Fq2::new(Fq::from(x), Fq::one()), true)
+            })
+            .find(|point| !point.is_in_correct_subgroup_assuming_on_curve())
+            .unwrap();
+        assert!(deserialize_point::<G2Point>(&to_bytes(&off_g2)).is_err());
+    }
+
+    #[test]
+    fn test_lenient_validation_skips_only_the_subgroup_check() {
+        use ark_bls12_381::Fq;
+        use ark_std::Zero;
+
+        let mut rng = test_rng();
+        let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+        let ssk = rc::register_server(&msk, b"server").unwrap();
+
+        let off_g1 = (1u64..)
+            .filter_map(|x| G1AffinePoint::get_point_from_x_unchecked(Fq::from(x), true))
+            .find(|point| !point.is_in_correct_subgroup_assuming_on_curve())
+            .unwrap();
+        let mut off_bytes = Vec::new();
+        off_g1.serialize_compressed(&mut off_bytes).unwrap();
+        let mut zero_bytes = Vec::new();
+        G1Point::zero()
+            .serialize_compressed(&mut zero_bytes)
+            .unwrap();
+
+        let parse =
+            |bytes: &[u8], validation| deserialize_point_validated::<G1Point>(bytes, validation);
+        assert!(parse(&off_bytes, PointValidation::Strict).is_err());
+        assert_eq!(
+            parse(&off_bytes, PointValidation::Lenient).unwrap(),
+            G1Point::from(off_g1)
+        );
+        // 宽松模式仍然拒绝无穷远点
+        assert!(parse(&zero_bytes, PointValidation::Lenient).is_err());
+
+        // 合法请求在两种模式下都能完成握手
+        for validation in [PointValidation::Strict, PointValidation::Lenient] {
+            let (request, state) =
+                user::initiate_authentication(&usk, b"alice", b"server", &params, &mut rng)
+                    .unwrap();
+            let (response, server_key, _) = server::process_user_request_with_validation(
+                &ssk,
+                &request,
+                b"server",
+                &params,
+                &mut rng,
+                32,
+                &[],
+                validation,
+            )
+            .unwrap();
+            let user_key =
+                user::process_server_response(&usk, &state, &response, b"server", &params, 32)
+                    .unwrap();
+            assert_eq!(user_key, server_key);
+        }
+
+        // 把 N 中的 X' 替换为子群外的点：严格模式在解析时拒绝，宽松模式接受该点，由签名校验拒绝
+        let (mut request, state) =
+            user::initiate_authentication(&usk, b"alice", b"server", &params, &mut rng).unwrap();
+        let mut x_bytes = Vec::new();
+        state.temp_x_pub.serialize_compressed(&mut x_bytes).unwrap();
+        let x_offset = request.n.len() - x_bytes.len();
+        for (i, (old, new)) in x_bytes.iter().zip(&off_bytes).enumerate() {
+            request.n[x_offset + i] ^= old ^ new;
+        }
+        let process = |validation, rng: &mut StdRng| {
+            server::process_user_request_with_validation(
+                &ssk,
+                &request,
+                b"server",
+                &params,
+                rng,
+                32,
+                &[],
+                validation,
+            )
+        };
+        // Ru' 仍然合法，说明请求被篡改而不是发给了其他服务器
+        let err = process(PointValidation::Strict, &mut rng).unwrap_err();
+        assert!(matches!(err, AAKAError::Deserialization(_)), "{err:?}");
+        assert!(err.to_string().contains("subgroup"));
+        assert_eq!(
+            process(PointValidation::Lenient, &mut rng).unwrap_err(),
+            AAKAError::SignatureVerificationFailed
+        );
+        let outcomes = server::process_user_requests_with_validation(
+            &ssk,
+            std::slice::from_ref(&request),
+            b"server",
+            &params,
+            &mut rng,
+            32,
+            PointValidation::Strict,
+        );
+        assert!(matches!(outcomes[0], Err(AAKAError::Deserialization(_))));
+    }
+
+    #[test]
+    fn test_msm_verification_matches_naive_check() {
+        use ark_ff::UniformRand;
+
+        let mut rng = test_rng();
+        let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+        let (request, state) =
+            user::initiate_authentication(&usk, b"alice", b"server", &params, &mut rng).unwrap();
+
+        let h_0 = hash_utils::h0(&state.user_id, &state.r_u).unwrap();
+        let h_3 = hash_utils::h3(
+            &state.user_id,
+            &state.r_u,
+            &state.temp_x_pub,
+            request.timestamp,
+        )
+        .unwrap();
+        // 原先的逐项计算：σP =? Ru + h0 * Ppub + h3 * X
+        let naive = |r_u: G1Point, x: G1Point, sigma: ScalarField| {
+            params.p * sigma == r_u.add(params.p_pub * h_0).add(x * h_3)
+        };
+
+        let random = ScalarField::rand(&mut rng);
+        let cases = [
+            (state.r_u, state.temp_x_pub, request.sigma),
+            (
+                state.r_u,
+                state.temp_x_pub,
+                request.sigma + ScalarField::from(1u64),
+            ),
+            (state.r_u, state.temp_x_pub, random),
+            (state.r_u + params.p, state.temp_x_pub, request.sigma),
+            (
+                state.r_u,
+                state.temp_x_pub + state.temp_x_pub,
+                request.sigma,
+            ),
+        ];
+        for (i, (r_u, x, sigma)) in cases.into_iter().enumerate() {
+            let fast = server::verify_signature(&params, &r_u, &x, h_0, h_3, sigma).unwrap();
+            assert_eq!(fast, naive(r_u, x, sigma), "case {i}");
+            assert_eq!(fast, i == 0, "case {i}");
+        }
+
+        assert!(msm(&[params.p], &[]).is_err());
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_process_user_request_emits_step_spans() {
+        use std::sync::{Arc, Mutex};
+        use tracing_subscriber::{Layer, layer::Context, prelude::*, registry::LookupSpan};
+
+        // 按创建顺序记录 span 名称
+        struct SpanNames(Arc<Mutex<Vec<&'static str>>>);
+        impl<S: tracing::Subscriber + for<'a> LookupSpan<'a>> Layer<S> for SpanNames {
+            fn on_new_span(
+                &self,
+                attrs: &tracing::span::Attributes<'_>,
+                _: &tracing::span::Id,
+                _: Context<'_, S>,
+            ) {
+                self.0.lock().unwrap().push(attrs.metadata().name());
+            }
+        }
+
+        let mut rng = test_rng();
+        let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+        let ssk = rc::register_server(&msk, b"server").unwrap();
+        let (request, _) =
+            user::initiate_authentication(&usk, b"alice", b"server", &params, &mut rng).unwrap();
+
+        let names = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = tracing_subscriber::registry().with(SpanNames(names.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            server::process_user_request(&ssk, &request, b"server", &params, &mut rng, 32).unwrap();
+        });
+
+        assert_eq!(
+            *names.lock().unwrap(),
+            [
+                "process_user_request",
+                "pairing",
+                "decrypt_n",
+                "verify_signature",
+                "derive_key"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verify_user_key() {
+        let mut rng = test_rng();
+        let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+        assert!(rc::verify_user_key(&params, b"alice", &usk).is_ok());
+
+        // 错误的 ID、被篡改的 SIDu、其他 RC 签发的密钥都不一致
+        assert!(rc::verify_user_key(&params, b"bob", &usk).is_err());
+        let tampered = UserSecretKey {
+            sid_u: usk.sid_u + ScalarField::from(1u64),
+            ..usk.clone()
+        };
+        assert!(rc::verify_user_key(&params, b"alice", &tampered).is_err());
+        assert_ne!(tampered.fingerprint(), usk.fingerprint());
+        let (other_params, _) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        assert!(rc::verify_user_key(&other_params, b"alice", &usk).is_err());
+
+        // 注册时返回的校验值同样可以在没有系统参数时完成检查
+        let check = rc::UserKeyCheck::new(&params, b"alice", &usk).unwrap();
+        assert!(check.verify(b"alice", &usk).is_ok());
+        assert!(check.verify(b"bob", &usk).is_err());
+        assert!(check.verify(b"alice", &tampered).is_err());
+        let other_check = rc::UserKeyCheck::new(&other_params, b"alice", &usk).unwrap();
+        assert!(other_check.verify(b"alice", &usk).is_err());
+    }
+
+    #[test]
+    fn test_pseudonyms_differ_per_epoch_and_authenticate() {
+        let mut rng = test_rng();
+        let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        let ssk = rc::register_server(&msk, b"server").unwrap();
+
+        let (id1, usk1) = rc::register_pseudonym(&msk, b"alice", 1).unwrap();
+        let (id2, usk2) = rc::register_pseudonym(&msk, b"alice", 2).unwrap();
+        assert_ne!(id1, id2);
+        assert!(!id1.contains("alice") && !id2.contains("alice"));
+        assert_ne!(usk1.r_u, usk2.r_u);
+
+        // 同一 epoch 得到相同的凭证；RC 可以为候选 ID 重新计算假名完成去匿名化
+        let (again, usk1_again) = rc::register_pseudonym(&msk, b"alice", 1).unwrap();
+        assert_eq!((&again, usk1_again.sid_u), (&id1, usk1.sid_u));
+        assert_eq!(rc::pseudonym_id(&msk, b"alice", 2).unwrap(), id2);
+        assert_ne!(rc::pseudonym_id(&msk, b"bob", 2).unwrap(), id2);
+
+        // 两个假名凭证各自可以独立完成握手，但不能冒用对方的假名
+        for (id, usk) in [(&id1, &usk1), (&id2, &usk2)] {
+            assert!(rc::verify_user_key(&params, id.as_bytes(), usk).is_ok());
+            let (request, state) =
+                user::initiate_authentication(usk, id.as_bytes(), b"server", &params, &mut rng)
+                    .unwrap();
+            let (response, server_sk) =
+                server::process_user_request(&ssk, &request, b"server", &params, &mut rng, 32)
+                    .unwrap();
+            let user_sk =
+                user::process_server_response(usk, &state, &response, b"server", &params, 32)
+                    .unwrap();
+            assert_eq!(user_sk, server_sk);
+        }
+        assert!(rc::verify_user_key(&params, id1.as_bytes(), &usk2).is_err());
+    }
+
+    #[test]
+    fn test_fingerprints() {
+        let mut rng = test_rng();
+        let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        let (other_params, other_msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+
+        assert_eq!(params.fingerprint(), params.clone().fingerprint());
+        assert_eq!(params.fingerprint().len(), 16);
+        assert_ne!(params.fingerprint(), other_params.fingerprint());
+
+        let ssk = rc::register_server(&msk, b"server").unwrap();
+        assert_eq!(
+            ssk.fingerprint(),
+            rc::register_server(&msk, b"server").unwrap().fingerprint()
+        );
+        assert_ne!(
+            ssk.fingerprint(),
+            rc::register_server(&other_msk, b"server")
+                .unwrap()
+                .fingerprint()
+        );
+
+        let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+        assert_eq!(usk.fingerprint(), usk.clone().fingerprint());
+        assert_ne!(
+            usk.fingerprint(),
+            rc::register_user(&msk, b"alice", &mut rng)
+                .unwrap()
+                .fingerprint()
+        );
+    }
+
+    #[test]
+    fn test_batch_response_verification_flags_only_tampered() {
+        let mut rng = test_rng();
+        let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+
+        let server_ids: [&[u8]; 3] = [b"edge-1", b"edge-2", b"edge-3"];
+        let mut states = Vec::new();
+        let mut responses = Vec::new();
+        let mut server_keys = Vec::new();
+        for id in server_ids {
+            let ssk = rc::register_server(&msk, id).unwrap();
+            let (request, state) =
+                user::initiate_authentication(&usk, b"alice", id, &params, &mut rng).unwrap();
+            let (response, sk) =
+                server::process_user_request(&ssk, &request, id, &params, &mut rng, 32).unwrap();
+            states.push(state);
+            responses.push(response);
+            server_keys.push(sk);
+        }
+        // 篡改第二个响应的 Y
+        responses[1].y += params.p;
+
+        let items: Vec<_> = (0..3)
+            .map(|i| (&responses[i], server_ids[i], &states[i]))
+            .collect();
+        let results = user::process_server_responses(&usk, &items, &params, 32);
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap(), &server_keys[0]);
+        assert_eq!(
+            results[1].as_ref().unwrap_err(),
+            &AAKAError::ServerResponseVerificationFailed
+        );
+        assert_eq!(results[2].as_ref().unwrap(), &server_keys[2]);
+    }
+
+    #[test]
+    fn test_server_id_mismatch_reported_as_identity_mismatch() {
+        let mut rng = test_rng();
+        let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+        let ssk = rc::register_server(&msk, b"server").unwrap();
+
+        // 用户以为服务器叫 "sever"：服务器无法解开 N
+        let (request, _) =
+            user::initiate_authentication(&usk, b"alice", b"sever", &params, &mut rng).unwrap();
+        let err = server::process_user_request(&ssk, &request, b"server", &params, &mut rng, 32)
+            .unwrap_err();
+        assert!(
+            matches!(err, AAKAError::ServerIdentityMismatch(_)),
+            "{err:?}"
+        );
+        assert!(err.to_string().contains("server ID"));
+
+        // N 在传输中损坏（Ru' 中的一位翻转）不是身份不一致
+        let (mut request, _) =
+            user::initiate_authentication(&usk, b"alice", b"server", &params, &mut rng).unwrap();
+        let r_u_offset = request.n.len() - 2 * hash_utils::compressed_g1_len();
+        request.n[r_u_offset + 10] ^= 0x01;
+        let err = server::process_user_request(&ssk, &request, b"server", &params, &mut rng, 32)
+            .unwrap_err();
+        assert!(matches!(err, AAKAError::Deserialization(_)), "{err:?}");
+        assert!(err.to_string().contains("Ru'"));
+
+        // 处理响应时使用了与发起认证时不同的服务器 ID
+        let (request, state) =
+            user::initiate_authentication(&usk, b"alice", b"server", &params, &mut rng).unwrap();
+        let (response, _) =
+            server::process_user_request(&ssk, &request, b"server", &params, &mut rng, 32).unwrap();
+        let err = user::process_server_response(&usk, &state, &response, b"sever", &params, 32)
+            .unwrap_err();
+        assert!(
+            matches!(err, AAKAError::ServerIdentityMismatch(_)),
+            "{err:?}"
+        );
+        assert!(err.to_string().contains("\"server\""));
+    }
+
+    #[test]
+    fn test_user_and_server_derive_same_session_id() {
+        let mut rng = test_rng();
+        let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+        let ssk = rc::register_server(&msk, b"server").unwrap();
+
+        let mut handshake = || {
+            let (request, state) =
+                user::initiate_authentication(&usk, b"alice", b"server", &params, &mut rng)
+                    .unwrap();
+            let (response, _, server_transcript) = server::process_user_request_with_transcript(
+                &ssk,
+                &request,
+                b"server",
+                &params,
+                &mut rng,
+                32,
+                &[],
+            )
+            .unwrap();
+            let user_transcript = Transcript::from_user(&state, &response);
+            assert_eq!(user_transcript, server_transcript);
+            (
+                session_id(&user_transcript).unwrap(),
+                session_id(&server_transcript).unwrap(),
+            )
+        };
+
+        let (user_sid, server_sid) = handshake();
+        assert_eq!(user_sid, server_sid);
+        // 每次握手的 X、Y 都是新的，会话 ID 随之不同
+        let (next_sid, _) = handshake();
+        assert_ne!(next_sid, user_sid);
+    }
+
+    #[test]
+    fn test_keystream_xof_must_match() {
+        let mut rng = test_rng();
+        let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        assert_eq!(params.xof, Xof::Shake256);
+        let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+        let ssk = rc::register_server(&msk, b"server").unwrap();
+        let with_xof = |xof| SystemParameters {
+            xof,
+            ..params.clone()
+        };
+
+        // 双方使用相同的 XOF 时握手成功
+        for xof in [Xof::Shake128, Xof::Shake256] {
+            let params = with_xof(xof);
+            let (request, state) =
+                user::initiate_authentication(&usk, b"alice", b"server", &params, &mut rng)
+                    .unwrap();
+            let (response, server_key) =
+                server::process_user_request(&ssk, &request, b"server", &params, &mut rng, 32)
+                    .unwrap();
+            let user_key =
+                user::process_server_response(&usk, &state, &response, b"server", &params, 32)
+                    .unwrap();
+            assert_eq!(user_key, server_key, "{xof:?}");
+        }
+
+        // 用户使用 SHAKE128、服务器使用 SHAKE256：服务器解不开 N
+        let (request, _) = user::initiate_authentication(
+            &usk,
+            b"alice",
+            b"server",
+            &with_xof(Xof::Shake128),
+            &mut rng,
+        )
+        .unwrap();
+        assert!(
+            server::process_user_request(
+                &ssk,
+                &request,
+                b"server",
+                &with_xof(Xof::Shake256),
+                &mut rng,
+                32
+            )
+            .is_err()
+        );
+
+        // XOF 参与参数的序列化与指纹
+        assert_ne!(
+            with_xof(Xof::Shake128).fingerprint(),
+            with_xof(Xof::Shake256).fingerprint()
+        );
+        let bytes = wire::to_bytes(&with_xof(Xof::Shake128)).unwrap();
+        assert_eq!(
+            SystemParameters::try_from(bytes.as_slice()).unwrap().xof,
+            Xof::Shake128
+        );
+    }
+
+    #[test]
+    fn test_deployment_info_separates_session_keys() {
+        let mut rng = test_rng();
+        let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        assert!(params.deployment.is_empty());
+        let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+        let ssk = rc::register_server(&msk, b"server").unwrap();
+        let with_deployment = |info: &str| SystemParameters {
+            deployment: DeploymentInfo::new(info.to_string()).unwrap(),
+            ..params.clone()
+        };
+
+        // 双方的部署标识相同时握手成功
+        let staging = with_deployment("staging");
+        let (request, state) =
+            user::initiate_authentication(&usk, b"alice", b"server", &staging, &mut rng).unwrap();
+        let (response, server_key) =
+            server::process_user_request(&ssk, &request, b"server", &staging, &mut rng, 32)
+                .unwrap();
+        let user_key = |params: &SystemParameters| {
+            user::process_server_response(&usk, &state, &response, b"server", params, 32).unwrap()
+        };
+        assert_eq!(user_key(&staging), server_key);
+        // 同一次握手，用户按另一个部署派生出不同的密钥
+        assert_ne!(user_key(&with_deployment("prod")).0, server_key.0);
+        assert_ne!(user_key(&params).0, server_key.0);
+
+        // 空标识与之前的输出一致；标识与 context 不会混淆
+        let k = params.p;
+        let h5 = |deployment: &[u8], context: &[u8]| {
+            hash_utils::h5(&k, b"alice", b"server", &k, &k, 32, deployment, context).unwrap()
+        };
+        assert_ne!(h5(b"prod", b""), h5(b"", b""));
+        assert_ne!(h5(b"prod", b""), h5(b"", b"prod"));
+        assert_ne!(h5(b"prod", b""), h5(b"", b"Dprod"));
+
+        // 标识参与参数的序列化与指纹，空标识不改变原有编码
+        assert_ne!(staging.fingerprint(), params.fingerprint());
+        let bytes = wire::to_bytes(&staging).unwrap();
+        assert_eq!(
+            SystemParameters::try_from(bytes.as_slice()).unwrap(),
+            staging
+        );
+        let legacy = wire::to_bytes(&params).unwrap();
+        assert_eq!(bytes.len(), legacy.len() + 1 + "staging".len());
+        assert_eq!(
+            SystemParameters::try_from(legacy.as_slice()).unwrap(),
+            params
+        );
+
+        assert!(DeploymentInfo::new("x".repeat(hash_utils::MAX_DEPLOYMENT_INFO_LEN)).is_ok());
+        assert!(DeploymentInfo::new("x".repeat(hash_utils::MAX_DEPLOYMENT_INFO_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn test_error_clone_and_eq() {
+        let err = AAKAError::InvalidInput("bad".to_string());
+        assert_eq!(err.clone(), AAKAError::InvalidInput("bad".to_string()));
+        assert_ne!(err, AAKAError::InvalidInput("other".to_string()));
+        assert_ne!(err, AAKAError::Other("bad".to_string()));
+    }
+
+    #[test]
+    fn test_error_codes_are_stable() {
+        let not_ready = NotReady::NoParams;
+        let s = String::new;
+        for (err, code) in [
+            (AAKAError::Serialization(s()), "ERR_SERIALIZATION"),
+            (AAKAError::Deserialization(s()), "ERR_DESERIALIZATION"),
+            (AAKAError::CryptoError(s()), "ERR_CRYPTO"),
+            (AAKAError::InvalidTimestamp, "ERR_STALE_TS"),
+            (AAKAError::SignatureVerificationFailed, "ERR_BAD_SIGNATURE"),
+            (
+                AAKAError::ServerResponseVerificationFailed,
+                "ERR_BAD_SERVER_RESPONSE",
+            ),
+            (
+                AAKAError::ServerIdentityMismatch(s()),
+                "ERR_SERVER_ID_MISMATCH",
+            ),
+            (AAKAError::InvalidInput(s()), "ERR_INVALID_INPUT"),
+            (AAKAError::NotInitialized(not_ready), "ERR_NOT_INITIALIZED"),
+            (AAKAError::HashError(s()), "ERR_HASH"),
+            (
+                AAKAError::UnsupportedSuite {
+                    requested: 0,
+                    supported: Vec::new(),
+                },
+                "ERR_UNSUPPORTED_SUITE",
+            ),
+            (
+                AAKAError::ClockSkew {
+                    skew_secs: 0,
+                    max_secs: 0,
+                },
+                "ERR_CLOCK_SKEW",
+            ),
+            (AAKAError::KeyConfirmationFailed, "ERR_KEY_CONFIRMATION"),
+            (AAKAError::Other(s()), "ERR_OTHER"),
+        ] {
+            assert_eq!(err.code(), code, "{err:?}");
+        }
+    }
+
+    #[test]
+    fn test_msk_encrypted_backup_roundtrip() {
+        let (params, msk) = rc::gen_parameter_and_msk(&mut test_rng()).unwrap();
+        let blob = msk.export_encrypted(b"correct horse").unwrap();
+        // 密文中不出现主密钥的明文
+        let s = wire::to_bytes(&msk.s).unwrap();
+        assert!(!blob.windows(s.len()).any(|window| window == s));
+
+        let restored = MasterSecretKey::import_encrypted(&blob, b"correct horse").unwrap();
+        assert_eq!(restored, msk);
+        let ssk = rc::register_server(&restored, b"server").unwrap();
+        rc::verify_server_key(&params, b"server", &ssk).unwrap();
+
+        assert!(matches!(
+            MasterSecretKey::import_encrypted(&blob, b"wrong"),
+            Err(AAKAError::CryptoError(_))
+        ));
+        let mut tampered = blob.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(MasterSecretKey::import_encrypted(&tampered, b"correct horse").is_err());
+        // 服务器密钥文件不能当作主密钥备份导入
+        let key_file = keyfile::seal(&params, &ssk, b"correct horse").unwrap();
+        assert!(MasterSecretKey::import_encrypted(&key_file, b"correct horse").is_err());
+    }
+
+    #[test]
+    fn test_mismatched_suite_is_rejected() {
+        use hash_utils::Xof;
+        use suite::Suite;
+
+        let mut rng = test_rng();
+        let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+        let ssk = rc::register_server(&msk, b"server").unwrap();
+
+        // 用户要 16 字节的会话密钥，服务器只派生 32 字节的
+        let (request, _) = user::initiate_authentication_with_key_len(
+            &usk, b"alice", b"server", &params, 16, &mut rng,
+        )
+        .unwrap();
+        assert_eq!(request.suite_id, 0x1210);
+        let err = server::process_user_request(&ssk, &request, b"server", &params, &mut rng, 32)
+            .unwrap_err();
+        assert_eq!(
+            err,
+            AAKAError::UnsupportedSuite {
+                requested: 0x1210,
+                supported: vec![0x1220],
+            }
+        );
+        assert_eq!(err.code(), "ERR_UNSUPPORTED_SUITE");
+        assert!(
+            err.to_string()
+                .contains("0x1220 (BLS12-381, SHA3-256, shake256, 32-byte key)"),
+            "{err}"
+        );
+
+        // XOF 不一致同样在计算之前被拒绝
+        let mut shake128 = params.clone();
+        shake128.xof = Xof::Shake128;
+        let (request, _) =
+            user::initiate_authentication(&usk, b"alice", b"server", &shake128, &mut rng).unwrap();
+        let err = server::process_user_request(&ssk, &request, b"server", &params, &mut rng, 32)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            AAKAError::UnsupportedSuite {
+                requested: 0x1120,
+                ..
+            }
+        ));
+
+        for suite in [
+            Suite::new(Xof::Shake256, 32).unwrap(),
+            Suite::new(Xof::Shake128, 255).unwrap(),
+        ] {
+            assert_eq!(Suite::from_id(suite.id()).unwrap(), suite);
+        }
+        assert!(Suite::new(Xof::Shake256, 256).is_err());
+        assert!(Suite::from_id(0x2220).is_err());
+        assert!(Suite::from_id(0x1200).is_err());
+    }
+
+    #[test]
+    fn test_params_validate() {
+        use ark_std::Zero;
+
+        let (params, _) = rc::gen_parameter_and_msk(&mut test_rng()).unwrap();
+        params.validate().unwrap();
+
+        let mut bad = params.clone();
+        bad.p_pub = G1Point::zero();
+        assert!(bad.validate().is_err());
+
+        let mut bad = params.clone();
+        bad.p = params.p_pub;
+        assert!(bad.validate().is_err());
+
+        let mut bad = params;
+        bad.g = bad.g + bad.g;
+        assert!(bad.validate().is_err());
+    }
+
+    #[test]
+    fn test_params_validate_strict_names_failing_field() {
+        use ark_bls12_381::Fq;
+        use ark_std::{One, Zero};
+
+        let (params, _) = rc::gen_parameter_and_msk(&mut test_rng()).unwrap();
+        params.validate_strict().unwrap();
+
+        let off_curve = G1Point::new_unchecked(Fq::one(), Fq::one(), Fq::one());
+        let off_subgroup: G1Point = (1u64..)
+            .filter_map(|x| G1AffinePoint::get_point_from_x_unchecked(Fq::from(x), true))
+            .find(|point| !point.is_in_correct_subgroup_assuming_on_curve())
+            .unwrap()
+            .into();
+
+        let corrupt = |f: &dyn Fn(&mut SystemParameters)| {
+            let mut bad = params.clone();
+            f(&mut bad);
+            bad.validate_strict().unwrap_err().to_string()
+        };
+        for (field, err) in [
+            ("`p`", corrupt(&|p| p.p = off_curve)),
+            ("`p`", corrupt(&|p| p.p = p.p_pub)),
+            ("`p_pub`", corrupt(&|p| p.p_pub = off_subgroup)),
+            ("`p_pub_hat`", corrupt(&|p| p.p_pub_hat = G1Point::zero())),
+            ("`g`", corrupt(&|p| p.g = p.g + p.g)),
+        ] {
+            assert!(err.contains(field), "{field}: {err}");
+        }
+    }
+
+    #[test]
+    fn test_session_key_seal_open_roundtrip() {
+        let sk = SessionKey(vec![7u8; 32]);
+        assert_ne!(sk.expand(b"a"), sk.expand(b"b"));
+        assert_ne!(&sk.expand(SessionKey::MESSAGE_LABEL)[..], &sk.0[..]);
+
+        let (ciphertext, nonce) = sk.seal(b"hello").unwrap();
+        assert_eq!(sk.open(&ciphertext, &nonce).unwrap(), b"hello");
+
+        // 不同的会话密钥无法解密，错误的 nonce 长度不会 panic
+        let other = SessionKey(vec![8u8; 32]);
+        assert!(other.open(&ciphertext, &nonce).is_err());
+        assert!(sk.open(&ciphertext, &nonce[..4]).is_err());
+    }
+
+    #[test]
+    fn test_shares_roundtrip() {
+        let (_params, msk) = rc::gen_parameter_and_msk(&mut test_rng()).unwrap();
+
+        let shares = msk.clone().into_shares(2, 3);
+        let recovered = MasterSecretKey::from_shares(shares[1..].to_vec(), 2).unwrap();
+        assert_eq!(msk, recovered);
+    }
+
+    #[test]
+    fn test_shares_duplicate_index_rejected() {
+        let (_params, msk) = rc::gen_parameter_and_msk(&mut test_rng()).unwrap();
+        let shares = msk.into_shares(2, 3);
+
+        // Two peers returning the same share (e.g. after a botched reshare)
+        let duplicated = vec![shares[0].clone(), shares[0].clone()];
+        match MasterSecretKey::from_shares(duplicated, 2) {
+            Err(AAKAError::InvalidInput(msg)) => assert!(msg.contains("duplicate share index")),
+            other => panic!("Expected duplicate index error, got {:?}", other),
+        }
+
+        // Same index but a different body
+        let mut forged = shares[1].clone();
+        forged.share.x = shares[0].share.x.clone();
+        let mismatched = vec![shares[0].clone(), forged, shares[2].clone()];
+        match MasterSecretKey::from_shares(mismatched, 2) {
+            Err(AAKAError::InvalidInput(msg)) => assert!(msg.contains("duplicate share index")),
+            other => panic!("Expected duplicate index error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_shares_inconsistent_length_rejected() {
+        let (_params, msk) = rc::gen_parameter_and_msk(&mut test_rng()).unwrap();
+        let mut shares = msk.into_shares(2, 3);
+        shares[1].share.y.truncate(32);
+
+        assert!(matches!(
+            MasterSecretKey::from_shares(shares, 2),
+            Err(AAKAError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_shares_from_different_epochs_rejected() {
+        let (_params, msk) = rc::gen_parameter_and_msk(&mut test_rng()).unwrap();
+        let old = msk.clone().into_shares(2, 3);
+        // 重新拆分同一个 msk（例如成员变更后的 reshare），后一次拆分的 epoch 更大
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let new = msk.clone().into_shares(2, 3);
+        assert_eq!(old[0].epoch, old[2].epoch);
+        assert!(new[0].epoch > old[0].epoch);
+
+        // 新旧 share 的索引互不重复，但来自不同的多项式，插值会得到错误的 msk
+        let mixed = vec![old[0].clone(), new[1].clone()];
+        match MasterSecretKey::from_shares(mixed, 2) {
+            Err(AAKAError::InvalidInput(msg)) => {
+                assert!(msg.contains("different master key epochs"))
+            }
+            other => panic!("Expected epoch mismatch error, got {:?}", other),
+        }
+
+        // epoch 随 share 一起经过信封传输
+        let envelope = ShareEnvelope::from(&new[1]);
+        assert_eq!(envelope.epoch, new[1].epoch);
+        let decoded = EpochShare::try_from(envelope).unwrap();
+        let recovered = MasterSecretKey::from_shares(vec![new[0].clone(), decoded], 2).unwrap();
+        assert_eq!(recovered, msk);
+    }
+
     // #[test]
     // fn test_shares() {
     //     let mut rng = StdRng::from_entropy();
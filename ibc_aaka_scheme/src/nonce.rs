@@ -0,0 +1,132 @@
+//! 临时随机数（用户的 x、服务器的 y）的重复检测。
+//!
+//! x 或 y 一旦重复，对应会话的密钥就可以由另一次会话推出。正常的 CSPRNG 不会出现重复，
+//! 这里作为纵深防御：进程内记录最近生成的随机数的指纹，出现重复时说明 RNG 已经损坏
+//! （例如被克隆后重复使用），丢弃该值并重新抽取。
+//!
+//! 只保存 SHA3-256 的前 16 字节，不保存随机数本身；最多保存 [`RECENT_NONCES_CAPACITY`] 个，
+//! 超出时淘汰最早的记录。记录由整个进程共享而不是按线程划分：服务器在 `spawn_blocking`
+//! 的线程池中生成 y，同一个损坏的 RNG 在不同线程上产生的重复值也要能发现。
+use crate::{AAKAError, ScalarField, wire};
+use ark_ff::UniformRand;
+use ark_std::rand::{CryptoRng, Rng};
+use digest::Digest;
+use std::collections::{HashSet, VecDeque};
+use std::sync::{LazyLock, Mutex};
+
+/// 进程内记录的随机数指纹数量上限
+pub const RECENT_NONCES_CAPACITY: usize = 4096;
+
+// 连续抽到重复值的次数上限，超过时 RNG 显然已经不可用
+const MAX_DRAWS: usize = 8;
+
+static RECENT: LazyLock<Mutex<RecentNonces>> =
+    LazyLock::new(|| Mutex::new(RecentNonces::new(RECENT_NONCES_CAPACITY)));
+
+type Fingerprint = [u8; 16];
+
+/// 最近生成的随机数的指纹，容量固定，按插入顺序淘汰
+pub struct RecentNonces {
+    capacity: usize,
+    order: VecDeque<Fingerprint>,
+    seen: HashSet<Fingerprint>,
+}
+
+impl RecentNonces {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            seen: HashSet::with_capacity(capacity),
+        }
+    }
+
+    /// 记录 `nonce`；最近已经出现过时返回 `false`
+    pub fn insert(&mut self, nonce: &ScalarField) -> Result<bool, AAKAError> {
+        let digest = sha3::Sha3_256::digest(wire::to_bytes(nonce)?);
+        let fingerprint: Fingerprint = digest[..16].try_into().expect("digest is 32 bytes");
+        if !self.seen.insert(fingerprint) {
+            return Ok(false);
+        }
+        self.order.push_back(fingerprint);
+        if self.order.len() > self.capacity
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.seen.remove(&oldest);
+        }
+        Ok(true)
+    }
+}
+
+/// 抽取一个本进程最近未出现过的随机标量，并记录下来
+pub(crate) fn fresh_nonce<R: Rng + CryptoRng>(rng: &mut R) -> Result<ScalarField, AAKAError> {
+    draw_fresh(&mut RECENT.lock().unwrap(), rng)
+}
+
+fn draw_fresh<R: Rng + CryptoRng>(
+    recent: &mut RecentNonces,
+    rng: &mut R,
+) -> Result<ScalarField, AAKAError> {
+    for _ in 0..MAX_DRAWS {
+        let nonce = ScalarField::rand(rng);
+        if recent.insert(&nonce)? {
+            return Ok(nonce);
+        }
+        #[cfg(feature = "tracing")]
+        tracing::warn!("RNG produced a recently used nonce, drawing a fresh one");
+    }
+    Err(AAKAError::CryptoError(format!(
+        "RNG produced a recently used nonce {MAX_DRAWS} times in a row"
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::Rigged;
+
+    #[test]
+    fn test_repeated_nonce_is_redrawn() {
+        let mut recent = RecentNonces::new(16);
+        // 每个标量消耗 4 个字：第一次与第二次抽取得到相同的值，第三次不同
+        let mut rng = Rigged::new(vec![1, 2, 3, 4, 1, 2, 3, 4, 5, 6, 7, 8]);
+        let first = draw_fresh(&mut recent, &mut rng).unwrap();
+        let second = draw_fresh(&mut recent, &mut rng).unwrap();
+        assert_ne!(first, second);
+        // 重复的值被丢弃，第二次调用消耗了两个标量的随机数
+        assert_eq!(rng.pos, 12);
+        assert_eq!(
+            second,
+            ScalarField::rand(&mut Rigged::new(vec![5, 6, 7, 8]))
+        );
+
+        // 一直重复的 RNG 最终报错
+        let mut stuck = Rigged::new(vec![1, 2, 3, 4]);
+        assert!(matches!(
+            draw_fresh(&mut recent, &mut stuck),
+            Err(AAKAError::CryptoError(_))
+        ));
+
+        // 超出容量后最早的记录被淘汰
+        let mut small = RecentNonces::new(1);
+        let (a, b) = (ScalarField::from(1u64), ScalarField::from(2u64));
+        assert!(small.insert(&a).unwrap());
+        assert!(!small.insert(&a).unwrap());
+        assert!(small.insert(&b).unwrap());
+        assert!(small.insert(&a).unwrap());
+    }
+
+    #[test]
+    fn test_repeat_detected_across_threads() {
+        // 同一个 RNG 的副本在两个线程上使用（例如 spawn_blocking 的线程池），重复值同样被丢弃
+        let words = vec![31, 37, 41, 43, 47, 53, 59, 61];
+        let draw = |words: Vec<u64>| {
+            std::thread::spawn(move || fresh_nonce(&mut Rigged::new(words)).unwrap())
+                .join()
+                .unwrap()
+        };
+        let first = draw(words.clone());
+        let second = draw(words);
+        assert_ne!(first, second);
+    }
+}
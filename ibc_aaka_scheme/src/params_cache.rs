@@ -0,0 +1,88 @@
+//! MS 和用户端共用的系统参数缓存。
+//!
+//! 通过 RC `/params` 返回的 `ETag` 判断参数是否轮换；刷新失败时保留上一次有效的参数，
+//! 因此 RC 短暂不可用不会影响已经在运行的服务。
+use crate::{
+    AAKAError, SystemParameters,
+    wire::{CBOR_CONTENT_TYPE, Cbor},
+};
+use reqwest::{StatusCode, header};
+use std::sync::RwLock;
+
+#[derive(Default)]
+struct Cached {
+    params: Option<SystemParameters>,
+    etag: Option<String>,
+}
+
+pub struct ParamsCache {
+    rc_url: String,
+    cached: RwLock<Cached>,
+}
+
+impl ParamsCache {
+    pub fn new(rc_url: impl Into<String>) -> Self {
+        Self {
+            rc_url: rc_url.into(),
+            cached: RwLock::default(),
+        }
+    }
+
+    /// 使用启动时已经获取的参数初始化缓存
+    pub fn with_params(rc_url: impl Into<String>, params: SystemParameters) -> Self {
+        let cache = Self::new(rc_url);
+        cache.cached.write().unwrap().params = Some(params);
+        cache
+    }
+
+    /// 最近一次有效的参数
+    pub fn get(&self) -> Option<SystemParameters> {
+        self.cached.read().unwrap().params.clone()
+    }
+
+    /// 向 RC 查询参数是否变化，返回 `Ok(true)` 表示缓存中的参数已被替换。
+    ///
+    /// RC 不可达、返回错误或无效参数（见 [`SystemParameters::validate`]）时返回错误，缓存保持不变。
+    pub async fn refresh(&self, client: &reqwest::Client) -> Result<bool, AAKAError> {
+        let etag = self.cached.read().unwrap().etag.clone();
+
+        let mut request = client
+            .get(format!("{}/params", self.rc_url))
+            .header(header::ACCEPT, CBOR_CONTENT_TYPE);
+        if let Some(etag) = &etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        let resp = request
+            .send()
+            .await
+            .map_err(|e| AAKAError::Other(format!("Failed to fetch params from RC: {e}")))?;
+
+        if resp.status() == StatusCode::NOT_MODIFIED {
+            return Ok(false);
+        }
+        if !resp.status().is_success() {
+            return Err(AAKAError::Other(format!(
+                "RC returned {} when fetching params",
+                resp.status()
+            )));
+        }
+
+        let new_etag = resp
+            .headers()
+            .get(header::ETAG)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let body = resp
+            .bytes()
+            .await
+            .map_err(|e| AAKAError::Other(format!("Failed to read params from RC: {e}")))?;
+        let params = SystemParameters::from_cbor(&body)?;
+        params.validate_strict()?;
+
+        let mut cached = self.cached.write().unwrap();
+        let changed = cached.params.as_ref() != Some(&params);
+        cached.params = Some(params);
+        cached.etag = new_etag;
+        Ok(changed)
+    }
+}
@@ -0,0 +1,74 @@
+//! 常用类型与函数的统一导出，使用 `use ibc_aaka_scheme::prelude::*;` 一次性导入。
+pub use crate::{
+    AAKAError, CheckedPoint, Curve, G1AffinePoint, G1Point, G2Point, GtPoint, MasterSecretKey,
+    PointValidation, ScalarField, ServerAuthResponse, ServerSecretKey, SessionKey,
+    SystemParameters, Transcript, UserAuthRequest, UserSecretKey, UserState, deserialize_point,
+    hash_utils::Xof,
+    params_cache::ParamsCache,
+    rc::{
+        self, gen_parameter_and_msk, register_server, register_user, verify_server_key,
+        verify_user_key,
+    },
+    replay::{BloomConfig, BloomReplayCache, MemoryReplayCache, ReplayCache, request_nonce},
+    server::{
+        self, process_user_request, process_user_request_with_context,
+        process_user_request_with_transcript, process_user_request_with_validation,
+        process_user_requests, process_user_requests_with_validation,
+    },
+    session_id,
+    suite::{DEFAULT_KEY_LEN, Suite},
+    user::{
+        self, initiate_authentication, initiate_authentication_with_key_len,
+        process_server_response, process_server_response_with_context, process_server_responses,
+    },
+    wire::{CBOR_CONTENT_TYPE, Cbor, PointEncoding},
+};
+
+#[cfg(test)]
+mod tests {
+    // 只依赖 prelude 完成一次完整的注册与握手，覆盖各应用当前从本 crate 导入的内容
+    #[test]
+    fn test_prelude_exposes_app_imports() {
+        use super::*;
+        use ark_std::rand::{SeedableRng, rngs::StdRng};
+
+        let mut rng = StdRng::from_entropy();
+        let (params, msk): (SystemParameters, MasterSecretKey) =
+            gen_parameter_and_msk(&mut rng).unwrap();
+        let usk: UserSecretKey = register_user(&msk, b"alice", &mut rng).unwrap();
+        let ssk: ServerSecretKey = register_server(&msk, b"server").unwrap();
+
+        let (request, state): (UserAuthRequest, UserState) =
+            initiate_authentication(&usk, b"alice", b"server", &params, &mut rng).unwrap();
+        let request = UserAuthRequest::from_cbor(&request.to_cbor().unwrap()).unwrap();
+        let (response, server_sk): (ServerAuthResponse, SessionKey) =
+            process_user_request(&ssk, &request, b"server", &params, &mut rng, 32).unwrap();
+        let user_sk =
+            process_server_response(&usk, &state, &response, b"server", &params, 32).unwrap();
+        assert_eq!(user_sk.0, server_sk.0);
+
+        let cache: &dyn ReplayCache = &MemoryReplayCache::default();
+        assert!(
+            cache
+                .check_and_insert(&request_nonce(&request).unwrap(), request.timestamp)
+                .unwrap()
+        );
+
+        let p: G1Point = deserialize_point(&crate::wire::to_bytes(&params.p).unwrap()).unwrap();
+        let _: Result<G1Point, AAKAError> = <G1Point as CheckedPoint>::deserialize_checked(&[]);
+        let _: (G1AffinePoint, G2Point, GtPoint, ScalarField) = Default::default();
+        let _ = (
+            rc::register_server,
+            server::process_user_request::<StdRng>,
+            user::process_server_response,
+        );
+        let _ = (ParamsCache::new("http://localhost"), CBOR_CONTENT_TYPE);
+        let _ = BloomReplayCache::new(&BloomConfig {
+            expected_rate: 1.0,
+            ttl_secs: 60,
+            false_positive_rate: 1e-3,
+        });
+        let _: Option<Curve> = None;
+        assert_eq!(p, params.p);
+    }
+}
@@ -0,0 +1,139 @@
+//! gRPC 接口的 protobuf 消息与 tonic 服务代码（`grpc` feature）。
+//!
+//! 消息中的点和标量与 [`crate::wire`] 一样以压缩后的原始字节传输。
+use crate::{
+    AAKAError, deserialize_point,
+    wire::{from_bytes, to_bytes},
+};
+
+tonic::include_proto!("aaka");
+
+impl TryFrom<&crate::SystemParameters> for SystemParameters {
+    type Error = AAKAError;
+
+    fn try_from(params: &crate::SystemParameters) -> Result<Self, AAKAError> {
+        Ok(Self {
+            p: to_bytes(&params.p)?,
+            p_pub: to_bytes(&params.p_pub)?,
+            p_pub_hat: to_bytes(&params.p_pub_hat)?,
+            g: to_bytes(&params.g)?,
+            xof: params.xof.as_str().to_string(),
+            deployment_info: params.deployment.as_str().to_string(),
+        })
+    }
+}
+
+impl TryFrom<SystemParameters> for crate::SystemParameters {
+    type Error = AAKAError;
+
+    fn try_from(msg: SystemParameters) -> Result<Self, AAKAError> {
+        Ok(Self {
+            p: deserialize_point(&msg.p)?,
+            p_pub: deserialize_point(&msg.p_pub)?,
+            p_pub_hat: deserialize_point(&msg.p_pub_hat)?,
+            g: from_bytes(&msg.g)?,
+            // 空字符串表示未携带此字段的旧消息
+            xof: match msg.xof.as_str() {
+                "" => Default::default(),
+                xof => xof.parse()?,
+            },
+            deployment: crate::hash_utils::DeploymentInfo::new(msg.deployment_info)?,
+        })
+    }
+}
+
+impl TryFrom<&crate::UserSecretKey> for UserSecretKey {
+    type Error = AAKAError;
+
+    fn try_from(usk: &crate::UserSecretKey) -> Result<Self, AAKAError> {
+        Ok(Self {
+            r_u: to_bytes(&usk.r_u)?,
+            sid_u: to_bytes(&usk.sid_u)?,
+        })
+    }
+}
+
+impl TryFrom<UserSecretKey> for crate::UserSecretKey {
+    type Error = AAKAError;
+
+    fn try_from(msg: UserSecretKey) -> Result<Self, AAKAError> {
+        Ok(Self {
+            r_u: deserialize_point(&msg.r_u)?,
+            sid_u: from_bytes(&msg.sid_u)?,
+        })
+    }
+}
+
+impl TryFrom<&crate::ServerSecretKey> for ServerSecretKey {
+    type Error = AAKAError;
+
+    fn try_from(ssk: &crate::ServerSecretKey) -> Result<Self, AAKAError> {
+        Ok(Self {
+            sid_ms: to_bytes(&ssk.sid_ms)?,
+        })
+    }
+}
+
+impl TryFrom<ServerSecretKey> for crate::ServerSecretKey {
+    type Error = AAKAError;
+
+    fn try_from(msg: ServerSecretKey) -> Result<Self, AAKAError> {
+        Ok(Self {
+            sid_ms: deserialize_point(&msg.sid_ms)?,
+        })
+    }
+}
+
+impl TryFrom<&crate::UserAuthRequest> for UserAuthRequest {
+    type Error = AAKAError;
+
+    fn try_from(request: &crate::UserAuthRequest) -> Result<Self, AAKAError> {
+        Ok(Self {
+            m: to_bytes(&request.m)?,
+            n: request.n.clone(),
+            sigma: to_bytes(&request.sigma)?,
+            timestamp: request.timestamp,
+            suite_id: request.suite_id.into(),
+        })
+    }
+}
+
+impl TryFrom<UserAuthRequest> for crate::UserAuthRequest {
+    type Error = AAKAError;
+
+    fn try_from(msg: UserAuthRequest) -> Result<Self, AAKAError> {
+        Ok(Self {
+            m: deserialize_point(&msg.m)?,
+            n: msg.n,
+            sigma: from_bytes(&msg.sigma)?,
+            timestamp: msg.timestamp,
+            suite_id: u16::try_from(msg.suite_id).map_err(|_| {
+                AAKAError::InvalidInput(format!("Unknown cipher suite {:#x}", msg.suite_id))
+            })?,
+        })
+    }
+}
+
+impl TryFrom<&crate::ServerAuthResponse> for ServerAuthResponse {
+    type Error = AAKAError;
+
+    fn try_from(response: &crate::ServerAuthResponse) -> Result<Self, AAKAError> {
+        Ok(Self {
+            t: to_bytes(&response.t)?,
+            y: to_bytes(&response.y)?,
+            timestamp: response.timestamp,
+        })
+    }
+}
+
+impl TryFrom<ServerAuthResponse> for crate::ServerAuthResponse {
+    type Error = AAKAError;
+
+    fn try_from(msg: ServerAuthResponse) -> Result<Self, AAKAError> {
+        Ok(Self {
+            t: from_bytes(&msg.t)?,
+            y: deserialize_point(&msg.y)?,
+            timestamp: msg.timestamp,
+        })
+    }
+}
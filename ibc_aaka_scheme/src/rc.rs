@@ -9,12 +9,15 @@ use crate::{
     SystemParameters,
     UserSecretKey, // Use the hash functions we defined
     hash_utils,
+    secret::Secret,
+    wire,
 };
 use ark_ec::{Group, pairing::Pairing}; // Need CurveGroup for zero(), Group for identity
-use ark_ff::{BigInt, Field, UniformRand}; // Need Field for inverse, UniformRand for random generation
+use ark_ff::{Field, UniformRand}; // Need Field for inverse, UniformRand for random generation
 use ark_std::Zero;
 use ark_std::ops::Add;
 use ark_std::rand::prelude::*; // For random number generation (e.g., thread_rng) // Need Add trait
+use sha3::{Digest, Sha3_256};
 
 // --- RC Logic Implementation ---
 
@@ -51,44 +54,127 @@ pub fn gen_parameter_and_msk<R: Rng + CryptoRng>(
         p_pub,
         p_pub_hat,
         g,
+        xof: hash_utils::Xof::default(),
+        deployment: hash_utils::DeploymentInfo::default(),
     };
 
     Ok((params, msk))
 }
 
+// 连续抽到退化的 ru 时最多重新抽取的次数，正常的 RNG 几乎不可能触发一次
+const MAX_USER_KEY_DRAWS: usize = 8;
+
 /// Registers a mobile user and generates their secret key.
 /// Requires the master secret key `s`.
+/// A draw of `ru` that yields a degenerate key (see [`degenerate_user_key`]) is discarded
+/// and `ru` is drawn again.
 pub fn register_user<R: Rng + CryptoRng>(
     msk: &MasterSecretKey,
     id_u: &[u8],
     rng: &mut R,
 ) -> Result<UserSecretKey, AAKAError> {
-    // 1. Choose random ru from Z_q*
-    let r_u_scalar = ScalarField::rand(rng);
-    if r_u_scalar.is_zero() {
-        return Err(AAKAError::CryptoError(
-            "User registration random scalar ru is zero".to_string(),
-        ));
-    }
+    for _ in 0..MAX_USER_KEY_DRAWS {
+        // 1. Choose random ru from Z_q*
+        let r_u_scalar = Secret::new(ScalarField::rand(rng));
 
-    // 2. Compute Ru = ru * P
-    let generator_p = G1Point::generator(); // Get the generator P
-    let r_u_point = generator_p * r_u_scalar;
+        // 2. Compute Ru = ru * P
+        let generator_p = G1Point::generator(); // Get the generator P
+        let r_u_point = generator_p * *r_u_scalar;
 
-    // 3. Compute hu = h0(IDu || Ru)
-    let h_u = hash_utils::h0(id_u, &r_u_point)?;
+        // 3. Compute hu = h0(IDu || Ru)
+        let h_u = hash_utils::h0(id_u, &r_u_point)?;
+
+        // 4. Compute SIDu = ru + s * hu (mod q)
+        // Ensure we use msk.s here
+        let s_mul_h_u = Secret::new(msk.s * h_u);
+        let sid_u = r_u_scalar.add(&*s_mul_h_u); // Perform addition in the field Z_q
+
+        match degenerate_user_key(&r_u_point, &h_u, &sid_u) {
+            None => {
+                return Ok(UserSecretKey {
+                    r_u: r_u_point,
+                    sid_u,
+                });
+            }
+            #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+            Some(reason) => {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(reason, "Drew a degenerate user key, drawing a fresh ru");
+            }
+        }
+    }
+    Err(AAKAError::CryptoError(format!(
+        "Drew a degenerate user key {MAX_USER_KEY_DRAWS} times in a row"
+    )))
+}
 
-    // 4. Compute SIDu = ru + s * hu (mod q)
-    // Ensure we use msk.s here
-    let s_mul_h_u = msk.s * h_u;
-    let sid_u = r_u_scalar.add(&s_mul_h_u); // Perform addition in the field Z_q
+const BATCH_RU_DOMAIN_SEP: &[u8] = b"IBC_AAKA_BATCH_RU";
 
-    Ok(UserSecretKey {
-        r_u: r_u_point,
-        sid_u,
+/// Registers every ID in `ids` with one msk, spread over up to `threads` threads.
+/// Each user's `ru` is drawn from its own RNG, seeded with SHA3(`seed` || index), so
+/// the keys depend only on `seed` and the order of `ids`, never on `threads`.
+/// `seed` must be fresh randomness for every batch.
+pub fn register_users(
+    msk: &MasterSecretKey,
+    ids: &[Vec<u8>],
+    seed: [u8; 32],
+    threads: usize,
+) -> Result<Vec<UserSecretKey>, AAKAError> {
+    let register = |offset: usize, ids: &[Vec<u8>]| {
+        ids.iter()
+            .enumerate()
+            .map(|(i, id)| register_user(msk, id, &mut batch_rng(&seed, offset + i)))
+            .collect::<Result<Vec<_>, _>>()
+    };
+    let chunk_len = ids.len().div_ceil(threads.max(1)).max(1);
+    if chunk_len >= ids.len() {
+        return register(0, ids);
+    }
+
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = ids
+            .chunks(chunk_len)
+            .enumerate()
+            .map(|(n, chunk)| scope.spawn(move || register(n * chunk_len, chunk)))
+            .collect();
+        let mut keys = Vec::with_capacity(ids.len());
+        for worker in workers {
+            let chunk = worker
+                .join()
+                .map_err(|_| AAKAError::Other("User registration worker panicked".to_string()))??;
+            keys.extend(chunk);
+        }
+        Ok(keys)
     })
 }
 
+// The RNG of the `index`-th user of a batch
+fn batch_rng(seed: &[u8; 32], index: usize) -> StdRng {
+    let mut hasher = Sha3_256::new();
+    hasher.update(BATCH_RU_DOMAIN_SEP);
+    hasher.update(seed);
+    hasher.update((index as u64).to_be_bytes());
+    StdRng::from_seed(hasher.finalize().into())
+}
+
+// 退化的用户私钥：Ru 为无穷远点（ru = 0）；hu = 0 时 SIDu = ru，与主密钥无关，
+// 任何人都能构造；SIDu = 0 时 SIDu·P 为无穷远点
+fn degenerate_user_key(
+    r_u: &G1Point,
+    h_u: &ScalarField,
+    sid_u: &ScalarField,
+) -> Option<&'static str> {
+    if r_u.is_zero() {
+        Some("Ru is the identity")
+    } else if h_u.is_zero() {
+        Some("h0(IDu || Ru) is zero")
+    } else if sid_u.is_zero() {
+        Some("SIDu is zero")
+    } else {
+        None
+    }
+}
+
 /// Registers an MEC server and generates its secret key.
 /// Requires the master secret key `ŝ`.
 pub fn register_server(
@@ -101,7 +187,7 @@ pub fn register_server(
 
     // 2. Compute denominator = ŝ + hms (mod q)
     // Ensure we use msk.s_hat here
-    let denominator = msk.s_hat.add(&h_ms);
+    let denominator = Secret::new(msk.s_hat.add(&h_ms));
 
     // Check if denominator is zero (should not happen with random s_hat and good hash)
     if denominator.is_zero() {
@@ -111,16 +197,195 @@ pub fn register_server(
     }
 
     // 3. Compute inverse of denominator: (ŝ + hms)^-1 mod q
-    let inv_denominator = denominator.inverse().ok_or_else(|| {
+    let inv_denominator = Secret::new(denominator.inverse().ok_or_else(|| {
         // Should only fail if denominator is zero, which we already checked
         AAKAError::CryptoError("Failed to compute modular inverse for server key".to_string())
-    })?;
+    })?);
 
     // 4. Compute SIDms = (ŝ + hms)^-1 * P
     let generator_p2 = G2Point::generator();
-    let sid_ms_point = generator_p2 * inv_denominator;
+    let sid_ms_point = generator_p2 * *inv_denominator;
 
     Ok(ServerSecretKey {
         sid_ms: sid_ms_point,
     })
 }
+
+/// Checks a stored user key against the public parameters, without the msk:
+/// SIDu * P == Ru + h0(IDu || Ru) * Ppub.
+/// Lets a user diagnose a bad registration without attempting a full authentication.
+pub fn verify_user_key(
+    params: &SystemParameters,
+    id_u: &[u8],
+    usk: &UserSecretKey,
+) -> Result<(), AAKAError> {
+    let h_u = hash_utils::h0(id_u, &usk.r_u)?;
+    if params.p * usk.sid_u != usk.r_u + params.p_pub * h_u {
+        return Err(AAKAError::CryptoError(
+            "User secret key is inconsistent with the system parameters".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// The check value returned alongside a freshly issued user key: `hu = h0(IDu || Ru)` and the
+/// `Ppub` the key was issued under. Lets the client validate the key right after registration
+/// without fetching the system parameters first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserKeyCheck {
+    pub h_u: ScalarField,
+    pub p_pub: G1Point,
+}
+
+impl UserKeyCheck {
+    pub fn new(
+        params: &SystemParameters,
+        id_u: &[u8],
+        usk: &UserSecretKey,
+    ) -> Result<Self, AAKAError> {
+        Ok(Self {
+            h_u: hash_utils::h0(id_u, &usk.r_u)?,
+            p_pub: params.p_pub,
+        })
+    }
+
+    /// SIDu * P == Ru + hu * Ppub, with P the standard G1 generator (see
+    /// [`gen_parameter_and_msk`]) and `hu` recomputed from `id_u` so the check is bound to the ID.
+    pub fn verify(&self, id_u: &[u8], usk: &UserSecretKey) -> Result<(), AAKAError> {
+        if hash_utils::h0(id_u, &usk.r_u)? != self.h_u
+            || G1Point::generator() * usk.sid_u != usk.r_u + self.p_pub * self.h_u
+        {
+            return Err(AAKAError::CryptoError(
+                "User secret key does not match the registration check value".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Checks a provisioned server key against the public parameters, without the msk:
+/// e(Ppub_hat + h1(IDms) * P, SIDms) == g, since SIDms = (ŝ + h1(IDms))^-1 * P2.
+/// Lets an MS reject a key file issued for another ID or another parameter set.
+pub fn verify_server_key(
+    params: &SystemParameters,
+    id_ms: &[u8],
+    ssk: &ServerSecretKey,
+) -> Result<(), AAKAError> {
+    let h_ms = hash_utils::h1(id_ms)?;
+    if Curve::pairing(params.p_pub_hat + params.p * h_ms, ssk.sid_ms) != params.g {
+        return Err(AAKAError::CryptoError(
+            "Server secret key is inconsistent with the system parameters".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// --- Pseudonyms ---
+
+const PSEUDONYM_DOMAIN_SEP: &[u8] = b"IBC_AAKA_PSEUDONYM";
+const PSEUDONYM_RU_DOMAIN_SEP: &[u8] = b"IBC_AAKA_PSEUDONYM_RU";
+
+// Keyed by the msk, so only the RC can compute or link pseudonyms of a base ID.
+fn pseudonym_digest(
+    domain: &[u8],
+    msk: &MasterSecretKey,
+    base_id: &[u8],
+    epoch: u64,
+) -> Result<[u8; 32], AAKAError> {
+    let mut hasher = Sha3_256::new();
+    hasher.update(domain);
+    hasher.update(wire::to_bytes(&msk.s)?);
+    hasher.update(wire::to_bytes(&msk.s_hat)?);
+    hasher.update((base_id.len() as u64).to_be_bytes());
+    hasher.update(base_id);
+    hasher.update(epoch.to_be_bytes());
+    Ok(hasher.finalize().into())
+}
+
+/// The pseudonym of `base_id` in `epoch`: `pn-` followed by 32 hex digits.
+/// Pseudonyms of different epochs cannot be linked without the msk; the RC
+/// de-anonymizes a pseudonym by recomputing it for a candidate base ID.
+pub fn pseudonym_id(
+    msk: &MasterSecretKey,
+    base_id: &[u8],
+    epoch: u64,
+) -> Result<String, AAKAError> {
+    let digest = pseudonym_digest(PSEUDONYM_DOMAIN_SEP, msk, base_id, epoch)?;
+    Ok(format!("pn-{}", hex::encode(&digest[..16])))
+}
+
+/// Issues the per-epoch pseudonym credential of `base_id`: an ordinary user key
+/// for the ID returned by [`pseudonym_id`], so the MS authenticates it like any
+/// other user. Unlike blinding `Ru` on the client, the RC chooses the pseudonym.
+/// `ru` is derived from the msk, so the same epoch always yields the same key.
+pub fn register_pseudonym(
+    msk: &MasterSecretKey,
+    base_id: &[u8],
+    epoch: u64,
+) -> Result<(String, UserSecretKey), AAKAError> {
+    let id = pseudonym_id(msk, base_id, epoch)?;
+    let seed = pseudonym_digest(PSEUDONYM_RU_DOMAIN_SEP, msk, base_id, epoch)?;
+    let usk = register_user(msk, id.as_bytes(), &mut StdRng::from_seed(seed))?;
+    Ok((id, usk))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::Rigged;
+
+    #[test]
+    fn test_degenerate_user_key_is_redrawn() {
+        let (params, msk) = gen_parameter_and_msk(&mut StdRng::seed_from_u64(0)).unwrap();
+
+        // 每个标量消耗 4 个字：第一次抽到 ru = 0（Ru 为无穷远点），被丢弃后重新抽取
+        let mut rng = Rigged::new(vec![0, 0, 0, 0, 1, 2, 3, 4]);
+        let usk = register_user(&msk, b"alice", &mut rng).unwrap();
+        assert_eq!(rng.pos, 8);
+        let expected = register_user(&msk, b"alice", &mut Rigged::new(vec![1, 2, 3, 4])).unwrap();
+        assert_eq!((usk.r_u, usk.sid_u), (expected.r_u, expected.sid_u));
+        verify_user_key(&params, b"alice", &usk).unwrap();
+
+        // 一直输出 0 的 RNG 最终报错
+        let mut stuck = Rigged::new(vec![0]);
+        assert!(matches!(
+            register_user(&msk, b"alice", &mut stuck),
+            Err(AAKAError::CryptoError(_))
+        ));
+        assert_eq!(stuck.pos, 4 * MAX_USER_KEY_DRAWS);
+
+        // hu 或 SIDu 为零同样视为退化
+        let (r_u, one) = (G1Point::generator(), ScalarField::from(1u64));
+        assert_eq!(degenerate_user_key(&r_u, &one, &one), None);
+        assert!(degenerate_user_key(&G1Point::zero(), &one, &one).is_some());
+        assert!(degenerate_user_key(&r_u, &ScalarField::zero(), &one).is_some());
+        assert!(degenerate_user_key(&r_u, &one, &ScalarField::zero()).is_some());
+    }
+
+    #[test]
+    fn test_parallel_batch_registration_matches_serial() {
+        let (params, msk) = gen_parameter_and_msk(&mut StdRng::seed_from_u64(0)).unwrap();
+        let ids: Vec<Vec<u8>> = (0..10).map(|i| format!("user-{i}").into_bytes()).collect();
+
+        let serial = register_users(&msk, &ids, [7; 32], 1).unwrap();
+        assert_eq!(serial.len(), ids.len());
+        for (id, usk) in ids.iter().zip(&serial) {
+            verify_user_key(&params, id, usk).unwrap();
+        }
+        // 线程数不影响结果，也不影响每个用户的 ru 来源
+        for threads in [0, 2, 3, 4, 16] {
+            let parallel = register_users(&msk, &ids, [7; 32], threads).unwrap();
+            let pairs = |keys: &[UserSecretKey]| -> Vec<_> {
+                keys.iter().map(|usk| (usk.r_u, usk.sid_u)).collect()
+            };
+            assert_eq!(pairs(&parallel), pairs(&serial), "threads = {threads}");
+        }
+        let single = register_user(&msk, &ids[3], &mut batch_rng(&[7; 32], 3)).unwrap();
+        assert_eq!(single.r_u, serial[3].r_u);
+
+        // 不同的种子得到不同的 ru，空的批次得到空结果
+        let reseeded = register_users(&msk, &ids, [8; 32], 4).unwrap();
+        assert_ne!(reseeded[0].r_u, serial[0].r_u);
+        assert!(register_users(&msk, &[], [7; 32], 4).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,355 @@
+//! MS 和用户端访问 RC 的接口。
+//!
+//! 应用只通过 [`RcClient`] 获取系统参数和申请私钥：[`HttpRcClient`] 访问真实的 RC，
+//! `MockRc` 在进程内持有一把主密钥，测试无需启动 RC 进程即可覆盖应用自身的逻辑
+//! （密钥文件的读写、重试、私钥校验等）。`MockRc` 只在启用 `testing` feature 时编译，
+//! 应用在 `[dev-dependencies]` 中开启它。
+use crate::{
+    AAKAError, ServerSecretKey, SystemParameters, UserSecretKey, deserialize_point,
+    hash_utils::{DeploymentInfo, Xof},
+    id::EncodedId,
+    rc,
+};
+use ark_serialize::CanonicalDeserialize;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::future::Future;
+
+/// 应用对 RC 的全部调用
+pub trait RcClient: Send + Sync {
+    /// 获取系统参数，返回前已通过 [`SystemParameters::validate_strict`]
+    fn fetch_params(&self) -> impl Future<Output = Result<SystemParameters, AAKAError>> + Send;
+
+    /// 为用户 `id` 申请私钥
+    fn register_user(
+        &self,
+        id: &[u8],
+    ) -> impl Future<Output = Result<UserSecretKey, AAKAError>> + Send;
+
+    /// 为服务器 `id` 申请私钥
+    fn register_server(
+        &self,
+        id: &[u8],
+    ) -> impl Future<Output = Result<ServerSecretKey, AAKAError>> + Send;
+
+    /// 为用户 `id` 申请 `epoch` 的假名私钥（见 [`rc::register_pseudonym`]），返回假名与私钥
+    fn register_pseudonym(
+        &self,
+        id: &[u8],
+        epoch: u64,
+    ) -> impl Future<Output = Result<(String, UserSecretKey), AAKAError>> + Send;
+}
+
+/// 通过 HTTP 接口访问 RC
+#[derive(Debug, Clone)]
+pub struct HttpRcClient {
+    client: reqwest::Client,
+    rc_url: String,
+}
+
+#[derive(Deserialize)]
+struct ParamsResponse {
+    p_hex: String,
+    p_pub_hex: String,
+    p_pub_hat_hex: String,
+    g_hex: String,
+    #[serde(default)]
+    xof: Xof,
+    // 较早的 RC 不返回部署标识
+    #[serde(default)]
+    deployment_info: String,
+}
+
+#[derive(Deserialize)]
+struct UserKeyResponse {
+    r_u_hex: String,
+    sid_u_hex: String,
+    // 较早的 RC 不返回校验值
+    #[serde(default)]
+    check: Option<UserKeyCheckResponse>,
+}
+
+#[derive(Deserialize)]
+struct UserKeyCheckResponse {
+    h_u_hex: String,
+    p_pub_hex: String,
+}
+
+#[derive(Deserialize)]
+struct ServerKeyResponse {
+    sid_ms_hex: String,
+}
+
+#[derive(Deserialize)]
+struct PseudonymResponse {
+    pseudonym: String,
+    #[serde(flatten)]
+    key: UserKeyResponse,
+}
+
+#[derive(Serialize)]
+struct RegisterRequest {
+    // 合法的 UTF-8 ID 原样发送，兼容不支持 `id_encoding` 的 RC；其他 ID 以 hex 发送
+    #[serde(flatten)]
+    id: EncodedId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    epoch: Option<u64>,
+}
+
+impl RegisterRequest {
+    fn new(id: &[u8], epoch: Option<u64>) -> Self {
+        Self {
+            id: EncodedId::from_bytes(id),
+            epoch,
+        }
+    }
+}
+
+fn decode_hex<T: CanonicalDeserialize>(field: &str, value: &str) -> Result<T, AAKAError> {
+    let bytes = hex::decode(value)
+        .map_err(|e| AAKAError::InvalidInput(format!("Invalid hex in {field}: {e}")))?;
+    Ok(T::deserialize_compressed(bytes.as_slice())?)
+}
+
+fn decode_point<P: crate::CheckedPoint>(field: &str, value: &str) -> Result<P, AAKAError> {
+    let bytes = hex::decode(value)
+        .map_err(|e| AAKAError::InvalidInput(format!("Invalid hex in {field}: {e}")))?;
+    deserialize_point(&bytes)
+}
+
+impl UserKeyResponse {
+    // RC 返回了校验值时，按 `id` 校验私钥，不需要系统参数
+    fn into_key(self, id: &[u8]) -> Result<UserSecretKey, AAKAError> {
+        let usk = UserSecretKey {
+            r_u: decode_point("r_u_hex", &self.r_u_hex)?,
+            sid_u: decode_hex("sid_u_hex", &self.sid_u_hex)?,
+        };
+        if let Some(check) = self.check {
+            rc::UserKeyCheck {
+                h_u: decode_hex("h_u_hex", &check.h_u_hex)?,
+                p_pub: decode_point("p_pub_hex", &check.p_pub_hex)?,
+            }
+            .verify(id, &usk)?;
+        }
+        Ok(usk)
+    }
+}
+
+impl HttpRcClient {
+    pub fn new(client: reqwest::Client, rc_url: impl Into<String>) -> Self {
+        Self {
+            client,
+            rc_url: rc_url.into(),
+        }
+    }
+
+    pub fn rc_url(&self) -> &str {
+        &self.rc_url
+    }
+
+    async fn send<T: DeserializeOwned>(
+        &self,
+        request: reqwest::RequestBuilder,
+        path: &str,
+    ) -> Result<T, AAKAError> {
+        let url = format!("{}{path}", self.rc_url);
+        let resp = request
+            .send()
+            .await
+            .map_err(|e| AAKAError::Other(format!("Failed to reach RC at {url}: {e}")))?;
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            return Err(AAKAError::Other(format!(
+                "RC returned {status} for {url}: {body}"
+            )));
+        }
+        resp.json()
+            .await
+            .map_err(|e| AAKAError::Other(format!("Invalid response from RC at {url}: {e}")))
+    }
+
+    async fn post<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &RegisterRequest,
+    ) -> Result<T, AAKAError> {
+        let request = self
+            .client
+            .post(format!("{}{path}", self.rc_url))
+            .json(body);
+        self.send(request, path).await
+    }
+}
+
+impl RcClient for HttpRcClient {
+    async fn fetch_params(&self) -> Result<SystemParameters, AAKAError> {
+        let request = self.client.get(format!("{}/params", self.rc_url));
+        let resp: ParamsResponse = self.send(request, "/params").await?;
+        let params = SystemParameters {
+            p: decode_point("p_hex", &resp.p_hex)?,
+            p_pub: decode_point("p_pub_hex", &resp.p_pub_hex)?,
+            p_pub_hat: decode_point("p_pub_hat_hex", &resp.p_pub_hat_hex)?,
+            g: decode_hex("g_hex", &resp.g_hex)?,
+            xof: resp.xof,
+            deployment: DeploymentInfo::new(resp.deployment_info)?,
+        };
+        params.validate_strict()?;
+        Ok(params)
+    }
+
+    async fn register_user(&self, id: &[u8]) -> Result<UserSecretKey, AAKAError> {
+        let body = RegisterRequest::new(id, None);
+        let resp: UserKeyResponse = self.post("/register/user", &body).await?;
+        resp.into_key(id)
+    }
+
+    async fn register_server(&self, id: &[u8]) -> Result<ServerSecretKey, AAKAError> {
+        let body = RegisterRequest::new(id, None);
+        let resp: ServerKeyResponse = self.post("/register/server", &body).await?;
+        Ok(ServerSecretKey {
+            sid_ms: decode_point("sid_ms_hex", &resp.sid_ms_hex)?,
+        })
+    }
+
+    async fn register_pseudonym(
+        &self,
+        id: &[u8],
+        epoch: u64,
+    ) -> Result<(String, UserSecretKey), AAKAError> {
+        let body = RegisterRequest::new(id, Some(epoch));
+        let resp: PseudonymResponse = self.post("/register/pseudonym", &body).await?;
+        let usk = resp.key.into_key(resp.pseudonym.as_bytes())?;
+        Ok((resp.pseudonym, usk))
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+pub use mock::MockRc;
+
+#[cfg(any(test, feature = "testing"))]
+mod mock {
+    use super::RcClient;
+    use crate::{AAKAError, MasterSecretKey, ServerSecretKey, SystemParameters, UserSecretKey, rc};
+    use rand::{CryptoRng, Rng, SeedableRng, rngs::StdRng};
+    use std::sync::{
+        Mutex,
+        atomic::{AtomicU32, Ordering},
+    };
+
+    /// 进程内的 RC，持有一把新生成的主密钥，供测试使用
+    pub struct MockRc {
+        params: SystemParameters,
+        msk: MasterSecretKey,
+        rng: Mutex<StdRng>,
+        // 接下来需要模拟失败的调用次数
+        failures: AtomicU32,
+        calls: AtomicU32,
+    }
+
+    impl MockRc {
+        /// 生成新的系统参数与主密钥（见 [`rc::gen_parameter_and_msk`]）
+        pub fn new<R: Rng + CryptoRng>(rng: &mut R) -> Result<Self, AAKAError> {
+            let (params, msk) = rc::gen_parameter_and_msk(rng)?;
+            let rng = StdRng::from_rng(rng)
+                .map_err(|e| AAKAError::Other(format!("Failed to seed mock RC: {e}")))?;
+            Ok(Self {
+                params,
+                msk,
+                rng: Mutex::new(rng),
+                failures: AtomicU32::new(0),
+                calls: AtomicU32::new(0),
+            })
+        }
+
+        pub fn params(&self) -> &SystemParameters {
+            &self.params
+        }
+
+        pub fn msk(&self) -> &MasterSecretKey {
+            &self.msk
+        }
+
+        /// 接下来的 `n` 次调用返回错误，模拟 RC 暂时不可用
+        pub fn fail_next(&self, n: u32) {
+            self.failures.store(n, Ordering::SeqCst);
+        }
+
+        /// 到目前为止收到的调用次数（包括模拟失败的调用）
+        pub fn calls(&self) -> u32 {
+            self.calls.load(Ordering::SeqCst)
+        }
+
+        fn call(&self) -> Result<(), AAKAError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let failed = self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok();
+            if failed {
+                return Err(AAKAError::Other("Mock RC is unavailable".to_string()));
+            }
+            Ok(())
+        }
+    }
+
+    impl RcClient for MockRc {
+        async fn fetch_params(&self) -> Result<SystemParameters, AAKAError> {
+            self.call()?;
+            Ok(self.params.clone())
+        }
+
+        async fn register_user(&self, id: &[u8]) -> Result<UserSecretKey, AAKAError> {
+            self.call()?;
+            rc::register_user(&self.msk, id, &mut *self.rng.lock().unwrap())
+        }
+
+        async fn register_server(&self, id: &[u8]) -> Result<ServerSecretKey, AAKAError> {
+            self.call()?;
+            rc::register_server(&self.msk, id)
+        }
+
+        async fn register_pseudonym(
+            &self,
+            id: &[u8],
+            epoch: u64,
+        ) -> Result<(String, UserSecretKey), AAKAError> {
+            self.call()?;
+            rc::register_pseudonym(&self.msk, id, epoch)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::thread_rng;
+
+    #[test]
+    fn test_register_request_encodes_binary_ids_as_hex() {
+        let json = |id: &[u8]| serde_json::to_value(RegisterRequest::new(id, None)).unwrap();
+        assert_eq!(
+            json(b"alice"),
+            serde_json::json!({ "id": "alice", "id_encoding": "utf8" })
+        );
+        assert_eq!(
+            json(b"dev\x00\xff"),
+            serde_json::json!({ "id": "64657600ff", "id_encoding": "hex" })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mock_rc_issues_valid_keys_and_simulates_outages() {
+        let mock = MockRc::new(&mut thread_rng()).unwrap();
+        let params = mock.fetch_params().await.unwrap();
+        let usk = mock.register_user(b"alice").await.unwrap();
+        rc::verify_user_key(&params, b"alice", &usk).unwrap();
+        let ssk = mock.register_server(b"server").await.unwrap();
+        rc::verify_server_key(&params, b"server", &ssk).unwrap();
+
+        mock.fail_next(2);
+        assert!(mock.fetch_params().await.is_err());
+        assert!(mock.register_user(b"alice").await.is_err());
+        assert!(mock.fetch_params().await.is_ok());
+        assert_eq!(mock.calls(), 6);
+    }
+}
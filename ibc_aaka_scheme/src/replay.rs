@@ -0,0 +1,480 @@
+//! MS 端的认证请求重放检测。
+//!
+//! 时间戳检查只能拒绝 [`ALLOWED_SKEW_SECONDS`] 窗口之外的请求，窗口内被截获的请求
+//! 仍可以原样重发。重放缓存记录窗口内见过的请求摘要，条目在请求时间戳离开窗口后过期。
+//!
+//! 默认使用 [`MemoryReplayCache`]；启用 `persistent-replay` feature 后可以使用
+//! [`SledReplayCache`] 将记录保存到磁盘，MS 重启后重放保护依然有效。
+//! 请求量很大时可以使用 [`BloomReplayCache`]，以极小的误拒率换取固定的内存占用。
+use crate::{
+    AAKAError, ALLOWED_SKEW_SECONDS, UserAuthRequest, get_current_timestamp, wire::to_bytes,
+};
+use serde::Deserialize;
+use sha3::{Digest, Sha3_256};
+use std::{collections::HashMap, sync::Mutex};
+
+/// 请求摘要，作为重放缓存的键
+pub type RequestNonce = [u8; 32];
+
+/// 计算认证请求的摘要：SHA3-256(M || sigma || N || timestamp)
+pub fn request_nonce(request: &UserAuthRequest) -> Result<RequestNonce, AAKAError> {
+    let mut hasher = Sha3_256::new();
+    hasher.update(to_bytes(&request.m)?);
+    hasher.update(to_bytes(&request.sigma)?);
+    hasher.update(&request.n);
+    hasher.update(request.timestamp.to_be_bytes());
+    Ok(hasher.finalize().into())
+}
+
+/// 请求时间戳离开新鲜度窗口的时刻，此后同一请求会被时间戳检查拒绝，无需继续记录
+fn expiry(timestamp: u64) -> u64 {
+    timestamp.saturating_add(ALLOWED_SKEW_SECONDS)
+}
+
+pub trait ReplayCache: Send + Sync {
+    /// 记录一次请求。返回 `Ok(false)` 表示该请求在窗口内已经出现过（重放）。
+    fn check_and_insert(&self, nonce: &RequestNonce, timestamp: u64) -> Result<bool, AAKAError>;
+
+    /// 当前保存的记录数，不按条目保存的实现（如布隆过滤器）返回 `None`
+    fn entries(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// 进程内的重放缓存，MS 重启后记录丢失
+#[derive(Default)]
+pub struct MemoryReplayCache {
+    // nonce -> 过期时间
+    seen: Mutex<HashMap<RequestNonce, u64>>,
+}
+
+impl MemoryReplayCache {
+    pub fn len(&self) -> usize {
+        self.seen.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl ReplayCache for MemoryReplayCache {
+    fn check_and_insert(&self, nonce: &RequestNonce, timestamp: u64) -> Result<bool, AAKAError> {
+        let now = get_current_timestamp()?;
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, expires_at| *expires_at >= now);
+
+        if seen.contains_key(nonce) {
+            return Ok(false);
+        }
+        seen.insert(*nonce, expiry(timestamp));
+        Ok(true)
+    }
+
+    fn entries(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+/// [`BloomReplayCache`] 的容量配置
+#[derive(Debug, Clone, Deserialize)]
+pub struct BloomConfig {
+    /// 预期的认证请求速率（每秒）
+    pub expected_rate: f64,
+    /// 记录需要保留的时长（秒）。请求时间戳最多超前 [`ALLOWED_SKEW_SECONDS`]，
+    /// 因此默认且至少为两倍的时间戳窗口，更小的值会被 [`BloomReplayCache::new`] 拒绝
+    #[serde(default = "default_bloom_ttl")]
+    pub ttl_secs: u64,
+    /// 请求速率不超过 `expected_rate` 时，新请求被误判为重放的概率
+    #[serde(default = "default_false_positive_rate")]
+    pub false_positive_rate: f64,
+}
+
+fn default_bloom_ttl() -> u64 {
+    2 * ALLOWED_SKEW_SECONDS
+}
+
+fn default_false_positive_rate() -> f64 {
+    1e-6
+}
+
+impl BloomConfig {
+    /// 窗口内需要同时记录的请求数
+    fn capacity(&self) -> f64 {
+        (self.expected_rate * self.ttl_secs as f64).max(1.0)
+    }
+
+    /// 计数器个数 m = -n·ln(p) / ln(2)²，每个计数器占 1 字节
+    pub fn memory_bytes(&self) -> usize {
+        let m = -self.capacity() * self.false_positive_rate.ln() / std::f64::consts::LN_2.powi(2);
+        (m.ceil() as usize).max(1)
+    }
+
+    /// 哈希函数个数 k = (m / n)·ln(2)
+    fn hash_count(&self) -> usize {
+        let k = self.memory_bytes() as f64 / self.capacity() * std::f64::consts::LN_2;
+        (k.round() as usize).clamp(1, 16)
+    }
+}
+
+/// 一个计数器可以表示的最远过期时间（以 epoch 计）。
+/// `ttl_secs` 不小于两倍时间戳窗口时，请求最多在 65 个 epoch 后过期，不会触及这个上限；
+/// 更短的 `ttl_secs` 会让过期时间被截断、记录提前清除，从而放过重放，因此构造时拒绝
+const MAX_EPOCHS_AHEAD: u64 = 127;
+
+/// 基于计数布隆过滤器的重放缓存，内存占用由 [`BloomConfig`] 决定，不随请求量增长。
+///
+/// 每个计数器保存其所覆盖请求中最晚的过期 epoch（epoch 长度为 `ttl_secs / 64`，向上取整），
+/// epoch 推进时清零已过期的计数器，因此不需要保存请求本身。
+///
+/// 代价是误拒：一个从未出现过的请求，如果它的 k 个计数器恰好都被其他请求占用，就会被当作重放拒绝。
+/// 请求速率不超过 `expected_rate` 时误拒率约为 `false_positive_rate`，超过后误拒率会上升。
+/// 被误拒的用户重新发起认证（新的随机数和时间戳）即可成功。真正的重放在窗口内一定会被拒绝。
+pub struct BloomReplayCache {
+    hash_count: usize,
+    epoch_secs: u64,
+    filter: Mutex<BloomFilter>,
+}
+
+struct BloomFilter {
+    // 0 表示空，否则为 (过期 epoch % 255) + 1
+    counters: Vec<u8>,
+    // 上一次清理时的 epoch
+    epoch: u64,
+}
+
+impl BloomFilter {
+    fn encode(expires: u64) -> u8 {
+        (expires % 255) as u8 + 1
+    }
+
+    /// 计数器距离过期还剩多少个 epoch，空计数器返回 `None`。
+    ///
+    /// 清理后所有非空计数器的过期 epoch 都在 `[epoch, epoch + MAX_EPOCHS_AHEAD]` 内，
+    /// 因此可以从模 255 的值中还原出来。
+    fn epochs_left(&self, counter: u8) -> Option<u64> {
+        (counter != 0).then(|| (counter as u64 - 1 + 255 - self.epoch % 255) % 255)
+    }
+
+    fn advance(&mut self, epoch: u64) {
+        if epoch <= self.epoch {
+            return;
+        }
+        for i in 0..self.counters.len() {
+            if self
+                .epochs_left(self.counters[i])
+                .is_none_or(|left| self.epoch + left < epoch)
+            {
+                self.counters[i] = 0;
+            }
+        }
+        self.epoch = epoch;
+    }
+}
+
+impl BloomReplayCache {
+    pub fn new(config: &BloomConfig) -> Result<Self, AAKAError> {
+        let valid = config.expected_rate > 0.0
+            && config.false_positive_rate > 0.0
+            && config.false_positive_rate < 1.0
+            && config.ttl_secs >= default_bloom_ttl();
+        if !valid {
+            return Err(AAKAError::InvalidInput(format!(
+                "Invalid bloom replay cache config (ttl_secs must be at least {}): {config:?}",
+                default_bloom_ttl()
+            )));
+        }
+
+        let epoch_secs = config.ttl_secs.div_ceil(64);
+        Ok(Self {
+            hash_count: config.hash_count(),
+            epoch_secs,
+            filter: Mutex::new(BloomFilter {
+                counters: vec![0; config.memory_bytes()],
+                epoch: get_current_timestamp()? / epoch_secs,
+            }),
+        })
+    }
+
+    /// 过滤器占用的字节数
+    pub fn memory_bytes(&self) -> usize {
+        self.filter.lock().unwrap().counters.len()
+    }
+
+    // 请求摘要本身是均匀的哈希值，用双重哈希 h1 + i·h2 派生出 k 个位置
+    fn positions(&self, nonce: &RequestNonce, len: usize) -> impl Iterator<Item = usize> {
+        let h1 = u64::from_le_bytes(nonce[..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(nonce[8..16].try_into().unwrap()) | 1;
+        (0..self.hash_count as u64)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len as u64) as usize)
+    }
+}
+
+impl ReplayCache for BloomReplayCache {
+    fn check_and_insert(&self, nonce: &RequestNonce, timestamp: u64) -> Result<bool, AAKAError> {
+        let mut filter = self.filter.lock().unwrap();
+        filter.advance(get_current_timestamp()? / self.epoch_secs);
+        // 时钟回拨时沿用上一次的 epoch
+        let now = filter.epoch;
+        let expires = expiry(timestamp)
+            .div_ceil(self.epoch_secs)
+            .clamp(now, now + MAX_EPOCHS_AHEAD);
+        let len = filter.counters.len();
+
+        if self.positions(nonce, len).all(|i| filter.counters[i] != 0) {
+            return Ok(false);
+        }
+        for i in self.positions(nonce, len) {
+            // 计数器保留覆盖的请求中最晚的过期时间
+            if filter
+                .epochs_left(filter.counters[i])
+                .is_none_or(|left| now + left < expires)
+            {
+                filter.counters[i] = BloomFilter::encode(expires);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(feature = "persistent-replay")]
+pub use persistent::SledReplayCache;
+
+#[cfg(feature = "persistent-replay")]
+mod persistent {
+    use super::{ReplayCache, RequestNonce, expiry};
+    use crate::{AAKAError, get_current_timestamp};
+    use std::{
+        path::Path,
+        sync::atomic::{AtomicU64, Ordering},
+    };
+
+    /// 每插入这么多条记录清理一次过期条目
+    const COMPACT_EVERY: u64 = 1024;
+
+    /// 基于 sled 的持久化重放缓存。每条记录写入后立即 flush，MS 崩溃或重启都不会丢失。
+    pub struct SledReplayCache {
+        db: sled::Db,
+        inserts: AtomicU64,
+    }
+
+    fn db_error(e: sled::Error) -> AAKAError {
+        AAKAError::Other(format!("Replay cache storage error: {e}"))
+    }
+
+    impl SledReplayCache {
+        pub fn open(path: impl AsRef<Path>) -> Result<Self, AAKAError> {
+            let cache = Self {
+                db: sled::open(path).map_err(db_error)?,
+                inserts: AtomicU64::new(0),
+            };
+            cache.compact()?;
+            Ok(cache)
+        }
+
+        /// 删除所有已过期的记录，返回删除的条数
+        pub fn compact(&self) -> Result<usize, AAKAError> {
+            let now = get_current_timestamp()?;
+            let mut removed = 0;
+            for entry in self.db.iter() {
+                let (key, value) = entry.map_err(db_error)?;
+                if decode_expiry(&value) < now {
+                    self.db.remove(key).map_err(db_error)?;
+                    removed += 1;
+                }
+            }
+            Ok(removed)
+        }
+
+        pub fn len(&self) -> usize {
+            self.db.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.db.is_empty()
+        }
+    }
+
+    fn decode_expiry(value: &[u8]) -> u64 {
+        // 格式不正确的记录视为已过期
+        value.try_into().map(u64::from_be_bytes).unwrap_or(0)
+    }
+
+    impl ReplayCache for SledReplayCache {
+        fn check_and_insert(
+            &self,
+            nonce: &RequestNonce,
+            timestamp: u64,
+        ) -> Result<bool, AAKAError> {
+            let now = get_current_timestamp()?;
+            let new = expiry(timestamp).to_be_bytes();
+
+            // 原子地插入：已有未过期的记录即为重放，过期的记录可以被覆盖
+            let mut current = self.db.get(nonce).map_err(db_error)?;
+            loop {
+                if current
+                    .as_ref()
+                    .is_some_and(|value| decode_expiry(value) >= now)
+                {
+                    return Ok(false);
+                }
+                match self
+                    .db
+                    .compare_and_swap(nonce, current, Some(&new[..]))
+                    .map_err(db_error)?
+                {
+                    Ok(()) => break,
+                    Err(e) => current = e.current,
+                }
+            }
+            self.db.flush().map_err(db_error)?;
+
+            if self.inserts.fetch_add(1, Ordering::Relaxed) % COMPACT_EVERY == COMPACT_EVERY - 1 {
+                self.compact()?;
+            }
+            Ok(true)
+        }
+
+        fn entries(&self) -> Option<usize> {
+            Some(self.len())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_cache_rejects_replay_and_expires_entries() {
+        let cache = MemoryReplayCache::default();
+        let now = get_current_timestamp().unwrap();
+
+        assert!(cache.check_and_insert(&[1; 32], now).unwrap());
+        assert!(!cache.check_and_insert(&[1; 32], now).unwrap());
+        assert!(cache.check_and_insert(&[2; 32], now).unwrap());
+
+        // 时间戳已离开窗口的记录会在下一次插入时被清理
+        assert!(
+            cache
+                .check_and_insert(&[3; 32], now - ALLOWED_SKEW_SECONDS - 1)
+                .unwrap()
+        );
+        assert!(cache.check_and_insert(&[4; 32], now).unwrap());
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn test_bloom_cache_rejects_replays_within_memory_budget() {
+        let config = BloomConfig {
+            expected_rate: 10.0,
+            ttl_secs: default_bloom_ttl(),
+            false_positive_rate: 1e-3,
+        };
+        let cache = BloomReplayCache::new(&config).unwrap();
+        let budget = config.memory_bytes();
+        let now = get_current_timestamp().unwrap();
+
+        // 窗口内满负荷的请求量
+        let requests = (config.expected_rate * config.ttl_secs as f64) as u32;
+        let nonce = |i: u32| -> RequestNonce { Sha3_256::digest(i.to_be_bytes()).into() };
+        let mut false_rejects = 0;
+        for i in 0..requests {
+            if !cache.check_and_insert(&nonce(i), now).unwrap() {
+                false_rejects += 1;
+            }
+        }
+
+        // 所有真正的重放都被拒绝
+        for i in 0..requests {
+            assert!(!cache.check_and_insert(&nonce(i), now).unwrap());
+        }
+        // 误拒率与配置的数量级一致
+        assert!((false_rejects as f64) < 5.0 * config.false_positive_rate * requests as f64);
+        // 内存占用固定，且远小于逐条保存摘要和过期时间
+        assert_eq!(cache.memory_bytes(), budget);
+        assert!(budget < requests as usize * (32 + 8) / 2);
+    }
+
+    #[test]
+    fn test_bloom_cache_expires_old_entries() {
+        let config = BloomConfig {
+            expected_rate: 1.0,
+            ttl_secs: default_bloom_ttl(),
+            false_positive_rate: 1e-3,
+        };
+        let cache = BloomReplayCache::new(&config).unwrap();
+        let now = get_current_timestamp().unwrap();
+
+        assert!(cache.check_and_insert(&[1; 32], now).unwrap());
+        // 模拟时间流逝：让过滤器推进到请求过期之后
+        let later = (now + ALLOWED_SKEW_SECONDS).div_ceil(cache.epoch_secs) + 1;
+        cache.filter.lock().unwrap().advance(later);
+        assert!(
+            cache
+                .filter
+                .lock()
+                .unwrap()
+                .counters
+                .iter()
+                .all(|&c| c == 0)
+        );
+
+        assert!(
+            BloomReplayCache::new(&BloomConfig {
+                expected_rate: 0.0,
+                ..config
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_bloom_cache_rejects_ttl_shorter_than_the_window() {
+        let config = |ttl_secs| BloomConfig {
+            expected_rate: 1.0,
+            ttl_secs,
+            false_positive_rate: 1e-3,
+        };
+        // 过期时间会被截断到 127 个 1 秒的 epoch，窗口内的重放会在两分钟后被放过
+        assert!(BloomReplayCache::new(&config(64)).is_err());
+        assert!(BloomReplayCache::new(&config(2 * ALLOWED_SKEW_SECONDS - 1)).is_err());
+
+        // 最早的合法时间戳与最晚的一样，在整个窗口内都被拒绝
+        let cache = BloomReplayCache::new(&config(2 * ALLOWED_SKEW_SECONDS)).unwrap();
+        let now = get_current_timestamp().unwrap();
+        let timestamp = now + ALLOWED_SKEW_SECONDS;
+        assert!(cache.check_and_insert(&[1; 32], timestamp).unwrap());
+        let last_valid = (timestamp + ALLOWED_SKEW_SECONDS) / cache.epoch_secs;
+        cache.filter.lock().unwrap().advance(last_valid);
+        assert!(!cache.check_and_insert(&[1; 32], timestamp).unwrap());
+    }
+
+    #[cfg(feature = "persistent-replay")]
+    #[test]
+    fn test_sled_cache_survives_restart() {
+        let dir = std::env::temp_dir().join(format!("replay_db_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let now = get_current_timestamp().unwrap();
+
+        {
+            let cache = SledReplayCache::open(&dir).unwrap();
+            assert!(cache.check_and_insert(&[1; 32], now).unwrap());
+            assert!(
+                cache
+                    .check_and_insert(&[2; 32], now - ALLOWED_SKEW_SECONDS - 1)
+                    .unwrap()
+            );
+            assert!(!cache.check_and_insert(&[1; 32], now).unwrap());
+        }
+
+        // 模拟重启：重新打开数据库，之前见过的请求仍被拒绝，过期记录被清理
+        let cache = SledReplayCache::open(&dir).unwrap();
+        assert_eq!(cache.len(), 1);
+        assert!(!cache.check_and_insert(&[1; 32], now).unwrap());
+        assert!(cache.check_and_insert(&[2; 32], now).unwrap());
+
+        drop(cache);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
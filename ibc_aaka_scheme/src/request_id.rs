@@ -0,0 +1,32 @@
+//! RC 与 MS 共用的请求关联 ID。
+//!
+//! [`request_id`] 中间件为每个请求生成一个 UUID，同时写入 tracing span（`request{request_id=...}`）、
+//! `x-request-id` 响应头和 [`REQUEST_ID`]，错误响应体据此带上同一个 ID，排查时可以在日志中找到对应的请求。
+use axum::{extract::Request, http::HeaderValue, middleware::Next, response::Response};
+use tracing::Instrument;
+
+/// 携带请求 ID 的响应头
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    /// 当前请求的 ID；中间件之外（如直接调用 handler）没有设置
+    pub static REQUEST_ID: String;
+}
+
+/// 当前请求的 ID，不在 [`request_id`] 中间件内时为 `None`
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// 用 `axum::middleware::from_fn(request_id)` 挂在路由的最外层
+pub async fn request_id(request: Request, next: Next) -> Response {
+    let id = uuid::Uuid::new_v4().to_string();
+    let span = tracing::info_span!("request", request_id = %id);
+    let mut response = REQUEST_ID
+        .scope(id.clone(), next.run(request).instrument(span))
+        .await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
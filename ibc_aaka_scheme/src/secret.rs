@@ -0,0 +1,74 @@
+//! 计算过程中临时持有的秘密值（注册时的 ru、服务器的 y 等）。
+//!
+//! [`Secret`] 在离开作用域时把内容清零，避免这些中间值留在已经释放的栈或堆内存中。
+//! 只包装函数内部的中间值；需要长期保存的密钥由各自的结构体负责。
+use std::fmt;
+use std::ops::Deref;
+use zeroize::Zeroize;
+
+/// 离开作用域时清零的秘密值，通过 `Deref` 读取
+pub struct Secret<T: Zeroize>(T);
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<T: Zeroize> Deref for Secret<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Zeroize> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+// 不在日志或错误信息中输出秘密值
+impl<T: Zeroize> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ScalarField;
+    use ark_std::Zero;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // 清零时记下清零之后的值，Secret 释放后无需读取已释放的内存
+    struct Probe {
+        value: ScalarField,
+        after_zeroize: Rc<Cell<Option<ScalarField>>>,
+    }
+
+    impl Zeroize for Probe {
+        fn zeroize(&mut self) {
+            self.value.zeroize();
+            self.after_zeroize.set(Some(self.value));
+        }
+    }
+
+    #[test]
+    fn test_secret_zeroizes_on_drop() {
+        let after_zeroize = Rc::new(Cell::new(None));
+        let secret = Secret::new(Probe {
+            value: ScalarField::from(42u64),
+            after_zeroize: after_zeroize.clone(),
+        });
+        assert_eq!(secret.value, ScalarField::from(42u64));
+        assert_eq!(format!("{secret:?}"), "Secret(<redacted>)");
+        assert_eq!(after_zeroize.get(), None);
+
+        drop(secret);
+        assert!(after_zeroize.get().is_some_and(|value| value.is_zero()));
+    }
+}
@@ -1,52 +1,278 @@
 use crate::{
-    AAKAError, Curve, G1AffinePoint, ScalarField, ServerAuthResponse, ServerSecretKey, SessionKey,
-    SystemParameters, UserAuthRequest, get_current_timestamp, hash_utils, is_timestamp_fresh,
+    AAKAError, Curve, G1Point, PointValidation, ScalarField, ServerAuthResponse, ServerSecretKey,
+    SessionKey, SystemParameters, Transcript, UserAuthRequest, deserialize_point_validated,
+    get_current_timestamp, hash_utils, is_timestamp_fresh, msm, nonce, secret::Secret, step,
+    suite::Suite,
 };
-use ark_ec::{
-    AffineRepr, // Group for identity, AffineRepr for deserialization/coords
-    pairing::Pairing,
-};
-use ark_ff::UniformRand; // Field for inverse, UniformRand for y
-use ark_serialize::{CanonicalDeserialize, CanonicalSerialize}; // For deserialization
+use ark_ec::pairing::Pairing;
+use ark_ff::UniformRand;
 use ark_std::rand::prelude::*;
-use ark_std::{Zero, ops::Add, vec::Vec};
+use ark_std::{One, Zero, vec::Vec};
 
 // --- Server Logic Implementation ---
 
 /// Processes a user's authentication request message.
 /// Verifies the user, generates a response, and computes the session key.
 pub fn process_user_request<R: Rng + CryptoRng>(
+    ssk: &ServerSecretKey,
+    request: &UserAuthRequest,
+    own_id: &[u8],
+    params: &SystemParameters,
+    rng: &mut R,
+    key_len_bytes: usize,
+) -> Result<(ServerAuthResponse, SessionKey), AAKAError> {
+    process_user_request_with_context(ssk, request, own_id, params, rng, key_len_bytes, &[])
+}
+
+/// Like [`process_user_request`], but folds an application `context` into the session key
+/// (see [`hash_utils::h5`]). The user must pass the same context to
+/// [`crate::user::process_server_response_with_context`], otherwise the keys differ.
+pub fn process_user_request_with_context<R: Rng + CryptoRng>(
+    ssk: &ServerSecretKey,
+    request: &UserAuthRequest,
+    own_id: &[u8],
+    params: &SystemParameters,
+    rng: &mut R,
+    key_len_bytes: usize,
+    context: &[u8],
+) -> Result<(ServerAuthResponse, SessionKey), AAKAError> {
+    process_user_request_with_transcript(ssk, request, own_id, params, rng, key_len_bytes, context)
+        .map(|(response, key, _)| (response, key))
+}
+
+/// Like [`process_user_request_with_context`], but also returns the handshake [`Transcript`]
+/// (with the decrypted IDu' and X'), e.g. to derive the [`crate::session_id`].
+pub fn process_user_request_with_transcript<R: Rng + CryptoRng>(
+    ssk: &ServerSecretKey,
+    request: &UserAuthRequest,
+    own_id: &[u8],
+    params: &SystemParameters,
+    rng: &mut R,
+    key_len_bytes: usize,
+    context: &[u8],
+) -> Result<(ServerAuthResponse, SessionKey, Transcript), AAKAError> {
+    process_user_request_with_validation(
+        ssk,
+        request,
+        own_id,
+        params,
+        rng,
+        key_len_bytes,
+        context,
+        PointValidation::Strict,
+    )
+}
+
+/// Like [`process_user_request_with_transcript`], but parses the decrypted Ru' and X' with
+/// the given [`PointValidation`]. Only use [`PointValidation::Lenient`] when requests come
+/// from a trusted source; every other entry point validates strictly.
+///
+/// With the `tracing` feature, each major step runs in its own span
+/// (`pairing`, `decrypt_n`, `verify_signature`, `derive_key`); failures are recorded
+/// on the enclosing `process_user_request` span. No secret values are logged.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "process_user_request", skip_all, err(Display))
+)]
+#[allow(clippy::too_many_arguments)]
+pub fn process_user_request_with_validation<R: Rng + CryptoRng>(
     ssk: &ServerSecretKey, // Server's own secret key (SIDms)
     request: &UserAuthRequest,
     own_id: &[u8], // Server's own ID (IDms)
     params: &SystemParameters,
     rng: &mut R,
     key_len_bytes: usize, // Desired session key length
-) -> Result<(ServerAuthResponse, SessionKey), AAKAError> {
+    context: &[u8],
+    validation: PointValidation,
+) -> Result<(ServerAuthResponse, SessionKey, Transcript), AAKAError> {
+    let opened = open_request(ssk, request, own_id, params, key_len_bytes, validation)?;
+
+    // 4-5. Verify signature: σP =? W + h3(ID'u || R'u || X' || Tu) * X',
+    //      where W = Ru' + h0(IDu' || Ru') * Ppub
+    let step = step!("verify_signature");
+    if !opened.verify(params)? {
+        return Err(AAKAError::SignatureVerificationFailed);
+    }
+    step.end();
+
+    respond(&opened, own_id, params, rng, key_len_bytes, context)
+}
+
+/// Processes several users' authentication requests at once (e.g. from a gateway).
+///
+/// The signatures of all requests that decrypt correctly are checked together as one
+/// random linear combination, `Σ ρi·(Ru'i + h0i·Ppub + h3i·X'i − σi·P) == O`, i.e. a single
+/// MSM instead of one per request. Only when the combined check fails is every signature
+/// checked on its own, to find the bad ones.
+///
+/// Returns one result per request in input order: a bad request is reported in its own slot
+/// and does not affect the others.
+pub fn process_user_requests<R: Rng + CryptoRng>(
+    ssk: &ServerSecretKey,
+    requests: &[UserAuthRequest],
+    own_id: &[u8],
+    params: &SystemParameters,
+    rng: &mut R,
+    key_len_bytes: usize,
+) -> Vec<Result<(ServerAuthResponse, SessionKey, Transcript), AAKAError>> {
+    process_user_requests_with_validation(
+        ssk,
+        requests,
+        own_id,
+        params,
+        rng,
+        key_len_bytes,
+        PointValidation::Strict,
+    )
+}
+
+/// Like [`process_user_requests`], but parses the decrypted points with the given
+/// [`PointValidation`] (see [`process_user_request_with_validation`]).
+pub fn process_user_requests_with_validation<R: Rng + CryptoRng>(
+    ssk: &ServerSecretKey,
+    requests: &[UserAuthRequest],
+    own_id: &[u8],
+    params: &SystemParameters,
+    rng: &mut R,
+    key_len_bytes: usize,
+    validation: PointValidation,
+) -> Vec<Result<(ServerAuthResponse, SessionKey, Transcript), AAKAError>> {
+    let opened: Vec<Result<Opened, AAKAError>> = requests
+        .iter()
+        .map(|request| open_request(ssk, request, own_id, params, key_len_bytes, validation))
+        .collect();
+
+    let candidates: Vec<&Opened> = opened.iter().filter_map(|o| o.as_ref().ok()).collect();
+    // 组合校验本身出错时按全部未通过处理，逐个校验
+    let all_valid = batch_verify(params, &candidates, rng).unwrap_or(false);
+
+    opened
+        .into_iter()
+        .map(|opened| {
+            let opened = opened?;
+            if !all_valid && !opened.verify(params)? {
+                return Err(AAKAError::SignatureVerificationFailed);
+            }
+            respond(&opened, own_id, params, rng, key_len_bytes, &[])
+        })
+        .collect()
+}
+
+/// 解密后的 N = IDu' || Ru' || X'，两个点均为压缩编码，其余的前缀是用户 ID
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecryptedRequest {
+    pub id_u: Vec<u8>,
+    pub r_u: G1Point,
+    pub x: G1Point,
+}
+
+impl DecryptedRequest {
+    /// 按长度切分解密后的 `payload` 并解析 Ru'、X'（按 `validation` 检查子群）。
+    /// 长度不足以容纳两个点或不含用户 ID 时返回 [`AAKAError::Deserialization`]，
+    /// 点不合法时返回指明 Ru' 或 X' 的 [`AAKAError::Deserialization`]
+    pub fn parse(payload: &[u8], validation: PointValidation) -> Result<Self, AAKAError> {
+        let (id_u, points) = Self::split(payload)?;
+        let (r_u, x) = points.split_at(hash_utils::compressed_g1_len());
+        let point = |name: &str, bytes: &[u8]| {
+            deserialize_point_validated(bytes, validation).map_err(|e| {
+                AAKAError::Deserialization(format!(
+                    "Failed to deserialize {name} from the decrypted N ({e})"
+                ))
+            })
+        };
+        Ok(Self {
+            id_u: id_u.to_vec(),
+            r_u: point("Ru'", r_u)?,
+            x: point("X'", x)?,
+        })
+    }
+
+    /// 解密后的 Ru' 与 X' 都不是素数阶子群中的点，即整个 `payload` 看起来是随机字节。
+    ///
+    /// 用错误的密钥解密时两个点都几乎不可能通过子群检查；N 在传输中只损坏或被篡改了一部分时，
+    /// 至少有一个点仍然合法
+    fn is_garbled(payload: &[u8]) -> bool {
+        let invalid = |bytes: &[u8]| {
+            deserialize_point_validated::<G1Point>(bytes, PointValidation::Strict).is_err()
+        };
+        Self::split(payload).is_ok_and(|(_, points)| {
+            let (r_u, x) = points.split_at(hash_utils::compressed_g1_len());
+            invalid(r_u) && invalid(x)
+        })
+    }
+
+    // 按长度切分为 IDu' 与 Ru' || X'
+    fn split(payload: &[u8]) -> Result<(&[u8], &[u8]), AAKAError> {
+        Ok(payload.split_at(Self::id_len(payload.len())?))
+    }
+
+    // 长度为 `len` 的 N 中用户 ID 的长度
+    fn id_len(len: usize) -> Result<usize, AAKAError> {
+        let points_len = 2 * hash_utils::compressed_g1_len();
+        match len.checked_sub(points_len) {
+            None => Err(AAKAError::Deserialization(format!(
+                "N is {len} bytes, too short to contain Ru' and X' ({points_len} bytes)"
+            ))),
+            Some(0) => Err(AAKAError::Deserialization(
+                "N contains Ru' and X' but no user ID".to_string(),
+            )),
+            Some(id_len) => Ok(id_len),
+        }
+    }
+}
+
+// 解密后的认证请求，签名尚未校验
+struct Opened {
+    decrypted: DecryptedRequest,
+    h_0: ScalarField,
+    h_3: ScalarField,
+    sigma: ScalarField,
+}
+
+impl Opened {
+    fn verify(&self, params: &SystemParameters) -> Result<bool, AAKAError> {
+        let DecryptedRequest { r_u, x, .. } = &self.decrypted;
+        verify_signature(params, r_u, x, self.h_0, self.h_3, self.sigma)
+    }
+}
+
+// 步骤 0-3：检查套件与时间戳，解密 N 得到 IDu'、Ru'、X'，并计算签名校验所需的 h0、h3
+fn open_request(
+    ssk: &ServerSecretKey,
+    request: &UserAuthRequest,
+    own_id: &[u8],
+    params: &SystemParameters,
+    key_len_bytes: usize,
+    validation: PointValidation,
+) -> Result<Opened, AAKAError> {
+    // 0. 算法套件不一致时后续计算只会得到不同的会话密钥，先明确拒绝
+    Suite::negotiate(request.suite_id, &[params.suite(key_len_bytes)?])?;
+
     // 1. Check timestamp Tu freshness
     if !is_timestamp_fresh(request.timestamp)? {
         return Err(AAKAError::InvalidTimestamp);
     }
 
+    // σ = 0 不会是合法签名，在配对之前直接拒绝，避免退化的标量进入后续运算。
+    // 反序列化已保证 σ 落在标量域内，这里只需检查零
+    if request.sigma.is_zero() {
+        return Err(AAKAError::InvalidInput(
+            "Signature sigma must be non-zero".to_string(),
+        ));
+    }
+
     // 2. Compute gx = e(M, SIDms)
     //    M is from request, SIDms is server's secret key
+    let step = step!("pairing");
     let g_x = Curve::pairing(request.m, ssk.sid_ms); // M is G1, SIDms is G2
+    step.end();
 
     // 3. Decrypt N = h2(gx) XOR (IDu || Ru || X) to get IDu', Ru', X'
-    //    First, deserialize Ru' and X' which are G1 points. Need their lengths.
-    //    Let's assume standard compressed G1 point size.
-    let g1_compressed_size = G1AffinePoint::default().compressed_size();
+    //    长度不足时不必计算 h2
+    let step = step!("decrypt_n");
     let n_len = request.n.len();
-    if n_len <= g1_compressed_size * 2 {
-        return Err(AAKAError::Deserialization(
-            "N parameter too short to contain Ru and X".to_string(),
-        ));
-    }
-    let id_len = n_len - 2 * g1_compressed_size;
-    let ru_offset = id_len;
-    let x_offset = id_len + g1_compressed_size;
-
-    let h2_output = hash_utils::h2(&g_x, n_len)?; // Use the fixed gx
+    DecryptedRequest::id_len(n_len)?;
+    let h2_output = hash_utils::h2(&g_x, n_len, params.xof)?; // Use the fixed gx
 
     // Perform XOR to get original payload bytes
     if h2_output.len() != request.n.len() {
@@ -62,39 +288,50 @@ pub fn process_user_request<R: Rng + CryptoRng>(
         .map(|(h, p)| h ^ p)
         .collect();
 
-    // Extract components
-    let id_u_prime = &n_payload[0..id_len];
-    let r_u_prime_bytes = &n_payload[ru_offset..x_offset];
-    let x_prime_bytes = &n_payload[x_offset..];
-
-    // Deserialize points
-    let r_u_prime = G1AffinePoint::deserialize_compressed(r_u_prime_bytes)
-        .map_err(|e| AAKAError::Deserialization(format!("Failed to deserialize Ru': {}", e)))?
-        .into_group(); // Convert to Projective for potential calculations
-    let x_prime = G1AffinePoint::deserialize_compressed(x_prime_bytes)
-        .map_err(|e| AAKAError::Deserialization(format!("Failed to deserialize X': {}", e)))?
-        .into_group();
-
-    // 4. Compute W = Ru' + h0(IDu' || Ru') * Ppub_hat
-    let h_0 = hash_utils::h0(id_u_prime, &r_u_prime)?;
-    let h0_ppub = params.p_pub * h_0; // <-- **Corrected: Use params.p_pub (sP)**
-    let w = r_u_prime.add(&h0_ppub); // <-- **Corrected: W = R'u + h0 * sP**
-
-    // 5. Verify signature: σP =? W + h3(ID'u || R'u || X' || Tu) * X'
-    let h_3 = hash_utils::h3(id_u_prime, &r_u_prime, &x_prime, request.timestamp)?;
-    let h3_x_prime = x_prime * h_3;
-    let rhs = w.add(&h3_x_prime); // Now RHS = R'u + h0*sP + h3*xP
+    // N 是用 IDms 对应的密钥加密的；用户使用了其他服务器的 ID 时解出的全是随机字节，
+    // Ru' 与 X' 都不是合法的点，此时报告身份不一致。只有一个点不合法说明 N 在传输中损坏或
+    // 被篡改，原样返回解析错误。长度已在解密前检查，这里的错误只来自 Ru'、X'
+    let decrypted = DecryptedRequest::parse(&n_payload, validation).map_err(|e| {
+        if !DecryptedRequest::is_garbled(&n_payload) {
+            return e;
+        }
+        AAKAError::ServerIdentityMismatch(format!(
+            "{e}; the request was likely encrypted for a server ID other than {:?}",
+            String::from_utf8_lossy(own_id)
+        ))
+    })?;
+    step.end();
 
-    let sigma_p = params.p * request.sigma; // LHS = (ru + s*h0 + x*h3) * P
-
-    if sigma_p != rhs {
-        return Err(AAKAError::SignatureVerificationFailed);
-    }
+    let h_0 = hash_utils::h0(&decrypted.id_u, &decrypted.r_u)?;
+    let h_3 = hash_utils::h3(
+        &decrypted.id_u,
+        &decrypted.r_u,
+        &decrypted.x,
+        request.timestamp,
+    )?;
+    Ok(Opened {
+        decrypted,
+        h_0,
+        h_3,
+        sigma: request.sigma,
+    })
+}
 
-    // User is authenticated if signature is valid.
+// 步骤 6-11：签名已通过校验，生成响应并计算会话密钥
+fn respond<R: Rng + CryptoRng>(
+    opened: &Opened,
+    own_id: &[u8],
+    params: &SystemParameters,
+    rng: &mut R,
+    key_len_bytes: usize,
+    context: &[u8],
+) -> Result<(ServerAuthResponse, SessionKey, Transcript), AAKAError> {
+    let decrypted = &opened.decrypted;
+    let (id_u_prime, r_u_prime, x_prime, h_0) =
+        (&decrypted.id_u[..], decrypted.r_u, decrypted.x, opened.h_0);
 
-    // 6. Choose random y from Z_q*
-    let y = ScalarField::rand(rng);
+    // 6. Choose random y from Z_q*, never one drawn recently in this process
+    let y = Secret::new(nonce::fresh_nonce(rng)?);
     if y.is_zero() {
         return Err(AAKAError::CryptoError(
             "Server random scalar y is zero".to_string(),
@@ -102,7 +339,7 @@ pub fn process_user_request<R: Rng + CryptoRng>(
     }
 
     // 7. Compute Y = y * P
-    let y_pub = params.p * y;
+    let y_pub = params.p * *y;
 
     // 8. Get timestamp Tms
     let timestamp_ms = get_current_timestamp()?;
@@ -110,10 +347,9 @@ pub fn process_user_request<R: Rng + CryptoRng>(
     // 9. Compute t = h4(IDu' || IDms || X' || Y || Tms)
     let t = hash_utils::h4(id_u_prime, own_id, &x_prime, &y_pub, timestamp_ms)?;
 
+    let step = step!("derive_key");
     // 10. Compute Kms-u = y * (t * X' + W)
-    let tx_prime = x_prime * t;
-    let inner_k = tx_prime.add(&w);
-    let k_ms_u_point = inner_k * y; // This is a G1Point
+    let k_ms_u_point = compute_k_ms_u(params, &y, t, &x_prime, &r_u_prime, h_0)?; // This is a G1Point
 
     // 11. Compute Session Key SKms-u = h5(Kms-u || IDu' || IDms || X' || Y)
     let session_key_bytes = hash_utils::h5(
@@ -123,7 +359,10 @@ pub fn process_user_request<R: Rng + CryptoRng>(
         &x_prime,
         &y_pub,
         key_len_bytes,
+        params.deployment.as_bytes(),
+        context,
     )?;
+    step.end();
 
     // Prepare response
     let response = ServerAuthResponse {
@@ -132,5 +371,68 @@ pub fn process_user_request<R: Rng + CryptoRng>(
         timestamp: timestamp_ms,
     };
 
-    Ok((response, SessionKey(session_key_bytes)))
+    let transcript = Transcript {
+        user_id: id_u_prime.to_vec(),
+        server_id: own_id.to_vec(),
+        x: x_prime,
+        y: y_pub,
+    };
+
+    Ok((response, SessionKey(session_key_bytes), transcript))
+}
+
+/// Computes the server's shared point Kms-u = y * (t * X' + W), where W = Ru' + h0 * Ppub,
+/// as the single MSM yt * X' + y * Ru' + y * h0 * Ppub. Must equal the user's
+/// [`crate::user::compute_k_u_ms`] before either side hashes it with `h5`.
+pub(crate) fn compute_k_ms_u(
+    params: &SystemParameters,
+    y: &ScalarField,
+    t: ScalarField,
+    x_prime: &G1Point,
+    r_u_prime: &G1Point,
+    h_0: ScalarField,
+) -> Result<G1Point, AAKAError> {
+    let scalars = Secret::new([*y * t, *y, *y * h_0]);
+    msm(&[*x_prime, *r_u_prime, params.p_pub], &*scalars)
+}
+
+/// Checks σP = Ru' + h0 * Ppub + h3 * X' as a single MSM:
+/// Ru' + h0 * Ppub + h3 * X' - σ * P == O.
+pub(crate) fn verify_signature(
+    params: &SystemParameters,
+    r_u: &G1Point,
+    x_pub: &G1Point,
+    h_0: ScalarField,
+    h_3: ScalarField,
+    sigma: ScalarField,
+) -> Result<bool, AAKAError> {
+    let sum = msm(
+        &[*r_u, params.p_pub, *x_pub, params.p],
+        &[ScalarField::one(), h_0, h_3, -sigma],
+    )?;
+    Ok(sum.is_zero())
+}
+
+// Σ ρi·(Ru'i + h0i·Ppub + h3i·X'i − σi·P) == O，ρi 为随机标量
+fn batch_verify<R: Rng + CryptoRng>(
+    params: &SystemParameters,
+    opened: &[&Opened],
+    rng: &mut R,
+) -> Result<bool, AAKAError> {
+    if opened.is_empty() {
+        return Ok(true);
+    }
+    let mut bases = Vec::with_capacity(2 * opened.len() + 2);
+    let mut scalars = Vec::with_capacity(2 * opened.len() + 2);
+    let (mut p_pub_scalar, mut p_scalar) = (ScalarField::zero(), ScalarField::zero());
+    for o in opened {
+        let rho = ScalarField::rand(rng);
+        bases.extend([o.decrypted.r_u, o.decrypted.x]);
+        scalars.extend([rho, rho * o.h_3]);
+        p_pub_scalar += rho * o.h_0;
+        p_scalar -= rho * o.sigma;
+    }
+    bases.extend([params.p_pub, params.p]);
+    scalars.extend([p_pub_scalar, p_scalar]);
+    Ok(msm(&bases, &scalars)?.is_zero())
 }
@@ -0,0 +1,87 @@
+//! 握手使用的算法套件。
+//!
+//! 用户与服务器必须使用相同的曲线、哈希、`h2` 的 XOF 与会话密钥长度，任何一项不一致时
+//! 握手都会失败，且只表现为“双方的会话密钥不同”。认证请求携带 `suite_id`，服务器在做任何
+//! 计算之前先比对，不一致时返回 [`AAKAError::UnsupportedSuite`] 并列出自己支持的套件。
+//!
+//! `suite_id` 的编码：高 4 位为曲线与哈希（目前只有 `1` = BLS12-381 + SHA3-256），
+//! 接下来 4 位为 XOF（`1` = SHAKE128，`2` = SHAKE256），低 8 位为会话密钥的字节数。
+//! 例如 BLS12-381 + SHA3-256 + SHAKE256 + 32 字节密钥为 `0x1220`。
+use crate::{AAKAError, hash_utils::Xof};
+use std::fmt;
+
+// BLS12-381 + SHA3-256
+const CURVE_HASH_BLS12_381_SHA3: u16 = 1;
+
+/// 会话密钥的默认长度（字节）
+pub const DEFAULT_KEY_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Suite {
+    pub xof: Xof,
+    pub key_len: usize,
+}
+
+impl Suite {
+    /// 会话密钥长度必须在 1 到 255 字节之间，才能编码进 `suite_id`
+    pub fn new(xof: Xof, key_len: usize) -> Result<Self, AAKAError> {
+        if !(1..=u8::MAX as usize).contains(&key_len) {
+            return Err(AAKAError::InvalidInput(format!(
+                "Session key length {key_len} is not supported, must be between 1 and 255 bytes"
+            )));
+        }
+        Ok(Self { xof, key_len })
+    }
+
+    pub fn id(&self) -> u16 {
+        CURVE_HASH_BLS12_381_SHA3 << 12 | u16::from(self.xof.tag()) << 8 | self.key_len as u16
+    }
+
+    pub fn from_id(id: u16) -> Result<Self, AAKAError> {
+        let unknown = || AAKAError::InvalidInput(format!("Unknown cipher suite {id:#06x}"));
+        if id >> 12 != CURVE_HASH_BLS12_381_SHA3 {
+            return Err(unknown());
+        }
+        let xof = match (id >> 8) & 0xf {
+            1 => Xof::Shake128,
+            2 => Xof::Shake256,
+            _ => return Err(unknown()),
+        };
+        Self::new(xof, usize::from(id as u8)).map_err(|_| unknown())
+    }
+
+    /// 请求的套件不在 `supported` 中时返回 [`AAKAError::UnsupportedSuite`]
+    pub fn negotiate(requested: u16, supported: &[Suite]) -> Result<Suite, AAKAError> {
+        supported
+            .iter()
+            .find(|suite| suite.id() == requested)
+            .copied()
+            .ok_or_else(|| AAKAError::UnsupportedSuite {
+                requested,
+                supported: supported.iter().map(Suite::id).collect(),
+            })
+    }
+}
+
+impl fmt::Display for Suite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#06x} (BLS12-381, SHA3-256, {}, {}-byte key)",
+            self.id(),
+            self.xof.as_str(),
+            self.key_len
+        )
+    }
+}
+
+/// 错误信息中列出的套件：能解析的给出完整描述，否则只给出编号
+pub(crate) fn describe(ids: &[u16]) -> String {
+    ids.iter()
+        .map(|&id| match Suite::from_id(id) {
+            Ok(suite) => suite.to_string(),
+            Err(_) => format!("{id:#06x}"),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
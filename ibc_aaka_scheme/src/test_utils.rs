@@ -0,0 +1,36 @@
+//! 各模块单元测试共用的辅助类型。
+use ark_std::rand::{CryptoRng, RngCore};
+
+/// 按给定序列输出 u64，用完后从头重复。`pos` 是已经输出的字数
+pub struct Rigged {
+    words: Vec<u64>,
+    pub pos: usize,
+}
+
+impl Rigged {
+    pub fn new(words: Vec<u64>) -> Self {
+        Self { words, pos: 0 }
+    }
+}
+
+impl RngCore for Rigged {
+    fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+    fn next_u64(&mut self) -> u64 {
+        let word = self.words[self.pos % self.words.len()];
+        self.pos += 1;
+        word
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            chunk.copy_from_slice(&self.next_u64().to_le_bytes()[..chunk.len()]);
+        }
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), ark_std::rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for Rigged {}
@@ -1,5 +1,6 @@
 use crate::{
     AAKAError,
+    G1Point,
     ScalarField,
     ServerAuthResponse,
     SessionKey,
@@ -8,10 +9,11 @@ use crate::{
     UserSecretKey,
     UserState,
     hash_utils, // Use the hash functions
+    nonce,
+    suite::DEFAULT_KEY_LEN,
 };
 use ark_ec::{CurveGroup, Group};
 use ark_ff::PrimeField;
-use ark_ff::UniformRand;
 use ark_serialize::CanonicalSerialize;
 // Need Field for checks, UniformRand for random x
 use ark_std::Zero;
@@ -22,6 +24,13 @@ use ark_std::{ops::Add, vec::Vec}; // Need Add for scalar math
 
 /// User initiates the authentication process.
 /// Generates the request message to be sent to the MEC server.
+///
+/// The request announces the suite for a [`DEFAULT_KEY_LEN`]-byte session key; use
+/// [`initiate_authentication_with_key_len`] for other lengths.
+///
+/// `IDu` and `Ru` travel only inside `N`, masked under a fresh `x`, so passive observers
+/// cannot link sessions; the MEC server still sees them. Users who must not be linked by
+/// the server should authenticate with RC-issued pseudonyms (`/register/pseudonym`).
 pub fn initiate_authentication<R: Rng + CryptoRng>(
     usk: &UserSecretKey,
     user_id: &[u8],
@@ -29,8 +38,24 @@ pub fn initiate_authentication<R: Rng + CryptoRng>(
     params: &SystemParameters,
     rng: &mut R,
 ) -> Result<(UserAuthRequest, UserState), AAKAError> {
-    // 1. Select random x from Z_q*
-    let x = ScalarField::rand(rng);
+    initiate_authentication_with_key_len(usk, user_id, server_id, params, DEFAULT_KEY_LEN, rng)
+}
+
+/// Like [`initiate_authentication`], but announces the suite for a `key_len_bytes`-byte
+/// session key. The same length must be passed to [`process_server_response`], and the
+/// server rejects the request if it derives keys of another length.
+pub fn initiate_authentication_with_key_len<R: Rng + CryptoRng>(
+    usk: &UserSecretKey,
+    user_id: &[u8],
+    server_id: &[u8],
+    params: &SystemParameters,
+    key_len_bytes: usize,
+    rng: &mut R,
+) -> Result<(UserAuthRequest, UserState), AAKAError> {
+    let suite_id = params.suite(key_len_bytes)?.id();
+
+    // 1. Select random x from Z_q*, never one drawn recently in this process
+    let x = nonce::fresh_nonce(rng)?;
     if x.is_zero() {
         return Err(AAKAError::CryptoError(
             "User random scalar x is zero".to_string(),
@@ -65,7 +90,7 @@ pub fn initiate_authentication<R: Rng + CryptoRng>(
     };
 
     let n_payload_len = user_id.len() + r_u_bytes.len() + x_pub_bytes.len();
-    let h2_output = hash_utils::h2(&g_x, n_payload_len)?;
+    let h2_output = hash_utils::h2(&g_x, n_payload_len, params.xof)?;
 
     let n_payload = {
         let mut buf = Vec::with_capacity(n_payload_len);
@@ -104,6 +129,7 @@ pub fn initiate_authentication<R: Rng + CryptoRng>(
         n,
         sigma,
         timestamp: timestamp_u,
+        suite_id,
     };
 
     // Prepare the state to keep for response processing
@@ -112,6 +138,7 @@ pub fn initiate_authentication<R: Rng + CryptoRng>(
         temp_x_pub, // Store X = xP
         user_id: user_id.to_vec(),
         r_u: usk.r_u,
+        server_id: server_id.to_vec(),
     };
 
     Ok((request, state))
@@ -124,8 +151,30 @@ pub fn process_server_response(
     state: &UserState,   // State saved from initiate_authentication (contains x, X, IDu, Ru)
     response: &ServerAuthResponse,
     server_id: &[u8],
-    _params: &SystemParameters, // Needed for h4 check maybe? (Check h4 input args)
-    key_len_bytes: usize,       // Desired session key length
+    params: &SystemParameters, // Needed for h4 check maybe? (Check h4 input args)
+    key_len_bytes: usize,      // Desired session key length
+) -> Result<SessionKey, AAKAError> {
+    process_server_response_with_context(
+        usk,
+        state,
+        response,
+        server_id,
+        params,
+        key_len_bytes,
+        &[],
+    )
+}
+
+/// Like [`process_server_response`], but folds an application `context` into the session key.
+/// Must match the context given to [`crate::server::process_user_request_with_context`].
+pub fn process_server_response_with_context(
+    usk: &UserSecretKey,
+    state: &UserState,
+    response: &ServerAuthResponse,
+    server_id: &[u8],
+    params: &SystemParameters,
+    key_len_bytes: usize,
+    context: &[u8],
 ) -> Result<SessionKey, AAKAError> {
     // 1. Check timestamp Tms freshness
     //    In a real implementation, compare with current time and allowance.
@@ -134,6 +183,70 @@ pub fn process_server_response(
         return Err(AAKAError::InvalidTimestamp);
     }
 
+    verify_response_tag(state, response, server_id)?;
+
+    // Server is authenticated if t matches.
+    derive_session_key(
+        usk,
+        state,
+        response,
+        server_id,
+        params,
+        key_len_bytes,
+        context,
+    )
+}
+
+/// User processes the responses of several servers at once (multi-server fan-out).
+///
+/// Each item is `(response, server_id, state)` for one `initiate_authentication` call.
+/// All `h4` checks run first and keys are only derived for the responses that passed,
+/// so a tampered response costs a hash rather than a scalar multiplication. `h4` is a
+/// hash and cannot be aggregated; the per-response work left is one `(SIDu + xt)·Y` each.
+///
+/// Returns one result per item in input order: a bad response is reported in its own slot
+/// and does not affect the others.
+pub fn process_server_responses(
+    usk: &UserSecretKey,
+    items: &[(&ServerAuthResponse, &[u8], &UserState)],
+    params: &SystemParameters,
+    key_len_bytes: usize,
+) -> Vec<Result<SessionKey, AAKAError>> {
+    let verified: Vec<Result<(), AAKAError>> = items
+        .iter()
+        .map(|(response, server_id, state)| {
+            if !crate::is_timestamp_fresh(response.timestamp)? {
+                return Err(AAKAError::InvalidTimestamp);
+            }
+            verify_response_tag(state, response, server_id)
+        })
+        .collect();
+
+    items
+        .iter()
+        .zip(verified)
+        .map(|((response, server_id, state), verified)| {
+            verified?;
+            derive_session_key(usk, state, response, server_id, params, key_len_bytes, &[])
+        })
+        .collect()
+}
+
+// 检查 IDms 与发起认证时一致，并校验 t = h4(IDu || IDms || X || Y || Tms)
+fn verify_response_tag(
+    state: &UserState,
+    response: &ServerAuthResponse,
+    server_id: &[u8],
+) -> Result<(), AAKAError> {
+    // IDms 参与 h4 和 h5，与发起认证时使用的不一致时 t 必然校验失败，单独报告以便排查配置
+    if server_id != state.server_id {
+        return Err(AAKAError::ServerIdentityMismatch(format!(
+            "request was sent to {:?} but the response is checked as {:?}",
+            String::from_utf8_lossy(&state.server_id),
+            String::from_utf8_lossy(server_id)
+        )));
+    }
+
     // 2. Verify t = h4(IDu || IDms || X || Y || Tms)
     //    We need IDu, IDms, X (from state), Y (from response), Tms (from response)
     let computed_t = hash_utils::h4(
@@ -147,14 +260,42 @@ pub fn process_server_response(
     if computed_t != response.t {
         return Err(AAKAError::ServerResponseVerificationFailed);
     }
+    Ok(())
+}
 
-    // Server is authenticated if t matches.
+/// Computes the user's shared point Ku-ms = (SIDu + x * t) * Y. Must equal the server's
+/// [`crate::server::compute_k_ms_u`] before either side hashes it with `h5`.
+pub(crate) fn compute_k_u_ms(
+    usk: &UserSecretKey,
+    x: &ScalarField,
+    t: ScalarField,
+    y_pub: &G1Point,
+) -> G1Point {
+    let xt = *x * t; // x*t
+    let sidu_plus_xt = usk.sid_u.add(&xt); // SIDu + x*t
+    *y_pub * sidu_plus_xt // (SIDu + x*t) * Y
+}
+
+// 由已通过 h4 校验的响应计算会话密钥
+fn derive_session_key(
+    usk: &UserSecretKey,
+    state: &UserState,
+    response: &ServerAuthResponse,
+    server_id: &[u8],
+    params: &SystemParameters,
+    key_len_bytes: usize,
+    context: &[u8],
+) -> Result<SessionKey, AAKAError> {
+    // x = 0 时 K 与 x 无关，会话密钥只取决于 SIDu 与 Y；调用者自行构造的 UserState 在此拒绝
+    if state.x.is_zero() {
+        return Err(AAKAError::InvalidInput(
+            "User random scalar x must be non-zero".to_string(),
+        ));
+    }
 
     // 3. Compute Ku-ms = (SIDu + x * t) * Y (mod q for scalar part)
     //    t is the received (and verified) t from the server response
-    let xt = state.x * response.t; // x*t
-    let sidu_plus_xt = usk.sid_u.add(&xt); // SIDu + x*t
-    let k_u_ms_point = response.y * sidu_plus_xt; // (SIDu + x*t) * Y
+    let k_u_ms_point = compute_k_u_ms(usk, &state.x, response.t, &response.y);
 
     // 4. Compute SKu-ms = h5(Ku-ms || IDu || IDms || X || Y)
     let session_key_bytes = hash_utils::h5(
@@ -176,6 +317,8 @@ pub fn process_server_response(
         &state.temp_x_pub, // X
         &response.y,       // Y
         key_len_bytes,
+        params.deployment.as_bytes(),
+        context,
     )?;
 
     // **Correction Needed for h5:** Let's assume h5 should operate on a value derived from the common secret.
@@ -188,25 +331,3 @@ pub fn process_server_response(
 
     Ok(SessionKey(session_key_bytes))
 }
-
-// Helper functions (placeholders, need actual implementation)
-// These should ideally be in a separate utility module.
-
-pub fn get_current_timestamp() -> Result<u64, AAKAError> {
-    use std::time::{SystemTime, UNIX_EPOCH};
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map(|d| d.as_secs())
-        .map_err(|e| AAKAError::CryptoError(format!("System time error: {}", e)))
-}
-
-pub fn is_timestamp_fresh(timestamp: u64) -> Result<bool, AAKAError> {
-    const ALLOWED_SKEW_SECONDS: u64 = 300; // Allow 5 minutes skew
-    let current_ts = get_current_timestamp()?;
-    let diff = if current_ts >= timestamp {
-        current_ts - timestamp
-    } else {
-        timestamp - current_ts // Handle potential clock skew in both directions
-    };
-    Ok(diff <= ALLOWED_SKEW_SECONDS)
-}
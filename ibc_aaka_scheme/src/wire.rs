@@ -0,0 +1,427 @@
+//! 线上消息的 CBOR 编码，以及密钥和消息的原始字节编码。
+//!
+//! 点和标量以压缩后的原始字节串编码，避免 JSON 中十六进制字符串带来的体积膨胀。
+//!
+//! 原始字节编码即 [`to_bytes`] 的输出（各字段按声明顺序压缩序列化后拼接），
+//! 通过各类型的 `TryFrom<&[u8]>` 解析，其中的点会经过 [`deserialize_point`] 的校验。
+//!
+//! CBOR 消息中的点默认压缩编码；[`Cbor::to_cbor_with`] 可改用非压缩编码，以带宽换取解码时
+//! 省去的开方运算。使用的编码记录在消息中，[`Cbor::from_cbor`] 据此解析。
+use crate::{
+    AAKAError, CheckedPoint, ScalarField, ServerAuthResponse, ServerSecretKey, SystemParameters,
+    UserAuthRequest, UserSecretKey, deserialize_point, deserialize_point_with,
+    hash_utils::{compressed_g1_len, compressed_g2_len},
+};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize, Compress, Validate};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::str::FromStr;
+
+/// CBOR 消息的 `Content-Type` / `Accept` 取值
+pub const CBOR_CONTENT_TYPE: &str = "application/cbor";
+
+/// 客户端希望 CBOR 响应使用的点编码（`compressed` 或 `uncompressed`）
+pub const POINT_ENCODING_HEADER: &str = "x-aaka-point-encoding";
+
+/// 曲线点的编码方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PointEncoding {
+    /// 只保存 x 坐标和符号位，体积小，解码需要开方
+    #[default]
+    Compressed,
+    /// 保存完整的 x、y 坐标，体积约为两倍，解码更快
+    Uncompressed,
+}
+
+impl PointEncoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PointEncoding::Compressed => "compressed",
+            PointEncoding::Uncompressed => "uncompressed",
+        }
+    }
+
+    fn is_compressed(&self) -> bool {
+        *self == PointEncoding::Compressed
+    }
+}
+
+impl FromStr for PointEncoding {
+    type Err = AAKAError;
+
+    fn from_str(s: &str) -> Result<Self, AAKAError> {
+        match s.trim() {
+            "compressed" => Ok(PointEncoding::Compressed),
+            "uncompressed" => Ok(PointEncoding::Uncompressed),
+            other => Err(AAKAError::InvalidInput(format!(
+                "Unknown point encoding {other:?}, expected \"compressed\" or \"uncompressed\""
+            ))),
+        }
+    }
+}
+
+impl From<PointEncoding> for Compress {
+    fn from(encoding: PointEncoding) -> Self {
+        match encoding {
+            PointEncoding::Compressed => Compress::Yes,
+            PointEncoding::Uncompressed => Compress::No,
+        }
+    }
+}
+
+/// 可以编码为 CBOR 的线上消息
+pub trait Cbor: Sized {
+    fn to_cbor(&self) -> Result<Vec<u8>, AAKAError> {
+        self.to_cbor_with(PointEncoding::Compressed)
+    }
+    /// 按 `encoding` 编码其中的点，并在消息中记录所用的编码
+    fn to_cbor_with(&self, encoding: PointEncoding) -> Result<Vec<u8>, AAKAError>;
+    fn from_cbor(bytes: &[u8]) -> Result<Self, AAKAError>;
+}
+
+#[derive(Serialize, Deserialize)]
+struct UserAuthRequestCbor {
+    #[serde(with = "serde_bytes")]
+    m: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    n: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    sigma: Vec<u8>,
+    timestamp: u64,
+    suite_id: u16,
+    // 缺省为压缩编码，兼容不带此字段的旧消息
+    #[serde(default, skip_serializing_if = "PointEncoding::is_compressed")]
+    encoding: PointEncoding,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ServerAuthResponseCbor {
+    #[serde(with = "serde_bytes")]
+    t: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    y: Vec<u8>,
+    timestamp: u64,
+    #[serde(default, skip_serializing_if = "PointEncoding::is_compressed")]
+    encoding: PointEncoding,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SystemParametersCbor {
+    #[serde(with = "serde_bytes")]
+    params: Vec<u8>,
+    #[serde(default, skip_serializing_if = "PointEncoding::is_compressed")]
+    encoding: PointEncoding,
+}
+
+/// 压缩序列化，结果可由对应类型的 `TryFrom<&[u8]>` 解析
+pub fn to_bytes<T: CanonicalSerialize>(item: &T) -> Result<Vec<u8>, AAKAError> {
+    to_bytes_with(item, PointEncoding::Compressed)
+}
+
+/// 按 `encoding` 序列化，需用相同的编码经 [`from_bytes_with`] 解析
+pub fn to_bytes_with<T: CanonicalSerialize>(
+    item: &T,
+    encoding: PointEncoding,
+) -> Result<Vec<u8>, AAKAError> {
+    let compress = encoding.into();
+    let mut buf = Vec::with_capacity(item.serialized_size(compress));
+    item.serialize_with_mode(&mut buf, compress)?;
+    Ok(buf)
+}
+
+pub(crate) fn from_bytes<T: CanonicalDeserialize>(bytes: &[u8]) -> Result<T, AAKAError> {
+    from_bytes_with(bytes, PointEncoding::Compressed)
+}
+
+/// 按 `encoding` 反序列化并校验（点在曲线上且属于素数阶子群）
+pub fn from_bytes_with<T: CanonicalDeserialize>(
+    bytes: &[u8],
+    encoding: PointEncoding,
+) -> Result<T, AAKAError> {
+    T::deserialize_with_mode(bytes, encoding.into(), Validate::Yes)
+        .map_err(|e| AAKAError::Deserialization(e.to_string()))
+}
+
+pub(crate) fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, AAKAError> {
+    let mut buf = Vec::new();
+    ciborium::into_writer(value, &mut buf).map_err(|e| AAKAError::Serialization(e.to_string()))?;
+    Ok(buf)
+}
+
+pub(crate) fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, AAKAError> {
+    ciborium::from_reader(bytes).map_err(|e| AAKAError::Deserialization(e.to_string()))
+}
+
+/// 逐字段解析原始字节编码的游标
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn point<P: CheckedPoint>(&mut self, len: usize) -> Result<P, AAKAError> {
+        if self.0.len() < len {
+            return Err(AAKAError::Deserialization(format!(
+                "Input too short: expected a {len}-byte point, got {} bytes",
+                self.0.len()
+            )));
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        deserialize_point(bytes)
+    }
+
+    fn field<T: CanonicalDeserialize>(&mut self) -> Result<T, AAKAError> {
+        T::deserialize_compressed(&mut self.0)
+            .map_err(|e| AAKAError::Deserialization(e.to_string()))
+    }
+
+    fn finish(self) -> Result<(), AAKAError> {
+        if !self.0.is_empty() {
+            return Err(AAKAError::Deserialization(format!(
+                "{} trailing bytes",
+                self.0.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<&[u8]> for SystemParameters {
+    type Error = AAKAError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, AAKAError> {
+        let mut reader = Reader(bytes);
+        let params = Self {
+            p: reader.point(compressed_g1_len())?,
+            p_pub: reader.point(compressed_g1_len())?,
+            p_pub_hat: reader.point(compressed_g1_len())?,
+            g: reader.field()?,
+            xof: reader.field()?,
+            deployment: reader.field()?,
+        };
+        reader.finish()?;
+        params.validate()?;
+        Ok(params)
+    }
+}
+
+impl TryFrom<&[u8]> for UserSecretKey {
+    type Error = AAKAError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, AAKAError> {
+        let mut reader = Reader(bytes);
+        let usk = Self {
+            r_u: reader.point(compressed_g1_len())?,
+            sid_u: reader.field::<ScalarField>()?,
+        };
+        reader.finish()?;
+        Ok(usk)
+    }
+}
+
+impl TryFrom<&[u8]> for ServerSecretKey {
+    type Error = AAKAError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, AAKAError> {
+        let mut reader = Reader(bytes);
+        let ssk = Self {
+            sid_ms: reader.point(compressed_g2_len())?,
+        };
+        reader.finish()?;
+        Ok(ssk)
+    }
+}
+
+impl TryFrom<&[u8]> for UserAuthRequest {
+    type Error = AAKAError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, AAKAError> {
+        let mut reader = Reader(bytes);
+        let request = Self {
+            m: reader.point(compressed_g1_len())?,
+            n: reader.field()?,
+            sigma: reader.field()?,
+            timestamp: reader.field()?,
+            suite_id: reader.field()?,
+        };
+        reader.finish()?;
+        Ok(request)
+    }
+}
+
+impl TryFrom<&[u8]> for ServerAuthResponse {
+    type Error = AAKAError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, AAKAError> {
+        let mut reader = Reader(bytes);
+        let response = Self {
+            t: reader.field()?,
+            y: reader.point(compressed_g1_len())?,
+            timestamp: reader.field()?,
+        };
+        reader.finish()?;
+        Ok(response)
+    }
+}
+
+impl Cbor for UserAuthRequest {
+    fn to_cbor_with(&self, encoding: PointEncoding) -> Result<Vec<u8>, AAKAError> {
+        encode(&UserAuthRequestCbor {
+            m: to_bytes_with(&self.m, encoding)?,
+            n: self.n.clone(),
+            sigma: to_bytes(&self.sigma)?,
+            timestamp: self.timestamp,
+            suite_id: self.suite_id,
+            encoding,
+        })
+    }
+
+    fn from_cbor(bytes: &[u8]) -> Result<Self, AAKAError> {
+        let msg: UserAuthRequestCbor = decode(bytes)?;
+        Ok(Self {
+            m: deserialize_point_with(&msg.m, msg.encoding)?,
+            n: msg.n,
+            sigma: from_bytes(&msg.sigma)?,
+            timestamp: msg.timestamp,
+            suite_id: msg.suite_id,
+        })
+    }
+}
+
+impl Cbor for ServerAuthResponse {
+    fn to_cbor_with(&self, encoding: PointEncoding) -> Result<Vec<u8>, AAKAError> {
+        encode(&ServerAuthResponseCbor {
+            t: to_bytes(&self.t)?,
+            y: to_bytes_with(&self.y, encoding)?,
+            timestamp: self.timestamp,
+            encoding,
+        })
+    }
+
+    fn from_cbor(bytes: &[u8]) -> Result<Self, AAKAError> {
+        let msg: ServerAuthResponseCbor = decode(bytes)?;
+        Ok(Self {
+            t: from_bytes(&msg.t)?,
+            y: deserialize_point_with(&msg.y, msg.encoding)?,
+            timestamp: msg.timestamp,
+        })
+    }
+}
+
+impl Cbor for SystemParameters {
+    fn to_cbor_with(&self, encoding: PointEncoding) -> Result<Vec<u8>, AAKAError> {
+        encode(&SystemParametersCbor {
+            params: to_bytes_with(self, encoding)?,
+            encoding,
+        })
+    }
+
+    fn from_cbor(bytes: &[u8]) -> Result<Self, AAKAError> {
+        let msg: SystemParametersCbor = decode(bytes)?;
+        from_bytes_with(&msg.params, msg.encoding)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rc, server, user};
+    use ark_std::rand::{SeedableRng, rngs::StdRng};
+
+    #[test]
+    fn test_cbor_roundtrip() {
+        let mut rng = StdRng::from_entropy();
+        let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+        let ssk = rc::register_server(&msk, b"server").unwrap();
+        let (request, _) =
+            user::initiate_authentication(&usk, b"alice", b"server", &params, &mut rng).unwrap();
+        let (response, _) =
+            server::process_user_request(&ssk, &request, b"server", &params, &mut rng, 32).unwrap();
+
+        let decoded = SystemParameters::from_cbor(&params.to_cbor().unwrap()).unwrap();
+        assert_eq!(decoded, params);
+        let decoded = UserAuthRequest::from_cbor(&request.to_cbor().unwrap()).unwrap();
+        assert_eq!(decoded, request);
+        let decoded = ServerAuthResponse::from_cbor(&response.to_cbor().unwrap()).unwrap();
+        assert_eq!(decoded, response);
+
+        assert!(UserAuthRequest::from_cbor(&[0xff, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_cbor_roundtrip_in_both_point_encodings() {
+        let mut rng = StdRng::from_entropy();
+        let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+        let ssk = rc::register_server(&msk, b"server").unwrap();
+        let (request, _) =
+            user::initiate_authentication(&usk, b"alice", b"server", &params, &mut rng).unwrap();
+        let (response, _) =
+            server::process_user_request(&ssk, &request, b"server", &params, &mut rng, 32).unwrap();
+
+        for encoding in [PointEncoding::Compressed, PointEncoding::Uncompressed] {
+            let bytes = params.to_cbor_with(encoding).unwrap();
+            assert_eq!(SystemParameters::from_cbor(&bytes).unwrap(), params);
+            let bytes = request.to_cbor_with(encoding).unwrap();
+            assert_eq!(UserAuthRequest::from_cbor(&bytes).unwrap(), request);
+            let bytes = response.to_cbor_with(encoding).unwrap();
+            assert_eq!(ServerAuthResponse::from_cbor(&bytes).unwrap(), response);
+        }
+
+        // 非压缩编码的点更大，默认编码与不带编码字段的旧消息一致
+        let compressed = request.to_cbor().unwrap();
+        let uncompressed = request.to_cbor_with(PointEncoding::Uncompressed).unwrap();
+        assert!(uncompressed.len() > compressed.len());
+        assert_eq!(
+            compressed,
+            request.to_cbor_with(PointEncoding::Compressed).unwrap()
+        );
+
+        // 非压缩编码同样校验点在曲线上：改动 y 坐标后被拒绝
+        let mut m = to_bytes_with(&request.m, PointEncoding::Uncompressed).unwrap();
+        *m.last_mut().unwrap() ^= 1;
+        assert!(deserialize_point_with::<crate::G1Point>(&m, PointEncoding::Uncompressed).is_err());
+
+        assert_eq!(
+            "uncompressed".parse::<PointEncoding>().unwrap(),
+            PointEncoding::Uncompressed
+        );
+        assert!("raw".parse::<PointEncoding>().is_err());
+    }
+
+    #[test]
+    fn test_raw_bytes_roundtrip_and_truncation() {
+        let mut rng = StdRng::from_entropy();
+        let (params, msk) = rc::gen_parameter_and_msk(&mut rng).unwrap();
+        let usk = rc::register_user(&msk, b"alice", &mut rng).unwrap();
+        let ssk = rc::register_server(&msk, b"server").unwrap();
+        let (request, _) =
+            user::initiate_authentication(&usk, b"alice", b"server", &params, &mut rng).unwrap();
+        let (response, _) =
+            server::process_user_request(&ssk, &request, b"server", &params, &mut rng, 32).unwrap();
+
+        fn check<T>(value: &T)
+        where
+            T: CanonicalSerialize
+                + PartialEq
+                + std::fmt::Debug
+                + for<'a> TryFrom<&'a [u8], Error = AAKAError>,
+        {
+            let bytes = to_bytes(value).unwrap();
+            assert_eq!(&T::try_from(&bytes[..]).unwrap(), value);
+
+            // 截断到任意长度都返回错误，多余的字节同样被拒绝
+            for len in [0, 1, bytes.len() / 2, bytes.len() - 1] {
+                assert!(T::try_from(&bytes[..len]).is_err());
+            }
+            let mut extended = bytes.clone();
+            extended.push(0);
+            assert!(T::try_from(&extended[..]).is_err());
+        }
+
+        check(&params);
+        check(&usk);
+        check(&ssk);
+        check(&request);
+        check(&response);
+    }
+}